@@ -0,0 +1,481 @@
+//! Derive macros for `demo_ntp::codec::TryWriteToBytes` and
+//! `demo_ntp::codec::TryReadFromBytes`.
+//!
+//! The fields of a struct are written one after the other, in declaration
+//! order, with their own implementation of the traits. Attributes change how a
+//! field or the whole struct is laid out:
+//!
+//! * `#[ntp(bits = N)]` packs consecutive fields into bit fields, most
+//!   significant bits first. A run of bit fields must fill whole bytes, up to
+//!   8 of them. Each field converts with `From<T> for u8` and `TryFrom<u8>`,
+//!   whose error converts into `CodecError`.
+//! * `#[ntp(size = N)]` gives a field exactly `N` bytes, padded with zeros.
+//! * `#[ntp(padding = N)]` follows a field with `N` zero bytes, which are
+//!   skipped when reading.
+//! * `#[ntp(validate = path)]` checks a field after reading it with
+//!   `fn(&T) -> Result<(), E>`, where `E` converts into `CodecError`.
+//! * `#[ntp(align = N)]` on the struct pads it with zeros to a multiple of `N`
+//!   bytes.
+//!
+//! Errors are `demo_ntp::codec::CodecError`s naming the innermost named field
+//! and the byte offset they happened at.
+
+use proc_macro::TokenStream;
+use proc_macro2::{Span, TokenStream as TokenStream2};
+use quote::{format_ident, quote};
+use syn::{
+    parse_macro_input, spanned::Spanned, Data, DeriveInput, Error, Fields, GenericParam, Ident,
+    LitInt, Member, Path, Result, Type,
+};
+
+#[proc_macro_derive(TryWriteToBytes, attributes(ntp))]
+pub fn derive_try_write_to_bytes(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand_write(&input)
+        .unwrap_or_else(Error::into_compile_error)
+        .into()
+}
+
+#[proc_macro_derive(TryReadFromBytes, attributes(ntp))]
+pub fn derive_try_read_from_bytes(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand_read(&input)
+        .unwrap_or_else(Error::into_compile_error)
+        .into()
+}
+
+/// Field of the struct being derived
+struct Field {
+    member: Member,
+    /// Local variable holding the field while reading
+    binding: Ident,
+    ty: Type,
+    bits: Option<u32>,
+    size: Option<usize>,
+    padding: usize,
+    validate: Option<Path>,
+}
+
+/// Piece of the wire format
+enum Item {
+    Field(Box<Field>),
+    /// Bit fields sharing `bytes` bytes
+    Bits {
+        fields: Vec<Field>,
+        bytes: usize,
+    },
+}
+
+struct Layout {
+    items: Vec<Item>,
+    align: Option<usize>,
+    /// Whether the struct is built with `Self { .. }` rather than `Self(..)`
+    named: bool,
+}
+
+fn layout(input: &DeriveInput) -> Result<Layout> {
+    if let Some(param) = input
+        .generics
+        .params
+        .iter()
+        .find(|param| !matches!(param, GenericParam::Lifetime(_)))
+    {
+        return Err(Error::new(
+            param.span(),
+            "only lifetime parameters are supported",
+        ));
+    }
+    let Data::Struct(data) = &input.data else {
+        return Err(Error::new(input.span(), "only structs can be derived"));
+    };
+
+    let mut align = None;
+    for attr in input
+        .attrs
+        .iter()
+        .filter(|attr| attr.path().is_ident("ntp"))
+    {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("align") {
+                align = Some(positive(&meta.value()?.parse()?)?);
+                Ok(())
+            } else {
+                Err(meta.error("expected `align`"))
+            }
+        })?;
+    }
+
+    let named = matches!(data.fields, Fields::Named(_));
+    let mut items = Vec::new();
+    let mut pending: Vec<Field> = Vec::new();
+    let mut pending_bits = 0;
+    for (index, field) in data.fields.iter().enumerate() {
+        let (member, binding) = match &field.ident {
+            Some(ident) => (Member::Named(ident.clone()), ident.clone()),
+            None => (Member::from(index), format_ident!("field_{index}")),
+        };
+        let mut parsed = Field {
+            member,
+            binding,
+            ty: field.ty.clone(),
+            bits: None,
+            size: None,
+            padding: 0,
+            validate: None,
+        };
+        for attr in field
+            .attrs
+            .iter()
+            .filter(|attr| attr.path().is_ident("ntp"))
+        {
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("bits") {
+                    let bits: LitInt = meta.value()?.parse()?;
+                    let value = positive(&bits)?;
+                    if value > 8 {
+                        return Err(Error::new(bits.span(), "bit fields hold at most 8 bits"));
+                    }
+                    parsed.bits = Some(value as u32);
+                } else if meta.path.is_ident("size") {
+                    parsed.size = Some(positive(&meta.value()?.parse()?)?);
+                } else if meta.path.is_ident("padding") {
+                    parsed.padding = positive(&meta.value()?.parse()?)?;
+                } else if meta.path.is_ident("validate") {
+                    parsed.validate = Some(meta.value()?.parse()?);
+                } else {
+                    return Err(meta.error("expected `bits`, `size`, `padding` or `validate`"));
+                }
+                Ok(())
+            })?;
+        }
+
+        match parsed.bits {
+            Some(bits) => {
+                if parsed.size.is_some() || parsed.padding > 0 {
+                    return Err(Error::new(
+                        field.span(),
+                        "bit fields cannot have a size or padding",
+                    ));
+                }
+                pending_bits += bits;
+                pending.push(parsed);
+                if pending_bits % 8 == 0 {
+                    let bytes = (pending_bits / 8) as usize;
+                    if bytes > 8 {
+                        return Err(Error::new(
+                            field.span(),
+                            "bit fields span more than 8 bytes",
+                        ));
+                    }
+                    items.push(Item::Bits {
+                        fields: std::mem::take(&mut pending),
+                        bytes,
+                    });
+                    pending_bits = 0;
+                }
+            }
+            None if !pending.is_empty() => {
+                return Err(Error::new(
+                    field.span(),
+                    "bit fields before this field do not fill whole bytes",
+                ));
+            }
+            None => items.push(Item::Field(Box::new(parsed))),
+        }
+    }
+    if !pending.is_empty() {
+        return Err(Error::new(
+            Span::call_site(),
+            "the last bit fields do not fill whole bytes",
+        ));
+    }
+    Ok(Layout {
+        items,
+        align,
+        named,
+    })
+}
+
+fn positive(literal: &LitInt) -> Result<usize> {
+    match literal.base10_parse::<usize>()? {
+        0 => Err(Error::new(literal.span(), "expected a positive number")),
+        value => Ok(value),
+    }
+}
+
+/// Shift and mask of each bit field of a group, most significant first
+fn bit_positions(fields: &[Field], bytes: usize) -> Vec<(u32, u64)> {
+    let mut shift = bytes as u32 * 8;
+    fields
+        .iter()
+        .map(|field| {
+            let bits = field.bits.expect("bit fields have a width");
+            shift -= bits;
+            (shift, (1u64 << bits) - 1)
+        })
+        .collect()
+}
+
+/// Maps an error of `field`, starting at `offset`, to a `CodecError` naming it
+fn context(field: &Field) -> TokenStream2 {
+    match &field.member {
+        Member::Named(ident) => {
+            let name = ident.to_string();
+            quote! { .in_field(#name, offset) }
+        }
+        Member::Unnamed(_) => quote! { .at(offset) },
+    }
+}
+
+/// Returns early unless `len` bytes are left at `offset`
+fn reserve(len: &TokenStream2, context: &TokenStream2) -> TokenStream2 {
+    quote! {
+        if bytes.len() - offset < #len {
+            return Err(
+                ::demo_ntp::codec::CodecError::buffer_too_small(#len, bytes.len() - offset)
+                    #context,
+            );
+        }
+    }
+}
+
+fn expand_write(input: &DeriveInput) -> Result<TokenStream2> {
+    let layout = layout(input)?;
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    let steps = layout.items.iter().map(|item| match item {
+        Item::Field(field) => {
+            let member = &field.member;
+            let context = context(field);
+            let write = match field.size {
+                Some(size) => {
+                    let reserve = reserve(&quote!(#size), &context);
+                    quote! {
+                        #reserve
+                        let field_bytes = &mut bytes[offset..offset + #size];
+                        let size = ::demo_ntp::codec::TryWriteToBytes::try_write_to_bytes(
+                            &self.#member,
+                            field_bytes,
+                        )
+                        .map_err(|error| ::demo_ntp::codec::CodecError::from(error) #context)?;
+                        field_bytes[size..].fill(0);
+                        total_bytes += #size;
+                    }
+                }
+                None => quote! {
+                    total_bytes += ::demo_ntp::codec::TryWriteToBytes::try_write_to_bytes(
+                        &self.#member,
+                        &mut bytes[offset..],
+                    )
+                    .map_err(|error| ::demo_ntp::codec::CodecError::from(error) #context)?;
+                },
+            };
+            let padding = field.padding;
+            let padding = (padding > 0).then(|| {
+                let reserve = reserve(&quote!(#padding), &context);
+                quote! {
+                    let offset = total_bytes;
+                    #reserve
+                    bytes[offset..offset + #padding].fill(0);
+                    total_bytes += #padding;
+                }
+            });
+            quote! {
+                let offset = total_bytes;
+                #write
+                #padding
+            }
+        }
+        Item::Bits { fields, bytes } => {
+            let parts =
+                fields
+                    .iter()
+                    .zip(bit_positions(fields, *bytes))
+                    .map(|(field, (shift, mask))| {
+                        let member = &field.member;
+                        quote! {
+                            bits |= (u64::from(u8::from(::core::clone::Clone::clone(&self.#member)))
+                                & #mask)
+                                << #shift;
+                        }
+                    });
+            let reserve = reserve(&quote!(#bytes), &context(&fields[0]));
+            quote! {
+                let offset = total_bytes;
+                #reserve
+                let mut bits = 0u64;
+                #(#parts)*
+                bytes[offset..offset + #bytes].copy_from_slice(&bits.to_be_bytes()[8 - #bytes..]);
+                total_bytes += #bytes;
+            }
+        }
+    });
+    let align = layout.align.map(|align| {
+        let reserve = reserve(&quote!(padding), &quote!(.at(offset)));
+        quote! {
+            let offset = total_bytes;
+            let padding = (#align - offset % #align) % #align;
+            #reserve
+            bytes[offset..offset + padding].fill(0);
+            total_bytes += padding;
+        }
+    });
+
+    Ok(quote! {
+        impl #impl_generics ::demo_ntp::codec::TryWriteToBytes for #name #ty_generics #where_clause {
+            type Error = ::demo_ntp::codec::CodecError;
+
+            fn try_write_to_bytes(&self, bytes: &mut [u8]) -> Result<usize, Self::Error> {
+                let mut total_bytes = 0usize;
+                #(#steps)*
+                #align
+                Ok(total_bytes)
+            }
+        }
+    })
+}
+
+fn expand_read(input: &DeriveInput) -> Result<TokenStream2> {
+    let layout = layout(input)?;
+    let name = &input.ident;
+    let (_, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    // The bytes are borrowed for the first lifetime of the struct, if any
+    let mut generics = input.generics.clone();
+    let lifetime = match generics.lifetimes().next() {
+        Some(param) => param.lifetime.clone(),
+        None => {
+            let lifetime: syn::Lifetime = syn::parse_quote!('ntp);
+            generics.params.insert(0, syn::parse_quote!(#lifetime));
+            lifetime
+        }
+    };
+    let (impl_generics, _, _) = generics.split_for_impl();
+
+    let validate = |field: &Field, context: &TokenStream2| {
+        field.validate.as_ref().map(|path| {
+            let binding = &field.binding;
+            quote! {
+                #path(&#binding)
+                    .map_err(|error| ::demo_ntp::codec::CodecError::from(error) #context)?;
+            }
+        })
+    };
+    let mut bindings = Vec::new();
+    let steps = layout
+        .items
+        .iter()
+        .map(|item| match item {
+            Item::Field(field) => {
+                let Field {
+                    binding, ty, size, ..
+                } = &**field;
+                bindings.push(&**field);
+                let context = context(field);
+                let read = match size {
+                    Some(size) => {
+                        let reserve = reserve(&quote!(#size), &context);
+                        quote! {
+                            #reserve
+                            let (#binding, _) =
+                                <#ty as ::demo_ntp::codec::TryReadFromBytes>::try_read_from_bytes(
+                                    &bytes[offset..offset + #size],
+                                )
+                                .map_err(|error| {
+                                    ::demo_ntp::codec::CodecError::from(error) #context
+                                })?;
+                            total_bytes += #size;
+                        }
+                    }
+                    None => quote! {
+                        let (#binding, size) =
+                            <#ty as ::demo_ntp::codec::TryReadFromBytes>::try_read_from_bytes(
+                                &bytes[offset..],
+                            )
+                            .map_err(|error| ::demo_ntp::codec::CodecError::from(error) #context)?;
+                        total_bytes += size;
+                    },
+                };
+                let validate = validate(field, &context);
+                let padding = field.padding;
+                let padding = (padding > 0).then(|| {
+                    let reserve = reserve(&quote!(#padding), &context);
+                    quote! {
+                        let offset = total_bytes;
+                        #reserve
+                        total_bytes += #padding;
+                    }
+                });
+                quote! {
+                    let offset = total_bytes;
+                    #read
+                    #validate
+                    #padding
+                }
+            }
+            Item::Bits { fields, bytes } => {
+                let parts = fields
+                    .iter()
+                    .zip(bit_positions(fields, *bytes))
+                    .map(|(field, (shift, mask))| {
+                        bindings.push(field);
+                        let Field { binding, ty, .. } = field;
+                        let context = context(field);
+                        let validate = validate(field, &context);
+                        quote! {
+                            let #binding = <#ty as ::core::convert::TryFrom<u8>>::try_from(
+                                ((bits >> #shift) & #mask) as u8,
+                            )
+                            .map_err(|error| ::demo_ntp::codec::CodecError::from(error) #context)?;
+                            #validate
+                        }
+                    })
+                    .collect::<Vec<_>>();
+                let reserve = reserve(&quote!(#bytes), &context(&fields[0]));
+                quote! {
+                    let offset = total_bytes;
+                    #reserve
+                    let mut group = [0u8; 8];
+                    group[8 - #bytes..].copy_from_slice(&bytes[offset..offset + #bytes]);
+                    let bits = u64::from_be_bytes(group);
+                    #(#parts)*
+                    total_bytes += #bytes;
+                }
+            }
+        })
+        .collect::<Vec<_>>();
+    let align = layout.align.map(|align| {
+        let reserve = reserve(&quote!(padding), &quote!(.at(offset)));
+        quote! {
+            let offset = total_bytes;
+            let padding = (#align - offset % #align) % #align;
+            #reserve
+            total_bytes += padding;
+        }
+    });
+
+    let construct = if layout.named {
+        let members = bindings.iter().map(|field| &field.member);
+        let values = bindings.iter().map(|field| &field.binding);
+        quote! { Self { #(#members: #values),* } }
+    } else {
+        let values = bindings.iter().map(|field| &field.binding);
+        quote! { Self(#(#values),*) }
+    };
+
+    Ok(quote! {
+        impl #impl_generics ::demo_ntp::codec::TryReadFromBytes<#lifetime> for #name #ty_generics
+        #where_clause
+        {
+            type Error = ::demo_ntp::codec::CodecError;
+
+            fn try_read_from_bytes(bytes: &#lifetime [u8]) -> Result<(Self, usize), Self::Error> {
+                let mut total_bytes = 0usize;
+                #(#steps)*
+                #align
+                Ok((#construct, total_bytes))
+            }
+        }
+    })
+}
@@ -0,0 +1,20 @@
+#![no_main]
+
+use demo_ntp::{
+    codec::{TryReadFromBytes, TryWriteToBytes},
+    ntp_message_protocol::{NtpPacketHeader, NtpPacketView},
+};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let view = NtpPacketView::new(data).ok();
+    assert_eq!(
+        view.map(|view| view.to_header()),
+        NtpPacketHeader::try_read_from_bytes(data).ok().map(|(header, _)| header)
+    );
+    if let Ok((header, size)) = NtpPacketHeader::try_read_from_bytes(data) {
+        let mut bytes = [0u8; 48];
+        assert_eq!(header.try_write_to_bytes(&mut bytes), Ok(size));
+        assert_eq!(bytes[..], data[..size]);
+    }
+});
@@ -0,0 +1,39 @@
+#![no_main]
+
+use demo_ntp::{
+    codec::{TryReadFromBytes, TryWriteToBytes},
+    types::{NtpShort, NtpTimestamp, Poll, Precision, RefId, Stratum},
+};
+use libfuzzer_sys::fuzz_target;
+
+/// Reads `data` as a `T` and, if it parses, checks that it is written back
+/// unchanged, also into buffers too small for it
+fn read<T>(data: &[u8])
+where
+    T: TryWriteToBytes + for<'a> TryReadFromBytes<'a>,
+{
+    if let Ok((value, size)) = T::try_read_from_bytes(data) {
+        let mut bytes = [0u8; 64];
+        for len in 0..size {
+            assert!(value.try_write_to_bytes(&mut bytes[..len]).is_err());
+        }
+        assert!(matches!(value.try_write_to_bytes(&mut bytes), Ok(written) if written == size));
+        assert_eq!(bytes[..size], data[..size]);
+    }
+}
+
+fuzz_target!(|data: &[u8]| {
+    read::<u8>(data);
+    read::<i8>(data);
+    read::<u16>(data);
+    read::<u32>(data);
+    read::<i32>(data);
+    read::<u64>(data);
+    read::<[u8; 16]>(data);
+    read::<Stratum>(data);
+    read::<Poll>(data);
+    read::<Precision>(data);
+    read::<RefId>(data);
+    read::<NtpShort>(data);
+    read::<NtpTimestamp>(data);
+});
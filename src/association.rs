@@ -0,0 +1,440 @@
+use std::{
+    fmt,
+    net::{SocketAddr, UdpSocket},
+    time::Duration,
+};
+
+use log::{debug, info, warn};
+
+use crate::{
+    client::{ClientError, NtpClient, NtpSample},
+    error::{NtpError, NtpResult},
+    filter::{ClockFilter, FilterSample, PHI},
+    logging,
+    ntp_message_protocol::NtpPacketHeader,
+    poll::PollScheduler,
+    refclock::{RefClock, RefClockSample},
+    selection::Candidate,
+    transport::Transport,
+    types::{
+        Leap, NtpShort, NtpTimestamp, Poll, Precision, Stratum, NTP_KISS_RATE, NTP_MAXSTRAT,
+        NTP_MODE_SERVER, NTP_VERSION_4,
+    },
+};
+
+/// Lower bound of the delay used in the root distance, in seconds
+const NTP_MINDISP: f64 = 0.005;
+
+/// Largest root distance of a selectable server by default, in seconds
+pub const NTP_MAXDIST: f64 = 1.5;
+
+/// Largest age of the reference time of a server by default, in seconds
+pub const NTP_MAX_REFERENCE_AGE: f64 = 86400.0;
+
+/// Thresholds past which the replies of a server are not used
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SampleLimits {
+    /// Largest root distance, in seconds
+    pub max_distance: f64,
+    /// Largest time since the server's clock was last updated, in seconds
+    pub max_reference_age: f64,
+}
+
+impl Default for SampleLimits {
+    fn default() -> Self {
+        Self {
+            max_distance: NTP_MAXDIST,
+            max_reference_age: NTP_MAX_REFERENCE_AGE,
+        }
+    }
+}
+
+/// Why the replies of a server are not used
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Rejection {
+    /// The leap indicator says the server is not synchronized
+    Unsynchronized,
+    /// The server advertises stratum 16 or above
+    Stratum,
+    /// The reference time is zero, the server was never synchronized
+    NoReference,
+    /// The reference time is older than the limit
+    StaleReference,
+    /// The root distance exceeds the limit
+    Distance,
+}
+
+impl Rejection {
+    /// Stable code of the reason, for logs and metrics
+    pub fn code(self) -> &'static str {
+        match self {
+            Self::Unsynchronized => "unsynchronized",
+            Self::Stratum => "stratum",
+            Self::NoReference => "no_reference",
+            Self::StaleReference => "stale_reference",
+            Self::Distance => "distance",
+        }
+    }
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.code())
+    }
+}
+
+/// Settings of one association
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AssociationConfig {
+    pub minpoll: i8,
+    pub maxpoll: i8,
+    /// Send a burst of requests at every poll
+    pub burst: bool,
+    /// Send a burst of requests while the server is unreachable
+    pub iburst: bool,
+}
+
+/// Where the samples of an association come from
+enum Source<T> {
+    Server(NtpClient<T>),
+    RefClock(Box<dyn RefClock>),
+}
+
+/// Client side of the exchange with one server, or with a reference clock:
+/// the samples received from it and how often it is polled
+pub struct Association<T = UdpSocket> {
+    name: String,
+    address: SocketAddr,
+    source: Source<T>,
+    filter: ClockFilter,
+    last: Option<NtpSample>,
+    kisses: u64,
+    scheduler: PollScheduler,
+    /// Precision of the local clock, in seconds
+    precision: f64,
+    limits: SampleLimits,
+    /// Why the last reply was not used
+    rejection: Option<Rejection>,
+    rejections: u64,
+}
+
+impl<T: Transport> Association<T>
+where
+    NtpError: From<T::Error>,
+{
+    /// Association with the server at `address`, known as `name` in the
+    /// configuration, queried with `client`
+    pub fn new(
+        name: impl Into<String>,
+        address: SocketAddr,
+        client: NtpClient<T>,
+        config: AssociationConfig,
+    ) -> Self {
+        Self::with_source(name, address, Source::Server(client), config)
+    }
+
+    /// Association with a reference clock, known by its pseudo-address
+    /// `address` and as `name` in the configuration
+    pub fn with_refclock(
+        name: impl Into<String>,
+        address: SocketAddr,
+        refclock: Box<dyn RefClock>,
+        config: AssociationConfig,
+    ) -> Self {
+        Self::with_source(name, address, Source::RefClock(refclock), config)
+    }
+
+    fn with_source(
+        name: impl Into<String>,
+        address: SocketAddr,
+        source: Source<T>,
+        config: AssociationConfig,
+    ) -> Self {
+        Self {
+            name: name.into(),
+            address,
+            source,
+            filter: ClockFilter::new(),
+            last: None,
+            kisses: 0,
+            scheduler: PollScheduler::new(config),
+            precision: 0.0,
+            limits: SampleLimits::default(),
+            rejection: None,
+            rejections: 0,
+        }
+    }
+
+    /// Stops using the replies of the server past `limits`
+    pub fn set_limits(&mut self, limits: SampleLimits) {
+        self.limits = limits;
+    }
+
+    /// Announces the precision of the local clock in the requests and adds
+    /// it to the dispersion of the samples
+    pub fn set_precision(&mut self, precision: Precision) {
+        if let Source::Server(client) = &mut self.source {
+            client.set_precision(precision);
+        }
+        self.precision = 2f64.powi(i8::from(precision).into());
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    /// Whether the source is a reference clock that does not name the second
+    pub fn pulse_only(&self) -> bool {
+        matches!(&self.source, Source::RefClock(refclock) if refclock.pulse_only())
+    }
+
+    /// Shift register with one bit per poll, set when the server replied
+    pub fn reach(&self) -> u8 {
+        self.scheduler.reach()
+    }
+
+    /// Number of kiss-o'-death packets received from the server
+    pub fn kisses(&self) -> u64 {
+        self.kisses
+    }
+
+    /// Why the replies of the server are not used, if they are not
+    pub fn rejection(&self) -> Option<Rejection> {
+        self.rejection
+    }
+
+    /// Number of replies of the server that were not used
+    pub fn rejections(&self) -> u64 {
+        self.rejections
+    }
+
+    /// Current poll interval, as a power of two in seconds
+    pub fn poll(&self) -> i8 {
+        self.scheduler.poll().into()
+    }
+
+    /// Time until the next request, lengthened by a fraction `random`, in
+    /// [0, 1), of the spread between polls
+    pub fn next_interval(&self, random: f64) -> Duration {
+        self.scheduler.interval(random)
+    }
+
+    /// Latest reply of the server
+    pub fn last_sample(&self) -> Option<&NtpSample> {
+        self.last.as_ref()
+    }
+
+    pub fn filter(&self) -> &ClockFilter {
+        &self.filter
+    }
+
+    /// Sends the next request of the poll schedule and feeds the reply to the
+    /// clock filter
+    pub fn poll_server(&mut self) -> NtpResult<Option<FilterSample>> {
+        let poll = self.poll();
+        let result = self.exchange();
+        self.log_poll_change(poll);
+        result
+    }
+
+    fn exchange(&mut self) -> NtpResult<Option<FilterSample>> {
+        let reachable = self.reach() != 0;
+        self.scheduler.request();
+        let sample = self.query();
+        let Ok(Some(sample)) = sample else {
+            if reachable && self.reach() == 0 {
+                warn!(target: logging::ASSOCIATION, server:% = self.name; "server unreachable");
+            }
+            return sample.map(|_| None);
+        };
+        if !reachable {
+            info!(
+                target: logging::ASSOCIATION,
+                server:% = self.name,
+                address:% = self.address;
+                "server reachable"
+            );
+        }
+        self.scheduler.reply();
+
+        let precision = 2f64.powi(i8::from(sample.header.precision).into()) + self.precision;
+        let filter_sample = FilterSample {
+            offset: sample.offset,
+            delay: sample.delay.max(0.0),
+            dispersion: precision + PHI * sample.delay.max(0.0),
+            time: sample.time,
+        };
+        let rejection = self.check(&sample);
+        self.last = Some(sample);
+        if let Some(reason) = rejection {
+            self.reject(Some(reason));
+            return Ok(None);
+        }
+        let selected = self.filter.add(filter_sample);
+        let distance = self.root_distance();
+        if distance.is_some_and(|distance| distance > self.limits.max_distance) {
+            self.reject(Some(Rejection::Distance));
+            return Ok(None);
+        }
+        // A sample selected while the server was rejected is new to the caller
+        let rejected = self.rejection.is_some();
+        self.reject(None);
+        Ok(if rejected {
+            self.filter.selected()
+        } else {
+            selected
+        })
+    }
+
+    /// Queries the server, or takes the latest sample of the reference clock,
+    /// which has none when nothing new came since the last poll
+    fn query(&mut self) -> NtpResult<Option<NtpSample>> {
+        let poll = self.scheduler.poll();
+        match &mut self.source {
+            Source::Server(client) => {
+                client.set_poll(poll);
+                let sample = client.query();
+                if let Err(ClientError::KissOfDeath(code)) = &sample {
+                    self.kisses += 1;
+                    if *code == NTP_KISS_RATE {
+                        self.scheduler.back_off();
+                    }
+                }
+                Ok(Some(sample?))
+            }
+            Source::RefClock(refclock) => Ok(refclock.sample()?.map(|sample| NtpSample {
+                source: self.address,
+                offset: sample.offset,
+                delay: 0.0,
+                time: sample.time,
+                header: refclock_header(refclock.as_ref(), &sample, poll),
+            })),
+        }
+    }
+
+    /// Checks that the server that sent `sample` is synchronized, to a recent
+    /// reference
+    fn check(&self, sample: &NtpSample) -> Option<Rejection> {
+        let header = &sample.header;
+        if header.leap_indicator == Leap::Unknown {
+            Some(Rejection::Unsynchronized)
+        } else if u8::from(header.stratum) >= NTP_MAXSTRAT {
+            Some(Rejection::Stratum)
+        } else if header.reftime == NtpTimestamp::new(0, 0) {
+            Some(Rejection::NoReference)
+        } else if header.xmt.diff_seconds(&header.reftime) > self.limits.max_reference_age {
+            Some(Rejection::StaleReference)
+        } else {
+            None
+        }
+    }
+
+    /// Records why the last reply was not used, or that it was
+    fn reject(&mut self, rejection: Option<Rejection>) {
+        if rejection.is_some() {
+            self.rejections += 1;
+        }
+        if rejection == self.rejection {
+            return;
+        }
+        match rejection {
+            Some(reason) => {
+                warn!(
+                    target: logging::ASSOCIATION,
+                    server:% = self.name,
+                    reason:% = reason;
+                    "server rejected"
+                )
+            }
+            None => info!(target: logging::ASSOCIATION, server:% = self.name; "server accepted"),
+        }
+        self.rejection = rejection;
+    }
+
+    /// Adapts the poll interval to an offset applied to the clock and the
+    /// jitter of the selection
+    pub fn adapt_poll(&mut self, offset: f64, jitter: f64) {
+        let poll = self.poll();
+        self.scheduler.adapt(offset, jitter);
+        self.log_poll_change(poll);
+    }
+
+    /// Returns to the shortest poll interval
+    pub fn reset_poll(&mut self) {
+        let poll = self.poll();
+        self.scheduler.reset();
+        self.log_poll_change(poll);
+    }
+
+    fn log_poll_change(&self, previous: i8) {
+        if self.poll() != previous {
+            debug!(
+                target: logging::ASSOCIATION,
+                server:% = self.name,
+                poll:% = self.poll();
+                "poll interval changed"
+            );
+        }
+    }
+
+    /// Forgets the samples, which are invalid after the clock has been stepped
+    pub fn reset(&mut self) {
+        self.filter.clear();
+    }
+
+    /// Candidate for the selection, if the server is reachable, synchronized
+    /// and has a selected sample
+    pub fn candidate(&self) -> Option<Candidate> {
+        let sample = self.filter.selected()?;
+        if self.reach() == 0 || self.rejection.is_some() {
+            return None;
+        }
+        Some(Candidate {
+            offset: sample.offset,
+            root_distance: self.root_distance()?,
+            jitter: self.filter.jitter(),
+        })
+    }
+
+    /// Bound of the error of the clock of the server relative to the primary
+    /// reference, from its root delay and dispersion and the delay and
+    /// dispersion of the samples, in seconds
+    pub fn root_distance(&self) -> Option<f64> {
+        let sample = self.filter.selected()?;
+        let last = self.last.as_ref()?;
+        Some(
+            (last.header.rootdelay.to_seconds() + sample.delay).max(NTP_MINDISP) / 2.0
+                + last.header.rootdisp.to_seconds()
+                + self.filter.dispersion()
+                + self.filter.jitter(),
+        )
+    }
+}
+
+/// Header a reference clock would send as a server of stratum 0
+fn refclock_header(
+    refclock: &dyn RefClock,
+    sample: &RefClockSample,
+    poll: Poll,
+) -> NtpPacketHeader {
+    let time = sample.time.add_seconds(sample.offset);
+    NtpPacketHeader {
+        leap_indicator: sample.leap,
+        version_number: NTP_VERSION_4,
+        mode: NTP_MODE_SERVER,
+        stratum: Stratum::from(0),
+        poll,
+        precision: refclock.precision(),
+        rootdelay: NtpShort::new(0, 0),
+        rootdisp: NtpShort::from_seconds(sample.dispersion),
+        refid: refclock.refid(),
+        reftime: time,
+        org: NtpTimestamp::new(0, 0),
+        rec: time,
+        xmt: time,
+    }
+}
@@ -0,0 +1,147 @@
+use md5::{Digest, Md5};
+use sha1::Sha1;
+
+use crate::codec::CodecError;
+
+/// Size of the key identifier that starts a MAC
+const NTP_KEY_ID_SIZE: usize = 4;
+
+/// Digest algorithm of a symmetric key
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyType {
+    Md5,
+    Sha1,
+}
+
+impl KeyType {
+    /// Size of the digest, in bytes
+    pub fn digest_len(&self) -> usize {
+        match self {
+            KeyType::Md5 => 16,
+            KeyType::Sha1 => 20,
+        }
+    }
+}
+
+/// Symmetric key used to authenticate packets with a message authentication
+/// code (MAC) appended after the header, as in RFC 5905
+#[derive(Clone, PartialEq, Eq)]
+pub struct SymmetricKey {
+    pub id: u32,
+    pub key_type: KeyType,
+    secret: Vec<u8>,
+}
+
+impl std::fmt::Debug for SymmetricKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SymmetricKey")
+            .field("id", &self.id)
+            .field("key_type", &self.key_type)
+            .finish_non_exhaustive()
+    }
+}
+
+impl SymmetricKey {
+    pub fn new(id: u32, key_type: KeyType, secret: impl Into<Vec<u8>>) -> Self {
+        Self {
+            id,
+            key_type,
+            secret: secret.into(),
+        }
+    }
+
+    /// Size of the MAC: key identifier followed by the digest
+    pub fn mac_len(&self) -> usize {
+        NTP_KEY_ID_SIZE + self.key_type.digest_len()
+    }
+
+    fn digest(&self, packet: &[u8]) -> Vec<u8> {
+        match self.key_type {
+            KeyType::Md5 => Md5::new()
+                .chain_update(&self.secret)
+                .chain_update(packet)
+                .finalize()
+                .to_vec(),
+            KeyType::Sha1 => Sha1::new()
+                .chain_update(&self.secret)
+                .chain_update(packet)
+                .finalize()
+                .to_vec(),
+        }
+    }
+
+    /// Writes the MAC of `packet` into `bytes` and returns its size
+    pub fn sign(&self, packet: &[u8], bytes: &mut [u8]) -> Result<usize, CodecError> {
+        let mac_len = self.mac_len();
+        if bytes.len() < mac_len {
+            return Err(CodecError::buffer_too_small(mac_len, bytes.len()).at(packet.len()));
+        }
+        bytes[..NTP_KEY_ID_SIZE].copy_from_slice(&self.id.to_be_bytes());
+        bytes[NTP_KEY_ID_SIZE..mac_len].copy_from_slice(&self.digest(packet));
+        Ok(mac_len)
+    }
+
+    /// Checks that `mac` is the MAC of `packet` with this key
+    pub fn verify(&self, packet: &[u8], mac: &[u8]) -> bool {
+        mac.len() == self.mac_len()
+            && mac_key_id(mac) == Some(self.id)
+            && constant_time_eq(&self.digest(packet), &mac[NTP_KEY_ID_SIZE..])
+    }
+}
+
+/// Whether `a` and `b` are equal, in a time that does not depend on where they
+/// differ, so that it does not tell how much of a forged MAC is right
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len()
+        && a.iter()
+            .zip(b)
+            .fold(0, |difference, (a, b)| difference | (a ^ b))
+            == 0
+}
+
+/// Key identifier a MAC was computed with
+pub fn mac_key_id(mac: &[u8]) -> Option<u32> {
+    let id = mac.get(..NTP_KEY_ID_SIZE)?;
+    Some(u32::from_be_bytes(id.try_into().ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signed_packet_verifies() {
+        let packet = [0x23u8; 48];
+        for key_type in [KeyType::Md5, KeyType::Sha1] {
+            let key = SymmetricKey::new(7, key_type, "secret");
+            let mut mac = [0u8; 24];
+
+            let size = key.sign(&packet, &mut mac).unwrap();
+
+            assert_eq!(size, 4 + key_type.digest_len());
+            assert_eq!(mac_key_id(&mac[..size]), Some(7));
+            assert!(key.verify(&packet, &mac[..size]));
+        }
+    }
+
+    #[test]
+    fn tampered_packet_or_wrong_key_fails() {
+        let packet = [0x23u8; 48];
+        let key = SymmetricKey::new(1, KeyType::Sha1, "secret");
+        let mut mac = [0u8; 24];
+        key.sign(&packet, &mut mac).unwrap();
+
+        let mut tampered = packet;
+        tampered[47] ^= 1;
+        assert!(!key.verify(&tampered, &mac));
+        assert!(!SymmetricKey::new(1, KeyType::Sha1, "other").verify(&packet, &mac));
+        assert!(!SymmetricKey::new(2, KeyType::Sha1, "secret").verify(&packet, &mac));
+        let mut forged = mac;
+        forged[23] ^= 1;
+        assert!(!key.verify(&packet, &forged));
+        assert_eq!(
+            key.sign(&packet, &mut [0u8; 8]),
+            Err(CodecError::buffer_too_small(24, 8).at(48))
+        );
+    }
+}
@@ -0,0 +1,548 @@
+//! NTP daemon: disciplines the local clock to the configured servers and
+//! serves time to clients. Reloads its configuration on SIGHUP, exits on
+//! SIGTERM or SIGINT, and reports its state to systemd when started as a
+//! `Type=notify` service.
+
+use std::{env, process::ExitCode};
+
+const USAGE: &str = "\
+Usage: demo-ntpd [OPTIONS]
+
+Options:
+  -c, --config FILE     Configuration file (default /etc/demo-ntpd.toml)
+  -f, --format FORMAT   Format of the configuration file: toml (default), ntp
+                        for an ntpd ntp.conf, or chrony for a chrony.conf
+  -h, --help            Print this help
+";
+
+const DEFAULT_CONFIG: &str = "/etc/demo-ntpd.toml";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Toml,
+    Ntp,
+    Chrony,
+}
+
+struct Options {
+    config: String,
+    format: Format,
+}
+
+fn parse_args(args: impl IntoIterator<Item = String>) -> Result<Options, String> {
+    let mut options = Options {
+        config: DEFAULT_CONFIG.to_string(),
+        format: Format::Toml,
+    };
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-c" | "--config" => options.config = args.next().ok_or("missing value for -c")?,
+            "-f" | "--format" => {
+                options.format = match args.next().ok_or("missing value for -f")?.as_str() {
+                    "toml" => Format::Toml,
+                    "ntp" => Format::Ntp,
+                    "chrony" => Format::Chrony,
+                    format => return Err(format!("unknown format: {format}")),
+                }
+            }
+            "-h" | "--help" => return Err(String::new()),
+            _ => return Err(format!("unknown argument: {arg}")),
+        }
+    }
+    Ok(options)
+}
+
+fn main() -> ExitCode {
+    let options = match parse_args(env::args().skip(1)) {
+        Ok(options) => options,
+        Err(error) if error.is_empty() => {
+            print!("{USAGE}");
+            return ExitCode::SUCCESS;
+        }
+        Err(error) => {
+            eprint!("demo-ntpd: {error}\n\n{USAGE}");
+            return ExitCode::from(2);
+        }
+    };
+
+    match daemon::run(&options) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(error) => {
+            eprintln!("demo-ntpd: {error}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod daemon {
+    pub fn run(_options: &super::Options) -> Result<(), String> {
+        Err("the daemon is only supported on Linux".to_string())
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod daemon {
+    use std::{
+        fmt::Write,
+        net::{SocketAddr, UdpSocket},
+        os::{linux::net::SocketAddrExt, unix::net::UnixDatagram},
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc, Mutex, RwLock,
+        },
+        thread::{self, JoinHandle},
+        time::{Duration, Instant},
+    };
+
+    use demo_ntp::{
+        clock::{linux::LinuxClock, measure_precision, Clock},
+        config::{Config, ConfigImport},
+        daemon::{associations_from_config, Daemon},
+        drift::{Drift, DriftFile},
+        error::NtpError,
+        leap::{LeapPolicy, LeapSecondTable, LeapSeconds},
+        logging::Verbosity,
+        server::{ServerState, ServerStats},
+        stats::{LoopStats, PeerStats, StatsFile, StatsKind, StatsRecord},
+    };
+
+    use super::{Format, Options};
+
+    /// Longest sleep between two checks of the signal flags
+    const TICK: Duration = Duration::from_secs(1);
+
+    /// How often the frequency is saved to the drift file
+    const DRIFT_INTERVAL: Duration = Duration::from_secs(3600);
+
+    static RELOAD: AtomicBool = AtomicBool::new(false);
+    static TERMINATE: AtomicBool = AtomicBool::new(false);
+
+    extern "C" fn on_reload(_: libc::c_int) {
+        RELOAD.store(true, Ordering::SeqCst);
+    }
+
+    extern "C" fn on_terminate(_: libc::c_int) {
+        TERMINATE.store(true, Ordering::SeqCst);
+    }
+
+    static LOGGER: StderrLogger = StderrLogger {
+        verbosity: RwLock::new(None),
+    };
+
+    /// Writes the log events to standard error as `LEVEL target: message
+    /// key=value...`, as verbose as the `[log]` table allows
+    struct StderrLogger {
+        verbosity: RwLock<Option<Verbosity>>,
+    }
+
+    impl log::Log for StderrLogger {
+        fn enabled(&self, metadata: &log::Metadata<'_>) -> bool {
+            self.verbosity
+                .read()
+                .unwrap()
+                .is_some_and(|verbosity| metadata.level() <= verbosity.level(metadata.target()))
+        }
+
+        fn log(&self, record: &log::Record<'_>) {
+            if !self.enabled(record.metadata()) {
+                return;
+            }
+            let mut line = format!("{} {}: {}", record.level(), record.target(), record.args());
+            let _ = record.key_values().visit(&mut Fields(&mut line));
+            eprintln!("{line}");
+        }
+
+        fn flush(&self) {}
+    }
+
+    /// Appends the key-values of an event to its line
+    struct Fields<'a>(&'a mut String);
+
+    impl<'kvs> log::kv::VisitSource<'kvs> for Fields<'_> {
+        fn visit_pair(
+            &mut self,
+            key: log::kv::Key<'kvs>,
+            value: log::kv::Value<'kvs>,
+        ) -> Result<(), log::kv::Error> {
+            let _ = write!(self.0, " {key}={value}");
+            Ok(())
+        }
+    }
+
+    fn set_verbosity(verbosity: Verbosity) {
+        *LOGGER.verbosity.write().unwrap() = Some(verbosity);
+        log::set_max_level(verbosity.max_level());
+    }
+
+    fn install_signal_handlers() {
+        let handlers: [(libc::c_int, extern "C" fn(libc::c_int)); 3] = [
+            (libc::SIGHUP, on_reload),
+            (libc::SIGTERM, on_terminate),
+            (libc::SIGINT, on_terminate),
+        ];
+        for (signal, handler) in handlers {
+            // SAFETY: the handlers only store to atomics, which is async-signal-safe
+            unsafe { libc::signal(signal, handler as libc::sighandler_t) };
+        }
+    }
+
+    /// Sends `state` to systemd, if it started the daemon with a notification socket
+    fn notify(state: &str) {
+        let Some(path) = std::env::var_os("NOTIFY_SOCKET") else {
+            return;
+        };
+        let Ok(socket) = UnixDatagram::unbound() else {
+            return;
+        };
+        let path = path.to_string_lossy();
+        let result = match path.strip_prefix('@') {
+            Some(name) => std::os::unix::net::SocketAddr::from_abstract_name(name)
+                .and_then(|address| socket.send_to_addr(state.as_bytes(), &address)),
+            None => socket.send_to(state.as_bytes(), path.as_ref()),
+        };
+        if let Err(error) = result {
+            eprintln!("demo-ntpd: cannot notify systemd: {error}");
+        }
+    }
+
+    fn monotonic_usec() -> u64 {
+        let mut timespec = libc::timespec {
+            tv_sec: 0,
+            tv_nsec: 0,
+        };
+        // SAFETY: timespec is a valid out pointer
+        unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut timespec) };
+        timespec.tv_sec as u64 * 1_000_000 + timespec.tv_nsec as u64 / 1_000
+    }
+
+    fn describe(error: &NtpError) -> String {
+        match error {
+            NtpError::Io(kind) => kind.to_string(),
+            error => format!("{error:?}"),
+        }
+    }
+
+    fn load_leap_seconds(config: &Config) -> LeapSeconds {
+        let leap_seconds = LeapSeconds::new(LeapPolicy::Step);
+        let Some(path) = &config.leapfile else {
+            return leap_seconds;
+        };
+        match LeapSecondTable::load(path) {
+            Ok(table) => leap_seconds.with_table(table),
+            Err(error) => {
+                eprintln!("demo-ntpd: ignoring {}: {error:?}", path.display());
+                leap_seconds
+            }
+        }
+    }
+
+    /// Sets the clock frequency saved by a previous run
+    fn restore_frequency(drift_file: &DriftFile, clock: &mut impl Clock) -> Result<(), String> {
+        let drift = match drift_file.load() {
+            Ok(Some(drift)) => drift,
+            Ok(None) => return Ok(()),
+            Err(error) => {
+                eprintln!(
+                    "demo-ntpd: ignoring {}: {error:?}",
+                    drift_file.path().display()
+                );
+                return Ok(());
+            }
+        };
+        clock
+            .set_frequency(drift.frequency)
+            .map_err(|error| format!("cannot set the frequency: {}", describe(&error)))
+    }
+
+    /// Saves the clock frequency, once the daemon has synchronized and the
+    /// frequency means something
+    fn save_frequency(drift_file: &DriftFile, daemon: &Daemon<LinuxClock>) {
+        if !daemon.is_synchronized() {
+            return;
+        }
+        let result = match daemon.clock().status() {
+            Ok(status) => drift_file
+                .save(&Drift {
+                    frequency: status.frequency,
+                    skew: None,
+                })
+                .map_err(|error| error.to_string()),
+            Err(error) => Err(describe(&error)),
+        };
+        if let Err(error) = result {
+            eprintln!(
+                "demo-ntpd: cannot write {}: {error}",
+                drift_file.path().display()
+            );
+        }
+    }
+
+    /// Threads answering clients, one per listening address
+    #[derive(Default)]
+    struct Servers {
+        stop: Arc<AtomicBool>,
+        threads: Vec<JoinHandle<()>>,
+    }
+
+    impl Servers {
+        fn start(
+            config: &Config,
+            state: &Arc<Mutex<ServerState>>,
+            stats: &Arc<ServerStats>,
+        ) -> Result<Self, String> {
+            let mut servers = Self::default();
+            for &address in &config.listen {
+                let server = bind(address).and_then(|socket| {
+                    config
+                        .server_builder(socket, LinuxClock::new())
+                        .leap_seconds(load_leap_seconds(config))
+                        .stats(stats.clone())
+                        .build()
+                        .map_err(|error| describe(&error))
+                });
+                let mut server = match server {
+                    Ok(server) => server,
+                    Err(error) => {
+                        servers.stop();
+                        return Err(error);
+                    }
+                };
+                let (stop, state) = (servers.stop.clone(), state.clone());
+                servers.threads.push(thread::spawn(move || {
+                    while !stop.load(Ordering::SeqCst) {
+                        server.set_state(*state.lock().unwrap());
+                        match server.serve_one() {
+                            Ok(())
+                            | Err(NtpError::Io(
+                                std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut,
+                            )) => {}
+                            Err(error) => eprintln!("demo-ntpd: {address}: {}", describe(&error)),
+                        }
+                    }
+                }));
+            }
+            Ok(servers)
+        }
+
+        fn stop(self) {
+            self.stop.store(true, Ordering::SeqCst);
+            for thread in self.threads {
+                let _ = thread.join();
+            }
+        }
+    }
+
+    fn bind(address: SocketAddr) -> Result<UdpSocket, String> {
+        let socket = UdpSocket::bind(address)
+            .map_err(|error| format!("cannot listen on {address}: {error}"))?;
+        socket
+            .set_read_timeout(Some(TICK))
+            .map_err(|error| error.to_string())?;
+        Ok(socket)
+    }
+
+    /// Starts the thread serving the metrics, if the configuration asks for
+    /// them. Returns where to publish the metrics to.
+    #[cfg(feature = "metrics")]
+    fn start_metrics(config: &Config) -> Result<Option<Arc<Mutex<String>>>, String> {
+        let Some(address) = config.metrics else {
+            return Ok(None);
+        };
+        let listener = std::net::TcpListener::bind(address)
+            .map_err(|error| format!("cannot serve metrics on {address}: {error}"))?;
+        let server = demo_ntp::metrics::MetricsServer::new(listener);
+        let metrics = server.metrics();
+        thread::spawn(move || loop {
+            if let Err(error) = server.serve_one() {
+                eprintln!("demo-ntpd: metrics: {error}");
+            }
+        });
+        Ok(Some(metrics))
+    }
+
+    #[cfg(not(feature = "metrics"))]
+    fn start_metrics(config: &Config) -> Result<Option<Arc<Mutex<String>>>, String> {
+        if config.metrics.is_some() {
+            eprintln!("demo-ntpd: ignoring metrics, built without the metrics feature");
+        }
+        Ok(None)
+    }
+
+    /// Renders the metrics for the next scrape
+    #[cfg(feature = "metrics")]
+    fn publish_metrics(
+        metrics: &Option<Arc<Mutex<String>>>,
+        daemon: &Daemon<LinuxClock>,
+        stats: &ServerStats,
+    ) {
+        let Some(metrics) = metrics else {
+            return;
+        };
+        match demo_ntp::metrics::encode(daemon, stats) {
+            Ok(encoded) => *metrics.lock().unwrap() = encoded,
+            Err(error) => eprintln!("demo-ntpd: metrics: {}", describe(&error)),
+        }
+    }
+
+    #[cfg(not(feature = "metrics"))]
+    fn publish_metrics(
+        _metrics: &Option<Arc<Mutex<String>>>,
+        _daemon: &Daemon<LinuxClock>,
+        _stats: &ServerStats,
+    ) {
+    }
+
+    /// Statistics files enabled by the configuration
+    struct Statistics {
+        peerstats: Option<StatsFile<PeerStats>>,
+        loopstats: Option<StatsFile<LoopStats>>,
+    }
+
+    impl Statistics {
+        fn new(config: &Config) -> Self {
+            let enabled = |kind| {
+                config
+                    .statsdir
+                    .as_ref()
+                    .filter(|_| config.statistics.contains(&kind))
+            };
+            Self {
+                peerstats: enabled(StatsKind::Peerstats).map(StatsFile::new),
+                loopstats: enabled(StatsKind::Loopstats).map(StatsFile::new),
+            }
+        }
+
+        /// Writes the records the daemon kept since the last call, and drops
+        /// those of disabled statistics
+        fn write(&mut self, daemon: &mut Daemon<LinuxClock>) {
+            fn append<R: StatsRecord>(file: &mut Option<StatsFile<R>>, records: Vec<R>) {
+                let Some(file) = file else {
+                    return;
+                };
+                for record in records {
+                    if let Err(error) = file.append(&record) {
+                        eprintln!("demo-ntpd: cannot write {}: {error}", R::NAME);
+                        return;
+                    }
+                }
+            }
+            append(&mut self.peerstats, daemon.take_peer_stats());
+            append(&mut self.loopstats, daemon.take_loop_stats());
+        }
+    }
+
+    fn load_associations(daemon: &mut Daemon<LinuxClock>, config: &Config) {
+        let (associations, errors) = associations_from_config(config);
+        for (server, error) in errors {
+            eprintln!("demo-ntpd: ignoring {server}: {}", describe(&error));
+        }
+        daemon.set_associations(associations);
+    }
+
+    /// Loads the configuration, warning about the directives of other
+    /// daemons' files that have no equivalent
+    fn load_config(options: &Options) -> Result<Config, String> {
+        let path = &options.config;
+        let import = match options.format {
+            Format::Toml => Config::load(path).map(|config| ConfigImport {
+                config,
+                ignored: Vec::new(),
+            }),
+            Format::Ntp => Config::load_ntp_conf(path),
+            Format::Chrony => Config::load_chrony_conf(path),
+        }
+        .map_err(|error| format!("{path}: {error:?}"))?;
+        for ignored in import.ignored {
+            eprintln!(
+                "demo-ntpd: {path}:{}: ignoring {}",
+                ignored.line, ignored.text
+            );
+        }
+        Ok(import.config)
+    }
+
+    pub fn run(options: &Options) -> Result<(), String> {
+        let load = || load_config(options);
+        let mut config = load()?;
+        install_signal_handlers();
+        let _ = log::set_logger(&LOGGER);
+        set_verbosity(config.log);
+
+        let clock = LinuxClock::new();
+        let precision = measure_precision(&clock)
+            .map_err(|error| format!("cannot measure the clock precision: {}", describe(&error)))?;
+        let mut daemon = Daemon::new(clock, load_leap_seconds(&config))
+            .with_precision(precision)
+            .with_discipline(config.clock_discipline())
+            .with_limits(config.sample_limits())
+            .with_statistics();
+        let mut statistics = Statistics::new(&config);
+        if let Some(drift_file) = config.drift_file() {
+            restore_frequency(&drift_file, daemon.clock_mut())?;
+        }
+        load_associations(&mut daemon, &config);
+
+        let state = Arc::new(Mutex::new(daemon.state()));
+        let stats = Arc::new(ServerStats::default());
+        let mut servers = Servers::start(&config, &state, &stats)?;
+        // The metrics address is only read at startup
+        let metrics = start_metrics(&config)?;
+        let mut drift_saved = Instant::now();
+        notify("READY=1");
+
+        while !TERMINATE.load(Ordering::SeqCst) {
+            if RELOAD.swap(false, Ordering::SeqCst) {
+                notify(&format!("RELOADING=1\nMONOTONIC_USEC={}", monotonic_usec()));
+                match load() {
+                    Ok(new_config) => {
+                        servers.stop();
+                        config = new_config;
+                        set_verbosity(config.log);
+                        statistics = Statistics::new(&config);
+                        daemon.set_leap_seconds(load_leap_seconds(&config));
+                        daemon.set_discipline(&config.clock_discipline());
+                        daemon.set_limits(config.sample_limits());
+                        load_associations(&mut daemon, &config);
+                        // Keep disciplining the clock if the new addresses
+                        // cannot be bound
+                        servers = Servers::start(&config, &state, &stats).unwrap_or_else(|error| {
+                            eprintln!("demo-ntpd: not serving time: {error}");
+                            Servers::default()
+                        });
+                    }
+                    Err(error) => eprintln!("demo-ntpd: keeping the old configuration: {error}"),
+                }
+                notify("READY=1");
+            }
+
+            let next_poll = daemon
+                .poll_due(Instant::now())
+                .map_err(|error| format!("cannot adjust the clock: {}", describe(&error)))?;
+            *state.lock().unwrap() = daemon.state();
+            statistics.write(&mut daemon);
+            publish_metrics(&metrics, &daemon, &stats);
+
+            if let Some(drift_file) = config.drift_file() {
+                if drift_saved.elapsed() >= DRIFT_INTERVAL {
+                    save_frequency(&drift_file, &daemon);
+                    drift_saved = Instant::now();
+                }
+            }
+
+            thread::sleep(
+                next_poll
+                    .saturating_duration_since(Instant::now())
+                    .min(TICK),
+            );
+        }
+
+        notify("STOPPING=1");
+        servers.stop();
+        if let Some(drift_file) = config.drift_file() {
+            save_frequency(&drift_file, &daemon);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,363 @@
+//! Queries NTP servers and prints offset, delay, stratum, refid and leap
+//! indicator for each of them, optionally correcting the local clock.
+
+use std::{
+    env,
+    net::{SocketAddr, ToSocketAddrs, UdpSocket},
+    process::ExitCode,
+    time::Duration,
+};
+
+use demo_ntp::{
+    client::{NtpClientBuilder, NtpSample},
+    error::NtpError,
+    types::Leap,
+};
+
+const USAGE: &str = "\
+Usage: demo-ntpdate [OPTIONS] SERVER...
+
+Options:
+  -4              Use IPv4 addresses only
+  -6              Use IPv6 addresses only
+  -t SECONDS      Reply timeout (default 2)
+  -j, --json      Print results as JSON
+  -s, --step      Step the local clock to the best server
+  -w, --slew      Slew the local clock towards the best server
+  -n, --dry-run   Compute clock corrections without applying them
+  -h, --help      Print this help
+";
+
+const DEFAULT_PORT: u16 = 123;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Family {
+    Any,
+    V4,
+    V6,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Correction {
+    None,
+    Step,
+    Slew,
+}
+
+struct Options {
+    family: Family,
+    timeout: Duration,
+    json: bool,
+    correction: Correction,
+    dry_run: bool,
+    servers: Vec<String>,
+}
+
+fn parse_args(args: impl IntoIterator<Item = String>) -> Result<Options, String> {
+    let mut options = Options {
+        family: Family::Any,
+        timeout: Duration::from_secs(2),
+        json: false,
+        correction: Correction::None,
+        dry_run: false,
+        servers: Vec::new(),
+    };
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-4" => options.family = Family::V4,
+            "-6" => options.family = Family::V6,
+            "-t" | "--timeout" => {
+                let value = args.next().ok_or("missing value for -t")?;
+                let seconds = value
+                    .parse::<f64>()
+                    .ok()
+                    .filter(|seconds| *seconds > 0.0)
+                    .ok_or_else(|| format!("invalid timeout: {value}"))?;
+                options.timeout = Duration::from_secs_f64(seconds);
+            }
+            "-j" | "--json" => options.json = true,
+            "-s" | "--step" => options.correction = Correction::Step,
+            "-w" | "--slew" => options.correction = Correction::Slew,
+            "-n" | "--dry-run" => options.dry_run = true,
+            "-h" | "--help" => return Err(String::new()),
+            _ if arg.starts_with('-') => return Err(format!("unknown option: {arg}")),
+            _ => options.servers.push(arg),
+        }
+    }
+
+    if options.servers.is_empty() {
+        return Err("no server given".to_string());
+    }
+    Ok(options)
+}
+
+/// Resolves `server`, adding the NTP port when none is given, and keeps the
+/// first address of the requested family
+fn resolve(server: &str, family: Family) -> Result<SocketAddr, String> {
+    let addresses = match server.to_socket_addrs() {
+        Ok(addresses) => addresses.collect::<Vec<_>>(),
+        Err(_) => (server, DEFAULT_PORT)
+            .to_socket_addrs()
+            .map_err(|error| format!("cannot resolve {server}: {error}"))?
+            .collect(),
+    };
+    addresses
+        .into_iter()
+        .find(|address| match family {
+            Family::Any => true,
+            Family::V4 => address.is_ipv4(),
+            Family::V6 => address.is_ipv6(),
+        })
+        .ok_or_else(|| format!("no suitable address for {server}"))
+}
+
+fn query(server: &str, options: &Options) -> Result<NtpSample, String> {
+    let address = resolve(server, options.family)?;
+    let local: SocketAddr = if address.is_ipv4() {
+        ([0, 0, 0, 0], 0).into()
+    } else {
+        ([0u16; 8], 0).into()
+    };
+    let udp_socket = UdpSocket::bind(local).map_err(|error| error.to_string())?;
+    // Kernel timestamps keep the scheduling latency out of the offset
+    #[cfg(target_os = "linux")]
+    let udp_socket = demo_ntp::transport::linux::TimestampingSocket::new(udp_socket)
+        .map_err(|error| error.to_string())?;
+    let mut client = NtpClientBuilder::new(udp_socket, address.to_string())
+        .timeout(options.timeout)
+        .build()
+        .map_err(describe)?;
+    #[cfg(target_os = "linux")]
+    client.set_precision(
+        demo_ntp::clock::measure_precision(&demo_ntp::clock::linux::LinuxClock::new())
+            .map_err(describe)?,
+    );
+    client.query().map_err(|error| describe(error.into()))
+}
+
+fn describe(error: NtpError) -> String {
+    match error {
+        NtpError::Io(std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut) => {
+            "timed out".to_string()
+        }
+        NtpError::Io(kind) => kind.to_string(),
+        NtpError::Codec(error) => format!("malformed reply: {error}"),
+        NtpError::UnexpectedResponse => "reply does not match the request".to_string(),
+        NtpError::Unauthenticated => "reply failed authentication".to_string(),
+        NtpError::KissOfDeath(refid) => {
+            let code = <[u8; 4]>::from(refid);
+            format!("kiss-o'-death {}", String::from_utf8_lossy(&code))
+        }
+    }
+}
+
+fn leap_name(leap: Leap) -> &'static str {
+    match leap {
+        Leap::NoWarning => "none",
+        Leap::LastMinuteHas61Seconds => "insert",
+        Leap::LastMinuteHas59Seconds => "delete",
+        Leap::Unknown => "unsynchronized",
+    }
+}
+
+fn json_string(value: &str) -> String {
+    let mut escaped = String::from("\"");
+    for character in value.chars() {
+        match character {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            character if character.is_control() => {
+                escaped.push_str(&format!("\\u{:04x}", character as u32))
+            }
+            character => escaped.push(character),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+/// `value` with nanosecond resolution, or `null` when JSON cannot represent it
+fn json_number(value: f64) -> String {
+    if value.is_finite() {
+        format!("{value:.9}")
+    } else {
+        "null".to_string()
+    }
+}
+
+fn print_text(server: &str, result: &Result<NtpSample, String>) {
+    match result {
+        Ok(sample) => println!(
+            "{server} ({}): offset {:+.6} s, delay {:.6} s, stratum {}, refid {}, leap {}",
+            sample.source,
+            sample.offset,
+            sample.delay,
+            u8::from(sample.stratum()),
+            sample.refid().kind(sample.stratum(), sample.source.ip()),
+            leap_name(sample.leap()),
+        ),
+        Err(error) => eprintln!("{server}: {error}"),
+    }
+}
+
+fn json_entry(server: &str, result: &Result<NtpSample, String>) -> String {
+    match result {
+        Ok(sample) => format!(
+            "{{\"server\":{},\"address\":{},\"offset\":{},\"delay\":{},\"stratum\":{},\"refid\":{},\"leap\":{}}}",
+            json_string(server),
+            json_string(&sample.source.to_string()),
+            json_number(sample.offset),
+            json_number(sample.delay),
+            u8::from(sample.stratum()),
+            json_string(&sample.refid().kind(sample.stratum(), sample.source.ip()).to_string()),
+            json_string(leap_name(sample.leap())),
+        ),
+        Err(error) => format!(
+            "{{\"server\":{},\"error\":{}}}",
+            json_string(server),
+            json_string(error)
+        ),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn correct_clock(offset: f64, correction: Correction, dry_run: bool) -> Result<(), String> {
+    use demo_ntp::clock::{linux::LinuxClock, Clock};
+
+    let mut clock = if dry_run {
+        LinuxClock::dry_run(libc::CLOCK_REALTIME).map_err(|error| format!("{error:?}"))?
+    } else {
+        LinuxClock::new()
+    };
+    let result = match correction {
+        Correction::Step => clock.step(offset),
+        Correction::Slew => clock.adjust_offset(offset),
+        Correction::None => Ok(()),
+    };
+    result.map_err(|error| format!("cannot adjust the clock: {}", describe(error)))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn correct_clock(_offset: f64, _correction: Correction, _dry_run: bool) -> Result<(), String> {
+    Err("adjusting the clock is only supported on Linux".to_string())
+}
+
+fn main() -> ExitCode {
+    let options = match parse_args(env::args().skip(1)) {
+        Ok(options) => options,
+        Err(error) if error.is_empty() => {
+            print!("{USAGE}");
+            return ExitCode::SUCCESS;
+        }
+        Err(error) => {
+            eprint!("demo-ntpdate: {error}\n\n{USAGE}");
+            return ExitCode::from(2);
+        }
+    };
+
+    let results = options
+        .servers
+        .iter()
+        .map(|server| (server.as_str(), query(server, &options)))
+        .collect::<Vec<_>>();
+
+    if options.json {
+        let entries = results
+            .iter()
+            .map(|(server, result)| json_entry(server, result))
+            .collect::<Vec<_>>();
+        println!("[{}]", entries.join(","));
+    } else {
+        for (server, result) in &results {
+            print_text(server, result);
+        }
+    }
+
+    // The reply with the lowest delay is the least affected by the network
+    let best = results
+        .iter()
+        .filter_map(|(_, result)| result.as_ref().ok())
+        .min_by(|a, b| a.delay.total_cmp(&b.delay));
+    let Some(best) = best else {
+        return ExitCode::FAILURE;
+    };
+
+    if options.correction != Correction::None {
+        if let Err(error) = correct_clock(best.offset, options.correction, options.dry_run) {
+            eprintln!("demo-ntpdate: {error}");
+            return ExitCode::FAILURE;
+        }
+    }
+    ExitCode::SUCCESS
+}
+
+#[cfg(test)]
+mod tests {
+    use demo_ntp::{codec::TryReadFromBytes, ntp_message_protocol::NtpPacketHeader};
+
+    use super::*;
+
+    fn args(args: &[&str]) -> Result<Options, String> {
+        parse_args(args.iter().map(|arg| arg.to_string()))
+    }
+
+    fn sample(offset: f64, delay: f64) -> NtpSample {
+        let mut reply = [0u8; 48];
+        reply[..2].copy_from_slice(&[0x24, 1]);
+        reply[12..15].copy_from_slice(b"GPS");
+        NtpSample {
+            source: "192.0.2.1:123".parse().unwrap(),
+            offset,
+            delay,
+            time: demo_ntp::types::NtpTimestamp::new(0, 0),
+            header: NtpPacketHeader::try_read_from_bytes(&reply).unwrap().0,
+        }
+    }
+
+    #[test]
+    fn options_are_parsed() {
+        let options = args(&["-6", "-t", "0.5", "--json", "-w", "-n", "a", "b"]).unwrap();
+
+        assert_eq!(options.family, Family::V6);
+        assert_eq!(options.timeout, Duration::from_millis(500));
+        assert!(options.json && options.dry_run);
+        assert_eq!(options.correction, Correction::Slew);
+        assert_eq!(options.servers, ["a", "b"]);
+        assert_eq!(args(&["a"]).unwrap().timeout, Duration::from_secs(2));
+    }
+
+    #[test]
+    fn invalid_options_are_rejected() {
+        assert_eq!(args(&[]).err().unwrap(), "no server given");
+        assert_eq!(args(&["-x", "a"]).err().unwrap(), "unknown option: -x");
+        assert_eq!(
+            args(&["-t", "-1", "a"]).err().unwrap(),
+            "invalid timeout: -1"
+        );
+        assert_eq!(args(&["a", "-t"]).err().unwrap(), "missing value for -t");
+        assert_eq!(args(&["-h"]).err().unwrap(), "");
+    }
+
+    #[test]
+    fn strings_are_escaped() {
+        assert_eq!(json_string("pool.ntp.org"), "\"pool.ntp.org\"");
+        assert_eq!(json_string("a\"b\\c\nd"), "\"a\\\"b\\\\c\\u000ad\"");
+    }
+
+    #[test]
+    fn entries_are_valid_json() {
+        assert_eq!(
+            json_entry("a", &Ok(sample(0.25, 0.0125))),
+            "{\"server\":\"a\",\"address\":\"192.0.2.1:123\",\"offset\":0.250000000,\
+             \"delay\":0.012500000,\"stratum\":1,\"refid\":\"GPS\",\"leap\":\"none\"}"
+        );
+        assert!(json_entry("a", &Ok(sample(f64::NAN, f64::INFINITY)))
+            .contains("\"offset\":null,\"delay\":null,"));
+        assert_eq!(
+            json_entry("a", &Err("timed out".to_string())),
+            "{\"server\":\"a\",\"error\":\"timed out\"}"
+        );
+    }
+}
@@ -1,83 +1,456 @@
+//! Client side of the protocol. `NtpClient` only needs `core`, and queries
+//! servers over any `Transport`; with `std`, `NtpClientBuilder` resolves host
+//! names, and sets timeouts and keys.
+
+#[cfg(feature = "std")]
+use crate::{
+    auth::SymmetricKey,
+    clock::system_time,
+    error::{NtpError, NtpResult},
+};
 use crate::{
-    codec::{TryReadFromBytes, TryWriteToBytes},
-    error::NtpResult,
+    codec::{CodecError, TryReadFromBytes, TryWriteToBytes},
+    logging,
     ntp_message_protocol::NtpPacketHeader,
+    transport::Transport,
     types::{
-        NtpShort, NtpTimestamp, Poll, Precision, RefId, Stratum, NTP_LEAP_NO_WARNING,
+        Leap, Mode, NtpShort, NtpTimestamp, Poll, Precision, RefId, Stratum, NTP_LEAP_NO_WARNING,
         NTP_MODE_CLIENT, NTP_VERSION_4,
     },
 };
-use std::{net::UdpSocket, time::UNIX_EPOCH};
+use core::{net::SocketAddr, time::Duration};
+use log::{debug, trace, warn};
+#[cfg(feature = "std")]
+use std::{
+    io,
+    net::{ToSocketAddrs, UdpSocket},
+};
+
+/// Why a query failed
+#[derive(Debug, PartialEq, Eq)]
+pub enum ClientError<E> {
+    /// The transport could not send the request or receive the reply
+    Transport(E),
+    /// A packet could not be parsed or serialized
+    Codec(CodecError),
+    /// Only datagrams that did not answer the request arrived before the
+    /// timeout
+    UnexpectedResponse,
+    /// The server asked the client to stop or slow down
+    KissOfDeath(RefId),
+    /// A reply was not signed with the expected key
+    Unauthenticated,
+}
 
-pub struct NtpClientBuilder {
-    udp_socket: UdpSocket,
-    server: &'static str,
+#[cfg(feature = "std")]
+pub struct NtpClientBuilder<T = UdpSocket> {
+    transport: T,
+    server: String,
+    timeout: Option<Duration>,
+    key: Option<SymmetricKey>,
 }
 
-impl NtpClientBuilder {
-    pub fn new(udp_socket: UdpSocket, server: &'static str) -> Self {
-        Self { udp_socket, server }
+#[cfg(feature = "std")]
+impl<T: Transport> NtpClientBuilder<T>
+where
+    NtpError: From<T::Error>,
+{
+    /// Client of `server`, a host name or address with a port, reached over
+    /// `transport`
+    pub fn new(transport: T, server: impl Into<String>) -> Self {
+        Self {
+            transport,
+            server: server.into(),
+            timeout: None,
+            key: None,
+        }
     }
 
-    pub fn build(self) -> NtpResult<NtpClient> {
-        Ok(NtpClient {
-            udp_socket: self.udp_socket,
-            server: self.server,
-        })
+    /// Gives up on a request when no reply arrives within `timeout`
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Signs requests with `key` and only accepts replies signed with it
+    pub fn key(mut self, key: SymmetricKey) -> Self {
+        self.key = Some(key);
+        self
+    }
+
+    /// Resolves the server and builds the client
+    pub fn build(self) -> NtpResult<NtpClient<T>> {
+        let server = self
+            .server
+            .to_socket_addrs()?
+            .next()
+            .ok_or(NtpError::Io(io::ErrorKind::NotFound))?;
+        let mut client = NtpClient::new(self.transport, server, system_time);
+        client.set_timeout(self.timeout);
+        client.key = self.key;
+        Ok(client)
+    }
+}
+
+/// Result of one request/reply exchange with a server
+#[derive(Debug, Clone, PartialEq)]
+pub struct NtpSample {
+    /// Address the reply came from
+    pub source: SocketAddr,
+    /// Offset of the server clock relative to the local clock, in seconds
+    pub offset: f64,
+    /// Round trip delay, in seconds
+    pub delay: f64,
+    /// Local time at which the reply was received
+    pub time: NtpTimestamp,
+    /// Reply as sent by the server
+    pub header: NtpPacketHeader,
+}
+
+impl NtpSample {
+    pub fn leap(&self) -> Leap {
+        self.header.leap_indicator
+    }
+
+    pub fn stratum(&self) -> Stratum {
+        self.header.stratum
+    }
+
+    pub fn refid(&self) -> RefId {
+        self.header.refid
     }
 }
 
-pub struct NtpClient {
-    udp_socket: UdpSocket,
-    server: &'static str,
+pub struct NtpClient<T> {
+    transport: T,
+    server: SocketAddr,
+    /// Reads the local time, when the transport does not timestamp datagrams
+    now: fn() -> NtpTimestamp,
+    timeout: Option<Duration>,
+    #[cfg(feature = "std")]
+    key: Option<SymmetricKey>,
+    poll: Poll,
+    precision: Precision,
 }
 
-impl NtpClient {
-    pub fn get_offset(&self) -> i64 {
-        const JAN_1970: u64 = 2208988800; /* 1970 - 1900 in seconds */
+impl<T: Transport> NtpClient<T> {
+    /// Client of the server at `server`, reached over `transport`, that reads
+    /// the local time with `now`. Without `std`, the transport is set up by
+    /// the caller.
+    pub fn new(transport: T, server: SocketAddr, now: fn() -> NtpTimestamp) -> Self {
+        Self {
+            transport,
+            server,
+            now,
+            timeout: None,
+            #[cfg(feature = "std")]
+            key: None,
+            poll: Poll::from(0),
+            precision: Precision::from(0),
+        }
+    }
+
+    /// Gives up on a request when no reply arrives within `timeout`, however
+    /// many other datagrams arrive, or waits as long as the transport does
+    /// with `None`
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    /// Announces in the requests that the server is polled every `poll`
+    pub fn set_poll(&mut self, poll: Poll) {
+        self.poll = poll;
+    }
+
+    /// Announces in the requests the precision of the local clock
+    pub fn set_precision(&mut self, precision: Precision) {
+        self.precision = precision;
+    }
 
+    /// Offset of the server clock relative to the local clock, in whole
+    /// seconds
+    pub fn get_offset(&mut self) -> Result<i64, ClientError<T::Error>> {
+        self.query().map(|sample| sample.offset as i64)
+    }
+
+    /// Sends one request to the server and measures offset and delay from the
+    /// reply, timestamped by the transport if it can
+    pub fn query(&mut self) -> Result<NtpSample, ClientError<T::Error>> {
+        let request_time = (self.now)();
         let ntp_transmit_message = NtpPacketHeader {
             leap_indicator: NTP_LEAP_NO_WARNING,
             version_number: NTP_VERSION_4,
             mode: NTP_MODE_CLIENT,
             stratum: Stratum::from(0),
-            poll: Poll::from(0),
-            precision: Precision::from(0),
+            poll: self.poll,
+            precision: self.precision,
             rootdelay: NtpShort::new(0, 0),
             rootdisp: NtpShort::new(0, 0),
             refid: RefId::from([0, 0, 0, 0]),
             reftime: NtpTimestamp::new(0, 0),
             org: NtpTimestamp::new(0, 0),
             rec: NtpTimestamp::new(0, 0),
-            xmt: NtpTimestamp::new(0, 0),
+            xmt: request_time,
         };
 
         let mut buffer = [0u8; 100];
-        let serialized_size = ntp_transmit_message
+        let header_size = ntp_transmit_message
             .try_write_to_bytes(&mut buffer)
-            .unwrap();
-
-        let client_transmission_time = std::time::SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap();
+            .map_err(ClientError::Codec)?;
+        let serialized_size = header_size
+            + self
+                .sign(&mut buffer, header_size)
+                .map_err(ClientError::Codec)?;
 
-        self.udp_socket
+        self.transport
             .send_to(&buffer[..serialized_size], self.server)
-            .unwrap();
+            .map_err(ClientError::Transport)?;
+        // The timestamp in the request only identifies the reply
+        let client_transmission_time = self
+            .transport
+            .transmit_timestamp()
+            .map_err(ClientError::Transport)?
+            .unwrap_or(request_time);
+        trace!(target: logging::CLIENT, server:% = self.server; "sent request");
 
-        let (recv_size, _) = self.udp_socket.recv_from(&mut buffer).unwrap();
-        let client_reception_time = std::time::SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap();
-        let (packet, _) = NtpPacketHeader::try_read_from_bytes(&buffer[..recv_size]).unwrap();
+        // Datagrams from elsewhere, or late replies to earlier requests, are
+        // skipped until the reply to this one arrives
+        let (packet, source, client_reception_time) = loop {
+            if let Some(timeout) = self.timeout {
+                let remaining = timeout.as_secs_f64() - (self.now)().diff_seconds(&request_time);
+                if remaining <= 0.0 {
+                    return Err(ClientError::UnexpectedResponse);
+                }
+                self.transport
+                    .set_timeout(Some(Duration::from_secs_f64(remaining)))
+                    .map_err(ClientError::Transport)?;
+            }
+            let received = self
+                .transport
+                .recv_from(&mut buffer)
+                .map_err(ClientError::Transport)?;
+            let client_reception_time = received.timestamp.unwrap_or_else(self.now);
+            let (recv_size, source) = (received.size, received.source);
+            if source != self.server {
+                debug!(
+                    target: logging::CLIENT,
+                    server:% = self.server,
+                    source:% = source;
+                    "ignored datagram from another address"
+                );
+                continue;
+            }
+            let (packet, header_size) = NtpPacketHeader::try_read_from_bytes(&buffer[..recv_size])
+                .map_err(ClientError::Codec)?;
+            if packet.mode != Mode::Server || packet.org != request_time {
+                debug!(
+                    target: logging::CLIENT,
+                    server:% = source,
+                    reason = "unexpected mode or origin timestamp";
+                    "ignored reply"
+                );
+                continue;
+            }
+            if !self.verify(&buffer[..recv_size], header_size) {
+                warn!(
+                    target: logging::CLIENT,
+                    server:% = source,
+                    reason = "bad MAC";
+                    "rejected reply"
+                );
+                return Err(ClientError::Unauthenticated);
+            }
+            break (packet, source, client_reception_time);
+        };
+        if u8::from(packet.stratum) == 0 {
+            warn!(
+                target: logging::CLIENT,
+                server:% = source,
+                code:% = packet.refid.kind(packet.stratum, source.ip());
+                "received kiss-o'-death"
+            );
+            return Err(ClientError::KissOfDeath(packet.refid));
+        }
 
-        let server_transmission_time = packet.xmt;
         let server_reception_time = packet.rec;
+        let server_transmission_time = packet.xmt;
+        let offset = (server_reception_time.diff_seconds(&client_transmission_time)
+            + server_transmission_time.diff_seconds(&client_reception_time))
+            / 2.0;
+        let delay = client_reception_time.diff_seconds(&client_transmission_time)
+            - server_transmission_time.diff_seconds(&server_reception_time);
+
+        debug!(
+            target: logging::CLIENT,
+            server:% = source,
+            offset = offset,
+            delay = delay,
+            stratum = u8::from(packet.stratum);
+            "received reply"
+        );
+        Ok(NtpSample {
+            source,
+            offset,
+            delay,
+            time: client_reception_time,
+            header: packet,
+        })
+    }
+
+    /// Appends the MAC of the `header_size` bytes of header in `buffer`, if
+    /// the client has a key, and returns its size
+    #[cfg(feature = "std")]
+    fn sign(&self, buffer: &mut [u8], header_size: usize) -> Result<usize, CodecError> {
+        let Some(key) = &self.key else {
+            return Ok(0);
+        };
+        let (header, mac) = buffer.split_at_mut(header_size);
+        key.sign(header, mac)
+    }
+
+    #[cfg(not(feature = "std"))]
+    fn sign(&self, _buffer: &mut [u8], _header_size: usize) -> Result<usize, CodecError> {
+        Ok(0)
+    }
+
+    /// Whether `reply` is signed with the key, if the client has one
+    #[cfg(feature = "std")]
+    fn verify(&self, reply: &[u8], header_size: usize) -> bool {
+        self.key.as_ref().is_none_or(|key| {
+            let (header, mac) = reply.split_at(header_size);
+            key.verify(header, mac)
+        })
+    }
+
+    #[cfg(not(feature = "std"))]
+    fn verify(&self, _reply: &[u8], _header_size: usize) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::{
+        net::{IpAddr, Ipv4Addr},
+        sync::atomic::{AtomicI64, Ordering},
+    };
+
+    use crate::{ntp_message_protocol::NtpPacketViewMut, transport::Received};
+
+    use super::*;
+
+    const SERVER: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)), 123);
+
+    const ELSEWHERE: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2)), 123);
+
+    /// Link to a server whose clock is `offset` seconds ahead, and that fails
+    /// with `()` once it has sent `replies` replies. Before each reply come
+    /// `strays` copies, from another address or with another origin timestamp.
+    struct Reflector {
+        request: Option<[u8; 48]>,
+        offset: f64,
+        replies: usize,
+        strays: usize,
+    }
+
+    impl Transport for Reflector {
+        type Error = ();
+
+        fn set_timeout(&mut self, _timeout: Option<Duration>) -> Result<(), ()> {
+            Ok(())
+        }
+
+        fn send_to(&mut self, bytes: &[u8], _address: SocketAddr) -> Result<(), ()> {
+            self.request = bytes.try_into().ok();
+            Ok(())
+        }
+
+        fn recv_from(&mut self, bytes: &mut [u8]) -> Result<Received, ()> {
+            let mut reply = self.request.ok_or(())?;
+            let mut packet = NtpPacketViewMut::new(&mut reply).unwrap();
+            let xmt = packet.as_view().xmt();
+            packet.set_mode(Mode::Server);
+            packet.set_stratum(Stratum::from(1));
+            packet.set_org(xmt);
+            packet.set_rec(xmt.add_seconds(self.offset));
+            packet.set_xmt(xmt.add_seconds(self.offset));
+            let source = match self.strays {
+                0 => {
+                    self.replies = self.replies.checked_sub(1).ok_or(())?;
+                    self.request = None;
+                    SERVER
+                }
+                strays => {
+                    self.strays -= 1;
+                    if strays % 2 == 0 {
+                        packet.set_org(xmt.add_seconds(-1.0));
+                        SERVER
+                    } else {
+                        ELSEWHERE
+                    }
+                }
+            };
+            bytes[..reply.len()].copy_from_slice(&reply);
+            Ok(Received {
+                size: reply.len(),
+                source,
+                timestamp: None,
+            })
+        }
+    }
+
+    fn fixed_time() -> NtpTimestamp {
+        NtpTimestamp::from_unix(1_700_000_000, 0)
+    }
+
+    #[test]
+    fn client_queries_over_any_transport() {
+        let reflector = Reflector {
+            request: None,
+            offset: 2.5,
+            replies: 1,
+            strays: 0,
+        };
+        let mut client = NtpClient::new(reflector, SERVER, fixed_time);
+
+        let sample = client.query().unwrap();
+        assert!((sample.offset - 2.5).abs() < 1e-6);
+        assert_eq!(sample.delay, 0.0);
+        assert_eq!(sample.source, SERVER);
+        assert_eq!(client.query(), Err(ClientError::Transport(())));
+    }
+
+    #[test]
+    fn other_datagrams_are_skipped() {
+        let reflector = Reflector {
+            request: None,
+            offset: 2.5,
+            replies: 1,
+            strays: 4,
+        };
+        let mut client = NtpClient::new(reflector, SERVER, fixed_time);
+
+        let sample = client.query().unwrap();
+        assert!((sample.offset - 2.5).abs() < 1e-6);
+        assert_eq!(sample.source, SERVER);
+    }
+
+    #[test]
+    fn other_datagrams_do_not_extend_the_timeout() {
+        static SECONDS: AtomicI64 = AtomicI64::new(1_700_000_000);
+        /// Clock that advances by a second each time it is read
+        fn ticking_time() -> NtpTimestamp {
+            NtpTimestamp::from_unix(SECONDS.fetch_add(1, Ordering::Relaxed), 0)
+        }
+        let reflector = Reflector {
+            request: None,
+            offset: 2.5,
+            replies: 1,
+            strays: 4,
+        };
+        let mut client = NtpClient::new(reflector, SERVER, ticking_time);
+        client.set_timeout(Some(Duration::from_millis(2500)));
 
-        (((server_reception_time.seconds() as u64 - JAN_1970) as i64
-            - client_transmission_time.as_secs() as i64)
-            + ((server_transmission_time.seconds() as u64 - JAN_1970) as i64
-                - client_reception_time.as_secs() as i64))
-            / 2
+        assert_eq!(client.query(), Err(ClientError::UnexpectedResponse));
     }
 }
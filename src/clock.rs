@@ -0,0 +1,113 @@
+#[cfg(target_os = "linux")]
+pub mod linux;
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::{
+    error::NtpResult,
+    types::{Leap, NtpTimestamp, Precision},
+};
+
+/// Smallest increment a measured precision can stand for, in seconds
+const MIN_TICK: f64 = 20e-9;
+
+/// Increments between readings to observe before settling on the smallest
+const MIN_CHANGES: u32 = 12;
+
+/// Readings to give up after, for clocks that do not advance
+const MAX_READINGS: u32 = 1_000_000;
+
+/// Local clock that can be read and disciplined
+pub trait Clock {
+    /// Reads the current time
+    fn now(&self) -> NtpResult<NtpTimestamp>;
+
+    /// Steps the clock by `offset` seconds
+    fn step(&mut self, offset: f64) -> NtpResult<()>;
+
+    /// Hands a phase offset, in seconds, to the clock so that it is slewed out gradually
+    fn adjust_offset(&mut self, offset: f64) -> NtpResult<()>;
+
+    /// Sets the frequency correction in parts per million
+    fn set_frequency(&mut self, ppm: f64) -> NtpResult<()>;
+
+    /// Sets the time constant of the phase-locked loop, as a power of two like
+    /// the poll interval it should follow
+    fn set_time_constant(&mut self, constant: i64) -> NtpResult<()>;
+
+    /// Announces an upcoming leap second, or clears a pending one
+    fn set_leap(&mut self, leap: Leap) -> NtpResult<()>;
+
+    /// Records whether the clock is synchronized and its estimated and maximum error in seconds
+    fn set_sync_status(
+        &mut self,
+        synchronized: bool,
+        est_error: f64,
+        max_error: f64,
+    ) -> NtpResult<()>;
+
+    /// Reads back the current discipline state
+    fn status(&self) -> NtpResult<ClockStatus>;
+}
+
+/// Current time of the system clock, for code that only reads it
+pub fn system_time() -> NtpTimestamp {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    NtpTimestamp::from_unix(now.as_secs() as i64, now.subsec_nanos())
+}
+
+/// Leap second state reported by the clock
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClockState {
+    /// No leap second pending
+    Ok,
+    /// A second will be inserted at the end of the day
+    InsertLeap,
+    /// A second will be deleted at the end of the day
+    DeleteLeap,
+    /// A leap second is being inserted
+    LeapInProgress,
+    /// A leap second has just occurred
+    LeapOccurred,
+    /// The clock is not synchronized
+    Error,
+}
+
+/// Snapshot of the clock discipline state
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ClockStatus {
+    pub state: ClockState,
+    pub synchronized: bool,
+    /// Remaining phase offset being slewed, in seconds
+    pub offset: f64,
+    /// Frequency correction in parts per million
+    pub frequency: f64,
+    /// Estimated error in seconds
+    pub est_error: f64,
+    /// Maximum error in seconds
+    pub max_error: f64,
+    /// PLL time constant
+    pub time_constant: i64,
+}
+
+/// Measures the precision of `clock` as ntpd does at startup: the smallest
+/// increment between successive readings, which is bounded by both the
+/// resolution and the time a reading takes, to the nearest power of two
+pub fn measure_precision(clock: &impl Clock) -> NtpResult<Precision> {
+    let mut tick = 1.0f64;
+    let mut changes = 0;
+    let mut last = clock.now()?;
+    for _ in 0..MAX_READINGS {
+        if changes >= MIN_CHANGES {
+            break;
+        }
+        let now = clock.now()?;
+        let increment = now.diff_seconds(&last);
+        last = now;
+        if increment > 0.0 {
+            tick = tick.min(increment);
+            changes += 1;
+        }
+    }
+    Ok(Precision::from(tick.max(MIN_TICK).log2().round() as i8))
+}
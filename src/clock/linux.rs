@@ -0,0 +1,354 @@
+use std::io;
+
+use crate::{
+    clock::{Clock, ClockState, ClockStatus},
+    error::NtpResult,
+    types::{Leap, NtpTimestamp},
+};
+
+/// Largest phase offset the kernel PLL accepts in one adjustment, in seconds
+const MAX_PLL_OFFSET: f64 = 0.5;
+
+/// Largest frequency correction the kernel accepts, in parts per million
+const MAX_FREQUENCY: f64 = 500.0;
+
+/// Largest PLL time constant the kernel accepts
+const MAX_TIME_CONSTANT: i64 = 10;
+
+/// Kernel frequency unit: parts per million with a 16-bit binary fraction
+const FREQUENCY_SCALE: f64 = 65536.0;
+
+/// Clock backed by the Linux `clock_gettime`, `clock_settime` and `clock_adjtime` calls.
+/// For `CLOCK_REALTIME`, `clock_adjtime` is the same interface as `adjtimex`.
+pub struct LinuxClock {
+    clock_id: libc::clockid_t,
+    dry_run: Option<DryRun>,
+}
+
+/// Kernel state simulated in memory when adjustments must not reach the clock
+struct DryRun {
+    timex: libc::timex,
+    step: f64,
+}
+
+impl Default for LinuxClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LinuxClock {
+    /// Disciplines the system wide `CLOCK_REALTIME`
+    pub fn new() -> Self {
+        Self::with_clock_id(libc::CLOCK_REALTIME)
+    }
+
+    /// Disciplines an arbitrary clock, for example a PTP hardware clock
+    pub fn with_clock_id(clock_id: libc::clockid_t) -> Self {
+        Self {
+            clock_id,
+            dry_run: None,
+        }
+    }
+
+    /// Reads `clock_id` but keeps every adjustment in memory instead of applying it.
+    /// This does not require any privileges.
+    pub fn dry_run(clock_id: libc::clockid_t) -> NtpResult<Self> {
+        let mut clock = Self::with_clock_id(clock_id);
+        let (timex, _) = clock.read_timex()?;
+        clock.dry_run = Some(DryRun { timex, step: 0.0 });
+        Ok(clock)
+    }
+
+    pub fn is_dry_run(&self) -> bool {
+        self.dry_run.is_some()
+    }
+
+    fn read_timex(&self) -> NtpResult<(libc::timex, libc::c_int)> {
+        // SAFETY: `timex` is a plain C struct for which all zeroes is a valid value
+        let mut timex: libc::timex = unsafe { std::mem::zeroed() };
+        if let Some(dry_run) = &self.dry_run {
+            timex = dry_run.timex;
+            return Ok((timex, kernel_state(timex.status)));
+        }
+
+        // SAFETY: `timex` is valid for writes and `modes` is zero, so nothing is changed
+        let state = unsafe { libc::clock_adjtime(self.clock_id, &mut timex) };
+        if state < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        Ok((timex, state))
+    }
+
+    fn adjust(&mut self, timex: &mut libc::timex) -> NtpResult<libc::c_int> {
+        if let Some(dry_run) = &mut self.dry_run {
+            merge_timex(&mut dry_run.timex, timex);
+            *timex = dry_run.timex;
+            return Ok(kernel_state(timex.status));
+        }
+
+        // SAFETY: `timex` is a valid, initialised struct owned by the caller
+        let state = unsafe { libc::clock_adjtime(self.clock_id, timex) };
+        if state < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        Ok(state)
+    }
+
+    fn read_timespec(&self) -> NtpResult<libc::timespec> {
+        let mut timespec = libc::timespec {
+            tv_sec: 0,
+            tv_nsec: 0,
+        };
+        // SAFETY: `timespec` is valid for writes
+        if unsafe { libc::clock_gettime(self.clock_id, &mut timespec) } < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        Ok(timespec)
+    }
+}
+
+impl Clock for LinuxClock {
+    fn now(&self) -> NtpResult<NtpTimestamp> {
+        let timespec = self.read_timespec()?;
+        let now = NtpTimestamp::from_unix(timespec.tv_sec as _, timespec.tv_nsec as _);
+        match &self.dry_run {
+            Some(dry_run) => Ok(now.add_seconds(dry_run.step)),
+            None => Ok(now),
+        }
+    }
+
+    fn step(&mut self, offset: f64) -> NtpResult<()> {
+        if let Some(dry_run) = &mut self.dry_run {
+            dry_run.step += offset;
+            return Ok(());
+        }
+
+        let timespec = self.read_timespec()?;
+        let current_nanos: i64 = timespec.tv_nsec as _;
+        let nanos = current_nanos + (offset.fract() * 1e9).round() as i64;
+        let target = libc::timespec {
+            tv_sec: timespec.tv_sec
+                + offset.trunc() as libc::time_t
+                + nanos.div_euclid(1_000_000_000) as libc::time_t,
+            tv_nsec: nanos.rem_euclid(1_000_000_000) as _,
+        };
+        // SAFETY: `target` is a valid timespec with a normalised nanosecond field
+        if unsafe { libc::clock_settime(self.clock_id, &target) } < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        Ok(())
+    }
+
+    fn adjust_offset(&mut self, offset: f64) -> NtpResult<()> {
+        let (mut timex, _) = self.read_timex()?;
+        let offset = offset.clamp(-MAX_PLL_OFFSET, MAX_PLL_OFFSET);
+        timex.modes = libc::ADJ_OFFSET | libc::ADJ_STATUS | libc::ADJ_NANO;
+        timex.status = (timex.status | libc::STA_PLL | libc::STA_NANO) & !libc::STA_FLL;
+        timex.offset = (offset * 1e9).round() as _;
+        self.adjust(&mut timex)?;
+        Ok(())
+    }
+
+    fn set_frequency(&mut self, ppm: f64) -> NtpResult<()> {
+        // SAFETY: `timex` is a plain C struct for which all zeroes is a valid value
+        let mut timex: libc::timex = unsafe { std::mem::zeroed() };
+        let ppm = ppm.clamp(-MAX_FREQUENCY, MAX_FREQUENCY);
+        timex.modes = libc::ADJ_FREQUENCY;
+        timex.freq = (ppm * FREQUENCY_SCALE).round() as _;
+        self.adjust(&mut timex)?;
+        Ok(())
+    }
+
+    fn set_time_constant(&mut self, constant: i64) -> NtpResult<()> {
+        // SAFETY: `timex` is a plain C struct for which all zeroes is a valid value
+        let mut timex: libc::timex = unsafe { std::mem::zeroed() };
+        timex.modes = libc::ADJ_TIMECONST;
+        // With `STA_NANO`, the kernel takes the constant as it is
+        timex.constant = constant.clamp(0, MAX_TIME_CONSTANT) as _;
+        self.adjust(&mut timex)?;
+        Ok(())
+    }
+
+    fn set_leap(&mut self, leap: Leap) -> NtpResult<()> {
+        let (mut timex, _) = self.read_timex()?;
+        timex.modes = libc::ADJ_STATUS;
+        timex.status &= !(libc::STA_INS | libc::STA_DEL);
+        timex.status |= match leap {
+            Leap::NoWarning => 0,
+            Leap::LastMinuteHas61Seconds => libc::STA_INS,
+            Leap::LastMinuteHas59Seconds => libc::STA_DEL,
+            Leap::Unknown => libc::STA_UNSYNC,
+        };
+        self.adjust(&mut timex)?;
+        Ok(())
+    }
+
+    fn set_sync_status(
+        &mut self,
+        synchronized: bool,
+        est_error: f64,
+        max_error: f64,
+    ) -> NtpResult<()> {
+        let (mut timex, _) = self.read_timex()?;
+        timex.modes = libc::ADJ_STATUS | libc::ADJ_ESTERROR | libc::ADJ_MAXERROR;
+        if synchronized {
+            timex.status &= !libc::STA_UNSYNC;
+        } else {
+            timex.status |= libc::STA_UNSYNC;
+        }
+        timex.esterror = (est_error * 1e6).round() as _;
+        timex.maxerror = (max_error * 1e6).round() as _;
+        self.adjust(&mut timex)?;
+        Ok(())
+    }
+
+    fn status(&self) -> NtpResult<ClockStatus> {
+        let (timex, state) = self.read_timex()?;
+        let offset_scale = if timex.status & libc::STA_NANO != 0 {
+            1e9
+        } else {
+            1e6
+        };
+        let state = match state {
+            libc::TIME_INS => ClockState::InsertLeap,
+            libc::TIME_DEL => ClockState::DeleteLeap,
+            libc::TIME_OOP => ClockState::LeapInProgress,
+            libc::TIME_WAIT => ClockState::LeapOccurred,
+            libc::TIME_ERROR => ClockState::Error,
+            _ => ClockState::Ok,
+        };
+
+        Ok(ClockStatus {
+            state,
+            synchronized: timex.status & libc::STA_UNSYNC == 0,
+            offset: timex.offset as f64 / offset_scale,
+            frequency: timex.freq as f64 / FREQUENCY_SCALE,
+            est_error: timex.esterror as f64 / 1e6,
+            max_error: timex.maxerror as f64 / 1e6,
+            time_constant: timex.constant as _,
+        })
+    }
+}
+
+/// Applies the fields selected by `update.modes` to `target`, as the kernel would
+fn merge_timex(target: &mut libc::timex, update: &libc::timex) {
+    let modes = update.modes;
+    if modes & libc::ADJ_NANO != 0 {
+        target.status |= libc::STA_NANO;
+    }
+    if modes & libc::ADJ_STATUS != 0 {
+        target.status = update.status;
+    }
+    if modes & libc::ADJ_OFFSET != 0 {
+        target.offset = update.offset;
+    }
+    if modes & libc::ADJ_FREQUENCY != 0 {
+        target.freq = update.freq;
+    }
+    if modes & libc::ADJ_ESTERROR != 0 {
+        target.esterror = update.esterror;
+    }
+    if modes & libc::ADJ_MAXERROR != 0 {
+        target.maxerror = update.maxerror;
+    }
+    if modes & libc::ADJ_TIMECONST != 0 {
+        target.constant = update.constant;
+    }
+}
+
+/// Derives the `clock_adjtime` return value from the status bits
+fn kernel_state(status: libc::c_int) -> libc::c_int {
+    if status & libc::STA_UNSYNC != 0 {
+        libc::TIME_ERROR
+    } else if status & libc::STA_INS != 0 {
+        libc::TIME_INS
+    } else if status & libc::STA_DEL != 0 {
+        libc::TIME_DEL
+    } else {
+        libc::TIME_OK
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::clock::system_time;
+
+    use super::*;
+
+    fn dry_run_clock() -> LinuxClock {
+        LinuxClock::dry_run(libc::CLOCK_REALTIME).unwrap()
+    }
+
+    #[test]
+    fn now_matches_system_time() {
+        let now = LinuxClock::new().now().unwrap();
+        assert!(now.diff_seconds(&system_time()).abs() < 1.0);
+    }
+
+    #[test]
+    fn status_can_be_read_without_privileges() {
+        let status = LinuxClock::new().status().unwrap();
+
+        assert!(status.frequency.abs() <= MAX_FREQUENCY);
+    }
+
+    #[test]
+    fn dry_run_step_shifts_reported_time() {
+        let mut clock = dry_run_clock();
+        let before = clock.now().unwrap();
+        clock.step(3600.25).unwrap();
+        let after = clock.now().unwrap();
+
+        assert!((after.diff_seconds(&before) - 3600.25).abs() < 0.1);
+    }
+
+    #[test]
+    fn dry_run_frequency_is_reported_back() {
+        let mut clock = dry_run_clock();
+        clock.set_frequency(-12.5).unwrap();
+
+        assert_eq!(clock.status().unwrap().frequency, -12.5);
+    }
+
+    #[test]
+    fn dry_run_offset_uses_nanosecond_pll_and_is_clamped() {
+        let mut clock = dry_run_clock();
+        clock.adjust_offset(0.000_250).unwrap();
+        assert_eq!(clock.status().unwrap().offset, 0.000_250);
+
+        clock.adjust_offset(2.0).unwrap();
+        assert_eq!(clock.status().unwrap().offset, MAX_PLL_OFFSET);
+    }
+
+    #[test]
+    fn system_clock_precision_is_well_below_a_millisecond() {
+        let precision = crate::clock::measure_precision(&LinuxClock::new()).unwrap();
+
+        assert!((-25..=-10).contains(&i8::from(precision)), "{precision:?}");
+    }
+
+    #[test]
+    fn dry_run_time_constant_is_clamped() {
+        let mut clock = dry_run_clock();
+        clock.set_time_constant(6).unwrap();
+        assert_eq!(clock.status().unwrap().time_constant, 6);
+
+        clock.set_time_constant(17).unwrap();
+        assert_eq!(clock.status().unwrap().time_constant, MAX_TIME_CONSTANT);
+    }
+
+    #[test]
+    fn dry_run_leap_sets_kernel_state() {
+        let mut clock = dry_run_clock();
+        clock.set_sync_status(true, 0.001, 0.01).unwrap();
+        clock.set_leap(Leap::LastMinuteHas61Seconds).unwrap();
+        assert_eq!(clock.status().unwrap().state, ClockState::InsertLeap);
+
+        clock.set_leap(Leap::NoWarning).unwrap();
+        let status = clock.status().unwrap();
+        assert_eq!(status.state, ClockState::Ok);
+        assert!(status.synchronized);
+        assert_eq!(status.max_error, 0.01);
+    }
+}
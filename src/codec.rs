@@ -1,3 +1,79 @@
+use core::{convert::Infallible, fmt};
+
+pub use demo_ntp_derive::{TryReadFromBytes, TryWriteToBytes};
+
+/// Error reading or writing a value, with the field and byte offset it
+/// happened at
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CodecError {
+    /// Innermost named field being read or written, if any
+    pub field: Option<&'static str>,
+    /// Offset of the failing value from the start of the buffer
+    pub offset: usize,
+    pub kind: CodecErrorKind,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CodecErrorKind {
+    /// The buffer ends before the value does
+    BufferTooSmall { needed: usize, available: usize },
+    /// The bytes do not encode a valid value
+    Invalid(&'static str),
+}
+
+impl CodecError {
+    /// `needed` bytes are missing at the offset, where `available` are left
+    pub fn buffer_too_small(needed: usize, available: usize) -> Self {
+        Self {
+            field: None,
+            offset: 0,
+            kind: CodecErrorKind::BufferTooSmall { needed, available },
+        }
+    }
+
+    pub fn invalid(reason: &'static str) -> Self {
+        Self {
+            field: None,
+            offset: 0,
+            kind: CodecErrorKind::Invalid(reason),
+        }
+    }
+
+    /// Moves the error to a value starting `offset` bytes into the buffer
+    pub fn at(mut self, offset: usize) -> Self {
+        self.offset += offset;
+        self
+    }
+
+    /// Attributes the error to `field`, starting `offset` bytes into the
+    /// buffer, unless a field within it was already named
+    pub fn in_field(mut self, field: &'static str, offset: usize) -> Self {
+        self.field.get_or_insert(field);
+        self.at(offset)
+    }
+}
+
+impl From<Infallible> for CodecError {
+    fn from(value: Infallible) -> Self {
+        match value {}
+    }
+}
+
+impl fmt::Display for CodecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.field {
+            Some(field) => write!(f, "{field} at byte {}: ", self.offset)?,
+            None => write!(f, "byte {}: ", self.offset)?,
+        }
+        match self.kind {
+            CodecErrorKind::BufferTooSmall { needed, available } => {
+                write!(f, "needs {needed} bytes, {available} available")
+            }
+            CodecErrorKind::Invalid(reason) => f.write_str(reason),
+        }
+    }
+}
+
 /// Trait for types that can be serialized to bytes
 pub trait TryWriteToBytes {
     type Error;
@@ -34,12 +110,12 @@ pub trait TryReadFromBytes<'a>: Sized {
 }
 
 impl TryWriteToBytes for u8 {
-    type Error = &'static str;
+    type Error = CodecError;
 
     fn try_write_to_bytes(&self, bytes: &mut [u8]) -> Result<usize, Self::Error> {
         #[allow(clippy::len_zero)]
         if bytes.len() < 1 {
-            return Err("Buffer too small");
+            return Err(CodecError::buffer_too_small(1, bytes.len()));
         }
 
         bytes[0] = *self;
@@ -48,24 +124,24 @@ impl TryWriteToBytes for u8 {
 }
 
 impl<'a> TryReadFromBytes<'a> for u8 {
-    type Error = &'static str;
+    type Error = CodecError;
 
     fn try_read_from_bytes(bytes: &'a [u8]) -> Result<(Self, usize), Self::Error> {
         #[allow(clippy::len_zero)]
         if bytes.len() < 1 {
-            return Err("Buffer too small");
+            return Err(CodecError::buffer_too_small(1, bytes.len()));
         }
         Ok((bytes[0], 1))
     }
 }
 
 impl TryWriteToBytes for i8 {
-    type Error = &'static str;
+    type Error = CodecError;
 
     fn try_write_to_bytes(&self, bytes: &mut [u8]) -> Result<usize, Self::Error> {
         #[allow(clippy::len_zero)]
         if bytes.len() < 1 {
-            return Err("Buffer too small");
+            return Err(CodecError::buffer_too_small(1, bytes.len()));
         }
 
         // https://doc.rust-lang.org/reference/expressions/operator-expr.html#numeric-cast
@@ -77,23 +153,23 @@ impl TryWriteToBytes for i8 {
 }
 
 impl<'a> TryReadFromBytes<'a> for i8 {
-    type Error = &'static str;
+    type Error = CodecError;
 
     fn try_read_from_bytes(bytes: &'a [u8]) -> Result<(Self, usize), Self::Error> {
         #[allow(clippy::len_zero)]
         if bytes.len() < 1 {
-            return Err("Buffer too small");
+            return Err(CodecError::buffer_too_small(1, bytes.len()));
         }
         Ok((bytes[0] as i8, 1))
     }
 }
 
 impl TryWriteToBytes for u16 {
-    type Error = &'static str;
+    type Error = CodecError;
 
     fn try_write_to_bytes(&self, bytes: &mut [u8]) -> Result<usize, Self::Error> {
         if bytes.len() < 2 {
-            return Err("Buffer too small");
+            return Err(CodecError::buffer_too_small(2, bytes.len()));
         }
         let value = self.to_be_bytes();
         bytes[0] = value[0];
@@ -103,11 +179,11 @@ impl TryWriteToBytes for u16 {
 }
 
 impl<'a> TryReadFromBytes<'a> for u16 {
-    type Error = &'static str;
+    type Error = CodecError;
 
     fn try_read_from_bytes(bytes: &'a [u8]) -> Result<(Self, usize), Self::Error> {
         if bytes.len() < 2 {
-            return Err("Buffer too small");
+            return Err(CodecError::buffer_too_small(2, bytes.len()));
         }
         let value = u16::from_be_bytes([bytes[0], bytes[1]]);
         Ok((value, 2))
@@ -115,11 +191,11 @@ impl<'a> TryReadFromBytes<'a> for u16 {
 }
 
 impl TryWriteToBytes for u32 {
-    type Error = &'static str;
+    type Error = CodecError;
 
     fn try_write_to_bytes(&self, bytes: &mut [u8]) -> Result<usize, Self::Error> {
         if bytes.len() < 4 {
-            return Err("Buffer too small");
+            return Err(CodecError::buffer_too_small(4, bytes.len()));
         }
         let value = self.to_be_bytes();
         bytes[0] = value[0];
@@ -131,11 +207,11 @@ impl TryWriteToBytes for u32 {
 }
 
 impl<'a> TryReadFromBytes<'a> for u32 {
-    type Error = &'static str;
+    type Error = CodecError;
 
     fn try_read_from_bytes(bytes: &'a [u8]) -> Result<(Self, usize), Self::Error> {
         if bytes.len() < 4 {
-            return Err("Buffer too small");
+            return Err(CodecError::buffer_too_small(4, bytes.len()));
         }
         let value = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
         Ok((value, 4))
@@ -143,11 +219,11 @@ impl<'a> TryReadFromBytes<'a> for u32 {
 }
 
 impl TryWriteToBytes for i32 {
-    type Error = &'static str;
+    type Error = CodecError;
 
     fn try_write_to_bytes(&self, bytes: &mut [u8]) -> Result<usize, Self::Error> {
         if bytes.len() < 4 {
-            return Err("Buffer too small");
+            return Err(CodecError::buffer_too_small(4, bytes.len()));
         }
         let value = self.to_be_bytes();
         bytes[0] = value[0];
@@ -159,11 +235,11 @@ impl TryWriteToBytes for i32 {
 }
 
 impl<'a> TryReadFromBytes<'a> for i32 {
-    type Error = &'static str;
+    type Error = CodecError;
 
     fn try_read_from_bytes(bytes: &'a [u8]) -> Result<(Self, usize), Self::Error> {
         if bytes.len() < 4 {
-            return Err("Buffer too small");
+            return Err(CodecError::buffer_too_small(4, bytes.len()));
         }
         let value = i32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
         Ok((value, 4))
@@ -171,11 +247,11 @@ impl<'a> TryReadFromBytes<'a> for i32 {
 }
 
 impl TryWriteToBytes for u64 {
-    type Error = &'static str;
+    type Error = CodecError;
 
     fn try_write_to_bytes(&self, bytes: &mut [u8]) -> Result<usize, Self::Error> {
-        if bytes.len() < 4 {
-            return Err("Buffer too small");
+        if bytes.len() < 8 {
+            return Err(CodecError::buffer_too_small(8, bytes.len()));
         }
         let value = self.to_be_bytes();
         bytes[0] = value[0];
@@ -191,11 +267,11 @@ impl TryWriteToBytes for u64 {
 }
 
 impl<'a> TryReadFromBytes<'a> for u64 {
-    type Error = &'static str;
+    type Error = CodecError;
 
     fn try_read_from_bytes(bytes: &'a [u8]) -> Result<(Self, usize), Self::Error> {
         if bytes.len() < 8 {
-            return Err("Buffer too small");
+            return Err(CodecError::buffer_too_small(8, bytes.len()));
         }
         let value = u64::from_be_bytes([
             bytes[0], bytes[1], bytes[2], bytes[3], bytes[4], bytes[5], bytes[6], bytes[7],
@@ -205,11 +281,11 @@ impl<'a> TryReadFromBytes<'a> for u64 {
 }
 
 impl<const N: usize> TryWriteToBytes for [u8; N] {
-    type Error = &'static str;
+    type Error = CodecError;
 
     fn try_write_to_bytes(&self, bytes: &mut [u8]) -> Result<usize, Self::Error> {
         if bytes.len() < N {
-            return Err("Buffer too small");
+            return Err(CodecError::buffer_too_small(N, bytes.len()));
         }
         bytes[0..N].copy_from_slice(self);
         Ok(N)
@@ -217,14 +293,60 @@ impl<const N: usize> TryWriteToBytes for [u8; N] {
 }
 
 impl<'a, const N: usize> TryReadFromBytes<'a> for [u8; N] {
-    type Error = &'static str;
+    type Error = CodecError;
 
     fn try_read_from_bytes(bytes: &'a [u8]) -> Result<(Self, usize), Self::Error> {
         if bytes.len() < N {
-            return Err("Buffer too small");
+            return Err(CodecError::buffer_too_small(N, bytes.len()));
         }
         let mut array = [0u8; N];
         array.copy_from_slice(&bytes[0..N]);
         Ok((array, N))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, PartialEq, TryWriteToBytes, TryReadFromBytes)]
+    #[ntp(align = 4)]
+    struct Extension {
+        #[ntp(bits = 4)]
+        flags: u8,
+        #[ntp(bits = 6)]
+        kind: u8,
+        #[ntp(bits = 6)]
+        subkind: u8,
+        #[ntp(padding = 1)]
+        length: u8,
+        #[ntp(size = 4)]
+        name: [u8; 3],
+        value: u8,
+    }
+
+    #[test]
+    fn derived_layout_has_sizes_padding_and_alignment() {
+        let extension = Extension {
+            flags: 0xa,
+            kind: 0x21,
+            subkind: 0x02,
+            length: 7,
+            name: *b"abc",
+            value: 9,
+        };
+        let mut bytes = [0xff; 16];
+
+        let size = extension.try_write_to_bytes(&mut bytes).unwrap();
+
+        assert_eq!(&bytes[..size], b"\xa8\x42\x07\x00abc\x00\x09\x00\x00\x00");
+        assert_eq!(
+            Extension::try_read_from_bytes(&bytes[..size]),
+            Ok((extension, 12))
+        );
+        assert_eq!(
+            Extension::try_read_from_bytes(&bytes[..size - 1]),
+            Err(CodecError::buffer_too_small(3, 2).at(9))
+        );
+    }
+}
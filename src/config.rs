@@ -0,0 +1,838 @@
+mod chrony;
+mod ntpd;
+
+use std::{
+    fs, io,
+    net::{SocketAddr, UdpSocket},
+    path::{Path, PathBuf},
+};
+
+use serde::Deserialize;
+
+use crate::{
+    association::SampleLimits,
+    auth::{KeyType, SymmetricKey},
+    client::NtpClientBuilder,
+    clock::Clock,
+    discipline::ClockDiscipline,
+    drift::{DriftFile, DriftFormat},
+    error::NtpResult,
+    logging::Verbosity,
+    refclock::{
+        nmea::NmeaRefClock, pps::PpsRefClock, refclock_address, RefClock, NMEA_DRIVER, PPS_DRIVER,
+        SHM_DRIVER,
+    },
+    server::{NtpServerBuilder, Restriction},
+    stats::StatsKind,
+};
+
+#[cfg(not(target_os = "linux"))]
+use crate::error::NtpError;
+#[cfg(target_os = "linux")]
+use crate::refclock::shm::ShmRefClock;
+
+/// Default shortest poll interval, as a power of two in seconds
+pub const NTP_MINPOLL: i8 = 6;
+
+/// Default longest poll interval, as a power of two in seconds
+pub const NTP_MAXPOLL: i8 = 10;
+
+/// Poll exponents a configuration may use
+const NTP_POLL_RANGE: std::ops::RangeInclusive<i8> = 3..=17;
+
+/// Error raised while loading a configuration file
+#[derive(Debug, PartialEq, Eq)]
+pub enum ConfigError {
+    Io(io::ErrorKind),
+    /// The file is not valid TOML or does not match the expected layout
+    Syntax(String),
+    /// A value is out of range or refers to something that does not exist
+    Invalid(String),
+    /// A directive of an `ntp.conf`, `chrony.conf` or key file could not be parsed
+    Directive {
+        line: usize,
+        reason: String,
+    },
+}
+
+impl From<io::Error> for ConfigError {
+    fn from(value: io::Error) -> Self {
+        Self::Io(value.kind())
+    }
+}
+
+/// Daemon configuration, read from a TOML file:
+///
+/// ```toml
+/// driftfile = "/var/lib/demo-ntpd/drift"
+/// listen = ["0.0.0.0:123"]
+///
+/// [[server]]
+/// address = "time.example.com"
+/// key = 1
+///
+/// [[pool]]
+/// address = "pool.ntp.org"
+/// max_sources = 4
+///
+/// [[key]]
+/// id = 1
+/// algorithm = "sha1"
+/// secret = "correct horse"
+///
+/// [[restrict]]
+/// address = "0.0.0.0/0"
+/// flags = ["limited", "kod"]
+/// ```
+#[derive(Debug, Clone, PartialEq, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default, rename = "server")]
+    pub servers: Vec<ServerConfig>,
+    #[serde(default, rename = "pool")]
+    pub pools: Vec<PoolConfig>,
+    /// Symmetric peers. They are polled like servers, as symmetric mode is not
+    /// supported.
+    #[serde(default, rename = "peer")]
+    pub peers: Vec<ServerConfig>,
+    #[serde(default, rename = "refclock")]
+    pub refclocks: Vec<RefClockConfig>,
+    #[serde(default, rename = "key")]
+    pub keys: Vec<KeyConfig>,
+    #[serde(default, rename = "restrict")]
+    pub restrictions: Vec<RestrictConfig>,
+    /// File the clock frequency is saved to across restarts
+    pub driftfile: Option<PathBuf>,
+    /// Format of the drift file, ntpd's by default
+    #[serde(default)]
+    pub drift_format: DriftFormat,
+    /// `leap-seconds.list` file announcing leap seconds
+    pub leapfile: Option<PathBuf>,
+    /// Addresses to serve time on. Nothing is served when empty.
+    #[serde(default)]
+    pub listen: Vec<SocketAddr>,
+    /// When the clock may be stepped instead of slewed
+    pub makestep: Option<MakeStep>,
+    /// Largest root distance of a server that can be selected, in seconds
+    pub max_distance: Option<f64>,
+    /// Largest time since a server's clock was last updated for its replies
+    /// to be used, in seconds
+    pub max_reference_age: Option<f64>,
+    /// Address to serve Prometheus metrics on, with the `metrics` feature
+    pub metrics: Option<SocketAddr>,
+    /// Directory the statistics files are written to
+    pub statsdir: Option<PathBuf>,
+    /// Statistics to write to `statsdir`
+    #[serde(default)]
+    pub statistics: Vec<StatsKind>,
+    /// Verbosity of the log, per subsystem
+    #[serde(default)]
+    pub log: Verbosity,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ServerConfig {
+    /// Host name or address, with an optional port
+    pub address: String,
+    /// Identifier of the key requests are signed with
+    pub key: Option<u32>,
+    #[serde(default = "default_minpoll")]
+    pub minpoll: i8,
+    #[serde(default = "default_maxpoll")]
+    pub maxpoll: i8,
+    /// Send a burst of requests when the server is unreachable
+    #[serde(default)]
+    pub iburst: bool,
+    /// Send a burst of requests at every poll
+    #[serde(default)]
+    pub burst: bool,
+}
+
+impl ServerConfig {
+    /// Server with the default settings
+    pub fn new(address: impl Into<String>) -> Self {
+        Self {
+            address: address.into(),
+            key: None,
+            minpoll: NTP_MINPOLL,
+            maxpoll: NTP_MAXPOLL,
+            iburst: false,
+            burst: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PoolConfig {
+    /// Host name resolving to several servers
+    pub address: String,
+    /// Number of the resolved servers to use
+    #[serde(default = "default_max_sources")]
+    pub max_sources: usize,
+    #[serde(default = "default_minpoll")]
+    pub minpoll: i8,
+    #[serde(default = "default_maxpoll")]
+    pub maxpoll: i8,
+    #[serde(default)]
+    pub iburst: bool,
+}
+
+impl PoolConfig {
+    /// Pool with the default settings
+    pub fn new(address: impl Into<String>) -> Self {
+        Self {
+            address: address.into(),
+            max_sources: default_max_sources(),
+            minpoll: NTP_MINPOLL,
+            maxpoll: NTP_MAXPOLL,
+            iburst: false,
+        }
+    }
+}
+
+/// Poll exponent of reference clocks, whose samples cost nothing
+pub const NTP_REFCLOCK_POLL: i8 = 4;
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RefClockConfig {
+    pub driver: RefClockDriver,
+    /// Number of the segment or device, which the pseudo-address ends with
+    #[serde(default)]
+    pub unit: u8,
+    /// Serial device of an NMEA receiver, or sysfs file of a PPS signal,
+    /// `/sys/class/pps/pps<unit>/assert` by default
+    pub path: Option<PathBuf>,
+    /// Delay of the sentences of an NMEA receiver after the second they
+    /// name, in seconds
+    #[serde(default)]
+    pub offset: f64,
+    #[serde(default = "default_refclock_poll")]
+    pub minpoll: i8,
+    #[serde(default = "default_refclock_poll")]
+    pub maxpoll: i8,
+}
+
+impl RefClockConfig {
+    /// Reference clock with the default settings
+    pub fn new(driver: RefClockDriver, unit: u8) -> Self {
+        Self {
+            driver,
+            unit,
+            path: None,
+            offset: 0.0,
+            minpoll: NTP_REFCLOCK_POLL,
+            maxpoll: NTP_REFCLOCK_POLL,
+        }
+    }
+
+    /// Name in the manner of ntpd, such as `NMEA(0)`
+    pub fn name(&self) -> String {
+        let driver = match self.driver {
+            RefClockDriver::Shm => "SHM",
+            RefClockDriver::Nmea => "NMEA",
+            RefClockDriver::Pps => "PPS",
+        };
+        format!("{driver}({})", self.unit)
+    }
+
+    /// Pseudo-address `127.127.t.u` of the reference clock
+    pub fn address(&self) -> SocketAddr {
+        let driver = match self.driver {
+            RefClockDriver::Shm => SHM_DRIVER,
+            RefClockDriver::Nmea => NMEA_DRIVER,
+            RefClockDriver::Pps => PPS_DRIVER,
+        };
+        refclock_address(driver, self.unit)
+    }
+
+    /// Opens the driver
+    pub fn open(&self) -> NtpResult<Box<dyn RefClock>> {
+        Ok(match self.driver {
+            #[cfg(target_os = "linux")]
+            RefClockDriver::Shm => Box::new(ShmRefClock::open(self.unit)?),
+            #[cfg(not(target_os = "linux"))]
+            RefClockDriver::Shm => return Err(NtpError::Io(io::ErrorKind::Unsupported)),
+            RefClockDriver::Nmea => {
+                let path = self
+                    .path
+                    .as_ref()
+                    .expect("paths are validated when loading");
+                Box::new(NmeaRefClock::open(path, self.offset)?)
+            }
+            RefClockDriver::Pps => {
+                Box::new(PpsRefClock::new(self.path.clone().unwrap_or_else(|| {
+                    PathBuf::from(format!("/sys/class/pps/pps{}/assert", self.unit))
+                })))
+            }
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RefClockDriver {
+    /// Shared memory segment in ntpd's format, as written by gpsd
+    Shm,
+    /// NMEA 0183 receiver on a serial port
+    Nmea,
+    /// Pulse per second signal of the Linux PPS subsystem
+    Pps,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct KeyConfig {
+    pub id: u32,
+    pub algorithm: KeyAlgorithm,
+    pub secret: String,
+    /// How `secret` is written
+    #[serde(default)]
+    pub encoding: SecretEncoding,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SecretEncoding {
+    /// The secret is used as is
+    #[default]
+    Ascii,
+    /// The secret is written as hexadecimal digits
+    Hex,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum KeyAlgorithm {
+    Md5,
+    Sha1,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RestrictConfig {
+    /// Network such as `192.0.2.0/24`, or a single address
+    pub address: String,
+    #[serde(default)]
+    pub flags: Vec<RestrictFlag>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RestrictFlag {
+    Ignore,
+    Noserve,
+    Limited,
+    Kod,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MakeStep {
+    /// Offsets larger than this are stepped, in seconds
+    pub threshold: f64,
+    /// Number of clock updates during which steps are allowed. Steps are
+    /// always allowed when absent.
+    pub limit: Option<u32>,
+}
+
+/// Directive of an `ntp.conf` or `chrony.conf` file that has no equivalent in
+/// the configuration and was left out
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IgnoredDirective {
+    pub line: usize,
+    /// The directive, or the option of a directive, that was left out
+    pub text: String,
+}
+
+/// Configuration translated from another daemon's configuration file
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfigImport {
+    pub config: Config,
+    pub ignored: Vec<IgnoredDirective>,
+}
+
+fn default_minpoll() -> i8 {
+    NTP_MINPOLL
+}
+
+fn default_maxpoll() -> i8 {
+    NTP_MAXPOLL
+}
+
+fn default_refclock_poll() -> i8 {
+    NTP_REFCLOCK_POLL
+}
+
+fn default_max_sources() -> usize {
+    4
+}
+
+impl Config {
+    pub fn from_toml(contents: &str) -> Result<Self, ConfigError> {
+        let config: Self =
+            toml::from_str(contents).map_err(|error| ConfigError::Syntax(error.to_string()))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, ConfigError> {
+        Self::from_toml(&fs::read_to_string(path)?)
+    }
+
+    /// Translates an `ntp.conf` file. `keys` is the contents of the file named
+    /// by its `keys` directive, if any.
+    pub fn from_ntp_conf(contents: &str, keys: Option<&str>) -> Result<ConfigImport, ConfigError> {
+        let mut import = ntpd::parse(contents)?;
+        if let Some(keys) = keys {
+            import.config.keys = ntpd::parse_keys(keys, &import.trusted_keys)?;
+        }
+        import.config.validate()?;
+        Ok(ConfigImport {
+            config: import.config,
+            ignored: import.ignored,
+        })
+    }
+
+    /// Loads an `ntp.conf` file and the key file it names
+    pub fn load_ntp_conf(path: impl AsRef<Path>) -> Result<ConfigImport, ConfigError> {
+        let contents = fs::read_to_string(path)?;
+        let keys = match ntpd::parse(&contents)?.keys_file {
+            Some(keys_file) => Some(fs::read_to_string(keys_file)?),
+            None => None,
+        };
+        Self::from_ntp_conf(&contents, keys.as_deref())
+    }
+
+    /// Translates a `chrony.conf` file. `keys` is the contents of the file
+    /// named by its `keyfile` directive, if any.
+    pub fn from_chrony_conf(
+        contents: &str,
+        keys: Option<&str>,
+    ) -> Result<ConfigImport, ConfigError> {
+        let mut import = chrony::parse(contents)?;
+        if let Some(keys) = keys {
+            import.config.keys = chrony::parse_keys(keys)?;
+        }
+        import.config.validate()?;
+        Ok(ConfigImport {
+            config: import.config,
+            ignored: import.ignored,
+        })
+    }
+
+    /// Loads a `chrony.conf` file and the key file it names
+    pub fn load_chrony_conf(path: impl AsRef<Path>) -> Result<ConfigImport, ConfigError> {
+        let contents = fs::read_to_string(path)?;
+        let keys = match chrony::parse(&contents)?.keys_file {
+            Some(keys_file) => Some(fs::read_to_string(keys_file)?),
+            None => None,
+        };
+        Self::from_chrony_conf(&contents, keys.as_deref())
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |message: String| Err(ConfigError::Invalid(message));
+
+        let polls = self
+            .servers
+            .iter()
+            .chain(&self.peers)
+            .map(|server| (&server.address, server.minpoll, server.maxpoll))
+            .chain(
+                self.pools
+                    .iter()
+                    .map(|pool| (&pool.address, pool.minpoll, pool.maxpoll)),
+            );
+        for (address, minpoll, maxpoll) in polls {
+            if !NTP_POLL_RANGE.contains(&minpoll)
+                || !NTP_POLL_RANGE.contains(&maxpoll)
+                || minpoll > maxpoll
+            {
+                return invalid(format!("invalid poll range for {address}"));
+            }
+        }
+        for refclock in &self.refclocks {
+            let name = refclock.name();
+            if !NTP_POLL_RANGE.contains(&refclock.minpoll)
+                || !NTP_POLL_RANGE.contains(&refclock.maxpoll)
+                || refclock.minpoll > refclock.maxpoll
+            {
+                return invalid(format!("invalid poll range for {name}"));
+            }
+            if refclock.driver == RefClockDriver::Nmea && refclock.path.is_none() {
+                return invalid(format!("{name} needs a path"));
+            }
+            if refclock.driver != RefClockDriver::Nmea && refclock.offset != 0.0 {
+                return invalid(format!("{name} takes no offset"));
+            }
+        }
+        let pulses_only = self.servers.is_empty()
+            && self.peers.is_empty()
+            && self.pools.is_empty()
+            && !self.refclocks.is_empty()
+            && self
+                .refclocks
+                .iter()
+                .all(|refclock| refclock.driver == RefClockDriver::Pps);
+        if pulses_only {
+            return invalid("a PPS signal needs another source to name the second".to_string());
+        }
+        if !self.statistics.is_empty() && self.statsdir.is_none() {
+            return invalid("statistics need a statsdir".to_string());
+        }
+        for server in self.servers.iter().chain(&self.peers) {
+            if let Some(id) = server.key {
+                if self.key(id).is_none() {
+                    return invalid(format!("unknown key {id} for {}", server.address));
+                }
+            }
+        }
+        for (index, key) in self.keys.iter().enumerate() {
+            if self.keys[..index].iter().any(|other| other.id == key.id) {
+                return invalid(format!("duplicate key {}", key.id));
+            }
+            if key.encoding == SecretEncoding::Hex && decode_hex(&key.secret).is_none() {
+                return invalid(format!("key {} is not hexadecimal", key.id));
+            }
+        }
+        if let Some(makestep) = self.makestep {
+            if makestep.threshold.is_nan() || makestep.threshold < 0.0 {
+                return invalid("invalid makestep threshold".to_string());
+            }
+        }
+        if self
+            .max_distance
+            .is_some_and(|distance| distance.is_nan() || distance <= 0.0)
+        {
+            return invalid("invalid maximum distance".to_string());
+        }
+        if self
+            .max_reference_age
+            .is_some_and(|age| age.is_nan() || age <= 0.0)
+        {
+            return invalid("invalid maximum reference age".to_string());
+        }
+        for restriction in &self.restrictions {
+            if let Err(reason) = restriction.address.parse::<Restriction>() {
+                return invalid(format!("{}: {reason}", restriction.address));
+            }
+        }
+        Ok(())
+    }
+
+    /// Key with the identifier `id`
+    pub fn key(&self, id: u32) -> Option<SymmetricKey> {
+        self.keys.iter().find(|key| key.id == id).map(|key| {
+            let key_type = match key.algorithm {
+                KeyAlgorithm::Md5 => KeyType::Md5,
+                KeyAlgorithm::Sha1 => KeyType::Sha1,
+            };
+            let secret = match key.encoding {
+                SecretEncoding::Ascii => key.secret.as_bytes().to_vec(),
+                SecretEncoding::Hex => {
+                    decode_hex(&key.secret).expect("keys are validated when loading")
+                }
+            };
+            SymmetricKey::new(key.id, key_type, secret)
+        })
+    }
+
+    /// Client for the server at `address`, signing requests with the key `key`
+    pub fn client_builder(
+        &self,
+        key: Option<u32>,
+        address: SocketAddr,
+    ) -> NtpResult<NtpClientBuilder> {
+        let local: SocketAddr = if address.is_ipv4() {
+            ([0, 0, 0, 0], 0).into()
+        } else {
+            ([0u16; 8], 0).into()
+        };
+        let builder = NtpClientBuilder::new(UdpSocket::bind(local)?, address.to_string());
+        Ok(match key.and_then(|id| self.key(id)) {
+            Some(key) => builder.key(key),
+            None => builder,
+        })
+    }
+
+    /// File the clock frequency is saved to, if any
+    pub fn drift_file(&self) -> Option<DriftFile> {
+        let path = self.driftfile.as_ref()?;
+        Some(DriftFile::new(path, self.drift_format))
+    }
+
+    /// Clock discipline following the `makestep` settings
+    pub fn clock_discipline(&self) -> ClockDiscipline {
+        let discipline = ClockDiscipline::new();
+        let Some(makestep) = self.makestep else {
+            return discipline;
+        };
+        let discipline = discipline.with_step_threshold(makestep.threshold);
+        match makestep.limit {
+            Some(limit) => discipline.with_step_limit(limit),
+            None => discipline,
+        }
+    }
+
+    /// Limits of the servers' replies, ntpd's unless configured
+    pub fn sample_limits(&self) -> SampleLimits {
+        let defaults = SampleLimits::default();
+        SampleLimits {
+            max_distance: self.max_distance.unwrap_or(defaults.max_distance),
+            max_reference_age: self.max_reference_age.unwrap_or(defaults.max_reference_age),
+        }
+    }
+
+    /// Server answering on `udp_socket` with the configured keys and restrictions
+    pub fn server_builder<C: Clock>(&self, udp_socket: UdpSocket, clock: C) -> NtpServerBuilder<C> {
+        NtpServerBuilder::new(udp_socket, clock)
+            .keys(self.symmetric_keys())
+            .restrictions(self.server_restrictions())
+    }
+
+    pub fn symmetric_keys(&self) -> Vec<SymmetricKey> {
+        self.keys
+            .iter()
+            .filter_map(|key| self.key(key.id))
+            .collect()
+    }
+
+    /// Restrictions for the server, in the order they were configured
+    pub fn server_restrictions(&self) -> Vec<Restriction> {
+        self.restrictions
+            .iter()
+            .map(|config| {
+                let mut restriction = config
+                    .address
+                    .parse::<Restriction>()
+                    .expect("restrictions are validated when loading");
+                for flag in &config.flags {
+                    match flag {
+                        RestrictFlag::Ignore => restriction.ignore = true,
+                        RestrictFlag::Noserve => restriction.noserve = true,
+                        RestrictFlag::Limited => restriction.limited = true,
+                        RestrictFlag::Kod => restriction.kod = true,
+                    }
+                }
+                restriction
+            })
+            .collect()
+    }
+}
+
+/// Lines of an `ntp.conf` style file split into words, without comments and
+/// blank lines, with their line numbers
+fn directives<'a>(
+    contents: &'a str,
+    comments: &'a [char],
+) -> impl Iterator<Item = (usize, Vec<&'a str>)> + 'a {
+    contents
+        .lines()
+        .enumerate()
+        .filter_map(move |(index, line)| {
+            let line = line.split(comments).next().unwrap_or_default();
+            let words = line.split_whitespace().collect::<Vec<_>>();
+            (!words.is_empty()).then_some((index + 1, words))
+        })
+}
+
+fn number<T: std::str::FromStr>(line: usize, word: &str) -> Result<T, ConfigError> {
+    word.parse().map_err(|_| ConfigError::Directive {
+        line,
+        reason: format!("invalid number {word}"),
+    })
+}
+
+fn decode_hex(digits: &str) -> Option<Vec<u8>> {
+    if !digits.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..digits.len())
+        .step_by(2)
+        .map(|index| u8::from_str_radix(digits.get(index..index + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use log::LevelFilter;
+
+    use crate::logging;
+
+    use super::*;
+
+    const CONFIG: &str = r#"
+driftfile = "/var/lib/demo-ntpd/drift"
+drift_format = "chrony"
+listen = ["127.0.0.1:1123"]
+
+[[server]]
+address = "192.0.2.1"
+key = 1
+minpoll = 4
+
+[[pool]]
+address = "pool.ntp.org"
+
+[[key]]
+id = 1
+algorithm = "md5"
+secret = "secret"
+
+[[restrict]]
+address = "192.0.2.0/24"
+flags = ["limited", "kod"]
+
+[log]
+level = "warn"
+clock = "debug"
+"#;
+
+    #[test]
+    fn full_config_is_parsed() {
+        let config = Config::from_toml(CONFIG).unwrap();
+
+        assert_eq!(
+            config.servers,
+            vec![ServerConfig {
+                address: "192.0.2.1".to_string(),
+                key: Some(1),
+                minpoll: 4,
+                ..ServerConfig::new("192.0.2.1")
+            }]
+        );
+        assert_eq!(config.pools[0].max_sources, 4);
+        assert_eq!(
+            config.drift_file(),
+            Some(DriftFile::new(
+                "/var/lib/demo-ntpd/drift",
+                DriftFormat::Chrony
+            ))
+        );
+        assert_eq!(config.listen, vec!["127.0.0.1:1123".parse().unwrap()]);
+        assert_eq!(config.log.level(logging::CLOCK), LevelFilter::Debug);
+        assert_eq!(config.log.level(logging::SERVER), LevelFilter::Warn);
+        assert_eq!(config.key(1).unwrap().key_type, KeyType::Md5);
+
+        let restriction = config.server_restrictions()[0];
+        assert_eq!(restriction.prefix_len, 24);
+        assert!(restriction.limited && restriction.kod);
+        assert!(!restriction.ignore && !restriction.noserve);
+    }
+
+    #[test]
+    fn empty_config_is_valid() {
+        assert_eq!(Config::from_toml(""), Ok(Config::default()));
+    }
+
+    #[test]
+    fn unknown_fields_are_rejected() {
+        let error = Config::from_toml("[[server]]\naddress = \"a\"\nprefer = true\n");
+
+        assert!(matches!(error, Err(ConfigError::Syntax(message)) if message.contains("prefer")));
+    }
+
+    #[test]
+    fn invalid_values_are_rejected() {
+        assert_eq!(
+            Config::from_toml("[[server]]\naddress = \"a\"\nkey = 2\n"),
+            Err(ConfigError::Invalid("unknown key 2 for a".to_string()))
+        );
+        assert_eq!(
+            Config::from_toml("[[server]]\naddress = \"a\"\nminpoll = 11\n"),
+            Err(ConfigError::Invalid("invalid poll range for a".to_string()))
+        );
+        assert_eq!(
+            Config::from_toml("[[restrict]]\naddress = \"nowhere\"\n"),
+            Err(ConfigError::Invalid(
+                "nowhere: Invalid network address".to_string()
+            ))
+        );
+        assert_eq!(
+            Config::from_toml("statistics = [\"loopstats\"]\n"),
+            Err(ConfigError::Invalid(
+                "statistics need a statsdir".to_string()
+            ))
+        );
+        assert_eq!(
+            Config::from_toml("max_reference_age = -1.0\n"),
+            Err(ConfigError::Invalid(
+                "invalid maximum reference age".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn refclocks_are_read() {
+        let config = Config::from_toml(
+            "[[refclock]]\ndriver = \"nmea\"\npath = \"/dev/ttyS0\"\noffset = 0.2\n\n\
+             [[refclock]]\ndriver = \"pps\"\nunit = 1\n",
+        )
+        .unwrap();
+
+        assert_eq!(config.refclocks[0].name(), "NMEA(0)");
+        assert_eq!(config.refclocks[0].offset, 0.2);
+        assert_eq!(config.refclocks[1].address().to_string(), "127.127.22.1:0");
+        assert_eq!(
+            Config::from_toml("[[refclock]]\ndriver = \"nmea\"\n"),
+            Err(ConfigError::Invalid("NMEA(0) needs a path".to_string()))
+        );
+        assert_eq!(
+            Config::from_toml("[[refclock]]\ndriver = \"shm\"\noffset = 0.1\n"),
+            Err(ConfigError::Invalid("SHM(0) takes no offset".to_string()))
+        );
+        assert_eq!(
+            Config::from_toml("[[refclock]]\ndriver = \"pps\"\n"),
+            Err(ConfigError::Invalid(
+                "a PPS signal needs another source to name the second".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn sample_limits_default_to_ntpd() {
+        let config = Config::from_toml("max_distance = 3.0\n").unwrap();
+
+        assert_eq!(
+            config.sample_limits(),
+            SampleLimits {
+                max_distance: 3.0,
+                ..SampleLimits::default()
+            }
+        );
+    }
+
+    #[test]
+    fn ntp_conf_keys_are_usable() {
+        let import = Config::from_ntp_conf(
+            "server 192.0.2.1 key 2\nkeys /etc/ntp/keys\ntrustedkey 2\n",
+            Some("1 M one\n2 SHA1 0123456789abcdef0123456789abcdef01234567\n"),
+        )
+        .unwrap();
+
+        let key = import.config.key(2).unwrap();
+        assert_eq!(key.key_type, KeyType::Sha1);
+        assert_eq!(import.config.key(1), None);
+        assert!(import.ignored.is_empty());
+        assert_eq!(
+            Config::from_ntp_conf("server 192.0.2.1 key 2\n", None),
+            Err(ConfigError::Invalid(
+                "unknown key 2 for 192.0.2.1".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn chrony_limits_are_translated() {
+        let import = Config::from_chrony_conf("makestep 1 3\nmaxdistance 2\n", None).unwrap();
+
+        assert_eq!(import.config.max_distance, Some(2.0));
+        assert_eq!(
+            Config::from_chrony_conf("maxdistance 0\n", None),
+            Err(ConfigError::Invalid("invalid maximum distance".to_string()))
+        );
+    }
+}
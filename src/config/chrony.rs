@@ -0,0 +1,359 @@
+//! Translation of chrony's `chrony.conf` and key file
+
+use std::{net::SocketAddr, path::PathBuf};
+
+use crate::drift::DriftFormat;
+
+use super::{
+    directives, number, Config, ConfigError, IgnoredDirective, KeyAlgorithm, KeyConfig, MakeStep,
+    PoolConfig, RestrictConfig, RestrictFlag, SecretEncoding, ServerConfig,
+};
+
+/// Characters that start a comment in chrony's files
+const CHRONY_COMMENTS: &[char] = &['#', '!', ';', '%'];
+
+/// Port chrony serves time on unless `port` says otherwise
+const CHRONY_DEFAULT_PORT: u16 = 123;
+
+pub(super) struct ChronyImport {
+    pub config: Config,
+    pub ignored: Vec<IgnoredDirective>,
+    pub keys_file: Option<PathBuf>,
+}
+
+pub(super) fn parse(contents: &str) -> Result<ChronyImport, ConfigError> {
+    let mut import = ChronyImport {
+        config: Config::default(),
+        ignored: Vec::new(),
+        keys_file: None,
+    };
+    let mut port = CHRONY_DEFAULT_PORT;
+    // chrony only serves time to the networks listed by `allow`
+    let mut serving = false;
+
+    for (line, words) in directives(contents, CHRONY_COMMENTS) {
+        let ignore = |text: &str| IgnoredDirective {
+            line,
+            text: text.to_string(),
+        };
+        let argument = |index: usize| {
+            words
+                .get(index)
+                .copied()
+                .ok_or_else(|| ConfigError::Directive {
+                    line,
+                    reason: format!("missing argument for {}", words[0]),
+                })
+        };
+        match words[0] {
+            "server" | "peer" | "pool" => {
+                let (server, max_sources, ignored) = parse_server(line, &words)?;
+                import
+                    .ignored
+                    .extend(ignored.iter().map(|text| ignore(text)));
+                match words[0] {
+                    "server" => import.config.servers.push(server),
+                    "peer" => import.config.peers.push(server),
+                    _ => {
+                        let mut pool = PoolConfig {
+                            minpoll: server.minpoll,
+                            maxpoll: server.maxpoll,
+                            iburst: server.iburst,
+                            ..PoolConfig::new(server.address)
+                        };
+                        if let Some(max_sources) = max_sources {
+                            pool.max_sources = max_sources;
+                        }
+                        import.config.pools.push(pool);
+                    }
+                }
+            }
+            "makestep" => {
+                let limit: i64 = number(line, argument(2)?)?;
+                import.config.makestep = Some(MakeStep {
+                    threshold: number(line, argument(1)?)?,
+                    limit: u32::try_from(limit).ok(),
+                });
+            }
+            "maxdistance" => import.config.max_distance = Some(number(line, argument(1)?)?),
+            "driftfile" => {
+                import.config.driftfile = Some(argument(1)?.into());
+                import.config.drift_format = DriftFormat::Chrony;
+            }
+            "leapseclist" => import.config.leapfile = Some(argument(1)?.into()),
+            "keyfile" => import.keys_file = Some(argument(1)?.into()),
+            "port" => port = number(line, argument(1)?)?,
+            "allow" | "deny" => {
+                if !serving {
+                    serving = true;
+                    import
+                        .config
+                        .restrictions
+                        .extend(everything(vec![RestrictFlag::Ignore]));
+                }
+                let flags = match words[0] {
+                    "allow" => Vec::new(),
+                    _ => vec![RestrictFlag::Ignore],
+                };
+                let networks = words[1..]
+                    .iter()
+                    .filter(|word| **word != "all")
+                    .collect::<Vec<_>>();
+                match networks[..] {
+                    [] => import.config.restrictions.extend(everything(flags)),
+                    [network] => import.config.restrictions.push(RestrictConfig {
+                        address: subnet(network),
+                        flags,
+                    }),
+                    _ => {
+                        return Err(ConfigError::Directive {
+                            line,
+                            reason: format!("too many arguments for {}", words[0]),
+                        })
+                    }
+                }
+            }
+            _ => import.ignored.push(ignore(&words.join(" "))),
+        }
+    }
+
+    if serving && port != 0 {
+        import.config.listen = vec![
+            SocketAddr::from(([0, 0, 0, 0], port)),
+            SocketAddr::from(([0u16; 8], port)),
+        ];
+    }
+    Ok(import)
+}
+
+/// Parses a `server`, `peer` or `pool` directive. Returns the `maxsources` of
+/// a pool and the options that were left out.
+fn parse_server<'a>(
+    line: usize,
+    words: &[&'a str],
+) -> Result<(ServerConfig, Option<usize>, Vec<&'a str>), ConfigError> {
+    let mut ignored = Vec::new();
+    let mut words = words[1..].iter().copied();
+    let address = words.next().ok_or_else(|| ConfigError::Directive {
+        line,
+        reason: "missing server address".to_string(),
+    })?;
+
+    let mut server = ServerConfig::new(address);
+    let mut max_sources = None;
+    while let Some(option) = words.next() {
+        let mut value = || {
+            words.next().ok_or_else(|| ConfigError::Directive {
+                line,
+                reason: format!("missing value for {option}"),
+            })
+        };
+        match option {
+            "key" => server.key = Some(number(line, value()?)?),
+            "minpoll" => server.minpoll = number(line, value()?)?,
+            "maxpoll" => server.maxpoll = number(line, value()?)?,
+            "maxsources" => max_sources = Some(number(line, value()?)?),
+            "port" => {
+                let port: u16 = number(line, value()?)?;
+                server.address = if address.contains(':') {
+                    format!("[{address}]:{port}")
+                } else {
+                    format!("{address}:{port}")
+                };
+            }
+            "iburst" => server.iburst = true,
+            "burst" => server.burst = true,
+            "polltarget" | "presend" | "maxdelay" | "maxdelayratio" | "maxdelaydevratio"
+            | "mindelay" | "asymmetry" | "offset" | "minsamples" | "maxsamples" | "filter"
+            | "version" | "certset" | "extfield" | "minstratum" => {
+                value()?;
+                ignored.push(option);
+            }
+            _ => ignored.push(option),
+        }
+    }
+    Ok((server, max_sources, ignored))
+}
+
+/// Restrictions covering every IPv4 and IPv6 address
+fn everything(flags: Vec<RestrictFlag>) -> [RestrictConfig; 2] {
+    ["0.0.0.0/0", "::/0"].map(|address| RestrictConfig {
+        address: address.to_string(),
+        flags: flags.clone(),
+    })
+}
+
+/// Expands chrony's shortened IPv4 subnets, such as `192.168` for `192.168.0.0/16`
+fn subnet(network: &str) -> String {
+    let octets = network.split('.').collect::<Vec<_>>();
+    let shortened = octets.len() < 4
+        && !network.contains('/')
+        && octets.iter().all(|octet| octet.parse::<u8>().is_ok());
+    if !shortened {
+        return network.to_string();
+    }
+    let prefix_len = octets.len() * 8;
+    let mut address = octets;
+    address.resize(4, "0");
+    format!("{}/{prefix_len}", address.join("."))
+}
+
+/// Parses chrony's key file: one `ID [TYPE] KEY` line per key, where the key is
+/// prefixed with `HEX:` or `ASCII:`, ASCII by default, and the type is MD5 by
+/// default
+pub(super) fn parse_keys(contents: &str) -> Result<Vec<KeyConfig>, ConfigError> {
+    let mut keys = Vec::new();
+    for (line, words) in directives(contents, CHRONY_COMMENTS) {
+        let (id, key_type, secret) = match words[..] {
+            [id, secret] => (id, "MD5", secret),
+            [id, key_type, secret] => (id, key_type, secret),
+            _ => {
+                return Err(ConfigError::Directive {
+                    line,
+                    reason: "expected key identifier, type and secret".to_string(),
+                })
+            }
+        };
+        let algorithm = match key_type.to_ascii_uppercase().as_str() {
+            "MD5" => KeyAlgorithm::Md5,
+            "SHA1" => KeyAlgorithm::Sha1,
+            _ => {
+                return Err(ConfigError::Directive {
+                    line,
+                    reason: format!("unsupported key type {key_type}"),
+                })
+            }
+        };
+        let (encoding, secret) = match secret.split_once(':') {
+            Some(("HEX", secret)) => (SecretEncoding::Hex, secret),
+            Some(("ASCII", secret)) => (SecretEncoding::Ascii, secret),
+            _ => (SecretEncoding::Ascii, secret),
+        };
+        keys.push(KeyConfig {
+            id: number(line, id)?,
+            algorithm,
+            secret: secret.to_string(),
+            encoding,
+        });
+    }
+    Ok(keys)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::drift::DriftFile;
+
+    use super::*;
+
+    const CHRONY_CONF: &str = "\
+# /etc/chrony.conf
+pool 2.pool.ntp.org iburst maxsources 2
+server 192.0.2.1 iburst minpoll 4 port 1123 prefer
+server 2001:db8::1 key 3 maxdelay 0.1
+driftfile /var/lib/chrony/drift
+makestep 1.0 3
+maxdistance 3
+rtcsync
+allow 192.168
+deny 192.168.7.0/24
+";
+
+    #[test]
+    fn chrony_conf_is_translated() {
+        let import = parse(CHRONY_CONF).unwrap();
+        let config = import.config;
+
+        assert_eq!(config.pools[0].max_sources, 2);
+        assert!(config.pools[0].iburst);
+        assert_eq!(
+            config.servers,
+            vec![
+                ServerConfig {
+                    minpoll: 4,
+                    iburst: true,
+                    ..ServerConfig::new("192.0.2.1:1123")
+                },
+                ServerConfig {
+                    key: Some(3),
+                    ..ServerConfig::new("2001:db8::1")
+                },
+            ]
+        );
+        assert_eq!(
+            config.makestep,
+            Some(MakeStep {
+                threshold: 1.0,
+                limit: Some(3)
+            })
+        );
+        assert_eq!(config.max_distance, Some(3.0));
+        assert_eq!(
+            config.drift_file(),
+            Some(DriftFile::new("/var/lib/chrony/drift", DriftFormat::Chrony))
+        );
+        assert_eq!(config.listen.len(), 2);
+
+        let restrictions = config
+            .restrictions
+            .iter()
+            .map(|restriction| (restriction.address.as_str(), restriction.flags.clone()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            restrictions,
+            vec![
+                ("0.0.0.0/0", vec![RestrictFlag::Ignore]),
+                ("::/0", vec![RestrictFlag::Ignore]),
+                ("192.168.0.0/16", vec![]),
+                ("192.168.7.0/24", vec![RestrictFlag::Ignore]),
+            ]
+        );
+    }
+
+    #[test]
+    fn unknown_directives_are_reported_with_their_line() {
+        let ignored = parse(CHRONY_CONF).unwrap().ignored;
+
+        let ignored = ignored
+            .iter()
+            .map(|ignored| (ignored.line, ignored.text.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            ignored,
+            vec![(3, "prefer"), (4, "maxdelay"), (8, "rtcsync")]
+        );
+    }
+
+    #[test]
+    fn makestep_without_limit_always_steps() {
+        let config = parse("makestep 0.1 -1\n").unwrap().config;
+
+        assert_eq!(
+            config.makestep,
+            Some(MakeStep {
+                threshold: 0.1,
+                limit: None
+            })
+        );
+        assert!(matches!(
+            parse("makestep 0.1\n").err(),
+            Some(ConfigError::Directive { line: 1, .. })
+        ));
+    }
+
+    #[test]
+    fn no_allow_means_no_serving() {
+        assert!(parse("server a\n").unwrap().config.listen.is_empty());
+        assert!(parse("allow\nport 0\n").unwrap().config.listen.is_empty());
+    }
+
+    #[test]
+    fn keys_file_is_parsed() {
+        let keys = parse_keys("1 secret\n2 SHA1 HEX:0011aabb\n").unwrap();
+
+        assert_eq!(keys[0].algorithm, KeyAlgorithm::Md5);
+        assert_eq!(keys[0].secret, "secret");
+        assert_eq!(keys[1].algorithm, KeyAlgorithm::Sha1);
+        assert_eq!(keys[1].encoding, SecretEncoding::Hex);
+        assert_eq!(keys[1].secret, "0011aabb");
+    }
+}
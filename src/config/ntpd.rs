@@ -0,0 +1,460 @@
+//! Translation of ntpd's `ntp.conf` and key file
+
+use std::{net::Ipv4Addr, path::PathBuf};
+
+use crate::{
+    refclock::{NMEA_DRIVER, PPS_DRIVER, SHM_DRIVER},
+    stats::StatsKind,
+};
+
+use super::{
+    directives, number, Config, ConfigError, IgnoredDirective, KeyAlgorithm, KeyConfig, PoolConfig,
+    RefClockConfig, RefClockDriver, RestrictConfig, RestrictFlag, SecretEncoding, ServerConfig,
+};
+
+/// Longest key ntpd reads as ASCII; longer keys are hexadecimal
+const NTPD_MAX_ASCII_KEY: usize = 20;
+
+pub(super) struct NtpdImport {
+    pub config: Config,
+    pub ignored: Vec<IgnoredDirective>,
+    pub keys_file: Option<PathBuf>,
+    /// Keys listed by `trustedkey`. Every key is trusted when empty.
+    pub trusted_keys: Vec<u32>,
+}
+
+pub(super) fn parse(contents: &str) -> Result<NtpdImport, ConfigError> {
+    let mut import = NtpdImport {
+        config: Config {
+            // ntpd always serves time
+            listen: vec![
+                "0.0.0.0:123".parse().expect("valid address"),
+                "[::]:123".parse().expect("valid address"),
+            ],
+            ..Config::default()
+        },
+        ignored: Vec::new(),
+        keys_file: None,
+        trusted_keys: Vec::new(),
+    };
+
+    for (line, words) in directives(contents, &['#']) {
+        let ignore = |text: &str| IgnoredDirective {
+            line,
+            text: text.to_string(),
+        };
+        let missing = || ConfigError::Directive {
+            line,
+            reason: format!("missing argument for {}", words[0]),
+        };
+        match words[0] {
+            "server" | "peer" | "pool" => {
+                let (server, ignored) = parse_server(line, &words)?;
+                import
+                    .ignored
+                    .extend(ignored.iter().map(|text| ignore(text)));
+                match words[0] {
+                    "server" => match address(&server) {
+                        Address::Network => import.config.servers.push(server),
+                        Address::RefClock(refclock) => import.config.refclocks.push(refclock),
+                        Address::UnsupportedRefClock => {
+                            import.ignored.push(ignore(&words.join(" ")))
+                        }
+                    },
+                    "peer" => import.config.peers.push(server),
+                    _ => import.config.pools.push(PoolConfig {
+                        minpoll: server.minpoll,
+                        maxpoll: server.maxpoll,
+                        iburst: server.iburst,
+                        ..PoolConfig::new(server.address)
+                    }),
+                }
+            }
+            "restrict" => {
+                let (restrictions, ignored) = parse_restrict(line, &words)?;
+                import
+                    .ignored
+                    .extend(ignored.iter().map(|text| ignore(text)));
+                import.config.restrictions.extend(restrictions);
+            }
+            "driftfile" => {
+                import.config.driftfile = Some(words.get(1).ok_or_else(missing)?.into());
+            }
+            "leapfile" => {
+                import.config.leapfile = Some(words.get(1).ok_or_else(missing)?.into());
+            }
+            "statsdir" => {
+                import.config.statsdir = Some(words.get(1).ok_or_else(missing)?.into());
+            }
+            "statistics" => {
+                for word in &words[1..] {
+                    match *word {
+                        "peerstats" => import.config.statistics.push(StatsKind::Peerstats),
+                        "loopstats" => import.config.statistics.push(StatsKind::Loopstats),
+                        "clockstats" => import.config.statistics.push(StatsKind::Clockstats),
+                        _ => import.ignored.push(ignore(word)),
+                    }
+                }
+            }
+            "tos" => {
+                for option in words[1..].chunks(2) {
+                    match option {
+                        ["maxdist", value] => {
+                            import.config.max_distance = Some(number(line, value)?);
+                        }
+                        _ => import.ignored.push(ignore(&option.join(" "))),
+                    }
+                }
+            }
+            "keys" => import.keys_file = Some(words.get(1).ok_or_else(missing)?.into()),
+            "trustedkey" => {
+                for word in &words[1..] {
+                    import.trusted_keys.push(number(line, word)?);
+                }
+            }
+            _ => import.ignored.push(ignore(&words.join(" "))),
+        }
+    }
+    Ok(import)
+}
+
+/// What the address of a `server` directive stands for
+enum Address {
+    /// A server on the network
+    Network,
+    /// A reference clock with a pseudo-address `127.127.t.u`
+    RefClock(RefClockConfig),
+    /// A reference clock whose driver is not supported, such as the local clock
+    /// `127.127.1.0`
+    UnsupportedRefClock,
+}
+
+/// Tells a reference clock from a server on the network by its address
+fn address(server: &ServerConfig) -> Address {
+    let Ok(address) = server.address.parse::<Ipv4Addr>() else {
+        return Address::Network;
+    };
+    let [127, 127, driver, unit] = address.octets() else {
+        return Address::Network;
+    };
+    let driver = match driver {
+        SHM_DRIVER => RefClockDriver::Shm,
+        NMEA_DRIVER => RefClockDriver::Nmea,
+        PPS_DRIVER => RefClockDriver::Pps,
+        _ => return Address::UnsupportedRefClock,
+    };
+    Address::RefClock(RefClockConfig {
+        // The device ntpd reads
+        path: (driver == RefClockDriver::Nmea).then(|| format!("/dev/gps{unit}").into()),
+        minpoll: server.minpoll,
+        maxpoll: server.maxpoll,
+        ..RefClockConfig::new(driver, unit)
+    })
+}
+
+/// Parses a `server`, `peer` or `pool` directive. Returns the options that
+/// were left out.
+fn parse_server<'a>(
+    line: usize,
+    words: &[&'a str],
+) -> Result<(ServerConfig, Vec<&'a str>), ConfigError> {
+    let mut ignored = Vec::new();
+    let mut words = words[1..].iter().copied();
+    let mut address = words.next();
+    // Address family restrictions only matter for name resolution
+    if let Some(family @ ("-4" | "-6")) = address {
+        ignored.push(family);
+        address = words.next();
+    }
+    let address = address.ok_or_else(|| ConfigError::Directive {
+        line,
+        reason: "missing server address".to_string(),
+    })?;
+
+    let mut server = ServerConfig::new(address);
+    while let Some(option) = words.next() {
+        let mut value = || {
+            words.next().ok_or_else(|| ConfigError::Directive {
+                line,
+                reason: format!("missing value for {option}"),
+            })
+        };
+        match option {
+            "key" => server.key = Some(number(line, value()?)?),
+            "minpoll" => server.minpoll = number(line, value()?)?,
+            "maxpoll" => server.maxpoll = number(line, value()?)?,
+            "iburst" => server.iburst = true,
+            "burst" => server.burst = true,
+            "version" | "mode" | "ttl" => {
+                value()?;
+                ignored.push(option);
+            }
+            _ => ignored.push(option),
+        }
+    }
+    Ok((server, ignored))
+}
+
+/// Parses a `restrict` directive. `default` expands to one restriction per
+/// address family. Returns the flags that were left out.
+fn parse_restrict<'a>(
+    line: usize,
+    words: &[&'a str],
+) -> Result<(Vec<RestrictConfig>, Vec<&'a str>), ConfigError> {
+    let mut ignored = Vec::new();
+    let mut words = words[1..].iter().copied().peekable();
+    let family = words.next_if(|word| matches!(*word, "-4" | "-6"));
+    let address = words.next().ok_or_else(|| ConfigError::Directive {
+        line,
+        reason: "missing restrict address".to_string(),
+    })?;
+
+    let addresses = match (address, family) {
+        ("default", Some("-4")) => vec!["0.0.0.0/0".to_string()],
+        ("default", Some("-6")) => vec!["::/0".to_string()],
+        ("default", _) => vec!["0.0.0.0/0".to_string(), "::/0".to_string()],
+        // Restrictions on the servers' own addresses
+        ("source", _) => return Ok((Vec::new(), vec!["restrict source"])),
+        (address, _) if words.next_if_eq(&"mask").is_some() => {
+            let mask = words.next().ok_or_else(|| ConfigError::Directive {
+                line,
+                reason: "missing value for mask".to_string(),
+            })?;
+            vec![format!("{address}/{}", prefix_len(line, mask)?)]
+        }
+        (address, _) => vec![address.to_string()],
+    };
+
+    let mut flags = Vec::new();
+    for flag in words {
+        match flag {
+            "ignore" => flags.push(RestrictFlag::Ignore),
+            "noserve" => flags.push(RestrictFlag::Noserve),
+            "limited" => flags.push(RestrictFlag::Limited),
+            "kod" => flags.push(RestrictFlag::Kod),
+            // There are no control or trap messages nor symmetric peers to refuse
+            "nomodify" | "noquery" | "notrap" | "nopeer" | "nomrulist" | "lowpriotrap" => {}
+            _ => ignored.push(flag),
+        }
+    }
+    let restrictions = addresses
+        .into_iter()
+        .map(|address| RestrictConfig {
+            address,
+            flags: flags.clone(),
+        })
+        .collect();
+    Ok((restrictions, ignored))
+}
+
+/// Converts a netmask such as `255.255.255.0` to a prefix length
+fn prefix_len(line: usize, mask: &str) -> Result<u32, ConfigError> {
+    let invalid = || ConfigError::Directive {
+        line,
+        reason: format!("invalid mask {mask}"),
+    };
+    let bits = match mask.parse().map_err(|_| invalid())? {
+        std::net::IpAddr::V4(mask) => u128::from(u32::from(mask)) << 96,
+        std::net::IpAddr::V6(mask) => u128::from(mask),
+    };
+    let prefix_len = bits.leading_ones();
+    if bits.checked_shl(prefix_len).unwrap_or(0) != 0 {
+        return Err(invalid());
+    }
+    Ok(prefix_len)
+}
+
+/// Parses ntpd's key file: one `ID TYPE KEY` line per key, where keys of up to
+/// 20 characters are ASCII and longer ones hexadecimal. Only keys listed in
+/// `trusted` are kept, unless it is empty.
+pub(super) fn parse_keys(contents: &str, trusted: &[u32]) -> Result<Vec<KeyConfig>, ConfigError> {
+    let mut keys = Vec::new();
+    for (line, words) in directives(contents, &['#']) {
+        let [id, key_type, secret] = words[..] else {
+            return Err(ConfigError::Directive {
+                line,
+                reason: "expected key identifier, type and secret".to_string(),
+            });
+        };
+        let algorithm = match key_type.to_ascii_uppercase().as_str() {
+            "M" | "MD5" => KeyAlgorithm::Md5,
+            "SHA1" | "SHA-1" => KeyAlgorithm::Sha1,
+            _ => {
+                return Err(ConfigError::Directive {
+                    line,
+                    reason: format!("unsupported key type {key_type}"),
+                })
+            }
+        };
+        let id = number(line, id)?;
+        if !trusted.is_empty() && !trusted.contains(&id) {
+            continue;
+        }
+        let encoding = if secret.len() > NTPD_MAX_ASCII_KEY {
+            SecretEncoding::Hex
+        } else {
+            SecretEncoding::Ascii
+        };
+        keys.push(KeyConfig {
+            id,
+            algorithm,
+            secret: secret.to_string(),
+            encoding,
+        });
+    }
+    Ok(keys)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NTP_CONF: &str = "\
+# /etc/ntp.conf
+driftfile /var/lib/ntp/ntp.drift
+leapfile /usr/share/zoneinfo/leap-seconds.list
+statsdir /var/log/ntpstats/
+statistics loopstats peerstats sysstats
+
+pool 0.debian.pool.ntp.org iburst
+server 192.0.2.1 key 1 minpoll 4 maxpoll 8 prefer
+peer 192.0.2.2
+
+restrict -4 default kod notrap nomodify nopeer noquery limited
+restrict -6 default kod notrap nomodify nopeer noquery limited
+restrict 127.0.0.1
+restrict 192.168.0.0 mask 255.255.0.0 nomodify notrust
+restrict source notrap nomodify noquery
+
+keys /etc/ntp/keys
+trustedkey 1
+tos maxdist 3 minclock 4
+server 127.127.20.0 minpoll 4 maxpoll 4
+server 127.127.1.0
+";
+
+    #[test]
+    fn ntp_conf_is_translated() {
+        let import = parse(NTP_CONF).unwrap();
+        let config = import.config;
+
+        assert_eq!(
+            config.servers,
+            vec![ServerConfig {
+                key: Some(1),
+                minpoll: 4,
+                maxpoll: 8,
+                ..ServerConfig::new("192.0.2.1")
+            }]
+        );
+        assert_eq!(config.peers, vec![ServerConfig::new("192.0.2.2")]);
+        assert_eq!(config.pools[0].address, "0.debian.pool.ntp.org");
+        assert!(config.pools[0].iburst);
+        assert_eq!(
+            config.driftfile,
+            Some(PathBuf::from("/var/lib/ntp/ntp.drift"))
+        );
+        assert_eq!(config.statsdir, Some(PathBuf::from("/var/log/ntpstats/")));
+        assert_eq!(
+            config.statistics,
+            vec![StatsKind::Loopstats, StatsKind::Peerstats]
+        );
+        assert_eq!(import.keys_file, Some(PathBuf::from("/etc/ntp/keys")));
+        assert_eq!(import.trusted_keys, vec![1]);
+        assert_eq!(config.listen.len(), 2);
+        assert_eq!(config.max_distance, Some(3.0));
+        assert_eq!(
+            config.refclocks,
+            vec![RefClockConfig {
+                path: Some(PathBuf::from("/dev/gps0")),
+                ..RefClockConfig::new(RefClockDriver::Nmea, 0)
+            }]
+        );
+
+        let restrictions = config
+            .restrictions
+            .iter()
+            .map(|restriction| (restriction.address.as_str(), restriction.flags.clone()))
+            .collect::<Vec<_>>();
+        let limited = vec![RestrictFlag::Kod, RestrictFlag::Limited];
+        assert_eq!(
+            restrictions,
+            vec![
+                ("0.0.0.0/0", limited.clone()),
+                ("::/0", limited),
+                ("127.0.0.1", vec![]),
+                ("192.168.0.0/16", vec![]),
+            ]
+        );
+    }
+
+    #[test]
+    fn unknown_directives_are_reported_with_their_line() {
+        let ignored = parse(NTP_CONF).unwrap().ignored;
+
+        assert_eq!(
+            ignored,
+            vec![
+                IgnoredDirective {
+                    line: 5,
+                    text: "sysstats".to_string()
+                },
+                IgnoredDirective {
+                    line: 8,
+                    text: "prefer".to_string()
+                },
+                IgnoredDirective {
+                    line: 14,
+                    text: "notrust".to_string()
+                },
+                IgnoredDirective {
+                    line: 15,
+                    text: "restrict source".to_string()
+                },
+                IgnoredDirective {
+                    line: 19,
+                    text: "minclock 4".to_string()
+                },
+                IgnoredDirective {
+                    line: 21,
+                    text: "server 127.127.1.0".to_string()
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn malformed_directives_are_errors() {
+        assert!(matches!(
+            parse("server\n").err(),
+            Some(ConfigError::Directive { line: 1, .. })
+        ));
+        assert!(matches!(
+            parse("\nserver a minpoll x\n").err(),
+            Some(ConfigError::Directive { line: 2, .. })
+        ));
+        assert!(matches!(
+            parse("restrict 10.0.0.0 mask 255.0.255.0\n").err(),
+            Some(ConfigError::Directive { line: 1, .. })
+        ));
+    }
+
+    #[test]
+    fn keys_file_is_parsed() {
+        let keys = parse_keys(
+            "# ntp keys\n1 M secret\n2 SHA1 0123456789abcdef0123456789abcdef01234567\n3 MD5 other\n",
+            &[1, 2],
+        )
+        .unwrap();
+
+        assert_eq!(keys.len(), 2);
+        assert_eq!(keys[0].algorithm, KeyAlgorithm::Md5);
+        assert_eq!(keys[0].encoding, SecretEncoding::Ascii);
+        assert_eq!(keys[1].algorithm, KeyAlgorithm::Sha1);
+        assert_eq!(keys[1].encoding, SecretEncoding::Hex);
+        assert!(matches!(
+            parse_keys("1 AES128CMAC secret\n", &[]),
+            Err(ConfigError::Directive { line: 1, .. })
+        ));
+    }
+}
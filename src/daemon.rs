@@ -0,0 +1,495 @@
+use std::{
+    net::{SocketAddr, ToSocketAddrs, UdpSocket},
+    time::{Duration, Instant},
+};
+
+use log::{info, warn};
+
+use crate::{
+    association::{Association, AssociationConfig, SampleLimits},
+    clock::Clock,
+    config::Config,
+    discipline::{ClockDiscipline, DisciplineAction},
+    error::{NtpError, NtpResult},
+    leap::LeapSeconds,
+    logging,
+    random::Random,
+    selection::{select, Candidate},
+    server::ServerState,
+    stats::{peer_status, LoopStats, PeerSelect, PeerStats, StatsTime},
+    transport::Transport,
+    types::{Leap, NtpShort, NtpTimestamp, Precision, RefId, Stratum, NTP_MAXSTRAT},
+};
+
+/// Port servers are queried on when the configuration does not give one
+const NTP_PORT: u16 = 123;
+
+/// Precision advertised to clients, as a power of two in seconds
+const NTP_DEFAULT_PRECISION: i8 = -20;
+
+/// How long to wait for a reply
+const NTP_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Number of updates the wander is averaged over
+const NTP_CLOCK_AVG: f64 = 8.0;
+
+/// Largest offset of the other sources at which a pulse per second is used,
+/// in seconds: beyond it, the pulse could mark another second
+const PPS_MAX_OFFSET: f64 = 0.5;
+
+/// Resolves the servers, peers and pools of `config` into associations, and
+/// opens its reference clocks. Servers that cannot be resolved and reference
+/// clocks that cannot be opened are returned with the error instead.
+pub fn associations_from_config(config: &Config) -> (Vec<Association>, Vec<(String, NtpError)>) {
+    let mut associations = Vec::new();
+    let mut errors = Vec::new();
+
+    for server in config.servers.iter().chain(&config.peers) {
+        let association_config = AssociationConfig {
+            minpoll: server.minpoll,
+            maxpoll: server.maxpoll,
+            burst: server.burst,
+            iburst: server.iburst,
+        };
+        let association = resolve(&server.address).and_then(|addresses| {
+            let client = config
+                .client_builder(server.key, addresses[0])?
+                .timeout(NTP_TIMEOUT)
+                .build()?;
+            Ok(Association::new(
+                &server.address,
+                addresses[0],
+                client,
+                association_config,
+            ))
+        });
+        match association {
+            Ok(association) => associations.push(association),
+            Err(error) => errors.push((server.address.clone(), error)),
+        }
+    }
+
+    for pool in &config.pools {
+        let association_config = AssociationConfig {
+            minpoll: pool.minpoll,
+            maxpoll: pool.maxpoll,
+            burst: false,
+            iburst: pool.iburst,
+        };
+        let addresses = match resolve(&pool.address) {
+            Ok(addresses) => addresses,
+            Err(error) => {
+                errors.push((pool.address.clone(), error));
+                continue;
+            }
+        };
+        for address in addresses.into_iter().take(pool.max_sources) {
+            let client = config
+                .client_builder(None, address)
+                .and_then(|builder| builder.timeout(NTP_TIMEOUT).build());
+            match client {
+                Ok(client) => associations.push(Association::new(
+                    &pool.address,
+                    address,
+                    client,
+                    association_config,
+                )),
+                Err(error) => errors.push((pool.address.clone(), error)),
+            }
+        }
+    }
+
+    for refclock in &config.refclocks {
+        let association_config = AssociationConfig {
+            minpoll: refclock.minpoll,
+            maxpoll: refclock.maxpoll,
+            burst: false,
+            iburst: false,
+        };
+        match refclock.open() {
+            Ok(driver) => associations.push(Association::with_refclock(
+                refclock.name(),
+                refclock.address(),
+                driver,
+                association_config,
+            )),
+            Err(error) => errors.push((refclock.name(), error)),
+        }
+    }
+
+    (associations, errors)
+}
+
+/// Resolves `address`, adding the NTP port when none is given
+fn resolve(address: &str) -> NtpResult<Vec<SocketAddr>> {
+    let addresses = match address.to_socket_addrs() {
+        Ok(addresses) => addresses.collect::<Vec<_>>(),
+        Err(_) => (address, NTP_PORT).to_socket_addrs()?.collect(),
+    };
+    if addresses.is_empty() {
+        return Err(NtpError::Io(std::io::ErrorKind::NotFound));
+    }
+    Ok(addresses)
+}
+
+/// Ties the associations, the selection and the clock discipline together,
+/// and keeps the state a server should advertise
+pub struct Daemon<C, T = UdpSocket> {
+    clock: C,
+    associations: Vec<Association<T>>,
+    next_polls: Vec<Instant>,
+    discipline: ClockDiscipline,
+    leap_seconds: LeapSeconds,
+    state: ServerState,
+    limits: SampleLimits,
+    /// Combined offset of the last selection
+    offset: Option<f64>,
+    /// Associations combined by the last selection
+    survivors: Vec<usize>,
+    system_peer: Option<usize>,
+    /// Frequency after the last update and its RMS change, in ppm
+    frequency: Option<f64>,
+    wander: f64,
+    /// Spreads the polls
+    random: Random,
+    /// Whether to keep statistics records until they are taken
+    statistics: bool,
+    peer_stats: Vec<PeerStats>,
+    loop_stats: Vec<LoopStats>,
+    /// Time of the system peer sample the clock was last updated with
+    last_update: Option<NtpTimestamp>,
+}
+
+impl<C: Clock, T: Transport> Daemon<C, T>
+where
+    NtpError: From<T::Error>,
+{
+    pub fn new(clock: C, leap_seconds: LeapSeconds) -> Self {
+        Self {
+            clock,
+            associations: Vec::new(),
+            next_polls: Vec::new(),
+            discipline: ClockDiscipline::new(),
+            leap_seconds,
+            state: ServerState {
+                precision: Precision::from(NTP_DEFAULT_PRECISION),
+                ..ServerState::default()
+            },
+            limits: SampleLimits::default(),
+            offset: None,
+            survivors: Vec::new(),
+            system_peer: None,
+            frequency: None,
+            wander: 0.0,
+            random: Random::from_entropy(),
+            statistics: false,
+            peer_stats: Vec::new(),
+            loop_stats: Vec::new(),
+            last_update: None,
+        }
+    }
+
+    pub fn with_discipline(mut self, discipline: ClockDiscipline) -> Self {
+        self.discipline = discipline;
+        self
+    }
+
+    /// Leaves out servers past `limits`, in place of ntpd's defaults
+    pub fn with_limits(mut self, limits: SampleLimits) -> Self {
+        self.set_limits(limits);
+        self
+    }
+
+    /// Advertises `precision`, as measured by `clock::measure_precision`,
+    /// in place of a typical one
+    pub fn with_precision(mut self, precision: Precision) -> Self {
+        self.state.precision = precision;
+        for association in &mut self.associations {
+            association.set_precision(precision);
+        }
+        self
+    }
+
+    /// Spreads the polls with a generator seeded with `seed`, so that runs
+    /// repeat
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.random = Random::new(seed);
+        self
+    }
+
+    /// Keeps peerstats and loopstats records, to be taken with
+    /// `take_peer_stats` and `take_loop_stats`
+    pub fn with_statistics(mut self) -> Self {
+        self.statistics = true;
+        self
+    }
+
+    /// Replaces the associations, for example after the configuration was
+    /// reloaded. The new ones are polled right away.
+    pub fn set_associations(&mut self, associations: Vec<Association<T>>) {
+        self.survivors.clear();
+        self.system_peer = None;
+        self.next_polls = vec![Instant::now(); associations.len()];
+        self.associations = associations;
+        for association in &mut self.associations {
+            association.set_precision(self.state.precision);
+            association.set_limits(self.limits);
+        }
+    }
+
+    /// Steps the clock as `discipline` does from now on, without losing track
+    /// of the offsets applied so far
+    pub fn set_discipline(&mut self, discipline: &ClockDiscipline) {
+        self.discipline.configure(discipline);
+    }
+
+    /// Leaves out servers past `limits` from now on
+    pub fn set_limits(&mut self, limits: SampleLimits) {
+        self.limits = limits;
+        for association in &mut self.associations {
+            association.set_limits(limits);
+        }
+    }
+
+    pub fn set_leap_seconds(&mut self, leap_seconds: LeapSeconds) {
+        self.leap_seconds = leap_seconds;
+    }
+
+    pub fn associations(&self) -> &[Association<T>] {
+        &self.associations
+    }
+
+    pub fn clock(&self) -> &C {
+        &self.clock
+    }
+
+    pub fn clock_mut(&mut self) -> &mut C {
+        &mut self.clock
+    }
+
+    /// State to advertise to clients
+    pub fn state(&self) -> ServerState {
+        self.state
+    }
+
+    /// Precision advertised to clients, in seconds
+    fn precision(&self) -> f64 {
+        2f64.powi(i8::from(self.state.precision).into())
+    }
+
+    /// Offset of the clock the last update applied, in seconds
+    pub fn offset(&self) -> Option<f64> {
+        self.offset
+    }
+
+    /// Samples of the servers received since the last call
+    pub fn take_peer_stats(&mut self) -> Vec<PeerStats> {
+        std::mem::take(&mut self.peer_stats)
+    }
+
+    /// Updates of the clock since the last call
+    pub fn take_loop_stats(&mut self) -> Vec<LoopStats> {
+        std::mem::take(&mut self.loop_stats)
+    }
+
+    /// Whether the clock has been updated from the servers yet
+    pub fn is_synchronized(&self) -> bool {
+        self.state.leap != Leap::Unknown
+    }
+
+    /// Polls the associations that are due at `now`, updates the clock when a
+    /// new sample was selected, and returns when the next poll is due. Servers
+    /// that do not answer are only recorded in their reach register.
+    pub fn poll_due(&mut self, now: Instant) -> NtpResult<Instant> {
+        let mut updated = false;
+        for index in 0..self.associations.len() {
+            if self.next_polls[index] > now {
+                continue;
+            }
+            let association = &mut self.associations[index];
+            let polled = matches!(association.poll_server(), Ok(Some(_)));
+            self.next_polls[index] = now + association.next_interval(self.random.uniform());
+            // Like ntpd, with the status of the previous selection
+            if polled && self.statistics {
+                self.record_peer_stats(index);
+            }
+            updated |= polled;
+        }
+        if updated {
+            self.update()?;
+        }
+        Ok(self
+            .next_polls
+            .iter()
+            .copied()
+            .min()
+            .unwrap_or(now + Duration::from_secs(1)))
+    }
+
+    /// Selects among the associations and disciplines the clock with the
+    /// result. Returns `None` when there was nothing new to apply.
+    pub fn update(&mut self) -> NtpResult<Option<DisciplineAction>> {
+        let candidates_of = |pulse_only: bool| {
+            self.associations
+                .iter()
+                .enumerate()
+                .filter(move |(_, association)| association.pulse_only() == pulse_only)
+                .filter_map(|(index, association)| Some((index, association.candidate()?)))
+        };
+        let (mut indices, mut candidates): (Vec<usize>, Vec<Candidate>) =
+            candidates_of(false).unzip();
+        // As ntpd with its prefer peer, pulses only count once the other
+        // sources agree the local clock is within half a second
+        if select(&candidates).is_some_and(|selection| selection.offset.abs() < PPS_MAX_OFFSET) {
+            (indices, candidates) = candidates_of(false).chain(candidates_of(true)).unzip();
+        }
+        let Some(selection) = select(&candidates) else {
+            if self.system_peer.is_some() {
+                warn!(
+                    target: logging::ASSOCIATION,
+                    candidates = candidates.len();
+                    "no majority of servers agrees"
+                );
+            }
+            self.survivors.clear();
+            self.system_peer = None;
+            return Ok(None);
+        };
+        self.survivors = selection
+            .survivors
+            .iter()
+            .map(|&index| indices[index])
+            .collect();
+        let system_peer = indices[selection.system_peer];
+        if self.system_peer != Some(system_peer) {
+            let association = &self.associations[system_peer];
+            info!(
+                target: logging::ASSOCIATION,
+                server:% = association.name(),
+                address:% = association.address(),
+                survivors = self.survivors.len();
+                "new system peer"
+            );
+        }
+        self.system_peer = Some(system_peer);
+        let peer = &self.associations[indices[selection.system_peer]];
+        let (Some(sample), Some(last)) = (peer.filter().selected(), peer.last_sample()) else {
+            return Ok(None);
+        };
+        if self
+            .last_update
+            .is_some_and(|time| sample.time.diff_seconds(&time) <= 0.0)
+        {
+            return Ok(None);
+        }
+        self.last_update = Some(sample.time);
+        self.offset = Some(selection.offset);
+
+        let now = self.clock.now()?;
+        let action = self
+            .discipline
+            .update(&mut self.clock, selection.offset, now)?;
+
+        let votes = selection
+            .survivors
+            .iter()
+            .filter_map(|&index| self.associations[indices[index]].last_sample())
+            .map(|sample| sample.leap())
+            .collect::<Vec<_>>();
+        let root_distance = candidates[selection.system_peer].root_distance;
+        let rootdisp = last.header.rootdisp.to_seconds()
+            + peer.filter().dispersion()
+            + selection.jitter
+            + selection.offset.abs();
+        let leap = self.leap_seconds.pending(now, &votes);
+        if leap != self.state.leap && self.state.leap != Leap::Unknown {
+            info!(
+                target: logging::LEAP,
+                from:? = self.state.leap,
+                to:? = leap;
+                "leap indicator changed"
+            );
+        }
+        self.state = ServerState {
+            leap,
+            stratum: Stratum::from((u8::from(last.stratum()) + 1).min(NTP_MAXSTRAT)),
+            precision: self.state.precision,
+            rootdelay: NtpShort::from_seconds(last.header.rootdelay.to_seconds() + sample.delay),
+            rootdisp: NtpShort::from_seconds(rootdisp),
+            // Synchronized to a reference clock, the refid names its kind
+            refid: if u8::from(last.stratum()) == 0 {
+                last.refid()
+            } else {
+                RefId::from(peer.address().ip())
+            },
+            reftime: now,
+        };
+        self.leap_seconds
+            .update_clock(&mut self.clock, now, &votes)?;
+        self.clock
+            .set_sync_status(true, selection.jitter, root_distance)?;
+
+        match action {
+            DisciplineAction::Stepped(_) => {
+                self.last_update = None;
+                for association in &mut self.associations {
+                    association.reset();
+                    association.reset_poll();
+                }
+            }
+            DisciplineAction::Slewed(_) => {
+                let jitter = selection.jitter.max(self.precision());
+                for &index in &selection.survivors {
+                    self.associations[indices[index]].adapt_poll(selection.offset, jitter);
+                }
+            }
+            DisciplineAction::Ignored(_) => return Ok(Some(action)),
+        }
+        // The loop responds as slowly as the system peer is polled
+        self.clock
+            .set_time_constant(self.associations[system_peer].poll().into())?;
+        let status = self.clock.status()?;
+        if let Some(last) = self.frequency {
+            let change = (status.frequency - last).powi(2);
+            self.wander =
+                (self.wander.powi(2) + (change - self.wander.powi(2)) / NTP_CLOCK_AVG).sqrt();
+        }
+        self.frequency = Some(status.frequency);
+        if self.statistics {
+            self.loop_stats.push(LoopStats {
+                time: StatsTime::from_timestamp(now),
+                offset: selection.offset,
+                frequency: status.frequency,
+                jitter: selection.jitter,
+                wander: self.wander,
+                time_constant: status.time_constant,
+            });
+        }
+        Ok(Some(action))
+    }
+
+    fn record_peer_stats(&mut self, index: usize) {
+        let association = &self.associations[index];
+        let Some(sample) = association.filter().selected() else {
+            return;
+        };
+        let select = if self.system_peer == Some(index) {
+            PeerSelect::SystemPeer
+        } else if self.survivors.contains(&index) {
+            PeerSelect::Candidate
+        } else if association.candidate().is_some() {
+            PeerSelect::Outlier
+        } else {
+            PeerSelect::Reject
+        };
+        self.peer_stats.push(PeerStats {
+            time: StatsTime::from_timestamp(sample.time),
+            address: association.address().ip().to_string(),
+            status: peer_status(true, association.reach() != 0, select),
+            offset: sample.offset,
+            delay: sample.delay,
+            dispersion: association.filter().dispersion(),
+            jitter: association.filter().jitter(),
+        });
+    }
+}
@@ -0,0 +1,250 @@
+use log::{debug, info};
+
+use crate::{clock::Clock, error::NtpResult, logging, types::NtpTimestamp};
+
+/// Offsets larger than this are stepped instead of slewed, in seconds
+pub const STEP_THRESHOLD: f64 = 0.128;
+
+/// How long an offset above the step threshold must persist before the clock
+/// is stepped, in seconds
+pub const STEPOUT: f64 = 900.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisciplineState {
+    /// No offset has been applied yet
+    Unset,
+    /// Offsets above the step threshold are being ignored until the stepout expires
+    Spike,
+    /// The clock is being slewed
+    Sync,
+}
+
+/// What the discipline did with an offset
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DisciplineAction {
+    /// The clock was stepped by this many seconds. Samples taken before are invalid.
+    Stepped(f64),
+    /// The offset was handed to the clock to be slewed out
+    Slewed(f64),
+    /// The offset looks like a spike and was ignored
+    Ignored(f64),
+}
+
+/// Clock discipline: steps the clock for large offsets that persist, and
+/// otherwise hands offsets to the clock, whose phase-locked loop slews them
+/// out and trains the frequency
+#[derive(Debug, Clone)]
+pub struct ClockDiscipline {
+    state: DisciplineState,
+    step_threshold: f64,
+    stepout: f64,
+    step_limit: Option<u32>,
+    updates: u32,
+    spike_since: Option<NtpTimestamp>,
+}
+
+impl Default for ClockDiscipline {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ClockDiscipline {
+    pub fn new() -> Self {
+        Self {
+            state: DisciplineState::Unset,
+            step_threshold: STEP_THRESHOLD,
+            stepout: STEPOUT,
+            step_limit: None,
+            updates: 0,
+            spike_since: None,
+        }
+    }
+
+    /// Uses a different step threshold, in seconds. An infinite threshold never steps.
+    pub fn with_step_threshold(mut self, step_threshold: f64) -> Self {
+        self.step_threshold = step_threshold;
+        self
+    }
+
+    /// Uses a different stepout, in seconds
+    pub fn with_stepout(mut self, stepout: f64) -> Self {
+        self.stepout = stepout;
+        self
+    }
+
+    /// Only steps the clock during the first `step_limit` updates, and slews
+    /// any offset afterwards
+    pub fn with_step_limit(mut self, step_limit: u32) -> Self {
+        self.step_limit = Some(step_limit);
+        self
+    }
+
+    /// Takes the step threshold, stepout and step limit of `settings`, for
+    /// example after the configuration was reloaded, and keeps the state
+    pub fn configure(&mut self, settings: &ClockDiscipline) {
+        self.step_threshold = settings.step_threshold;
+        self.stepout = settings.stepout;
+        self.step_limit = settings.step_limit;
+    }
+
+    pub fn state(&self) -> DisciplineState {
+        self.state
+    }
+
+    /// Applies the combined `offset` of the selected servers, measured at `now`
+    pub fn update(
+        &mut self,
+        clock: &mut impl Clock,
+        offset: f64,
+        now: NtpTimestamp,
+    ) -> NtpResult<DisciplineAction> {
+        let may_step = self.step_limit.is_none_or(|limit| self.updates < limit);
+        self.updates = self.updates.saturating_add(1);
+        let large = may_step && offset.abs() > self.step_threshold;
+        let step = match self.state {
+            DisciplineState::Unset => large,
+            DisciplineState::Sync if large => {
+                self.state = DisciplineState::Spike;
+                self.spike_since = Some(now);
+                info!(target: logging::CLOCK, offset = offset; "ignoring spike");
+                return Ok(DisciplineAction::Ignored(offset));
+            }
+            DisciplineState::Sync => false,
+            DisciplineState::Spike if large => {
+                let since = self.spike_since.unwrap_or(now);
+                if now.diff_seconds(&since) < self.stepout {
+                    debug!(target: logging::CLOCK, offset = offset; "ignoring spike");
+                    return Ok(DisciplineAction::Ignored(offset));
+                }
+                true
+            }
+            DisciplineState::Spike => false,
+        };
+
+        self.state = DisciplineState::Sync;
+        self.spike_since = None;
+        if step {
+            clock.step(offset)?;
+            info!(target: logging::CLOCK, offset = offset; "stepped clock");
+            Ok(DisciplineAction::Stepped(offset))
+        } else {
+            clock.adjust_offset(offset)?;
+            debug!(target: logging::CLOCK, offset = offset; "slewing clock");
+            Ok(DisciplineAction::Slewed(offset))
+        }
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use crate::clock::linux::LinuxClock;
+
+    use super::*;
+
+    fn clock() -> LinuxClock {
+        LinuxClock::dry_run(libc::CLOCK_REALTIME).unwrap()
+    }
+
+    #[test]
+    fn first_large_offset_is_stepped() {
+        let mut clock = clock();
+        let mut discipline = ClockDiscipline::new();
+
+        let action = discipline
+            .update(&mut clock, 3.0, NtpTimestamp::new(1000, 0))
+            .unwrap();
+
+        assert_eq!(action, DisciplineAction::Stepped(3.0));
+        assert_eq!(discipline.state(), DisciplineState::Sync);
+    }
+
+    #[test]
+    fn small_offset_is_slewed() {
+        let mut clock = clock();
+        let mut discipline = ClockDiscipline::new();
+
+        let action = discipline
+            .update(&mut clock, 0.002, NtpTimestamp::new(1000, 0))
+            .unwrap();
+
+        assert_eq!(action, DisciplineAction::Slewed(0.002));
+        assert_eq!(clock.status().unwrap().offset, 0.002);
+    }
+
+    #[test]
+    fn configure_keeps_the_state() {
+        let mut clock = clock();
+        let mut discipline = ClockDiscipline::new();
+        discipline
+            .update(&mut clock, 0.001, NtpTimestamp::new(1000, 0))
+            .unwrap();
+
+        discipline.configure(&ClockDiscipline::new().with_step_threshold(1.0));
+        let action = discipline
+            .update(&mut clock, 0.5, NtpTimestamp::new(1064, 0))
+            .unwrap();
+
+        assert_eq!(action, DisciplineAction::Slewed(0.5));
+        assert_eq!(discipline.state(), DisciplineState::Sync);
+    }
+
+    #[test]
+    fn spike_is_ignored_until_stepout() {
+        let mut clock = clock();
+        let mut discipline = ClockDiscipline::new();
+        discipline
+            .update(&mut clock, 0.001, NtpTimestamp::new(1000, 0))
+            .unwrap();
+
+        let action = discipline
+            .update(&mut clock, 0.5, NtpTimestamp::new(1064, 0))
+            .unwrap();
+        assert_eq!(action, DisciplineAction::Ignored(0.5));
+        assert_eq!(discipline.state(), DisciplineState::Spike);
+
+        let action = discipline
+            .update(&mut clock, 0.5, NtpTimestamp::new(1128, 0))
+            .unwrap();
+        assert_eq!(action, DisciplineAction::Ignored(0.5));
+
+        let action = discipline
+            .update(&mut clock, 0.5, NtpTimestamp::new(2000, 0))
+            .unwrap();
+        assert_eq!(action, DisciplineAction::Stepped(0.5));
+    }
+
+    #[test]
+    fn spike_that_goes_away_is_slewed() {
+        let mut clock = clock();
+        let mut discipline = ClockDiscipline::new();
+        discipline
+            .update(&mut clock, 0.001, NtpTimestamp::new(1000, 0))
+            .unwrap();
+        discipline
+            .update(&mut clock, 0.5, NtpTimestamp::new(1064, 0))
+            .unwrap();
+
+        let action = discipline
+            .update(&mut clock, 0.003, NtpTimestamp::new(1128, 0))
+            .unwrap();
+
+        assert_eq!(action, DisciplineAction::Slewed(0.003));
+        assert_eq!(discipline.state(), DisciplineState::Sync);
+    }
+
+    #[test]
+    fn no_step_after_step_limit() {
+        let mut clock = clock();
+        let mut discipline = ClockDiscipline::new().with_step_limit(1);
+        discipline
+            .update(&mut clock, 0.001, NtpTimestamp::new(1000, 0))
+            .unwrap();
+
+        let action = discipline
+            .update(&mut clock, 0.3, NtpTimestamp::new(1064, 0))
+            .unwrap();
+
+        assert_eq!(action, DisciplineAction::Slewed(0.3));
+    }
+}
@@ -0,0 +1,199 @@
+use std::{
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use serde::Deserialize;
+
+/// Frequency error bound written to chrony drift files, in parts per million.
+/// The kernel PLL does not estimate it, so a conservative value is used.
+const CHRONY_DEFAULT_SKEW: f64 = 1.0;
+
+/// Error raised while reading a drift file
+#[derive(Debug, PartialEq, Eq)]
+pub enum DriftFileError {
+    Io(io::ErrorKind),
+    /// The file does not hold a frequency
+    Syntax,
+}
+
+impl From<io::Error> for DriftFileError {
+    fn from(value: io::Error) -> Self {
+        Self::Io(value.kind())
+    }
+}
+
+/// Layout of a drift file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DriftFormat {
+    /// ntpd's `ntp.drift`: the frequency correction in ppm
+    #[default]
+    Ntpd,
+    /// chrony's `drift`: the frequency error of the clock and its error bound,
+    /// in ppm. chrony counts a fast clock as positive, which is the opposite
+    /// of the correction.
+    Chrony,
+}
+
+/// Frequency estimate kept across restarts
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Drift {
+    /// Frequency correction in parts per million, as given to `Clock::set_frequency`
+    pub frequency: f64,
+    /// Error bound of the frequency in parts per million, if known
+    pub skew: Option<f64>,
+}
+
+/// File the clock frequency is saved to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DriftFile {
+    path: PathBuf,
+    format: DriftFormat,
+}
+
+impl DriftFile {
+    pub fn new(path: impl Into<PathBuf>, format: DriftFormat) -> Self {
+        Self {
+            path: path.into(),
+            format,
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn format(&self) -> DriftFormat {
+        self.format
+    }
+
+    /// Reads the saved frequency. Returns `None` when nothing was saved yet.
+    pub fn load(&self) -> Result<Option<Drift>, DriftFileError> {
+        match fs::read_to_string(&self.path) {
+            Ok(contents) => self.parse(&contents).map(Some),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(error) => Err(error.into()),
+        }
+    }
+
+    pub fn parse(&self, contents: &str) -> Result<Drift, DriftFileError> {
+        let mut values = contents
+            .split_whitespace()
+            .map(|value| value.parse::<f64>().ok().filter(|value| value.is_finite()));
+        let frequency = values.next().flatten().ok_or(DriftFileError::Syntax)?;
+        let skew = values.next().map(|skew| skew.ok_or(DriftFileError::Syntax));
+        if values.next().is_some() {
+            return Err(DriftFileError::Syntax);
+        }
+
+        match (self.format, skew) {
+            (DriftFormat::Ntpd, None) => Ok(Drift {
+                frequency,
+                skew: None,
+            }),
+            (DriftFormat::Chrony, Some(skew)) => Ok(Drift {
+                frequency: -frequency,
+                skew: Some(skew?),
+            }),
+            _ => Err(DriftFileError::Syntax),
+        }
+    }
+
+    pub fn format_drift(&self, drift: &Drift) -> String {
+        match self.format {
+            DriftFormat::Ntpd => format!("{:.3}\n", drift.frequency),
+            DriftFormat::Chrony => format!(
+                "{:.6} {:.6}\n",
+                -drift.frequency,
+                drift.skew.unwrap_or(CHRONY_DEFAULT_SKEW)
+            ),
+        }
+    }
+
+    /// Writes the frequency to a temporary file that then replaces the drift
+    /// file, so that a crash never leaves a truncated file behind
+    pub fn save(&self, drift: &Drift) -> io::Result<()> {
+        let mut temporary = self.path.clone().into_os_string();
+        temporary.push(".tmp");
+        let temporary = PathBuf::from(temporary);
+
+        let mut file = File::create(&temporary)?;
+        file.write_all(self.format_drift(drift).as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&temporary, &self.path)?;
+
+        // Make the rename itself durable
+        if let Some(directory) = self
+            .path
+            .parent()
+            .filter(|parent| !parent.as_os_str().is_empty())
+        {
+            File::open(directory)?.sync_all()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temporary_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("demo_ntp-{}-{name}", std::process::id()))
+    }
+
+    #[test]
+    fn ntpd_format_round_trips() {
+        let path = temporary_path("ntp.drift");
+        let drift_file = DriftFile::new(&path, DriftFormat::Ntpd);
+        assert_eq!(drift_file.load(), Ok(None));
+
+        let drift = Drift {
+            frequency: -12.345,
+            skew: None,
+        };
+        drift_file.save(&drift).unwrap();
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "-12.345\n");
+        assert_eq!(drift_file.load(), Ok(Some(drift)));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn chrony_format_inverts_the_sign() {
+        let path = temporary_path("drift");
+        let drift_file = DriftFile::new(&path, DriftFormat::Chrony);
+
+        drift_file
+            .save(&Drift {
+                frequency: 4.5,
+                skew: Some(0.25),
+            })
+            .unwrap();
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "-4.500000 0.250000\n");
+        assert_eq!(
+            drift_file.parse("           -4.500000            0.250000\n"),
+            Ok(Drift {
+                frequency: 4.5,
+                skew: Some(0.25)
+            })
+        );
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn malformed_files_are_rejected() {
+        let ntpd = DriftFile::new("ntp.drift", DriftFormat::Ntpd);
+        let chrony = DriftFile::new("drift", DriftFormat::Chrony);
+
+        assert_eq!(ntpd.parse(""), Err(DriftFileError::Syntax));
+        assert_eq!(ntpd.parse("fast"), Err(DriftFileError::Syntax));
+        assert_eq!(ntpd.parse("1.0 2.0"), Err(DriftFileError::Syntax));
+        assert_eq!(ntpd.parse("NaN"), Err(DriftFileError::Syntax));
+        assert_eq!(chrony.parse("1.0"), Err(DriftFileError::Syntax));
+        assert_eq!(chrony.parse("1.0 x"), Err(DriftFileError::Syntax));
+    }
+}
@@ -1,4 +1,39 @@
+use crate::{client::ClientError, codec::CodecError, types::RefId};
+
 pub type NtpResult<T> = Result<T, NtpError>;
 
 #[derive(Debug, PartialEq, Eq)]
-pub enum NtpError {}
+pub enum NtpError {
+    /// A system call failed
+    Io(std::io::ErrorKind),
+    /// A packet could not be parsed or serialized
+    Codec(CodecError),
+    /// Only datagrams that did not answer the request arrived before the
+    /// timeout
+    UnexpectedResponse,
+    /// The server asked the client to stop or slow down
+    KissOfDeath(RefId),
+    /// A reply was not signed with the expected key
+    Unauthenticated,
+}
+
+impl From<std::io::Error> for NtpError {
+    fn from(value: std::io::Error) -> Self {
+        Self::Io(value.kind())
+    }
+}
+
+impl<E> From<ClientError<E>> for NtpError
+where
+    NtpError: From<E>,
+{
+    fn from(value: ClientError<E>) -> Self {
+        match value {
+            ClientError::Transport(error) => error.into(),
+            ClientError::Codec(error) => Self::Codec(error),
+            ClientError::UnexpectedResponse => Self::UnexpectedResponse,
+            ClientError::KissOfDeath(code) => Self::KissOfDeath(code),
+            ClientError::Unauthenticated => Self::Unauthenticated,
+        }
+    }
+}
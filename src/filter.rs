@@ -0,0 +1,188 @@
+use crate::types::NtpTimestamp;
+
+/// Number of samples kept per server
+pub const FILTER_STAGES: usize = 8;
+
+/// Rate at which the dispersion of a sample grows, in seconds per second
+pub const PHI: f64 = 15e-6;
+
+/// Dispersion given to empty filter stages, in seconds
+pub const MAX_DISPERSION: f64 = 16.0;
+
+/// One offset measurement
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FilterSample {
+    /// Offset of the server clock relative to the local clock, in seconds
+    pub offset: f64,
+    /// Round trip delay, in seconds
+    pub delay: f64,
+    /// Maximum error of the measurement, in seconds
+    pub dispersion: f64,
+    /// Local time of the measurement
+    pub time: NtpTimestamp,
+}
+
+/// Clock filter of RFC 5905: keeps the last eight samples of a server and
+/// picks the one with the lowest delay, which is the least affected by
+/// network queuing
+#[derive(Debug, Clone, Default)]
+pub struct ClockFilter {
+    /// Newest sample first
+    stages: [Option<FilterSample>; FILTER_STAGES],
+    selected: Option<FilterSample>,
+    dispersion: f64,
+    jitter: f64,
+}
+
+impl ClockFilter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a sample and returns the newly selected one, if it changed. A
+    /// sample older than the one already selected is never used again.
+    pub fn add(&mut self, sample: FilterSample) -> Option<FilterSample> {
+        self.stages.rotate_right(1);
+        self.stages[0] = Some(sample);
+
+        let now = sample.time;
+        let mut ordered = self
+            .stages
+            .iter()
+            .map(|stage| match stage {
+                Some(stage) => (
+                    stage.delay,
+                    stage.dispersion + PHI * now.diff_seconds(&stage.time),
+                    Some(stage),
+                ),
+                // Sorted after every sample, however long its delay
+                None => (f64::INFINITY, MAX_DISPERSION, None),
+            })
+            .collect::<Vec<_>>();
+        ordered.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+        // Closer to the lowest delay weighs more
+        self.dispersion = ordered
+            .iter()
+            .enumerate()
+            .map(|(index, (_, dispersion, _))| dispersion / 2f64.powi(index as i32 + 1))
+            .sum();
+
+        let best = *ordered
+            .iter()
+            .find_map(|(_, _, stage)| *stage)
+            .expect("the newest stage is always filled");
+        let offsets = ordered
+            .iter()
+            .filter_map(|(_, _, stage)| stage.map(|stage| stage.offset))
+            .collect::<Vec<_>>();
+        self.jitter = if offsets.len() > 1 {
+            let squares: f64 = offsets[1..]
+                .iter()
+                .map(|offset| (offset - best.offset).powi(2))
+                .sum();
+            (squares / (offsets.len() - 1) as f64).sqrt()
+        } else {
+            0.0
+        };
+
+        if let Some(selected) = self.selected {
+            if best.time.diff_seconds(&selected.time) <= 0.0 {
+                return None;
+            }
+        }
+        self.selected = Some(best);
+        Some(best)
+    }
+
+    /// Forgets every sample, for example after the clock has been stepped
+    pub fn clear(&mut self) {
+        *self = Self::default();
+    }
+
+    pub fn selected(&self) -> Option<FilterSample> {
+        self.selected
+    }
+
+    /// Filter dispersion, in seconds
+    pub fn dispersion(&self) -> f64 {
+        self.dispersion
+    }
+
+    /// RMS difference between the selected offset and the other samples, in seconds
+    pub fn jitter(&self) -> f64 {
+        self.jitter
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(second: u32, offset: f64, delay: f64) -> FilterSample {
+        FilterSample {
+            offset,
+            delay,
+            dispersion: 0.001,
+            time: NtpTimestamp::new(second, 0),
+        }
+    }
+
+    #[test]
+    fn lowest_delay_sample_is_selected() {
+        let mut filter = ClockFilter::new();
+
+        assert_eq!(
+            filter.add(sample(1, 0.010, 0.050)),
+            Some(sample(1, 0.010, 0.050))
+        );
+        assert_eq!(
+            filter.add(sample(2, 0.002, 0.020)),
+            Some(sample(2, 0.002, 0.020))
+        );
+        // A later sample with a higher delay does not replace it
+        assert_eq!(filter.add(sample(3, 0.030, 0.090)), None);
+        assert_eq!(filter.selected(), Some(sample(2, 0.002, 0.020)));
+    }
+
+    #[test]
+    fn older_samples_are_not_reused() {
+        let mut filter = ClockFilter::new();
+        filter.add(sample(1, 0.001, 0.010));
+        filter.add(sample(2, 0.005, 0.040));
+
+        assert_eq!(filter.add(sample(3, 0.005, 0.040)), None);
+        assert_eq!(filter.selected(), Some(sample(1, 0.001, 0.010)));
+    }
+
+    #[test]
+    fn samples_with_a_long_delay_are_selected() {
+        let mut filter = ClockFilter::new();
+
+        assert_eq!(
+            filter.add(sample(1, 10.0, 20.0)),
+            Some(sample(1, 10.0, 20.0))
+        );
+        assert_eq!(
+            filter.add(sample(2, 0.001, 0.010)),
+            Some(sample(2, 0.001, 0.010))
+        );
+        assert!(filter.dispersion() > MAX_DISPERSION / 8.0);
+    }
+
+    #[test]
+    fn jitter_and_dispersion_reflect_the_samples() {
+        let mut filter = ClockFilter::new();
+        filter.add(sample(1, 0.001, 0.010));
+        assert_eq!(filter.jitter(), 0.0);
+        assert!(filter.dispersion() > MAX_DISPERSION / 4.0);
+
+        filter.add(sample(2, 0.004, 0.020));
+        assert!((filter.jitter() - 0.003).abs() < 1e-12);
+
+        for second in 3..=10 {
+            filter.add(sample(second, 0.001, 0.010));
+        }
+        assert!(filter.dispersion() < 0.01);
+    }
+}
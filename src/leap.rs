@@ -0,0 +1,667 @@
+use std::{fs, io, path::Path};
+
+use sha1::{Digest, Sha1};
+
+use crate::{
+    clock::Clock,
+    error::NtpResult,
+    types::{
+        Leap, NtpTimestamp, NTP_LEAP_LAST_MINUTE_HAS_59_SECONDS,
+        NTP_LEAP_LAST_MINUTE_HAS_61_SECONDS, NTP_LEAP_NO_WARNING, NTP_LEAP_UNKNOWN,
+        NTP_UNIX_EPOCH_OFFSET,
+    },
+};
+
+const SECONDS_PER_DAY: i64 = 86_400;
+
+/// Error raised while loading a `leap-seconds.list` file
+#[derive(Debug, PartialEq, Eq)]
+pub enum LeapFileError {
+    Io(io::ErrorKind),
+    /// A line could not be parsed
+    Syntax {
+        line: usize,
+    },
+    MissingUpdateTime,
+    MissingExpiry,
+    MissingHash,
+    /// The `#h` line does not match the contents of the file
+    HashMismatch,
+}
+
+impl From<io::Error> for LeapFileError {
+    fn from(value: io::Error) -> Self {
+        Self::Io(value.kind())
+    }
+}
+
+/// Leap second taking effect at `time`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LeapEvent {
+    /// First second after the leap, always the start of a UTC month
+    pub time: NtpTimestamp,
+    /// Whether a second is inserted or deleted before `time`
+    pub leap: Leap,
+    /// TAI - UTC in seconds from `time` onwards
+    pub tai_offset: i32,
+}
+
+/// Contents of an IETF/NIST `leap-seconds.list` file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LeapSecondTable {
+    updated: NtpTimestamp,
+    expires: NtpTimestamp,
+    /// TAI - UTC offsets in chronological order
+    entries: Vec<(NtpTimestamp, i32)>,
+}
+
+impl LeapSecondTable {
+    /// Parses the contents of a `leap-seconds.list` file and checks its SHA-1 hash
+    pub fn parse(contents: &str) -> Result<Self, LeapFileError> {
+        let mut updated = None;
+        let mut expires = None;
+        let mut hash = None;
+        let mut entries = Vec::new();
+        // The hash covers the digits of the update time, the expiry and every entry
+        let mut hashed = String::new();
+
+        for (index, line) in contents.lines().enumerate() {
+            let syntax = || LeapFileError::Syntax { line: index + 1 };
+            if let Some(value) = line.strip_prefix("#$") {
+                updated = Some(value.trim().parse::<u32>().map_err(|_| syntax())?);
+            } else if let Some(value) = line.strip_prefix("#@") {
+                expires = Some(value.trim().parse::<u32>().map_err(|_| syntax())?);
+            } else if let Some(value) = line.strip_prefix("#h") {
+                let words = value
+                    .split_whitespace()
+                    .map(|word| u32::from_str_radix(word, 16))
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(|_| syntax())?;
+                hash = Some(<[u32; 5]>::try_from(words).map_err(|_| syntax())?);
+            } else if line.starts_with('#') || line.trim().is_empty() {
+                continue;
+            } else {
+                let data = line.split('#').next().unwrap_or_default();
+                let mut fields = data.split_whitespace();
+                let (Some(time), Some(offset), None) =
+                    (fields.next(), fields.next(), fields.next())
+                else {
+                    return Err(syntax());
+                };
+                let time = time.parse::<u32>().map_err(|_| syntax())?;
+                let offset = offset.parse::<i32>().map_err(|_| syntax())?;
+                hashed.push_str(&format!("{time}{offset}"));
+                entries.push((NtpTimestamp::new(time, 0), offset));
+            }
+        }
+
+        let updated = updated.ok_or(LeapFileError::MissingUpdateTime)?;
+        let expires = expires.ok_or(LeapFileError::MissingExpiry)?;
+        let hash = hash.ok_or(LeapFileError::MissingHash)?;
+
+        let digest = Sha1::digest(format!("{updated}{expires}{hashed}"));
+        let matches = digest.chunks_exact(4).zip(hash).all(|(chunk, word)| {
+            u32::from_be_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]) == word
+        });
+        if !matches {
+            return Err(LeapFileError::HashMismatch);
+        }
+
+        Ok(Self {
+            updated: NtpTimestamp::new(updated, 0),
+            expires: NtpTimestamp::new(expires, 0),
+            entries,
+        })
+    }
+
+    /// Reads and parses a `leap-seconds.list` file
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, LeapFileError> {
+        Self::parse(&fs::read_to_string(path)?)
+    }
+
+    pub fn updated(&self) -> NtpTimestamp {
+        self.updated
+    }
+
+    pub fn expires(&self) -> NtpTimestamp {
+        self.expires
+    }
+
+    /// Whether the table can no longer be trusted to list every upcoming leap second
+    pub fn is_expired(&self, now: NtpTimestamp) -> bool {
+        now.diff_seconds(&self.expires) >= 0.0
+    }
+
+    /// TAI - UTC at `time`, or `None` if `time` predates the table
+    pub fn tai_offset(&self, time: NtpTimestamp) -> Option<i32> {
+        self.entries
+            .iter()
+            .take_while(|(start, _)| time.diff_seconds(start) >= 0.0)
+            .last()
+            .map(|(_, offset)| *offset)
+    }
+
+    /// First leap second after `now`, if the table lists one
+    pub fn next_leap(&self, now: NtpTimestamp) -> Option<LeapEvent> {
+        self.entries
+            .windows(2)
+            .find(|pair| pair[1].0.diff_seconds(&now) > 0.0)
+            .and_then(|pair| {
+                let leap = match pair[1].1 - pair[0].1 {
+                    1 => NTP_LEAP_LAST_MINUTE_HAS_61_SECONDS,
+                    -1 => NTP_LEAP_LAST_MINUTE_HAS_59_SECONDS,
+                    _ => return None,
+                };
+                Some(LeapEvent {
+                    time: pair[1].0,
+                    leap,
+                    tai_offset: pair[1].1,
+                })
+            })
+    }
+
+    /// Leap indicator that applies at `now`: a leap is announced during the
+    /// whole UTC month at whose end it happens
+    pub fn leap_indicator(&self, now: NtpTimestamp) -> Leap {
+        match self.next_leap(now) {
+            Some(event) if now.diff_seconds(&month_start_before(event.time)) >= 0.0 => event.leap,
+            _ => NTP_LEAP_NO_WARNING,
+        }
+    }
+}
+
+/// Combines the leap indicators of the selected servers. A leap is only
+/// accepted when more than half of the servers announce it.
+pub fn vote(indicators: impl IntoIterator<Item = Leap>) -> Leap {
+    let (mut total, mut insert, mut delete) = (0, 0, 0);
+    for leap in indicators {
+        total += 1;
+        match leap {
+            Leap::LastMinuteHas61Seconds => insert += 1,
+            Leap::LastMinuteHas59Seconds => delete += 1,
+            Leap::NoWarning | Leap::Unknown => {}
+        }
+    }
+
+    if insert * 2 > total {
+        NTP_LEAP_LAST_MINUTE_HAS_61_SECONDS
+    } else if delete * 2 > total {
+        NTP_LEAP_LAST_MINUTE_HAS_59_SECONDS
+    } else {
+        NTP_LEAP_NO_WARNING
+    }
+}
+
+/// How the local clock goes through a leap second
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LeapPolicy {
+    /// Arm the clock so that it inserts or deletes the second at midnight
+    Step,
+    /// Leave the clock disarmed and slew the second out across the smear window
+    Smear(LeapSmear),
+}
+
+/// Shape of the correction across a smear window
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SmearShape {
+    /// Constant rate over the whole window
+    Linear,
+    /// Rate rising and falling smoothly, so that the frequency never jumps
+    Cosine,
+}
+
+/// Spreads a leap second over a window of time instead of stepping it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LeapSmear {
+    pub shape: SmearShape,
+    /// Seconds from the start of the window to the leap
+    pub lead: f64,
+    /// Length of the window in seconds
+    pub duration: f64,
+}
+
+impl LeapSmear {
+    /// 24 hour linear smear from noon to noon UTC around the leap, as done by Google
+    pub fn google() -> Self {
+        Self::linear(SECONDS_PER_DAY as f64)
+    }
+
+    /// Linear smear over `duration` seconds centred on the leap
+    pub fn linear(duration: f64) -> Self {
+        Self {
+            shape: SmearShape::Linear,
+            lead: duration / 2.0,
+            duration,
+        }
+    }
+
+    /// Cosine smear over `duration` seconds centred on the leap
+    pub fn cosine(duration: f64) -> Self {
+        Self {
+            shape: SmearShape::Cosine,
+            lead: duration / 2.0,
+            duration,
+        }
+    }
+
+    /// Fraction of the leap second spread out `elapsed` seconds after the leap
+    fn progress(&self, elapsed: f64) -> f64 {
+        let position = ((elapsed + self.lead) / self.duration).clamp(0.0, 1.0);
+        match self.shape {
+            SmearShape::Linear => position,
+            SmearShape::Cosine => (1.0 - (std::f64::consts::PI * position).cos()) / 2.0,
+        }
+    }
+
+    /// Whether `now` falls inside the window around `event`
+    pub fn is_active(&self, event: &LeapEvent, now: NtpTimestamp) -> bool {
+        let elapsed = now.diff_seconds(&event.time);
+        elapsed >= -self.lead && elapsed < self.duration - self.lead
+    }
+
+    /// Offset to add to `now`, read from a clock that follows UTC through the
+    /// leap, to obtain smeared time. Outside the window the offset is zero.
+    pub fn offset(&self, event: &LeapEvent, now: NtpTimestamp) -> f64 {
+        let after = now.diff_seconds(&event.time) >= 0.0;
+        self.offset_from(event, now, after)
+    }
+
+    /// Same as `offset`, for a timestamp read while the clock repeats the
+    /// inserted second. NTP timestamps cannot tell it apart from the second
+    /// before it, so the caller has to know from the clock state.
+    pub fn offset_in_inserted_second(&self, event: &LeapEvent, now: NtpTimestamp) -> f64 {
+        self.offset_from(event, now, true)
+    }
+
+    fn offset_from(&self, event: &LeapEvent, now: NtpTimestamp, after: bool) -> f64 {
+        // Inserting a second slows smeared time down, deleting one speeds it up
+        let sign = match event.leap {
+            Leap::LastMinuteHas61Seconds => -1.0,
+            _ => 1.0,
+        };
+        let elapsed = now.diff_seconds(&event.time);
+        if after {
+            // Past the leap UTC has already been stepped by one second
+            sign * (self.progress(elapsed - sign) - 1.0)
+        } else {
+            sign * self.progress(elapsed)
+        }
+    }
+}
+
+/// Decides which leap second is pending, from a leap second table when a
+/// current one is available and from the servers' votes otherwise
+#[derive(Debug, Clone)]
+pub struct LeapSeconds {
+    table: Option<LeapSecondTable>,
+    policy: LeapPolicy,
+}
+
+impl LeapSeconds {
+    pub fn new(policy: LeapPolicy) -> Self {
+        Self {
+            table: None,
+            policy,
+        }
+    }
+
+    pub fn with_table(mut self, table: LeapSecondTable) -> Self {
+        self.table = Some(table);
+        self
+    }
+
+    pub fn policy(&self) -> LeapPolicy {
+        self.policy
+    }
+
+    pub fn table(&self) -> Option<&LeapSecondTable> {
+        self.table.as_ref()
+    }
+
+    fn current_table(&self, now: NtpTimestamp) -> Option<&LeapSecondTable> {
+        self.table.as_ref().filter(|table| !table.is_expired(now))
+    }
+
+    /// Leap second pending at `now`, given the indicators of the selected servers
+    pub fn pending(&self, now: NtpTimestamp, votes: &[Leap]) -> Leap {
+        match self.current_table(now) {
+            Some(table) => table.leap_indicator(now),
+            None => vote(votes.iter().copied()),
+        }
+    }
+
+    /// Upcoming leap second, if a current table lists one
+    pub fn upcoming(&self, now: NtpTimestamp) -> Option<LeapEvent> {
+        self.current_table(now)?.next_leap(now)
+    }
+
+    /// Leap second whose smear window contains `now`, if any
+    pub fn smeared(&self, smear: &LeapSmear, now: NtpTimestamp) -> Option<LeapEvent> {
+        let window_start = now.add_seconds(-(smear.duration - smear.lead) - 1.0);
+        self.current_table(now)?
+            .next_leap(window_start)
+            .filter(|event| smear.is_active(event, now))
+    }
+
+    /// TAI - UTC at `now`, if a current table is loaded
+    pub fn tai_offset(&self, now: NtpTimestamp) -> Option<i32> {
+        self.current_table(now)?.tai_offset(now)
+    }
+
+    /// Leap indicator a server should put in its replies. An unsynchronized
+    /// server always advertises `NTP_LEAP_UNKNOWN`, and a smearing server hides
+    /// the leap from its clients.
+    pub fn advertised(&self, now: NtpTimestamp, votes: &[Leap], synchronized: bool) -> Leap {
+        if !synchronized {
+            return NTP_LEAP_UNKNOWN;
+        }
+        match self.policy {
+            LeapPolicy::Step => self.pending(now, votes),
+            LeapPolicy::Smear(_) => NTP_LEAP_NO_WARNING,
+        }
+    }
+
+    /// Part of the leap second the local clock should have slewed out at `now`,
+    /// in seconds. Always zero under `LeapPolicy::Step`.
+    pub fn smear_offset(&self, now: NtpTimestamp) -> f64 {
+        let LeapPolicy::Smear(smear) = self.policy else {
+            return 0.0;
+        };
+        match self.smeared(&smear, now) {
+            // The clock is never stepped, so it stays on the pre-leap side
+            Some(event) => smear.offset_from(&event, now, false),
+            None => 0.0,
+        }
+    }
+
+    /// Arms `clock` for the pending leap second under `LeapPolicy::Step`, and
+    /// keeps it disarmed when smearing
+    pub fn update_clock(
+        &self,
+        clock: &mut impl Clock,
+        now: NtpTimestamp,
+        votes: &[Leap],
+    ) -> NtpResult<()> {
+        let leap = match self.policy {
+            LeapPolicy::Step => self.pending(now, votes),
+            LeapPolicy::Smear(_) => NTP_LEAP_NO_WARNING,
+        };
+        clock.set_leap(leap)
+    }
+}
+
+/// Start of the UTC month that ends at `time`
+fn month_start_before(time: NtpTimestamp) -> NtpTimestamp {
+    let days = (time.seconds() as i64 - NTP_UNIX_EPOCH_OFFSET - 1).div_euclid(SECONDS_PER_DAY);
+    let (year, month, _) = civil_from_days(days);
+    let days = days_from_civil(year, month, 1).expect("date of a timestamp");
+    NtpTimestamp::from_unix(days * SECONDS_PER_DAY, 0)
+}
+
+/// Converts days since 1970-01-01 to a (year, month, day) proleptic Gregorian date
+pub(crate) fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * shifted_month + 2) / 5 + 1) as u32;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    } as u32;
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+/// Converts a proleptic Gregorian date to days since 1970-01-01, or `None` if
+/// that overflows
+pub(crate) fn days_from_civil(year: i64, month: u32, day: u32) -> Option<i64> {
+    let year = year.checked_sub(i64::from(month <= 2))?;
+    let era = year.div_euclid(400);
+    let year_of_era = year.rem_euclid(400);
+    let shifted_month = (i64::from(month) + 9) % 12;
+    let day_of_year = (153 * shifted_month + 2) / 5 + i64::from(day) - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era.checked_mul(146_097)?
+        .checked_add(day_of_era)?
+        .checked_sub(719_468)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LEAP_SECONDS_LIST: &str = "\
+# Trimmed copy of the IETF leap-seconds.list
+#$\t 3676924800
+#@\t 3928521600
+2272060800\t10\t# 1 Jan 1972
+2287785600\t11\t# 1 Jul 1972
+2303683200\t12\t# 1 Jan 1973
+3439756800\t34\t# 1 Jan 2009
+3550089600\t35\t# 1 Jul 2012
+3644697600\t36\t# 1 Jul 2015
+3692217600\t37\t# 1 Jan 2017
+#h\td52adec4 e1353342 bf49b873 223eccc0 83c09b34
+";
+
+    /// 2016-12-31T00:00:00Z, the last day before the most recent leap second
+    const DEC_31_2016: u32 = 3692131200;
+
+    fn table() -> LeapSecondTable {
+        LeapSecondTable::parse(LEAP_SECONDS_LIST).unwrap()
+    }
+
+    #[test]
+    fn parse_valid_leap_seconds_list() {
+        let table = table();
+
+        assert_eq!(table.updated(), NtpTimestamp::new(3676924800, 0));
+        assert_eq!(table.expires(), NtpTimestamp::new(3928521600, 0));
+        assert_eq!(table.tai_offset(NtpTimestamp::new(3692217600, 0)), Some(37));
+        assert_eq!(table.tai_offset(NtpTimestamp::new(3692217599, 0)), Some(36));
+        assert_eq!(table.tai_offset(NtpTimestamp::new(2272060799, 0)), None);
+    }
+
+    #[test]
+    fn reject_tampered_leap_seconds_list() {
+        let tampered = LEAP_SECONDS_LIST.replace("3692217600\t37", "3692217600\t38");
+
+        assert_eq!(
+            LeapSecondTable::parse(&tampered),
+            Err(LeapFileError::HashMismatch)
+        );
+    }
+
+    #[test]
+    fn reject_malformed_line() {
+        let malformed = LEAP_SECONDS_LIST.replace("2287785600\t11", "2287785600");
+
+        assert_eq!(
+            LeapSecondTable::parse(&malformed),
+            Err(LeapFileError::Syntax { line: 5 })
+        );
+    }
+
+    #[test]
+    fn expiry_is_reported() {
+        let table = table();
+
+        assert!(!table.is_expired(NtpTimestamp::new(3928521599, 0)));
+        assert!(table.is_expired(NtpTimestamp::new(3928521600, 0)));
+    }
+
+    #[test]
+    fn leap_is_announced_during_the_last_month() {
+        let table = table();
+        let event = table.next_leap(NtpTimestamp::new(DEC_31_2016, 0)).unwrap();
+
+        assert_eq!(event.time, NtpTimestamp::new(3692217600, 0));
+        assert_eq!(event.leap, NTP_LEAP_LAST_MINUTE_HAS_61_SECONDS);
+        // 2016-12-01T00:00:00Z
+        let december = NtpTimestamp::new(DEC_31_2016 - 30 * 86_400, 0);
+        assert_eq!(
+            table.leap_indicator(december),
+            NTP_LEAP_LAST_MINUTE_HAS_61_SECONDS
+        );
+        assert_eq!(
+            table.leap_indicator(december.add_seconds(-1.0)),
+            NTP_LEAP_NO_WARNING
+        );
+        assert_eq!(
+            table.leap_indicator(NtpTimestamp::new(3692217600, 0)),
+            NTP_LEAP_NO_WARNING
+        );
+    }
+
+    #[test]
+    fn vote_requires_a_majority() {
+        let insert = NTP_LEAP_LAST_MINUTE_HAS_61_SECONDS;
+
+        assert_eq!(vote([insert, insert, NTP_LEAP_NO_WARNING]), insert);
+        assert_eq!(vote([insert, NTP_LEAP_NO_WARNING]), NTP_LEAP_NO_WARNING);
+        assert_eq!(vote([]), NTP_LEAP_NO_WARNING);
+    }
+
+    #[test]
+    fn expired_table_falls_back_to_votes() {
+        let leap_seconds = LeapSeconds::new(LeapPolicy::Step).with_table(table());
+        let delete = NTP_LEAP_LAST_MINUTE_HAS_59_SECONDS;
+
+        let now = NtpTimestamp::new(DEC_31_2016, 0);
+        assert_eq!(
+            leap_seconds.pending(now, &[delete]),
+            NTP_LEAP_LAST_MINUTE_HAS_61_SECONDS
+        );
+        let now = NtpTimestamp::new(3928521600, 0);
+        assert_eq!(leap_seconds.pending(now, &[delete]), delete);
+    }
+
+    #[test]
+    fn advertised_leap_depends_on_policy() {
+        let now = NtpTimestamp::new(DEC_31_2016, 0);
+        let step = LeapSeconds::new(LeapPolicy::Step).with_table(table());
+        let smear = LeapSeconds::new(LeapPolicy::Smear(LeapSmear::google())).with_table(table());
+
+        assert_eq!(
+            step.advertised(now, &[], true),
+            NTP_LEAP_LAST_MINUTE_HAS_61_SECONDS
+        );
+        assert_eq!(step.advertised(now, &[], false), NTP_LEAP_UNKNOWN);
+        assert_eq!(smear.advertised(now, &[], true), NTP_LEAP_NO_WARNING);
+    }
+
+    #[test]
+    fn local_smear_offset_grows_linearly_until_the_leap() {
+        let smear = LeapSmear {
+            shape: SmearShape::Linear,
+            lead: 86_400.0,
+            duration: 86_400.0,
+        };
+        let smear = LeapSeconds::new(LeapPolicy::Smear(smear)).with_table(table());
+
+        let start = NtpTimestamp::new(DEC_31_2016, 0);
+        assert_eq!(smear.smear_offset(start), 0.0);
+        assert_eq!(smear.smear_offset(start.add_seconds(43_200.0)), -0.5);
+        assert_close(smear.smear_offset(NtpTimestamp::new(3692217599, 0)), -1.0);
+    }
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!((actual - expected).abs() < 1e-4, "{actual} != {expected}");
+    }
+
+    fn insert_event() -> LeapEvent {
+        table()
+            .next_leap(NtpTimestamp::new(DEC_31_2016, 0))
+            .unwrap()
+    }
+
+    #[test]
+    fn google_smear_is_centred_on_the_leap() {
+        let smear = LeapSmear::google();
+        let event = insert_event();
+
+        let window_start = event.time.add_seconds(-43_200.0);
+        assert_eq!(smear.offset(&event, window_start.add_seconds(-1.0)), 0.0);
+        assert_eq!(smear.offset(&event, window_start), 0.0);
+        assert_close(smear.offset(&event, event.time.add_seconds(-1.0)), -0.5);
+        // The clock has been stepped back, so half a second remains to smear
+        assert_close(smear.offset(&event, event.time), 0.5);
+        assert_eq!(smear.offset(&event, event.time.add_seconds(43_199.0)), 0.0);
+        assert!(!smear.is_active(&event, event.time.add_seconds(43_200.0)));
+    }
+
+    #[test]
+    fn smeared_time_is_continuous_through_the_inserted_second() {
+        let smear = LeapSmear::google();
+        let event = insert_event();
+        let before = event.time.add_seconds(-0.5);
+
+        let first = before.add_seconds(smear.offset(&event, before));
+        let repeated = before.add_seconds(smear.offset_in_inserted_second(&event, before));
+        let after = event.time.add_seconds(0.5);
+        let after = after.add_seconds(smear.offset(&event, after));
+
+        assert_close(repeated.diff_seconds(&first), 1.0);
+        assert_close(after.diff_seconds(&repeated), 1.0);
+    }
+
+    #[test]
+    fn cosine_smear_starts_and_ends_slowly() {
+        let smear = LeapSmear::cosine(86_400.0);
+        let event = insert_event();
+
+        let quarter = event.time.add_seconds(-21_600.0);
+        assert_close(smear.offset(&event, quarter), -0.146_446_6);
+        assert_close(smear.offset(&event, event.time.add_seconds(-1.0)), -0.5);
+    }
+
+    #[test]
+    fn smeared_event_is_found_on_both_sides_of_the_leap() {
+        let leap_seconds = LeapSeconds::new(LeapPolicy::Step).with_table(table());
+        let smear = LeapSmear::google();
+        let event = insert_event();
+
+        assert_eq!(
+            leap_seconds.smeared(&smear, event.time.add_seconds(-100.0)),
+            Some(event)
+        );
+        assert_eq!(
+            leap_seconds.smeared(&smear, event.time.add_seconds(100.0)),
+            Some(event)
+        );
+        assert_eq!(
+            leap_seconds.smeared(&smear, event.time.add_seconds(-50_000.0)),
+            None
+        );
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn step_policy_arms_the_clock() {
+        use crate::clock::{linux::LinuxClock, ClockState};
+
+        let mut clock = LinuxClock::dry_run(libc::CLOCK_REALTIME).unwrap();
+        clock.set_sync_status(true, 0.0, 0.0).unwrap();
+        let leap_seconds = LeapSeconds::new(LeapPolicy::Step).with_table(table());
+
+        leap_seconds
+            .update_clock(&mut clock, NtpTimestamp::new(DEC_31_2016, 0), &[])
+            .unwrap();
+
+        assert_eq!(clock.status().unwrap().state, ClockState::InsertLeap);
+    }
+
+    #[test]
+    fn civil_date_round_trip() {
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(civil_from_days(17_166), (2016, 12, 31));
+        assert_eq!(days_from_civil(2017, 1, 1), Some(17_167));
+        assert_eq!(days_from_civil(1900, 1, 1), Some(-25_567));
+        assert_eq!(days_from_civil(i64::MAX, 12, 31), None);
+    }
+}
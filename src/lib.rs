@@ -1,5 +1,51 @@
+//! Without the default `std` feature, only `client`, `codec`, `logging`,
+//! `ntp_message_protocol`, `transport` and `types` are built. They depend on
+//! `core` alone and never allocate.
+
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
+
+// The derives name the traits through `::demo_ntp`, in this crate as well
+extern crate self as demo_ntp;
+
+#[cfg(feature = "std")]
+pub mod association;
+#[cfg(feature = "std")]
+pub mod auth;
 pub mod client;
+#[cfg(feature = "std")]
+pub mod clock;
 pub mod codec;
+#[cfg(feature = "std")]
+pub mod config;
+#[cfg(feature = "std")]
+pub mod daemon;
+#[cfg(feature = "std")]
+pub mod discipline;
+#[cfg(feature = "std")]
+pub mod drift;
+#[cfg(feature = "std")]
 pub mod error;
+#[cfg(feature = "std")]
+pub mod filter;
+#[cfg(feature = "std")]
+pub mod leap;
+pub mod logging;
+#[cfg(feature = "metrics")]
+pub mod metrics;
 pub mod ntp_message_protocol;
+#[cfg(feature = "std")]
+pub mod poll;
+#[cfg(feature = "std")]
+mod random;
+#[cfg(feature = "std")]
+pub mod refclock;
+#[cfg(feature = "std")]
+pub mod selection;
+#[cfg(feature = "std")]
+pub mod server;
+#[cfg(feature = "std")]
+pub mod stats;
+#[cfg(feature = "testing")]
+pub mod testing;
+pub mod transport;
 pub mod types;
@@ -0,0 +1,104 @@
+//! Protocol events are logged through the `log` facade, with one target per
+//! subsystem and the details as key-values. Any `log` backend receives them,
+//! for example `env_logger`, or `tracing` through `tracing_log::LogTracer`.
+//! Events are dropped until a backend is installed.
+
+#[cfg(feature = "std")]
+use log::LevelFilter;
+#[cfg(feature = "std")]
+use serde::Deserialize;
+
+/// Requests sent to servers and their replies
+pub const CLIENT: &str = "demo_ntp::client";
+
+/// Requests received from clients and their replies
+pub const SERVER: &str = "demo_ntp::server";
+
+/// Reachability, poll interval and selection of the servers
+pub const ASSOCIATION: &str = "demo_ntp::association";
+
+/// Steps and slews of the local clock
+pub const CLOCK: &str = "demo_ntp::clock";
+
+/// Leap second announcements
+pub const LEAP: &str = "demo_ntp::leap";
+
+/// Most verbose level logged for each subsystem, for backends to filter the
+/// events on
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Verbosity {
+    /// Level of the subsystems not listed, and of other targets
+    #[serde(default = "Verbosity::default_level")]
+    pub level: LevelFilter,
+    pub client: Option<LevelFilter>,
+    pub server: Option<LevelFilter>,
+    pub association: Option<LevelFilter>,
+    pub clock: Option<LevelFilter>,
+    pub leap: Option<LevelFilter>,
+}
+
+#[cfg(feature = "std")]
+impl Default for Verbosity {
+    fn default() -> Self {
+        Self {
+            level: Self::default_level(),
+            client: None,
+            server: None,
+            association: None,
+            clock: None,
+            leap: None,
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl Verbosity {
+    fn default_level() -> LevelFilter {
+        LevelFilter::Info
+    }
+
+    /// Most verbose level logged for the events of `target`
+    pub fn level(&self, target: &str) -> LevelFilter {
+        match target {
+            CLIENT => self.client,
+            SERVER => self.server,
+            ASSOCIATION => self.association,
+            CLOCK => self.clock,
+            LEAP => self.leap,
+            _ => None,
+        }
+        .unwrap_or(self.level)
+    }
+
+    /// Most verbose level of any subsystem, for `log::set_max_level`
+    pub fn max_level(&self) -> LevelFilter {
+        [
+            self.client,
+            self.server,
+            self.association,
+            self.clock,
+            self.leap,
+        ]
+        .into_iter()
+        .flatten()
+        .fold(self.level, Ord::max)
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn subsystems_fall_back_to_the_default_level() {
+        let verbosity: Verbosity =
+            toml::from_str("level = \"warn\"\nclient = \"trace\"\n").unwrap();
+
+        assert_eq!(verbosity.level(CLIENT), LevelFilter::Trace);
+        assert_eq!(verbosity.level(CLOCK), LevelFilter::Warn);
+        assert_eq!(verbosity.level("other"), LevelFilter::Warn);
+        assert_eq!(verbosity.max_level(), LevelFilter::Trace);
+    }
+}
@@ -0,0 +1,365 @@
+//! Prometheus metrics of the daemon and of its servers, in the text exposition
+//! format, and a minimal HTTP endpoint to scrape them from
+
+use std::{
+    fmt::Write as _,
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{TcpListener, TcpStream},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::{clock::Clock, daemon::Daemon, error::NtpResult, server::ServerStats};
+
+/// Content type of the text exposition format
+const METRICS_CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// How long a scraper may take to send its request
+const METRICS_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Most bytes read of a request, headers included
+const METRICS_REQUEST_LIMIT: u64 = 8192;
+
+/// Renders the metrics of `daemon` and of the servers counted in `server_stats`
+pub fn encode<C: Clock>(daemon: &Daemon<C>, server_stats: &ServerStats) -> NtpResult<String> {
+    let mut metrics = Metrics::default();
+
+    let associations = daemon.associations();
+    let labels = associations
+        .iter()
+        .map(|association| {
+            format!(
+                "name=\"{}\",address=\"{}\"",
+                escape(association.name()),
+                association.address()
+            )
+        })
+        .collect::<Vec<_>>();
+    let samples = associations
+        .iter()
+        .zip(&labels)
+        .filter_map(|(association, labels)| Some((association.filter().selected()?, labels)))
+        .collect::<Vec<_>>();
+
+    metrics.family(
+        "ntp_association_offset_seconds",
+        "gauge",
+        "Offset of the server's clock relative to the local clock",
+    );
+    for (sample, labels) in &samples {
+        metrics.sample("ntp_association_offset_seconds", labels, sample.offset);
+    }
+    metrics.family(
+        "ntp_association_delay_seconds",
+        "gauge",
+        "Round-trip delay to the server",
+    );
+    for (sample, labels) in &samples {
+        metrics.sample("ntp_association_delay_seconds", labels, sample.delay);
+    }
+    metrics.family(
+        "ntp_association_jitter_seconds",
+        "gauge",
+        "Jitter of the offsets in the clock filter",
+    );
+    for (association, labels) in associations.iter().zip(&labels) {
+        let jitter = association.filter().jitter();
+        metrics.sample("ntp_association_jitter_seconds", labels, jitter);
+    }
+    metrics.family(
+        "ntp_association_stratum",
+        "gauge",
+        "Stratum advertised by the server",
+    );
+    for (association, labels) in associations.iter().zip(&labels) {
+        if let Some(last) = association.last_sample() {
+            let stratum = u8::from(last.stratum());
+            metrics.sample("ntp_association_stratum", labels, stratum.into());
+        }
+    }
+    metrics.family(
+        "ntp_association_reach",
+        "gauge",
+        "Reachability register, one bit per poll",
+    );
+    for (association, labels) in associations.iter().zip(&labels) {
+        metrics.sample("ntp_association_reach", labels, association.reach().into());
+    }
+    metrics.family(
+        "ntp_association_kisses_total",
+        "counter",
+        "Kiss-o'-death packets received from the server",
+    );
+    for (association, labels) in associations.iter().zip(&labels) {
+        let kisses = association.kisses() as f64;
+        metrics.sample("ntp_association_kisses_total", labels, kisses);
+    }
+    metrics.family(
+        "ntp_association_rejections_total",
+        "counter",
+        "Replies of the server that were not used",
+    );
+    for (association, labels) in associations.iter().zip(&labels) {
+        let rejections = association.rejections() as f64;
+        metrics.sample("ntp_association_rejections_total", labels, rejections);
+    }
+    metrics.family(
+        "ntp_association_rejected",
+        "gauge",
+        "Reason the replies of the server are not used, 1 while they are not",
+    );
+    for (association, labels) in associations.iter().zip(&labels) {
+        if let Some(reason) = association.rejection() {
+            let labels = format!("{labels},reason=\"{reason}\"");
+            metrics.sample("ntp_association_rejected", &labels, 1.0);
+        }
+    }
+
+    metrics.family(
+        "ntp_system_offset_seconds",
+        "gauge",
+        "Offset applied by the last clock update",
+    );
+    if let Some(offset) = daemon.offset() {
+        metrics.sample("ntp_system_offset_seconds", "", offset);
+    }
+    metrics.family(
+        "ntp_system_frequency_ppm",
+        "gauge",
+        "Frequency correction of the local clock",
+    );
+    let frequency = daemon.clock().status()?.frequency;
+    metrics.sample("ntp_system_frequency_ppm", "", frequency);
+    metrics.family(
+        "ntp_system_leap",
+        "gauge",
+        "Leap indicator advertised to clients, 3 while unsynchronized",
+    );
+    let leap = u8::from(daemon.state().leap);
+    metrics.sample("ntp_system_leap", "", leap.into());
+
+    for (name, help, value) in [
+        (
+            "ntp_server_requests_total",
+            "Packets received by the servers",
+            server_stats.requests(),
+        ),
+        (
+            "ntp_server_responses_total",
+            "Replies sent by the servers",
+            server_stats.responses(),
+        ),
+        (
+            "ntp_server_rate_limited_total",
+            "Requests of clients that exceeded the rate limit",
+            server_stats.rate_limited(),
+        ),
+        (
+            "ntp_server_denied_total",
+            "Requests of clients that may not be served",
+            server_stats.denied(),
+        ),
+        (
+            "ntp_server_unauthenticated_total",
+            "Requests dropped because their MAC did not verify",
+            server_stats.unauthenticated(),
+        ),
+    ] {
+        metrics.family(name, "counter", help);
+        metrics.sample(name, "", value as f64);
+    }
+    Ok(metrics.0)
+}
+
+/// Text of the metrics being rendered
+#[derive(Default)]
+struct Metrics(String);
+
+impl Metrics {
+    fn family(&mut self, name: &str, kind: &str, help: &str) {
+        let _ = writeln!(self.0, "# HELP {name} {help}");
+        let _ = writeln!(self.0, "# TYPE {name} {kind}");
+    }
+
+    fn sample(&mut self, name: &str, labels: &str, value: f64) {
+        let value = match value {
+            value if value.is_nan() => "NaN".to_string(),
+            f64::INFINITY => "+Inf".to_string(),
+            f64::NEG_INFINITY => "-Inf".to_string(),
+            value => value.to_string(),
+        };
+        if labels.is_empty() {
+            let _ = writeln!(self.0, "{name} {value}");
+        } else {
+            let _ = writeln!(self.0, "{name}{{{labels}}} {value}");
+        }
+    }
+}
+
+/// Escapes a label value
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// HTTP endpoint serving the latest metrics on `/metrics`
+pub struct MetricsServer {
+    listener: TcpListener,
+    metrics: Arc<Mutex<String>>,
+}
+
+impl MetricsServer {
+    pub fn new(listener: TcpListener) -> Self {
+        Self {
+            listener,
+            metrics: Arc::default(),
+        }
+    }
+
+    /// Metrics served to scrapers, to be replaced with the output of `encode`
+    /// as the daemon runs
+    pub fn metrics(&self) -> Arc<Mutex<String>> {
+        self.metrics.clone()
+    }
+
+    /// Waits for one scrape and answers it
+    pub fn serve_one(&self) -> io::Result<()> {
+        let (mut stream, _) = self.listener.accept()?;
+
+        let mut reader = BufReader::new(
+            Deadline {
+                stream: &stream,
+                deadline: Instant::now() + METRICS_TIMEOUT,
+            }
+            .take(METRICS_REQUEST_LIMIT),
+        );
+        let mut request = String::new();
+        reader.read_line(&mut request)?;
+        // Only the request line matters
+        let mut header = String::new();
+        while reader.read_line(&mut header)? > 2 {
+            header.clear();
+        }
+
+        let mut words = request.split_whitespace();
+        let response = match (words.next(), words.next()) {
+            (Some("GET"), Some("/metrics")) => {
+                let body = self.metrics.lock().unwrap().clone();
+                format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: {METRICS_CONTENT_TYPE}\r\n\
+                     Content-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                    body.len()
+                )
+            }
+            _ => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                .to_string(),
+        };
+        stream.write_all(response.as_bytes())
+    }
+}
+
+/// Stream whose reads fail once `deadline` passes, however slowly the peer
+/// sends its bytes
+struct Deadline<'a> {
+    stream: &'a TcpStream,
+    deadline: Instant,
+}
+
+impl Read for Deadline<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let remaining = self.deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(io::ErrorKind::TimedOut.into());
+        }
+        self.stream.set_read_timeout(Some(remaining))?;
+        self.stream.read(buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Read, net::TcpStream, thread};
+
+    use super::*;
+
+    #[test]
+    fn label_values_are_escaped() {
+        assert_eq!(escape("a\"b\\c\nd"), "a\\\"b\\\\c\\nd");
+    }
+
+    #[test]
+    fn samples_follow_the_exposition_format() {
+        let mut metrics = Metrics::default();
+        metrics.family("ntp_test", "gauge", "Test");
+        metrics.sample("ntp_test", "", 0.25);
+        metrics.sample("ntp_test", "name=\"a\"", f64::INFINITY);
+
+        assert_eq!(
+            metrics.0,
+            "# HELP ntp_test Test\n# TYPE ntp_test gauge\nntp_test 0.25\nntp_test{name=\"a\"} +Inf\n"
+        );
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn unsynchronized_daemon_is_encoded() {
+        use crate::{
+            clock::linux::LinuxClock,
+            leap::{LeapPolicy, LeapSeconds},
+        };
+
+        let clock = LinuxClock::dry_run(libc::CLOCK_REALTIME).unwrap();
+        let daemon = Daemon::new(clock, LeapSeconds::new(LeapPolicy::Step));
+
+        let metrics = encode(&daemon, &ServerStats::default()).unwrap();
+
+        assert!(metrics.contains("\nntp_system_leap 3\n"));
+        assert!(metrics.contains("\nntp_server_requests_total 0\n"));
+        assert!(!metrics.contains("\nntp_system_offset_seconds "));
+    }
+
+    #[test]
+    fn metrics_are_served_over_http() {
+        let server = MetricsServer::new(TcpListener::bind("127.0.0.1:0").unwrap());
+        let address = server.listener.local_addr().unwrap();
+        *server.metrics().lock().unwrap() = "ntp_test 1\n".to_string();
+        let scraper = thread::spawn(move || {
+            let mut stream = TcpStream::connect(address).unwrap();
+            stream
+                .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+                .unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        });
+
+        server.serve_one().unwrap();
+
+        let response = scraper.join().unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("\r\n\r\nntp_test 1\n"));
+    }
+
+    #[test]
+    fn requests_are_read_up_to_the_limit() {
+        let server = MetricsServer::new(TcpListener::bind("127.0.0.1:0").unwrap());
+        let address = server.listener.local_addr().unwrap();
+        let scraper = thread::spawn(move || {
+            let mut stream = TcpStream::connect(address).unwrap();
+            // A request line that never ends
+            stream
+                .write_all(&[b'a'; METRICS_REQUEST_LIMIT as usize])
+                .unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        });
+
+        server.serve_one().unwrap();
+
+        let response = scraper.join().unwrap();
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+}
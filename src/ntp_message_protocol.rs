@@ -1,12 +1,15 @@
 use crate::{
-    codec::{TryReadFromBytes, TryWriteToBytes},
+    codec::{CodecError, TryReadFromBytes, TryWriteToBytes},
     types::{Leap, Mode, NtpShort, NtpTimestamp, Poll, Precision, RefId, Stratum, Version},
 };
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, TryWriteToBytes, TryReadFromBytes)]
 pub struct NtpPacketHeader {
+    #[ntp(bits = 2)]
     pub leap_indicator: Leap,
+    #[ntp(bits = 3, validate = supported_version)]
     pub version_number: Version,
+    #[ntp(bits = 3)]
     pub mode: Mode,
     pub stratum: Stratum,
     pub poll: Poll,
@@ -20,107 +23,255 @@ pub struct NtpPacketHeader {
     pub xmt: NtpTimestamp,
 }
 
-impl TryWriteToBytes for NtpPacketHeader {
-    type Error = &'static str;
+/// Size of the packet header, which extension fields and MACs follow
+pub const NTP_HEADER_SIZE: usize = 48;
+
+// Offsets of the header fields
+const NTP_STRATUM_OFFSET: usize = 1;
+const NTP_POLL_OFFSET: usize = 2;
+const NTP_PRECISION_OFFSET: usize = 3;
+const NTP_ROOTDELAY_OFFSET: usize = 4;
+const NTP_ROOTDISP_OFFSET: usize = 8;
+const NTP_REFID_OFFSET: usize = 12;
+const NTP_REFTIME_OFFSET: usize = 16;
+const NTP_ORG_OFFSET: usize = 24;
+const NTP_REC_OFFSET: usize = 32;
+const NTP_XMT_OFFSET: usize = 40;
+
+fn supported_version(version: &Version) -> Result<(), CodecError> {
+    if version.is_supported() {
+        Ok(())
+    } else {
+        Err(CodecError::invalid("Unsupported version"))
+    }
+}
+
+/// Checks that `bytes` start with a header that can be read in place
+fn check_header(bytes: &[u8]) -> Result<(), CodecError> {
+    if bytes.len() < NTP_HEADER_SIZE {
+        return Err(CodecError::buffer_too_small(NTP_HEADER_SIZE, bytes.len()));
+    }
+    supported_version(&header_bits(bytes[0]).1).map_err(|error| error.in_field("version_number", 0))
+}
+
+/// Leap indicator, version and mode packed in the first byte of a header
+fn header_bits(byte: u8) -> (Leap, Version, Mode) {
+    let leap = Leap::try_from(byte >> 6).expect("leap indicator has 2 bits");
+    let version = Version::try_from((byte >> 3) & 0b111).expect("version has 3 bits");
+    let mode = Mode::try_from(byte & 0b111).expect("mode has 3 bits");
+    (leap, version, mode)
+}
+
+/// Reads the field of type `T` at `offset` of a checked header
+fn field<'a, T: TryReadFromBytes<'a, Error = CodecError>>(bytes: &'a [u8], offset: usize) -> T {
+    let (value, _) = T::try_read_from_bytes(&bytes[offset..]).expect("header was checked");
+    value
+}
+
+/// Packet read in place from a borrowed buffer. The header is checked once,
+/// when the view is created, and its fields are decoded as they are accessed.
+#[derive(Debug, Clone, Copy)]
+pub struct NtpPacketView<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> NtpPacketView<'a> {
+    /// Views the packet in `bytes`, which must hold a header of a supported
+    /// version
+    pub fn new(bytes: &'a [u8]) -> Result<Self, CodecError> {
+        check_header(bytes)?;
+        Ok(Self { bytes })
+    }
+
+    pub fn leap_indicator(&self) -> Leap {
+        header_bits(self.bytes[0]).0
+    }
+
+    pub fn version_number(&self) -> Version {
+        header_bits(self.bytes[0]).1
+    }
+
+    pub fn mode(&self) -> Mode {
+        header_bits(self.bytes[0]).2
+    }
+
+    pub fn stratum(&self) -> Stratum {
+        field(self.bytes, NTP_STRATUM_OFFSET)
+    }
+
+    pub fn poll(&self) -> Poll {
+        field(self.bytes, NTP_POLL_OFFSET)
+    }
+
+    pub fn precision(&self) -> Precision {
+        field(self.bytes, NTP_PRECISION_OFFSET)
+    }
 
-    fn try_write_to_bytes(&self, bytes: &mut [u8]) -> Result<usize, Self::Error> {
-        let mut total_bytes = 0;
-        if bytes.is_empty() {
-            return Err("Not enough space in buffer");
+    pub fn rootdelay(&self) -> NtpShort {
+        field(self.bytes, NTP_ROOTDELAY_OFFSET)
+    }
+
+    pub fn rootdisp(&self) -> NtpShort {
+        field(self.bytes, NTP_ROOTDISP_OFFSET)
+    }
+
+    pub fn refid(&self) -> RefId {
+        field(self.bytes, NTP_REFID_OFFSET)
+    }
+
+    pub fn reftime(&self) -> NtpTimestamp {
+        field(self.bytes, NTP_REFTIME_OFFSET)
+    }
+
+    pub fn org(&self) -> NtpTimestamp {
+        field(self.bytes, NTP_ORG_OFFSET)
+    }
+
+    pub fn rec(&self) -> NtpTimestamp {
+        field(self.bytes, NTP_REC_OFFSET)
+    }
+
+    pub fn xmt(&self) -> NtpTimestamp {
+        field(self.bytes, NTP_XMT_OFFSET)
+    }
+
+    /// Bytes of the header
+    pub fn header(&self) -> &'a [u8] {
+        &self.bytes[..NTP_HEADER_SIZE]
+    }
+
+    /// Bytes following the header, such as extension fields and a MAC
+    pub fn trailer(&self) -> &'a [u8] {
+        &self.bytes[NTP_HEADER_SIZE..]
+    }
+
+    /// Copies the header out of the buffer
+    pub fn to_header(&self) -> NtpPacketHeader {
+        NtpPacketHeader {
+            leap_indicator: self.leap_indicator(),
+            version_number: self.version_number(),
+            mode: self.mode(),
+            stratum: self.stratum(),
+            poll: self.poll(),
+            precision: self.precision(),
+            rootdelay: self.rootdelay(),
+            rootdisp: self.rootdisp(),
+            refid: self.refid(),
+            reftime: self.reftime(),
+            org: self.org(),
+            rec: self.rec(),
+            xmt: self.xmt(),
         }
-        bytes[0] = (u8::from(self.leap_indicator) << 6)
-            | (u8::from(self.version_number) << 3)
-            | u8::from(self.mode);
-
-        total_bytes += 1;
-        total_bytes += self.stratum.try_write_to_bytes(&mut bytes[total_bytes..])?;
-        total_bytes += self.poll.try_write_to_bytes(&mut bytes[total_bytes..])?;
-        total_bytes += self
-            .precision
-            .try_write_to_bytes(&mut bytes[total_bytes..])?;
-        total_bytes += self
-            .rootdelay
-            .try_write_to_bytes(&mut bytes[total_bytes..])?;
-        total_bytes += self
-            .rootdisp
-            .try_write_to_bytes(&mut bytes[total_bytes..])?;
-        total_bytes += self.refid.try_write_to_bytes(&mut bytes[total_bytes..])?;
-        total_bytes += self.reftime.try_write_to_bytes(&mut bytes[total_bytes..])?;
-        total_bytes += self.org.try_write_to_bytes(&mut bytes[total_bytes..])?;
-        total_bytes += self.rec.try_write_to_bytes(&mut bytes[total_bytes..])?;
-        total_bytes += self.xmt.try_write_to_bytes(&mut bytes[total_bytes..])?;
-
-        Ok(total_bytes)
     }
 }
 
-impl<'a> TryReadFromBytes<'a> for NtpPacketHeader {
-    type Error = &'static str;
+impl<'a> TryReadFromBytes<'a> for NtpPacketView<'a> {
+    type Error = CodecError;
 
+    /// Views the header only, leaving the rest of `bytes` to the caller
     fn try_read_from_bytes(bytes: &'a [u8]) -> Result<(Self, usize), Self::Error> {
-        let mut total_bytes = 0;
+        let view = Self::new(bytes.get(..NTP_HEADER_SIZE).unwrap_or(bytes))?;
+        Ok((view, NTP_HEADER_SIZE))
+    }
+}
 
-        if bytes.is_empty() {
-            return Err("Not enough space in buffer");
-        }
-        let leap_indicator = Leap::try_from((bytes[0] & 0b11_000_000) >> 6)?;
-        let version_number = Version::try_from((bytes[0] & 0b00_111_000) >> 3)?;
-        let mode = Mode::try_from(bytes[0] & 0b00_000_111)?;
-        total_bytes += 1;
-        let (stratum, size) = Stratum::try_read_from_bytes(&bytes[total_bytes..])?;
-        total_bytes += size;
-
-        let (poll, size) = Poll::try_read_from_bytes(&bytes[total_bytes..])?;
-        total_bytes += size;
-
-        let (precision, size) = Precision::try_read_from_bytes(&bytes[total_bytes..])?;
-        total_bytes += size;
-
-        let (rootdelay, size) = NtpShort::try_read_from_bytes(&bytes[total_bytes..])?;
-        total_bytes += size;
-
-        let (rootdisp, size) = NtpShort::try_read_from_bytes(&bytes[total_bytes..])?;
-        total_bytes += size;
-
-        let (refid, size) = RefId::try_read_from_bytes(&bytes[total_bytes..])?;
-        total_bytes += size;
-
-        let (reftime, size) = NtpTimestamp::try_read_from_bytes(&bytes[total_bytes..])?;
-        total_bytes += size;
-
-        let (org, size) = NtpTimestamp::try_read_from_bytes(&bytes[total_bytes..])?;
-        total_bytes += size;
-
-        let (rec, size) = NtpTimestamp::try_read_from_bytes(&bytes[total_bytes..])?;
-        total_bytes += size;
-
-        let (xmt, size) = NtpTimestamp::try_read_from_bytes(&bytes[total_bytes..])?;
-        total_bytes += size;
-
-        Ok((
-            Self {
-                leap_indicator,
-                version_number,
-                mode,
-                stratum,
-                poll,
-                precision,
-                rootdelay,
-                rootdisp,
-                refid,
-                reftime,
-                org,
-                rec,
-                xmt,
-            },
-            total_bytes,
-        ))
+/// Packet edited in place in a borrowed buffer, for example to turn a request
+/// into its reply without copying it
+#[derive(Debug)]
+pub struct NtpPacketViewMut<'a> {
+    bytes: &'a mut [u8],
+}
+
+impl<'a> NtpPacketViewMut<'a> {
+    /// Views the packet in `bytes`, which must hold a header of a supported
+    /// version
+    pub fn new(bytes: &'a mut [u8]) -> Result<Self, CodecError> {
+        check_header(bytes)?;
+        Ok(Self { bytes })
+    }
+
+    /// Read access to the fields
+    pub fn as_view(&self) -> NtpPacketView<'_> {
+        NtpPacketView { bytes: self.bytes }
+    }
+
+    fn set_header_bits(&mut self, leap: Leap, version: Version, mode: Mode) {
+        self.bytes[0] = (u8::from(leap) << 6) | ((u8::from(version) & 0b111) << 3) | u8::from(mode);
+    }
+
+    fn set<T: TryWriteToBytes<Error = CodecError>>(&mut self, offset: usize, value: T) {
+        value
+            .try_write_to_bytes(&mut self.bytes[offset..])
+            .expect("header was checked");
+    }
+
+    pub fn set_leap_indicator(&mut self, leap: Leap) {
+        let (_, version, mode) = header_bits(self.bytes[0]);
+        self.set_header_bits(leap, version, mode);
+    }
+
+    pub fn set_version_number(&mut self, version: Version) {
+        let (leap, _, mode) = header_bits(self.bytes[0]);
+        self.set_header_bits(leap, version, mode);
+    }
+
+    pub fn set_mode(&mut self, mode: Mode) {
+        let (leap, version, _) = header_bits(self.bytes[0]);
+        self.set_header_bits(leap, version, mode);
+    }
+
+    pub fn set_stratum(&mut self, stratum: Stratum) {
+        self.set(NTP_STRATUM_OFFSET, stratum);
+    }
+
+    pub fn set_poll(&mut self, poll: Poll) {
+        self.set(NTP_POLL_OFFSET, poll);
+    }
+
+    pub fn set_precision(&mut self, precision: Precision) {
+        self.set(NTP_PRECISION_OFFSET, precision);
+    }
+
+    pub fn set_rootdelay(&mut self, rootdelay: NtpShort) {
+        self.set(NTP_ROOTDELAY_OFFSET, rootdelay);
+    }
+
+    pub fn set_rootdisp(&mut self, rootdisp: NtpShort) {
+        self.set(NTP_ROOTDISP_OFFSET, rootdisp);
+    }
+
+    pub fn set_refid(&mut self, refid: RefId) {
+        self.set(NTP_REFID_OFFSET, refid);
+    }
+
+    pub fn set_reftime(&mut self, reftime: NtpTimestamp) {
+        self.set(NTP_REFTIME_OFFSET, reftime);
+    }
+
+    pub fn set_org(&mut self, org: NtpTimestamp) {
+        self.set(NTP_ORG_OFFSET, org);
+    }
+
+    pub fn set_rec(&mut self, rec: NtpTimestamp) {
+        self.set(NTP_REC_OFFSET, rec);
+    }
+
+    pub fn set_xmt(&mut self, xmt: NtpTimestamp) {
+        self.set(NTP_XMT_OFFSET, xmt);
+    }
+
+    /// Bytes following the header, such as extension fields and a MAC
+    pub fn trailer_mut(&mut self) -> &mut [u8] {
+        &mut self.bytes[NTP_HEADER_SIZE..]
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::types::{NTP_LEAP_NO_WARNING, NTP_MODE_CLIENT, NTP_VERSION_4};
+    use crate::{
+        codec::CodecErrorKind,
+        types::{NTP_LEAP_NO_WARNING, NTP_MODE_CLIENT, NTP_MODE_SERVER, NTP_VERSION_4},
+    };
 
     use super::*;
 
@@ -238,6 +389,26 @@ mod tests {
         assert_eq!(packet, expected);
     }
 
+    #[test]
+    fn read_packet_header_rejects_unsupported_versions() {
+        let mut bytes = [0u8; 48];
+        for version in [0, 5, 6, 7] {
+            bytes[0] = (version << 3) | 0b011;
+            assert_eq!(
+                NtpPacketHeader::try_read_from_bytes(&bytes),
+                Err(CodecError {
+                    field: Some("version_number"),
+                    offset: 0,
+                    kind: CodecErrorKind::Invalid("Unsupported version"),
+                })
+            );
+        }
+
+        bytes[0] = 0b00_011_011;
+        let (packet, _) = NtpPacketHeader::try_read_from_bytes(&bytes).unwrap();
+        assert_eq!(packet.version_number, Version::V3);
+    }
+
     #[test]
     fn read_packet_header_different_information_from_bytes() {
         #[rustfmt::skip]
@@ -275,4 +446,73 @@ mod tests {
 
         assert_eq!(packet, expected);
     }
+
+    #[test]
+    fn read_truncated_packet_header_names_the_field() {
+        let bytes = [0x23u8; 43];
+
+        let error = NtpPacketHeader::try_read_from_bytes(&bytes).unwrap_err();
+
+        assert_eq!(error.field, Some("xmt"));
+        assert_eq!(error.offset, 40);
+        assert_eq!(
+            error.kind,
+            CodecErrorKind::BufferTooSmall {
+                needed: 8,
+                available: 3
+            }
+        );
+        assert_eq!(
+            error.to_string(),
+            "xmt at byte 40: needs 8 bytes, 3 available"
+        );
+    }
+
+    #[test]
+    fn view_reads_fields_in_place() {
+        let mut bytes = [0u8; 52];
+        bytes[0] = 0b00_100_011;
+        bytes[1] = 2;
+        bytes[40..48].copy_from_slice(&[0, 0, 0, 10, 0, 0, 3, 232]);
+        bytes[48..].copy_from_slice(b"tail");
+
+        let view = NtpPacketView::new(&bytes).unwrap();
+
+        assert_eq!(view.mode(), NTP_MODE_CLIENT);
+        assert_eq!(view.version_number(), NTP_VERSION_4);
+        assert_eq!(view.stratum(), Stratum::from(2));
+        assert_eq!(view.xmt(), NtpTimestamp::new(10, 1000));
+        assert_eq!(view.trailer(), b"tail");
+        assert_eq!(
+            view.to_header(),
+            NtpPacketHeader::try_read_from_bytes(&bytes).unwrap().0
+        );
+        assert!(NtpPacketView::new(&bytes[..47]).is_err());
+        bytes[0] = 0b00_101_011;
+        assert!(NtpPacketView::new(&bytes).is_err());
+    }
+
+    #[test]
+    fn request_is_turned_into_a_reply_in_place() {
+        let mut bytes = [0u8; 48];
+        bytes[0] = 0b00_011_011;
+        bytes[40..].copy_from_slice(&[0, 0, 0, 10, 0, 0, 3, 232]);
+
+        let mut packet = NtpPacketViewMut::new(&mut bytes).unwrap();
+        let request_xmt = packet.as_view().xmt();
+        packet.set_mode(NTP_MODE_SERVER);
+        packet.set_leap_indicator(Leap::LastMinuteHas61Seconds);
+        packet.set_stratum(Stratum::from(1));
+        packet.set_refid(RefId::from(*b"GPS\0"));
+        packet.set_org(request_xmt);
+        packet.set_xmt(NtpTimestamp::new(11, 0));
+
+        let reply = packet.as_view().to_header();
+        assert_eq!(reply.leap_indicator, Leap::LastMinuteHas61Seconds);
+        assert_eq!(reply.version_number, Version::V3);
+        assert_eq!(reply.mode, NTP_MODE_SERVER);
+        assert_eq!(reply.org, NtpTimestamp::new(10, 1000));
+        assert_eq!(reply.xmt, NtpTimestamp::new(11, 0));
+        assert_eq!(&bytes[12..16], b"GPS\0");
+    }
 }
@@ -0,0 +1,259 @@
+//! When to poll a server: the reachability register, the poll exponent
+//! adapted between minpoll and maxpoll, bursts, and randomized intervals
+
+use std::time::Duration;
+
+use crate::{association::AssociationConfig, types::Poll};
+
+/// Requests sent in a burst
+pub const BURST_COUNT: u8 = 8;
+
+/// Interval between the requests of a burst
+pub const BURST_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Polls without a reply before the server counts as gone and the poll
+/// interval backs off
+pub const NTP_UNREACH: u32 = 10;
+
+/// Offsets below this many times the jitter count as the clock being stable
+const POLL_GATE: f64 = 4.0;
+
+/// Hysteresis of the poll exponent: how far the counter goes before it changes
+const POLL_LIMIT: i32 = 30;
+
+/// Polls are delayed by up to this fraction of the interval, so that clients
+/// started together do not stay in step
+const POLL_SPREAD: f64 = 1.0 / 16.0;
+
+/// Poll schedule of one association, in the manner of ntpd
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PollScheduler {
+    /// Shift register with one bit per poll, set when the server replied
+    reach: u8,
+    poll: i8,
+    minpoll: i8,
+    maxpoll: i8,
+    /// Hysteresis counter of the poll adaptation
+    counter: i32,
+    burst: bool,
+    iburst: bool,
+    /// Requests left in the current burst
+    burst_left: u8,
+    /// Polls since the last reply
+    unreach: u32,
+}
+
+impl PollScheduler {
+    pub fn new(config: AssociationConfig) -> Self {
+        Self {
+            reach: 0,
+            poll: config.minpoll,
+            minpoll: config.minpoll,
+            maxpoll: config.maxpoll,
+            counter: 0,
+            burst: config.burst,
+            iburst: config.iburst,
+            burst_left: 0,
+            unreach: 0,
+        }
+    }
+
+    pub fn reach(&self) -> u8 {
+        self.reach
+    }
+
+    pub fn poll(&self) -> Poll {
+        Poll::from(self.poll)
+    }
+
+    /// Whether a burst is under way
+    pub fn in_burst(&self) -> bool {
+        self.burst_left > 0
+    }
+
+    /// Records a request about to be sent. The first request of a poll shifts
+    /// the reach register, and starts a burst with `burst` while the server is
+    /// reachable or with `iburst` until it is. Servers silent for
+    /// `NTP_UNREACH` polls are polled less often.
+    pub fn request(&mut self) {
+        if self.burst_left > 0 {
+            self.burst_left -= 1;
+            return;
+        }
+        let reachable = self.reach != 0;
+        self.reach <<= 1;
+        if self.unreach >= NTP_UNREACH {
+            self.poll = (self.poll + 1).min(self.maxpoll);
+        }
+        self.unreach = self.unreach.saturating_add(1);
+        let burst = if reachable {
+            self.burst
+        } else {
+            self.iburst && self.unreach <= NTP_UNREACH
+        };
+        if burst {
+            self.burst_left = BURST_COUNT - 1;
+        }
+    }
+
+    /// Records a reply to the last request
+    pub fn reply(&mut self) {
+        self.reach |= 1;
+        self.unreach = 0;
+    }
+
+    /// Adapts the poll exponent to an offset applied to the clock: it grows
+    /// while offsets stay within the jitter, and shrinks when they do not
+    pub fn adapt(&mut self, offset: f64, jitter: f64) {
+        if offset.abs() < POLL_GATE * jitter {
+            self.counter += i32::from(self.poll);
+            if self.counter > POLL_LIMIT {
+                self.counter = POLL_LIMIT;
+                if self.poll < self.maxpoll {
+                    self.counter = 0;
+                    self.poll += 1;
+                }
+            }
+        } else {
+            self.counter -= 2 * i32::from(self.poll);
+            if self.counter < -POLL_LIMIT {
+                self.counter = -POLL_LIMIT;
+                if self.poll > self.minpoll {
+                    self.counter = 0;
+                    self.poll -= 1;
+                }
+            }
+        }
+    }
+
+    /// Polls less often, as a server sending a RATE kiss-o'-death asks
+    pub fn back_off(&mut self) {
+        self.poll = (self.poll + 1).min(self.maxpoll);
+        self.counter = 0;
+    }
+
+    /// Returns to the shortest poll interval
+    pub fn reset(&mut self) {
+        self.poll = self.minpoll;
+        self.counter = 0;
+    }
+
+    /// Time until the next request, lengthened by a fraction `random`, in
+    /// [0, 1), of the spread
+    pub fn interval(&self, random: f64) -> Duration {
+        if self.burst_left > 0 {
+            return BURST_INTERVAL;
+        }
+        let interval = self.poll().to_duration();
+        interval + interval.mul_f64(random * POLL_SPREAD)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scheduler(burst: bool, iburst: bool) -> PollScheduler {
+        PollScheduler::new(AssociationConfig {
+            minpoll: 6,
+            maxpoll: 10,
+            burst,
+            iburst,
+        })
+    }
+
+    #[test]
+    fn reach_register_shifts_once_per_poll() {
+        let mut scheduler = scheduler(false, false);
+
+        scheduler.request();
+        scheduler.reply();
+        scheduler.request();
+        scheduler.request();
+        scheduler.reply();
+
+        assert_eq!(scheduler.reach(), 0b101);
+        assert_eq!(scheduler.interval(0.0), Duration::from_secs(64));
+    }
+
+    #[test]
+    fn iburst_sends_a_burst_until_the_server_replies() {
+        let mut scheduler = scheduler(false, true);
+
+        scheduler.request();
+        assert!(scheduler.in_burst());
+        assert_eq!(scheduler.interval(0.5), BURST_INTERVAL);
+        scheduler.reply();
+        for _ in 1..BURST_COUNT {
+            scheduler.request();
+        }
+        assert!(!scheduler.in_burst());
+        assert_eq!(scheduler.reach(), 1);
+
+        scheduler.request();
+        assert!(!scheduler.in_burst());
+    }
+
+    #[test]
+    fn burst_is_sent_while_the_server_is_reachable() {
+        let mut scheduler = scheduler(true, false);
+
+        scheduler.request();
+        assert!(!scheduler.in_burst());
+        scheduler.reply();
+        scheduler.request();
+        assert!(scheduler.in_burst());
+    }
+
+    #[test]
+    fn silent_server_is_polled_less_often() {
+        let mut scheduler = scheduler(false, true);
+
+        for _ in 0..NTP_UNREACH {
+            scheduler.request();
+            while scheduler.in_burst() {
+                scheduler.request();
+            }
+        }
+        assert_eq!(scheduler.poll(), Poll::from(6));
+
+        scheduler.request();
+        assert!(!scheduler.in_burst());
+        assert_eq!(scheduler.poll(), Poll::from(7));
+        for _ in 0..10 {
+            scheduler.request();
+        }
+        assert_eq!(scheduler.poll(), Poll::from(10));
+    }
+
+    #[test]
+    fn poll_adapts_with_hysteresis() {
+        let mut scheduler = scheduler(false, false);
+
+        for _ in 0..5 {
+            scheduler.adapt(0.0001, 0.001);
+        }
+        assert_eq!(scheduler.poll(), Poll::from(6));
+        scheduler.adapt(0.0001, 0.001);
+        assert_eq!(scheduler.poll(), Poll::from(7));
+
+        for _ in 0..2 {
+            scheduler.adapt(0.01, 0.001);
+        }
+        assert_eq!(scheduler.poll(), Poll::from(7));
+        scheduler.adapt(0.01, 0.001);
+        assert_eq!(scheduler.poll(), Poll::from(6));
+        for _ in 0..10 {
+            scheduler.adapt(0.01, 0.001);
+        }
+        assert_eq!(scheduler.poll(), Poll::from(6));
+    }
+
+    #[test]
+    fn interval_is_spread() {
+        let scheduler = scheduler(false, false);
+
+        assert_eq!(scheduler.interval(0.0), Duration::from_secs(64));
+        assert_eq!(scheduler.interval(0.5), Duration::from_secs(66));
+    }
+}
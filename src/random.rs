@@ -0,0 +1,43 @@
+//! Pseudo-random numbers to spread polls over time, and for simulations
+
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+};
+
+/// Xorshift generator, so that runs repeat with the same seed
+#[derive(Debug, Clone)]
+pub(crate) struct Random(u64);
+
+impl Random {
+    pub(crate) fn new(seed: u64) -> Self {
+        // Xorshift never leaves zero
+        Self(seed.max(1))
+    }
+
+    /// Seeded from the keys the standard library draws for hash maps
+    pub(crate) fn from_entropy() -> Self {
+        Self::new(RandomState::new().build_hasher().finish())
+    }
+
+    /// Uniformly distributed in [0, 1)
+    pub(crate) fn uniform(&mut self) -> f64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// Normally distributed with mean 0 and standard deviation 1
+    #[cfg(feature = "testing")]
+    pub(crate) fn normal(&mut self) -> f64 {
+        let radius = (-2.0 * (1.0 - self.uniform()).ln()).sqrt();
+        radius * (2.0 * std::f64::consts::PI * self.uniform()).cos()
+    }
+
+    /// Exponentially distributed with the mean
+    #[cfg(feature = "testing")]
+    pub(crate) fn exponential(&mut self, mean: f64) -> f64 {
+        -mean * (1.0 - self.uniform()).ln()
+    }
+}
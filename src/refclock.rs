@@ -0,0 +1,66 @@
+//! Reference clocks: local sources of time, such as GPS receivers, that make
+//! this host a primary (stratum 1) server. Their samples go through the same
+//! filter and selection as those of network servers. As in ntpd, each has a
+//! pseudo-address `127.127.t.u`, with the driver type `t` and unit `u`.
+
+use std::net::SocketAddr;
+
+use crate::{
+    error::NtpResult,
+    types::{Leap, NtpTimestamp, Precision, RefId},
+};
+
+pub mod nmea;
+pub mod pps;
+#[cfg(target_os = "linux")]
+pub mod shm;
+
+/// ntpd's driver type of NMEA receivers
+pub const NMEA_DRIVER: u8 = 20;
+
+/// ntpd's driver type of PPS signals
+pub const PPS_DRIVER: u8 = 22;
+
+/// ntpd's driver type of shared memory segments
+pub const SHM_DRIVER: u8 = 28;
+
+/// One comparison of the reference with the local clock
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RefClockSample {
+    /// Offset of the reference relative to the local clock, in seconds
+    pub offset: f64,
+    /// Local time of the comparison
+    pub time: NtpTimestamp,
+    /// Leap second warning of the reference, `Leap::Unknown` while it has no
+    /// fix
+    pub leap: Leap,
+    /// Maximum error of the reference itself, in seconds
+    pub dispersion: f64,
+}
+
+/// Driver of a reference clock. Like the servers of stratum 0 in the protocol,
+/// a reference clock is not synchronized to anything else, and the host
+/// synchronized to it advertises stratum 1 and the reference identifier of
+/// the driver.
+pub trait RefClock: Send {
+    /// Four characters naming the kind of reference, such as `GPS` or `PPS`
+    fn refid(&self) -> RefId;
+
+    /// Resolution of the samples, as a power of two in seconds
+    fn precision(&self) -> Precision;
+
+    /// Latest sample, or `None` when there is none since the last call
+    fn sample(&mut self) -> NtpResult<Option<RefClockSample>>;
+
+    /// Whether the samples only tell where in the second the local clock is,
+    /// as those of a pulse per second do, so that another source has to name
+    /// the second
+    fn pulse_only(&self) -> bool {
+        false
+    }
+}
+
+/// ntpd's pseudo-address of unit `unit` of driver type `driver`
+pub fn refclock_address(driver: u8, unit: u8) -> SocketAddr {
+    SocketAddr::from(([127, 127, driver, unit], 0))
+}
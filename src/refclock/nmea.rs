@@ -0,0 +1,290 @@
+//! NMEA 0183 receivers, such as GPS receivers on a serial port, timed by the
+//! arrival of their RMC and ZDA sentences
+
+use std::{
+    fs::File,
+    io::{self, BufRead, BufReader},
+    ops::RangeInclusive,
+    path::Path,
+    sync::{Arc, Mutex},
+    thread,
+};
+
+use log::warn;
+
+use crate::{
+    clock::system_time,
+    error::NtpResult,
+    leap::days_from_civil,
+    logging,
+    types::{Leap, NtpTimestamp, Precision, RefId},
+};
+
+use super::{RefClock, RefClockSample};
+
+/// Resolution of the arrival of the sentences, about two milliseconds
+const NMEA_PRECISION: i8 = -9;
+
+const SECONDS_PER_DAY: i64 = 86_400;
+
+/// Years an RMC sentence can name, which ZDA sentences are held to as well
+const NMEA_YEARS: RangeInclusive<i64> = 2000..=2099;
+
+/// Receiver sending a sentence with the time every second. The sentences
+/// arrive some time after the second they name begins, which the `offset`
+/// given to the driver corrects.
+pub struct NmeaRefClock {
+    shared: Arc<Mutex<Shared>>,
+}
+
+/// State the reader thread hands to the driver
+#[derive(Default)]
+struct Shared {
+    latest: Option<RefClockSample>,
+    /// Why the reader stopped, which the samples report from then on
+    stopped: Option<io::ErrorKind>,
+}
+
+impl NmeaRefClock {
+    /// Reads the sentences from the device at `path`, whose speed is
+    /// already set to the receiver's
+    pub fn open(path: impl AsRef<Path>, offset: f64) -> NtpResult<Self> {
+        let device = File::open(path)?;
+        Ok(Self::new(BufReader::new(device), offset))
+    }
+
+    /// Reads the sentences from `reader` on a thread of its own, which stops
+    /// at the end of the input, on a read error, or after the driver is
+    /// dropped. `offset`, in seconds, is added to the samples.
+    pub fn new(reader: impl BufRead + Send + 'static, offset: f64) -> Self {
+        let shared = Arc::new(Mutex::new(Shared::default()));
+        let weak = Arc::downgrade(&shared);
+        thread::spawn(move || {
+            let mut parser = NmeaParser::default();
+            let mut lines = reader.lines();
+            let stopped = loop {
+                let line = lines.next();
+                let received = system_time();
+                let Some(shared) = weak.upgrade() else {
+                    return;
+                };
+                let line = match line {
+                    Some(Ok(line)) => line,
+                    Some(Err(error)) => break error.kind(),
+                    None => break io::ErrorKind::UnexpectedEof,
+                };
+                if let Some(time) = parser.parse(&line) {
+                    shared.lock().unwrap().latest = Some(RefClockSample {
+                        offset: time.diff_seconds(&received) + offset,
+                        time: received,
+                        leap: Leap::NoWarning,
+                        dispersion: 0.0,
+                    });
+                }
+            };
+            warn!(target: logging::ASSOCIATION, error:% = stopped; "NMEA receiver stopped");
+            if let Some(shared) = weak.upgrade() {
+                shared.lock().unwrap().stopped = Some(stopped);
+            }
+        });
+        Self { shared }
+    }
+}
+
+impl RefClock for NmeaRefClock {
+    fn refid(&self) -> RefId {
+        RefId::from(*b"GPS\0")
+    }
+
+    fn precision(&self) -> Precision {
+        Precision::from(NMEA_PRECISION)
+    }
+
+    fn sample(&mut self) -> NtpResult<Option<RefClockSample>> {
+        let mut shared = self.shared.lock().unwrap();
+        match (shared.latest.take(), shared.stopped) {
+            (Some(sample), _) => Ok(Some(sample)),
+            (None, Some(stopped)) => Err(io::Error::from(stopped).into()),
+            (None, None) => Ok(None),
+        }
+    }
+}
+
+/// Reads the time from the sentences of any talker. ZDA sentences say
+/// nothing of the fix, so they are only trusted while the latest RMC sentence
+/// reports a valid one.
+#[derive(Default)]
+struct NmeaParser {
+    fix: bool,
+}
+
+impl NmeaParser {
+    /// UTC time named by `line`, if it is a sentence to trust
+    fn parse(&mut self, line: &str) -> Option<NtpTimestamp> {
+        let (body, checksum) = line.trim().strip_prefix('$')?.split_once('*')?;
+        let checksum = u8::from_str_radix(checksum, 16).ok()?;
+        if body.bytes().fold(0, |sum, byte| sum ^ byte) != checksum {
+            return None;
+        }
+        let fields = body.split(',').collect::<Vec<_>>();
+        match fields[0].get(2..)? {
+            "RMC" => {
+                self.fix = fields.get(2) == Some(&"A");
+                if !self.fix {
+                    return None;
+                }
+                let date = fields.get(9)?;
+                let year: i64 = date.get(4..6)?.parse().ok()?;
+                timestamp(
+                    fields[1],
+                    date.get(0..2)?.parse().ok()?,
+                    date.get(2..4)?.parse().ok()?,
+                    2000 + year,
+                )
+            }
+            "ZDA" if self.fix => timestamp(
+                fields.get(1)?,
+                fields.get(2)?.parse().ok()?,
+                fields.get(3)?.parse().ok()?,
+                fields.get(4)?.parse().ok()?,
+            ),
+            _ => None,
+        }
+    }
+}
+
+/// Time of day `time`, as `hhmmss.ss`, of a date, if every field is in range
+fn timestamp(time: &str, day: u32, month: u32, year: i64) -> Option<NtpTimestamp> {
+    let hours: u32 = time.get(0..2)?.parse().ok()?;
+    let minutes: u32 = time.get(2..4)?.parse().ok()?;
+    let seconds: f64 = time.get(4..)?.parse().ok()?;
+    // Up to 60.999 in a leap second; the range also keeps out NaN and
+    // infinities
+    if hours >= 24
+        || minutes >= 60
+        || !(0.0..61.0).contains(&seconds)
+        || !(1..=12).contains(&month)
+        || !(1..=31).contains(&day)
+        || !NMEA_YEARS.contains(&year)
+    {
+        return None;
+    }
+    let time_of_day = i64::from(hours * 3600 + minutes * 60) + seconds.trunc() as i64;
+    let unix_seconds = days_from_civil(year, month, day)?
+        .checked_mul(SECONDS_PER_DAY)?
+        .checked_add(time_of_day)?;
+    Some(NtpTimestamp::from_unix(
+        unix_seconds,
+        (seconds.fract() * 1e9) as u32,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Cursor, time::Duration};
+
+    use crate::error::NtpError;
+
+    use super::*;
+
+    /// Valid fix, at the time `ZDA` names
+    const FIX: &str = "$GPRMC,235959.25,A,,,,,,,311225,,,A*65";
+
+    /// Fix lost
+    const NO_FIX: &str = "$GPRMC,235959.00,V,,,,,,,311225,,,N*7A";
+
+    const ZDA: &str = "$GPZDA,235959.25,31,12,2025,00,00*64";
+
+    #[test]
+    fn rmc_and_zda_sentences_are_parsed() {
+        let mut parser = NmeaParser::default();
+
+        assert_eq!(
+            parser.parse(
+                "$GNRMC,120000.50,A,4717.11437,N,00833.91522,E,0.004,77.52,180326,,,A*4B\r\n"
+            ),
+            Some(NtpTimestamp::from_unix(1_773_835_200, 500_000_000))
+        );
+        assert_eq!(
+            parser.parse(ZDA),
+            Some(NtpTimestamp::from_unix(1_767_225_599, 250_000_000))
+        );
+        assert_eq!(
+            parser.parse("$GPZDA,235960.50,31,12,2016,00,00*6C"),
+            Some(NtpTimestamp::from_unix(1_483_228_800, 500_000_000))
+        );
+        // A wrong checksum, and another sentence
+        assert_eq!(parser.parse("$GPZDA,235959.25,31,12,2025,00,00*65"), None);
+        assert_eq!(parser.parse("$GPGSV,1,1,00*79"), None);
+    }
+
+    #[test]
+    fn zda_sentences_need_a_fix() {
+        let mut parser = NmeaParser::default();
+
+        assert_eq!(parser.parse(ZDA), None);
+        assert!(parser.parse(FIX).is_some());
+        assert!(parser.parse(ZDA).is_some());
+        assert_eq!(parser.parse(NO_FIX), None);
+        assert_eq!(parser.parse(ZDA), None);
+    }
+
+    #[test]
+    fn fields_out_of_range_are_rejected() {
+        let mut parser = NmeaParser::default();
+        parser.parse(FIX).unwrap();
+
+        for sentence in [
+            "$GPZDA,245959.25,31,12,2025,00,00*63",
+            "$GPZDA,236059.25,31,12,2025,00,00*6E",
+            "$GPZDA,235961.00,31,12,2025,00,00*68",
+            "$GPZDA,-10000.00,31,12,2025,00,00*7E",
+            "$GPZDA,2359NaN,31,12,2025,00,00*20",
+            "$GPZDA,2359inf,31,12,2025,00,00*20",
+            "$GPZDA,235959.25,32,12,2025,00,00*67",
+            "$GPZDA,235959.25,31,13,2025,00,00*65",
+            "$GPZDA,235959.25,31,0,2025,00,00*57",
+            "$GPZDA,235959.25,31,12,99999999999999999,00,00*58",
+        ] {
+            assert_eq!(parser.parse(sentence), None, "{sentence}");
+        }
+    }
+
+    #[test]
+    fn latest_sentence_gives_the_sample() {
+        let input = format!("{FIX}\r\n{ZDA}\r\n$GPGSV,1,1,00*79\r\n");
+        let mut nmea = NmeaRefClock::new(Cursor::new(input), 0.1);
+
+        let mut sample = None;
+        for _ in 0..100 {
+            sample = nmea.sample().unwrap();
+            if sample.is_some() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        let sample = sample.unwrap();
+        let expected = NtpTimestamp::from_unix(1_767_225_599, 350_000_000);
+        assert!((sample.offset - expected.diff_seconds(&sample.time)).abs() < 1e-6);
+    }
+
+    #[test]
+    fn end_of_input_is_reported() {
+        let mut nmea = NmeaRefClock::new(Cursor::new(""), 0.0);
+
+        let mut result = Ok(None);
+        for _ in 0..100 {
+            result = nmea.sample();
+            if result.is_err() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        assert_eq!(
+            result.unwrap_err(),
+            NtpError::Io(io::ErrorKind::UnexpectedEof)
+        );
+    }
+}
@@ -0,0 +1,115 @@
+//! Pulse per second signals, as captured by the Linux PPS subsystem and shown
+//! in its sysfs files, such as `/sys/class/pps/pps0/assert`
+
+use std::{fs, io, path::PathBuf};
+
+use crate::{
+    error::{NtpError, NtpResult},
+    types::{Leap, NtpTimestamp, Precision, RefId},
+};
+
+use super::{RefClock, RefClockSample};
+
+/// Resolution of the pulse timestamps, about a microsecond
+const PPS_PRECISION: i8 = -20;
+
+/// Signal whose pulses mark the start of each second. It only tells where in
+/// the second the local clock is, so the clock has to be set to the right
+/// second by another source.
+pub struct PpsRefClock {
+    path: PathBuf,
+    /// Sequence number of the last pulse used
+    sequence: u64,
+}
+
+impl PpsRefClock {
+    /// Reads the pulses from the sysfs file at `path`, such as
+    /// `/sys/class/pps/pps0/assert`
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            sequence: 0,
+        }
+    }
+}
+
+impl RefClock for PpsRefClock {
+    fn refid(&self) -> RefId {
+        RefId::from(*b"PPS\0")
+    }
+
+    fn precision(&self) -> Precision {
+        Precision::from(PPS_PRECISION)
+    }
+
+    fn pulse_only(&self) -> bool {
+        true
+    }
+
+    fn sample(&mut self) -> NtpResult<Option<RefClockSample>> {
+        let contents = fs::read_to_string(&self.path)?;
+        let (time, sequence) =
+            parse_pulse(&contents).ok_or(NtpError::Io(io::ErrorKind::InvalidData))?;
+        // The sequence starts at 1 with the first pulse
+        if sequence == self.sequence {
+            return Ok(None);
+        }
+        self.sequence = sequence;
+        let (_, nanos) = time.to_unix();
+        let fraction = f64::from(nanos) / 1e9;
+        let offset = if fraction < 0.5 {
+            -fraction
+        } else {
+            1.0 - fraction
+        };
+        Ok(Some(RefClockSample {
+            offset,
+            time,
+            leap: Leap::NoWarning,
+            dispersion: 0.0,
+        }))
+    }
+}
+
+/// Local time and sequence number of a pulse, written as
+/// `1700000000.123456789#42`
+fn parse_pulse(contents: &str) -> Option<(NtpTimestamp, u64)> {
+    let (time, sequence) = contents.trim().split_once('#')?;
+    let (seconds, nanos) = time.split_once('.')?;
+    let nanos = nanos.parse().ok().filter(|&nanos| nanos < 1_000_000_000)?;
+    Some((
+        NtpTimestamp::from_unix(seconds.parse().ok()?, nanos),
+        sequence.parse().ok()?,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pulses_give_the_offset_within_the_second() {
+        let path = std::env::temp_dir().join(format!("demo_ntp-pps-{}", std::process::id()));
+        let mut pps = PpsRefClock::new(&path);
+
+        fs::write(&path, "0.000000000#0\n").unwrap();
+        assert_eq!(pps.sample().unwrap(), None);
+
+        fs::write(&path, "1700000000.999750000#1\n").unwrap();
+        let sample = pps.sample().unwrap().unwrap();
+        assert!((sample.offset - 0.00025).abs() < 1e-8);
+        assert_eq!(
+            sample.time,
+            NtpTimestamp::from_unix(1_700_000_000, 999_750_000)
+        );
+        assert_eq!(pps.sample().unwrap(), None);
+
+        fs::write(&path, "1700000001.000100000#2\n").unwrap();
+        let sample = pps.sample().unwrap().unwrap();
+        assert!((sample.offset + 0.0001).abs() < 1e-8);
+
+        fs::write(&path, "garbage\n").unwrap();
+        assert!(pps.sample().is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,204 @@
+//! Shared memory segments in ntpd's SHM format, which gpsd and other time
+//! sources write their samples to
+
+use std::{io, mem, ptr::NonNull};
+
+use crate::{
+    error::NtpResult,
+    types::{Leap, NtpTimestamp, Precision, RefId},
+};
+
+use super::{RefClock, RefClockSample};
+
+/// Key of the segment of unit 0, "NTP0"
+const SHM_KEY: libc::key_t = 0x4e54_5030;
+
+/// Layout of a segment, `struct shmTime` in ntpd
+#[repr(C)]
+#[derive(Debug, Default)]
+struct ShmTime {
+    /// 1 when the writer increments `count` around its writes
+    mode: libc::c_int,
+    count: libc::c_int,
+    /// Time of the reference
+    clock_seconds: libc::time_t,
+    clock_micros: libc::c_int,
+    /// Local time at which the reference was read
+    receive_seconds: libc::time_t,
+    receive_micros: libc::c_int,
+    leap: libc::c_int,
+    precision: libc::c_int,
+    samples: libc::c_int,
+    /// Set by the writer, cleared by the reader
+    valid: libc::c_int,
+    clock_nanos: libc::c_uint,
+    receive_nanos: libc::c_uint,
+    reserved: [libc::c_int; 8],
+}
+
+/// Segment of one unit, attached for the lifetime of the driver
+pub struct ShmRefClock {
+    segment: NonNull<ShmTime>,
+    precision: Precision,
+}
+
+// SAFETY: the mapping of the segment is valid in every thread of the process,
+// and it stays attached until the driver is dropped. Other processes write to
+// the segment at any time, from whichever thread it is read: `take` only reads
+// it with volatile reads, and drops copies torn by a concurrent write. Each
+// driver attaches a mapping of its own and only reads it through `&mut self`,
+// so no other thread of this process reads it at the same time.
+unsafe impl Send for ShmRefClock {}
+
+impl ShmRefClock {
+    /// Attaches the segment of `unit`, creating it if needed. As in ntpd, the
+    /// segments of units 0 and 1 can only be written by root.
+    pub fn open(unit: u8) -> NtpResult<Self> {
+        let permissions = if unit < 2 { 0o600 } else { 0o666 };
+        // SAFETY: `shmget` takes no pointers
+        let id = unsafe {
+            libc::shmget(
+                SHM_KEY + libc::key_t::from(unit),
+                mem::size_of::<ShmTime>(),
+                libc::IPC_CREAT | permissions,
+            )
+        };
+        if id < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        // SAFETY: with a null address, the kernel maps the segment where
+        // nothing else is mapped
+        let address = unsafe { libc::shmat(id, std::ptr::null(), 0) };
+        if address as isize == -1 {
+            return Err(io::Error::last_os_error().into());
+        }
+        Ok(Self {
+            segment: NonNull::new(address.cast()).expect("attached segments are not null"),
+            precision: Precision::from(0),
+        })
+    }
+}
+
+impl Drop for ShmRefClock {
+    fn drop(&mut self) {
+        // SAFETY: `segment` was attached by `shmat` in `open`, and no copy of
+        // the pointer outlives the driver
+        unsafe { libc::shmdt(self.segment.as_ptr().cast()) };
+    }
+}
+
+impl RefClock for ShmRefClock {
+    fn refid(&self) -> RefId {
+        RefId::from(*b"SHM\0")
+    }
+
+    fn precision(&self) -> Precision {
+        self.precision
+    }
+
+    fn sample(&mut self) -> NtpResult<Option<RefClockSample>> {
+        // SAFETY: the segment stays attached until the driver is dropped
+        let Some(shm) = (unsafe { take(self.segment) }) else {
+            return Ok(None);
+        };
+        self.precision = precision(&shm);
+        Ok(Some(sample(&shm)))
+    }
+}
+
+/// Copies the segment if the writer marked it valid, and marks it read. In
+/// mode 1, copies taken while the writer was at work are dropped.
+///
+/// # Safety
+///
+/// `segment` must point to a segment that stays attached during the call.
+unsafe fn take(segment: NonNull<ShmTime>) -> Option<ShmTime> {
+    let segment = segment.as_ptr();
+    if std::ptr::addr_of!((*segment).valid).read_volatile() == 0 {
+        return None;
+    }
+    let count = std::ptr::addr_of!((*segment).count).read_volatile();
+    let shm = segment.read_volatile();
+    let unchanged = std::ptr::addr_of!((*segment).count).read_volatile() == count;
+    std::ptr::addr_of_mut!((*segment).valid).write_volatile(0);
+    (shm.mode != 1 || unchanged).then_some(shm)
+}
+
+/// Precision announced by the writer, clamped to what a packet can carry
+fn precision(shm: &ShmTime) -> Precision {
+    Precision::from(shm.precision.clamp(i8::MIN.into(), i8::MAX.into()) as i8)
+}
+
+fn sample(shm: &ShmTime) -> RefClockSample {
+    let clock = timestamp(shm.clock_seconds, shm.clock_micros, shm.clock_nanos);
+    let receive = timestamp(shm.receive_seconds, shm.receive_micros, shm.receive_nanos);
+    RefClockSample {
+        offset: clock.diff_seconds(&receive),
+        time: receive,
+        leap: u8::try_from(shm.leap)
+            .ok()
+            .and_then(|leap| Leap::try_from(leap).ok())
+            .unwrap_or(Leap::Unknown),
+        dispersion: 0.0,
+    }
+}
+
+/// Time from its seconds and microseconds, with the nanoseconds when writers
+/// that only know the microseconds left them consistent
+fn timestamp(seconds: libc::time_t, micros: libc::c_int, nanos: libc::c_uint) -> NtpTimestamp {
+    let nanos = if i64::from(nanos / 1000) == i64::from(micros) {
+        nanos
+    } else {
+        micros as u32 * 1000
+    };
+    NtpTimestamp::from_unix(seconds, nanos)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn valid_segment_is_read_once() {
+        let mut shm = Box::new(ShmTime {
+            mode: 1,
+            count: 7,
+            clock_seconds: 1_700_000_000,
+            clock_micros: 1,
+            receive_seconds: 1_700_000_000,
+            receive_micros: 250_001,
+            leap: 1,
+            precision: -10,
+            valid: 1,
+            clock_nanos: 1_500,
+            // Left over by a writer that only sets the microseconds
+            receive_nanos: 42,
+            ..ShmTime::default()
+        });
+        let segment = NonNull::from(shm.as_mut());
+
+        // SAFETY: `shm` outlives both calls
+        let copy = unsafe { take(segment) }.unwrap();
+        let sample = sample(&copy);
+
+        assert!((sample.offset + 0.2499995).abs() < 1e-9);
+        assert_eq!(
+            sample.time,
+            NtpTimestamp::from_unix(1_700_000_000, 250_001_000)
+        );
+        assert_eq!(sample.leap, Leap::LastMinuteHas61Seconds);
+        assert_eq!(precision(&copy), Precision::from(-10));
+        assert_eq!(unsafe { take(segment) }.map(|shm| shm.count), None);
+    }
+
+    #[test]
+    fn precision_is_clamped() {
+        let shm = |precision| ShmTime {
+            precision,
+            ..ShmTime::default()
+        };
+
+        assert_eq!(precision(&shm(-300)), Precision::from(i8::MIN));
+        assert_eq!(precision(&shm(200)), Precision::from(i8::MAX));
+    }
+}
@@ -0,0 +1,213 @@
+/// Server taking part in the selection
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Candidate {
+    /// Offset of the server clock relative to the local clock, in seconds
+    pub offset: f64,
+    /// Maximum error of the offset, in seconds
+    pub root_distance: f64,
+    /// Jitter of the offset, in seconds
+    pub jitter: f64,
+}
+
+/// Outcome of the selection
+#[derive(Debug, Clone, PartialEq)]
+pub struct Selection {
+    /// Indices of the candidates that agree with each other
+    pub survivors: Vec<usize>,
+    /// Index of the survivor with the lowest root distance
+    pub system_peer: usize,
+    /// Combined offset of the survivors, in seconds
+    pub offset: f64,
+    /// Combined jitter of the survivors, in seconds
+    pub jitter: f64,
+}
+
+/// Fewest survivors the clustering keeps
+const MIN_CLUSTER: usize = 3;
+
+/// Selects the servers whose correctness intervals intersect, using the
+/// intersection algorithm of RFC 5905, prunes outliers and combines the rest.
+/// Returns `None` when no majority of the candidates agrees.
+pub fn select(candidates: &[Candidate]) -> Option<Selection> {
+    let count = candidates.len();
+    if count == 0 {
+        return None;
+    }
+
+    // Edges of the correctness intervals: -1 opens, 0 is a midpoint, +1 closes
+    let mut edges: Vec<(f64, i64)> = candidates
+        .iter()
+        .flat_map(|candidate| {
+            [
+                (candidate.offset - candidate.root_distance, -1),
+                (candidate.offset, 0),
+                (candidate.offset + candidate.root_distance, 1),
+            ]
+        })
+        .collect();
+    edges.sort_by(|a, b| a.0.total_cmp(&b.0).then(a.1.cmp(&b.1)));
+
+    let mut interval = None;
+    for falsetickers in 0..count.div_ceil(2) {
+        let required = (count - falsetickers) as i64;
+        let (mut found, mut midpoints) = (0i64, 0);
+        let mut low = None;
+        for &(edge, kind) in &edges {
+            found -= kind;
+            if found >= required {
+                low = Some(edge);
+                break;
+            }
+            if kind == 0 {
+                midpoints += 1;
+            }
+        }
+        found = 0;
+        let mut high = None;
+        for &(edge, kind) in edges.iter().rev() {
+            found += kind;
+            if found >= required {
+                high = Some(edge);
+                break;
+            }
+            if kind == 0 {
+                midpoints += 1;
+            }
+        }
+        if let (Some(low), Some(high)) = (low, high) {
+            if midpoints <= falsetickers && low <= high {
+                interval = Some((low, high));
+                break;
+            }
+        }
+    }
+    let (low, high) = interval?;
+
+    let mut survivors = (0..count)
+        .filter(|&index| (low..=high).contains(&candidates[index].offset))
+        .collect::<Vec<_>>();
+    if survivors.is_empty() {
+        return None;
+    }
+
+    // Drop the survivor contributing the most jitter while that is worse than
+    // the jitter of the best individual server
+    while survivors.len() > MIN_CLUSTER {
+        let (worst, selection_jitter) = survivors
+            .iter()
+            .map(|&index| (index, selection_jitter(candidates, &survivors, index)))
+            .max_by(|a, b| a.1.total_cmp(&b.1))
+            .expect("survivors are not empty");
+        let best_jitter = survivors
+            .iter()
+            .map(|&index| candidates[index].jitter)
+            .fold(f64::INFINITY, f64::min);
+        if selection_jitter <= best_jitter {
+            break;
+        }
+        survivors.retain(|&index| index != worst);
+    }
+
+    let system_peer = *survivors
+        .iter()
+        .min_by(|&&a, &&b| {
+            candidates[a]
+                .root_distance
+                .total_cmp(&candidates[b].root_distance)
+        })
+        .expect("survivors are not empty");
+
+    // Weigh each survivor by the inverse of its root distance
+    let weight = |index: usize| 1.0 / candidates[index].root_distance.max(f64::EPSILON);
+    let total_weight: f64 = survivors.iter().map(|&index| weight(index)).sum();
+    let offset = survivors
+        .iter()
+        .map(|&index| weight(index) * candidates[index].offset)
+        .sum::<f64>()
+        / total_weight;
+    let jitter = (survivors
+        .iter()
+        .map(|&index| weight(index) * (candidates[index].offset - offset).powi(2))
+        .sum::<f64>()
+        / total_weight)
+        .sqrt();
+
+    Some(Selection {
+        survivors,
+        system_peer,
+        offset,
+        jitter,
+    })
+}
+
+/// RMS distance of one survivor's offset to the others
+fn selection_jitter(candidates: &[Candidate], survivors: &[usize], index: usize) -> f64 {
+    let offset = candidates[index].offset;
+    let squares: f64 = survivors
+        .iter()
+        .map(|&other| (candidates[other].offset - offset).powi(2))
+        .sum();
+    (squares / (survivors.len() - 1) as f64).sqrt()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn candidate(offset: f64, root_distance: f64) -> Candidate {
+        Candidate {
+            offset,
+            root_distance,
+            jitter: 0.001,
+        }
+    }
+
+    #[test]
+    fn falseticker_is_excluded() {
+        let candidates = [
+            candidate(0.010, 0.020),
+            candidate(0.012, 0.010),
+            candidate(0.008, 0.030),
+            candidate(5.000, 0.010),
+        ];
+
+        let selection = select(&candidates).unwrap();
+
+        assert_eq!(selection.survivors, vec![0, 1, 2]);
+        assert_eq!(selection.system_peer, 1);
+        assert!((selection.offset - 0.0107).abs() < 0.001);
+    }
+
+    #[test]
+    fn no_majority_means_no_selection() {
+        let candidates = [candidate(0.0, 0.010), candidate(1.0, 0.010)];
+
+        assert_eq!(select(&candidates), None);
+        assert_eq!(select(&[]), None);
+    }
+
+    #[test]
+    fn single_server_is_selected() {
+        let selection = select(&[candidate(-0.25, 0.05)]).unwrap();
+
+        assert_eq!(selection.survivors, vec![0]);
+        assert_eq!(selection.offset, -0.25);
+        assert_eq!(selection.jitter, 0.0);
+    }
+
+    #[test]
+    fn outlier_is_clustered_away() {
+        let candidates = [
+            candidate(0.000, 0.5),
+            candidate(0.001, 0.5),
+            candidate(0.002, 0.5),
+            candidate(0.001, 0.5),
+            candidate(0.300, 0.5),
+        ];
+
+        let selection = select(&candidates).unwrap();
+
+        assert!(!selection.survivors.contains(&4));
+        assert!(selection.offset.abs() < 0.002);
+    }
+}
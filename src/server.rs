@@ -0,0 +1,798 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::{IpAddr, UdpSocket},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use log::{debug, trace};
+
+use crate::{
+    auth::{mac_key_id, SymmetricKey},
+    clock::{Clock, ClockState},
+    codec::{TryReadFromBytes, TryWriteToBytes},
+    error::NtpResult,
+    leap::{LeapEvent, LeapSeconds, LeapSmear},
+    logging,
+    ntp_message_protocol::NtpPacketHeader,
+    types::{
+        Leap, Mode, NtpShort, NtpTimestamp, Precision, RefId, Stratum, NTP_KISS_DENY,
+        NTP_KISS_INIT, NTP_KISS_RATE,
+    },
+};
+
+/// System variables a server advertises in its replies
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ServerState {
+    pub leap: Leap,
+    pub stratum: Stratum,
+    pub precision: Precision,
+    pub rootdelay: NtpShort,
+    pub rootdisp: NtpShort,
+    pub refid: RefId,
+    pub reftime: NtpTimestamp,
+}
+
+impl Default for ServerState {
+    /// State of a server that has not synchronized yet
+    fn default() -> Self {
+        Self {
+            leap: Leap::Unknown,
+            stratum: Stratum::from(0),
+            precision: Precision::from(0),
+            rootdelay: NtpShort::new(0, 0),
+            rootdisp: NtpShort::new(0, 0),
+            refid: NTP_KISS_INIT,
+            reftime: NtpTimestamp::new(0, 0),
+        }
+    }
+}
+
+/// Shortest interval between two requests of a rate-limited client, in seconds
+pub const NTP_RATE_MIN_INTERVAL: f64 = 2.0;
+
+/// Number of clients remembered for rate limiting before old ones are forgotten
+const NTP_RATE_CLIENTS: usize = 4096;
+
+/// Time of the last request of the most recently seen clients. Once full, the
+/// least recently seen client is forgotten, as in the MRU list of ntpd.
+#[derive(Debug, Default)]
+struct RecentClients {
+    /// Last request of each client, with its sequence number
+    clients: HashMap<IpAddr, (NtpTimestamp, u64)>,
+    /// Clients by the sequence number of their last request, oldest first
+    order: BTreeMap<u64, IpAddr>,
+    sequence: u64,
+}
+
+impl RecentClients {
+    /// Records a request of `client` at `now` and returns the time of its
+    /// previous one
+    fn insert(&mut self, client: IpAddr, now: NtpTimestamp) -> Option<NtpTimestamp> {
+        self.sequence += 1;
+        let previous = self.clients.insert(client, (now, self.sequence));
+        match previous {
+            Some((_, sequence)) => {
+                self.order.remove(&sequence);
+            }
+            None if self.clients.len() > NTP_RATE_CLIENTS => {
+                if let Some((_, oldest)) = self.order.pop_first() {
+                    self.clients.remove(&oldest);
+                }
+            }
+            None => {}
+        }
+        self.order.insert(self.sequence, client);
+        previous.map(|(last, _)| last)
+    }
+}
+
+/// Access control for the clients of one network
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Restriction {
+    pub network: IpAddr,
+    pub prefix_len: u8,
+    /// Drop every packet
+    pub ignore: bool,
+    /// Do not answer time requests
+    pub noserve: bool,
+    /// Limit clients to one request every `NTP_RATE_MIN_INTERVAL` seconds
+    pub limited: bool,
+    /// Answer refused requests with a kiss-o'-death instead of dropping them
+    pub kod: bool,
+}
+
+impl Restriction {
+    /// Restriction on the network without any flag set
+    pub fn new(network: IpAddr, prefix_len: u8) -> Self {
+        Self {
+            network,
+            prefix_len,
+            ignore: false,
+            noserve: false,
+            limited: false,
+            kod: false,
+        }
+    }
+
+    pub fn matches(&self, address: IpAddr) -> bool {
+        let prefix_len = u32::from(self.prefix_len);
+        match (self.network, address.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(address)) => {
+                let mask = u32::MAX.checked_shl(32 - prefix_len).unwrap_or(0);
+                u32::from(network) & mask == u32::from(address) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(address)) => {
+                let mask = u128::MAX.checked_shl(128 - prefix_len).unwrap_or(0);
+                u128::from(network) & mask == u128::from(address) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Restriction {
+    type Err = &'static str;
+
+    /// Parses a network such as `192.0.2.0/24`, or a single address
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (address, prefix_len) = match value.split_once('/') {
+            Some((address, prefix_len)) => (address, Some(prefix_len)),
+            None => (value, None),
+        };
+        let network = address
+            .parse::<IpAddr>()
+            .map_err(|_| "Invalid network address")?;
+        let max_prefix_len = if network.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len
+                .parse::<u8>()
+                .ok()
+                .filter(|prefix_len| *prefix_len <= max_prefix_len)
+                .ok_or("Invalid prefix length")?,
+            None => max_prefix_len,
+        };
+        Ok(Self::new(network, prefix_len))
+    }
+}
+
+/// Counters of the requests handled by servers, which can be shared between
+/// the servers of several sockets
+#[derive(Debug, Default)]
+pub struct ServerStats {
+    requests: AtomicU64,
+    responses: AtomicU64,
+    rate_limited: AtomicU64,
+    denied: AtomicU64,
+    unauthenticated: AtomicU64,
+}
+
+impl ServerStats {
+    /// Packets received
+    pub fn requests(&self) -> u64 {
+        self.requests.load(Ordering::Relaxed)
+    }
+
+    /// Replies sent, kiss-o'-death packets included
+    pub fn responses(&self) -> u64 {
+        self.responses.load(Ordering::Relaxed)
+    }
+
+    /// Requests of clients that exceeded the rate limit
+    pub fn rate_limited(&self) -> u64 {
+        self.rate_limited.load(Ordering::Relaxed)
+    }
+
+    /// Requests of clients that may not be served
+    pub fn denied(&self) -> u64 {
+        self.denied.load(Ordering::Relaxed)
+    }
+
+    /// Requests dropped because their MAC did not verify
+    pub fn unauthenticated(&self) -> u64 {
+        self.unauthenticated.load(Ordering::Relaxed)
+    }
+
+    fn count(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// What to do with a request, according to the restrictions
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Admission {
+    Serve,
+    Drop,
+    Kiss(RefId),
+}
+
+pub struct NtpServerBuilder<C> {
+    udp_socket: UdpSocket,
+    clock: C,
+    state: ServerState,
+    leap_seconds: Option<LeapSeconds>,
+    smear: Option<LeapSmear>,
+    keys: Vec<SymmetricKey>,
+    restrictions: Vec<Restriction>,
+    stats: Arc<ServerStats>,
+}
+
+impl<C: Clock> NtpServerBuilder<C> {
+    pub fn new(udp_socket: UdpSocket, clock: C) -> Self {
+        Self {
+            udp_socket,
+            clock,
+            state: ServerState::default(),
+            leap_seconds: None,
+            smear: None,
+            keys: Vec::new(),
+            restrictions: Vec::new(),
+            stats: Arc::default(),
+        }
+    }
+
+    pub fn state(mut self, state: ServerState) -> Self {
+        self.state = state;
+        self
+    }
+
+    /// Uses a leap second table to decide which leap indicator to advertise
+    pub fn leap_seconds(mut self, leap_seconds: LeapSeconds) -> Self {
+        self.leap_seconds = Some(leap_seconds);
+        self
+    }
+
+    /// Serves smeared time around leap seconds. Requires `leap_seconds` with a
+    /// table to know when the leap seconds happen.
+    pub fn smear(mut self, smear: LeapSmear) -> Self {
+        self.smear = Some(smear);
+        self
+    }
+
+    /// Keys clients may sign their requests with. A signed request is
+    /// answered with a reply signed with the same key.
+    pub fn keys(mut self, keys: Vec<SymmetricKey>) -> Self {
+        self.keys = keys;
+        self
+    }
+
+    /// Access control. The restriction with the longest matching prefix applies;
+    /// clients that match none are served.
+    pub fn restrictions(mut self, restrictions: Vec<Restriction>) -> Self {
+        self.restrictions = restrictions;
+        self
+    }
+
+    /// Counts the requests in `stats`, to share the counters with other servers
+    pub fn stats(mut self, stats: Arc<ServerStats>) -> Self {
+        self.stats = stats;
+        self
+    }
+
+    pub fn build(self) -> NtpResult<NtpServer<C>> {
+        Ok(NtpServer {
+            udp_socket: self.udp_socket,
+            clock: self.clock,
+            state: self.state,
+            leap_seconds: self.leap_seconds,
+            smear: self.smear,
+            keys: self.keys,
+            restrictions: self.restrictions,
+            stats: self.stats,
+            last_requests: RecentClients::default(),
+        })
+    }
+}
+
+pub struct NtpServer<C> {
+    udp_socket: UdpSocket,
+    clock: C,
+    state: ServerState,
+    leap_seconds: Option<LeapSeconds>,
+    smear: Option<LeapSmear>,
+    keys: Vec<SymmetricKey>,
+    restrictions: Vec<Restriction>,
+    stats: Arc<ServerStats>,
+    /// Time of the last request of each rate-limited client
+    last_requests: RecentClients,
+}
+
+impl<C: Clock> NtpServer<C> {
+    pub fn state(&self) -> &ServerState {
+        &self.state
+    }
+
+    pub fn set_state(&mut self, state: ServerState) {
+        self.state = state;
+    }
+
+    pub fn stats(&self) -> &Arc<ServerStats> {
+        &self.stats
+    }
+
+    /// Waits for one request and answers it. Requests that cannot be parsed,
+    /// that are not client requests, that fail authentication or that the
+    /// restrictions refuse are dropped.
+    pub fn serve_one(&mut self) -> NtpResult<()> {
+        let mut buffer = [0u8; 1024];
+        let (size, peer) = self.udp_socket.recv_from(&mut buffer)?;
+        let rec = self.clock.now()?;
+        ServerStats::count(&self.stats.requests);
+
+        trace!(target: logging::SERVER, client:% = peer; "received request");
+
+        let admission = self.admit(peer.ip(), rec);
+        if admission == Admission::Drop {
+            debug!(
+                target: logging::SERVER,
+                client:% = peer,
+                reason = "restricted";
+                "dropped request"
+            );
+            return Ok(());
+        }
+        let (request, header_size) = match NtpPacketHeader::try_read_from_bytes(&buffer[..size]) {
+            Ok(request) => request,
+            Err(error) => {
+                debug!(
+                    target: logging::SERVER,
+                    client:% = peer,
+                    reason = "malformed",
+                    error:% = error;
+                    "dropped request"
+                );
+                return Ok(());
+            }
+        };
+        let (header, mac) = buffer[..size].split_at(header_size);
+        let key = match mac_key_id(mac) {
+            Some(id) => {
+                let key = self.keys.iter().find(|key| key.id == id);
+                match key.filter(|key| key.verify(header, mac)) {
+                    Some(key) => Some(key.clone()),
+                    None => {
+                        ServerStats::count(&self.stats.unauthenticated);
+                        debug!(
+                            target: logging::SERVER,
+                            client:% = peer,
+                            reason = "bad MAC";
+                            "dropped request"
+                        );
+                        return Ok(());
+                    }
+                }
+            }
+            None => None,
+        };
+
+        let Some(mut reply) = self.reply(&request, rec, self.clock.now()?) else {
+            debug!(
+                target: logging::SERVER,
+                client:% = peer,
+                reason = "not a client request";
+                "dropped request"
+            );
+            return Ok(());
+        };
+        if let Admission::Kiss(code) = admission {
+            debug!(
+                target: logging::SERVER,
+                client:% = peer,
+                code:% = code.kind(Stratum::from(0), peer.ip());
+                "sent kiss-o'-death"
+            );
+            reply.leap_indicator = Leap::Unknown;
+            reply.stratum = Stratum::from(0);
+            reply.refid = code;
+        }
+
+        let mut size = reply
+            .try_write_to_bytes(&mut buffer)
+            .expect("buffer holds a packet header");
+        if let Some(key) = key {
+            let (packet, mac) = buffer.split_at_mut(size);
+            size += key.sign(packet, mac).expect("buffer holds a MAC");
+        }
+        self.udp_socket.send_to(&buffer[..size], peer)?;
+        ServerStats::count(&self.stats.responses);
+        trace!(target: logging::SERVER, client:% = peer; "sent reply");
+        Ok(())
+    }
+
+    /// Applies the restriction with the longest prefix matching `peer`
+    fn admit(&mut self, peer: IpAddr, now: NtpTimestamp) -> Admission {
+        let Some(restriction) = self
+            .restrictions
+            .iter()
+            .filter(|restriction| restriction.matches(peer))
+            .max_by_key(|restriction| restriction.prefix_len)
+            .copied()
+        else {
+            return Admission::Serve;
+        };
+        let refuse = |code| {
+            if restriction.kod {
+                Admission::Kiss(code)
+            } else {
+                Admission::Drop
+            }
+        };
+
+        if restriction.ignore {
+            return Admission::Drop;
+        }
+        if restriction.noserve {
+            ServerStats::count(&self.stats.denied);
+            return refuse(NTP_KISS_DENY);
+        }
+        if restriction.limited {
+            let last = self.last_requests.insert(peer, now);
+            if last.is_some_and(|last| now.diff_seconds(&last) < NTP_RATE_MIN_INTERVAL) {
+                ServerStats::count(&self.stats.rate_limited);
+                return refuse(NTP_KISS_RATE);
+            }
+        }
+        Admission::Serve
+    }
+
+    /// Builds the reply to `request`, received at `rec` and answered at `xmt`
+    pub fn reply(
+        &self,
+        request: &NtpPacketHeader,
+        rec: NtpTimestamp,
+        xmt: NtpTimestamp,
+    ) -> Option<NtpPacketHeader> {
+        match request.mode {
+            Mode::Client => {}
+            _ => return None,
+        }
+
+        let mut reply = NtpPacketHeader {
+            leap_indicator: self.advertised_leap(rec),
+            version_number: request.version_number,
+            mode: Mode::Server,
+            stratum: self.state.stratum,
+            poll: request.poll,
+            precision: self.state.precision,
+            rootdelay: self.state.rootdelay,
+            rootdisp: self.state.rootdisp,
+            refid: self.state.refid,
+            reftime: self.state.reftime,
+            org: request.xmt,
+            rec,
+            xmt,
+        };
+
+        if let Some((smear, event)) = self.active_smear(rec) {
+            let in_inserted_second = self
+                .clock
+                .status()
+                .is_ok_and(|status| status.state == ClockState::LeapInProgress);
+            let offset = |time| {
+                if in_inserted_second {
+                    smear.offset_in_inserted_second(&event, time)
+                } else {
+                    smear.offset(&event, time)
+                }
+            };
+            let xmt_offset = offset(xmt);
+            reply.rec = rec.add_seconds(offset(rec));
+            reply.xmt = xmt.add_seconds(xmt_offset);
+            reply.refid = smear_refid(xmt_offset);
+        }
+
+        Some(reply)
+    }
+
+    fn advertised_leap(&self, now: NtpTimestamp) -> Leap {
+        let synchronized = self.state.leap != Leap::Unknown;
+        let leap = match &self.leap_seconds {
+            Some(leap_seconds) => leap_seconds.advertised(now, &[self.state.leap], synchronized),
+            None => self.state.leap,
+        };
+        // Clients of a smearing server must never see the leap second
+        match leap {
+            Leap::LastMinuteHas61Seconds | Leap::LastMinuteHas59Seconds if self.smear.is_some() => {
+                Leap::NoWarning
+            }
+            _ => leap,
+        }
+    }
+
+    /// Smear settings and leap second whose window contains `now`
+    fn active_smear(&self, now: NtpTimestamp) -> Option<(LeapSmear, LeapEvent)> {
+        let smear = self.smear?;
+        let event = self.leap_seconds.as_ref()?.smeared(&smear, now)?;
+        Some((smear, event))
+    }
+}
+
+/// Reference identifier advertised while smearing: 127 followed by the absolute
+/// smear offset in microseconds, so that clients can tell smeared servers apart
+fn smear_refid(offset: f64) -> RefId {
+    let micros = ((offset.abs() * 1e6).round() as u32).min(0x00ff_ffff);
+    let [_, high, middle, low] = micros.to_be_bytes();
+    RefId::from([127, high, middle, low])
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        clock::ClockStatus,
+        leap::{LeapPolicy, LeapSecondTable},
+        types::{
+            Poll, NTP_LEAP_LAST_MINUTE_HAS_61_SECONDS, NTP_LEAP_NO_WARNING, NTP_MODE_BROADCAST,
+            NTP_MODE_CLIENT, NTP_VERSION_4,
+        },
+    };
+
+    use super::*;
+
+    /// Clock that never moves and reports a fixed leap state
+    struct FixedClock(ClockState);
+
+    impl Clock for FixedClock {
+        fn now(&self) -> NtpResult<NtpTimestamp> {
+            Ok(NtpTimestamp::new(0, 0))
+        }
+
+        fn step(&mut self, _offset: f64) -> NtpResult<()> {
+            Ok(())
+        }
+
+        fn adjust_offset(&mut self, _offset: f64) -> NtpResult<()> {
+            Ok(())
+        }
+
+        fn set_frequency(&mut self, _ppm: f64) -> NtpResult<()> {
+            Ok(())
+        }
+
+        fn set_time_constant(&mut self, _constant: i64) -> NtpResult<()> {
+            Ok(())
+        }
+
+        fn set_leap(&mut self, _leap: Leap) -> NtpResult<()> {
+            Ok(())
+        }
+
+        fn set_sync_status(&mut self, _: bool, _: f64, _: f64) -> NtpResult<()> {
+            Ok(())
+        }
+
+        fn status(&self) -> NtpResult<ClockStatus> {
+            Ok(ClockStatus {
+                state: self.0,
+                synchronized: true,
+                offset: 0.0,
+                frequency: 0.0,
+                est_error: 0.0,
+                max_error: 0.0,
+                time_constant: 0,
+            })
+        }
+    }
+
+    const LEAP_SECONDS_LIST: &str = "\
+#$\t 3676924800
+#@\t 3928521600
+3644697600\t36
+3692217600\t37
+#h\t7e3e2943 3fa11956 e4e658de c4dca0e6 bf6622c9
+";
+
+    /// 2017-01-01T00:00:00Z, right after the most recent leap second
+    const JAN_1_2017: u32 = 3692217600;
+
+    fn synchronized_state() -> ServerState {
+        ServerState {
+            leap: NTP_LEAP_NO_WARNING,
+            stratum: Stratum::from(2),
+            precision: Precision::from(-20),
+            rootdelay: NtpShort::new(0, 100),
+            rootdisp: NtpShort::new(0, 200),
+            refid: RefId::from([192, 0, 2, 1]),
+            reftime: NtpTimestamp::new(JAN_1_2017 - 64, 0),
+        }
+    }
+
+    fn server(state: ClockState, smear: Option<LeapSmear>) -> NtpServer<FixedClock> {
+        let table = LeapSecondTable::parse(LEAP_SECONDS_LIST).unwrap();
+        let mut builder =
+            NtpServerBuilder::new(UdpSocket::bind("127.0.0.1:0").unwrap(), FixedClock(state))
+                .state(synchronized_state())
+                .leap_seconds(LeapSeconds::new(LeapPolicy::Step).with_table(table));
+        if let Some(smear) = smear {
+            builder = builder.smear(smear);
+        }
+        builder.build().unwrap()
+    }
+
+    fn request(xmt: NtpTimestamp) -> NtpPacketHeader {
+        NtpPacketHeader {
+            leap_indicator: NTP_LEAP_NO_WARNING,
+            version_number: NTP_VERSION_4,
+            mode: NTP_MODE_CLIENT,
+            stratum: Stratum::from(0),
+            poll: Poll::from(6),
+            precision: Precision::from(0),
+            rootdelay: NtpShort::new(0, 0),
+            rootdisp: NtpShort::new(0, 0),
+            refid: RefId::from([0, 0, 0, 0]),
+            reftime: NtpTimestamp::new(0, 0),
+            org: NtpTimestamp::new(0, 0),
+            rec: NtpTimestamp::new(0, 0),
+            xmt,
+        }
+    }
+
+    #[test]
+    fn reply_copies_state_and_timestamps() {
+        let server = server(ClockState::Ok, None);
+        let rec = NtpTimestamp::new(JAN_1_2017 + 86_400, 1);
+        let xmt = NtpTimestamp::new(JAN_1_2017 + 86_400, 2);
+
+        let reply = server
+            .reply(&request(NtpTimestamp::new(1, 2)), rec, xmt)
+            .unwrap();
+
+        assert_eq!(reply.mode, Mode::Server);
+        assert_eq!(reply.poll, Poll::from(6));
+        assert_eq!(reply.stratum, Stratum::from(2));
+        assert_eq!(reply.refid, RefId::from([192, 0, 2, 1]));
+        assert_eq!(reply.org, NtpTimestamp::new(1, 2));
+        assert_eq!(reply.rec, rec);
+        assert_eq!(reply.xmt, xmt);
+    }
+
+    #[test]
+    fn only_client_requests_are_answered() {
+        let server = server(ClockState::Ok, None);
+        let mut request = request(NtpTimestamp::new(1, 2));
+        request.mode = NTP_MODE_BROADCAST;
+
+        let now = NtpTimestamp::new(JAN_1_2017, 0);
+        assert_eq!(server.reply(&request, now, now), None);
+    }
+
+    #[test]
+    fn leap_is_advertised_from_the_table() {
+        let server = server(ClockState::Ok, None);
+        let now = NtpTimestamp::new(JAN_1_2017 - 3600, 0);
+
+        let reply = server.reply(&request(now), now, now).unwrap();
+
+        assert_eq!(reply.leap_indicator, NTP_LEAP_LAST_MINUTE_HAS_61_SECONDS);
+    }
+
+    #[test]
+    fn smeared_reply_hides_the_leap() {
+        let server = server(ClockState::Ok, Some(LeapSmear::google()));
+        let rec = NtpTimestamp::new(JAN_1_2017 - 21_600, 0);
+        let xmt = rec.add_seconds(0.001);
+
+        let reply = server.reply(&request(rec), rec, xmt).unwrap();
+
+        assert_eq!(reply.leap_indicator, NTP_LEAP_NO_WARNING);
+        assert!((reply.rec.diff_seconds(&rec) + 0.25).abs() < 1e-6);
+        assert!((reply.xmt.diff_seconds(&xmt) + 0.25).abs() < 1e-6);
+        assert_eq!(reply.refid, smear_refid(0.25));
+        assert_eq!(<[u8; 4]>::from(reply.refid)[0], 127);
+    }
+
+    #[test]
+    fn smeared_reply_uses_clock_state_during_inserted_second() {
+        let server = server(ClockState::LeapInProgress, Some(LeapSmear::google()));
+        let rec = NtpTimestamp::new(JAN_1_2017 - 1, 0);
+
+        let reply = server.reply(&request(rec), rec, rec).unwrap();
+
+        assert!((reply.rec.diff_seconds(&rec) - 0.5).abs() < 1e-4);
+    }
+
+    #[test]
+    fn reply_outside_smear_window_is_not_smeared() {
+        let server = server(ClockState::Ok, Some(LeapSmear::google()));
+        let rec = NtpTimestamp::new(JAN_1_2017 + 86_400, 0);
+
+        let reply = server.reply(&request(rec), rec, rec).unwrap();
+
+        assert_eq!(reply.rec, rec);
+        assert_eq!(reply.refid, RefId::from([192, 0, 2, 1]));
+    }
+
+    #[test]
+    fn restriction_matches_prefix() {
+        let network: Restriction = "192.0.2.0/24".parse().unwrap();
+        assert!(network.matches("192.0.2.77".parse().unwrap()));
+        assert!(network.matches("::ffff:192.0.2.1".parse().unwrap()));
+        assert!(!network.matches("192.0.3.1".parse().unwrap()));
+        assert!(!network.matches("2001:db8::1".parse().unwrap()));
+
+        let everything: Restriction = "::/0".parse().unwrap();
+        assert!(everything.matches("2001:db8::1".parse().unwrap()));
+        assert_eq!(
+            "2001:db8::1".parse::<Restriction>().unwrap().prefix_len,
+            128
+        );
+        assert_eq!(
+            "192.0.2.0/33".parse::<Restriction>(),
+            Err("Invalid prefix length")
+        );
+    }
+
+    #[test]
+    fn longest_prefix_restriction_applies() {
+        let mut server = server(ClockState::Ok, None);
+        let everything = Restriction {
+            ignore: true,
+            ..Restriction::new("0.0.0.0".parse().unwrap(), 0)
+        };
+        let local = Restriction {
+            noserve: true,
+            kod: true,
+            ..Restriction::new("127.0.0.0".parse().unwrap(), 8)
+        };
+        server.restrictions = vec![everything, local];
+        let now = NtpTimestamp::new(JAN_1_2017, 0);
+
+        assert_eq!(
+            server.admit("127.0.0.1".parse().unwrap(), now),
+            Admission::Kiss(NTP_KISS_DENY)
+        );
+        assert_eq!(
+            server.admit("192.0.2.1".parse().unwrap(), now),
+            Admission::Drop
+        );
+        assert_eq!(server.admit("::1".parse().unwrap(), now), Admission::Serve);
+        assert_eq!(server.stats().denied(), 1);
+    }
+
+    #[test]
+    fn limited_client_is_kissed_when_too_fast() {
+        let mut server = server(ClockState::Ok, None);
+        server.restrictions = vec![Restriction {
+            limited: true,
+            kod: true,
+            ..Restriction::new("0.0.0.0".parse().unwrap(), 0)
+        }];
+        let client = "192.0.2.1".parse().unwrap();
+        let now = NtpTimestamp::new(JAN_1_2017, 0);
+
+        assert_eq!(server.admit(client, now), Admission::Serve);
+        assert_eq!(
+            server.admit(client, now.add_seconds(0.5)),
+            Admission::Kiss(NTP_KISS_RATE)
+        );
+        assert_eq!(server.admit(client, now.add_seconds(3.0)), Admission::Serve);
+        assert_eq!(server.stats().rate_limited(), 1);
+    }
+
+    #[test]
+    fn rate_limiting_remembers_a_bounded_number_of_clients() {
+        let mut server = server(ClockState::Ok, None);
+        server.restrictions = vec![Restriction {
+            limited: true,
+            kod: true,
+            ..Restriction::new("0.0.0.0".parse().unwrap(), 0)
+        }];
+        let client = |index: usize| IpAddr::from([10, 0, (index >> 8) as u8, index as u8]);
+        let now = NtpTimestamp::new(JAN_1_2017, 0);
+
+        for index in 0..NTP_RATE_CLIENTS + 100 {
+            assert_eq!(server.admit(client(index), now), Admission::Serve);
+        }
+
+        assert_eq!(server.last_requests.clients.len(), NTP_RATE_CLIENTS);
+        // The least recently seen clients were forgotten, the others are not
+        assert_eq!(server.admit(client(0), now), Admission::Serve);
+        assert_eq!(
+            server.admit(client(NTP_RATE_CLIENTS + 99), now),
+            Admission::Kiss(NTP_KISS_RATE)
+        );
+        assert_eq!(server.last_requests.clients.len(), NTP_RATE_CLIENTS);
+    }
+}
@@ -0,0 +1,484 @@
+//! Statistics files in ntpd's format, as read by ntpviz and similar tools.
+//! Each kind of record goes to its own file in the statistics directory, with
+//! one file per UTC day named like ntpd's `filegen ... type day`, for example
+//! `peerstats.20170101`.
+
+use std::{
+    fmt,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    marker::PhantomData,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use serde::Deserialize;
+
+use crate::{leap::civil_from_days, types::NtpTimestamp};
+
+/// Modified Julian Day of the Unix epoch
+const MJD_UNIX_EPOCH: i64 = 40_587;
+
+const SECONDS_PER_DAY: i64 = 86_400;
+
+/// Peer status flag: the association was configured
+pub const NTP_PEER_STATUS_CONFIGURED: u16 = 0x8000;
+
+/// Peer status flag: the server replied to one of the last 8 polls
+pub const NTP_PEER_STATUS_REACHABLE: u16 = 0x1000;
+
+/// Error raised while reading a statistics file
+#[derive(Debug, PartialEq, Eq)]
+pub enum StatsError {
+    Io(io::ErrorKind),
+    /// A line could not be parsed
+    Syntax {
+        line: usize,
+        reason: &'static str,
+    },
+}
+
+impl From<io::Error> for StatsError {
+    fn from(value: io::Error) -> Self {
+        Self::Io(value.kind())
+    }
+}
+
+/// Kind of statistics, as named by ntpd's `statistics` directive
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StatsKind {
+    Peerstats,
+    Loopstats,
+    Clockstats,
+}
+
+/// Time of a record: Modified Julian Day and seconds past midnight UTC
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StatsTime {
+    pub mjd: u32,
+    pub seconds: f64,
+}
+
+impl StatsTime {
+    pub fn from_timestamp(time: NtpTimestamp) -> Self {
+        let (seconds, nanos) = time.to_unix();
+        Self {
+            mjd: (seconds.div_euclid(SECONDS_PER_DAY) + MJD_UNIX_EPOCH) as u32,
+            seconds: seconds.rem_euclid(SECONDS_PER_DAY) as f64 + f64::from(nanos) / 1e9,
+        }
+    }
+
+    pub fn to_timestamp(&self) -> NtpTimestamp {
+        let days = i64::from(self.mjd) - MJD_UNIX_EPOCH;
+        let seconds = self.seconds.trunc();
+        let nanos = ((self.seconds - seconds) * 1e9).round() as u32;
+        NtpTimestamp::from_unix(days * SECONDS_PER_DAY + seconds as i64, nanos)
+    }
+}
+
+impl fmt::Display for StatsTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {:.3}", self.mjd, self.seconds)
+    }
+}
+
+/// Outcome of the selection for one association, in the peer status word
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PeerSelect {
+    /// Not a candidate, for example because it is unreachable
+    Reject = 0,
+    /// Outside the intersection of the correctness intervals
+    Falsetick = 1,
+    /// Left out of the combined offset
+    Outlier = 3,
+    /// Combined into the system offset
+    Candidate = 4,
+    /// The server the clock is synchronized to
+    SystemPeer = 6,
+}
+
+/// Builds ntpd's peer status word. The event counter and code are left at zero.
+pub fn peer_status(configured: bool, reachable: bool, select: PeerSelect) -> u16 {
+    let mut status = (select as u16) << 8;
+    if configured {
+        status |= NTP_PEER_STATUS_CONFIGURED;
+    }
+    if reachable {
+        status |= NTP_PEER_STATUS_REACHABLE;
+    }
+    status
+}
+
+/// Line of a statistics file
+pub trait StatsRecord: fmt::Display + FromStr<Err = &'static str> {
+    /// Base name of the files holding these records
+    const NAME: &'static str;
+
+    fn time(&self) -> StatsTime;
+}
+
+/// Sample of one server: `MJD seconds address status offset delay dispersion jitter`
+#[derive(Debug, Clone, PartialEq)]
+pub struct PeerStats {
+    pub time: StatsTime,
+    pub address: String,
+    /// Peer status word, see `peer_status`
+    pub status: u16,
+    /// Seconds
+    pub offset: f64,
+    /// Seconds
+    pub delay: f64,
+    /// Seconds
+    pub dispersion: f64,
+    /// Seconds
+    pub jitter: f64,
+}
+
+impl StatsRecord for PeerStats {
+    const NAME: &'static str = "peerstats";
+
+    fn time(&self) -> StatsTime {
+        self.time
+    }
+}
+
+impl fmt::Display for PeerStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} {:04x} {:.9} {:.9} {:.9} {:.9}",
+            self.time,
+            self.address,
+            self.status,
+            self.offset,
+            self.delay,
+            self.dispersion,
+            self.jitter
+        )
+    }
+}
+
+impl FromStr for PeerStats {
+    type Err = &'static str;
+
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let mut fields = Fields::new(line);
+        let record = Self {
+            time: fields.time()?,
+            address: fields.next()?.to_string(),
+            status: u16::from_str_radix(fields.next()?, 16).map_err(|_| "Invalid status")?,
+            offset: fields.number()?,
+            delay: fields.number()?,
+            dispersion: fields.number()?,
+            jitter: fields.number()?,
+        };
+        fields.end()?;
+        Ok(record)
+    }
+}
+
+/// Update of the clock discipline:
+/// `MJD seconds offset frequency jitter wander time_constant`
+#[derive(Debug, Clone, PartialEq)]
+pub struct LoopStats {
+    pub time: StatsTime,
+    /// Seconds
+    pub offset: f64,
+    /// Parts per million
+    pub frequency: f64,
+    /// Seconds
+    pub jitter: f64,
+    /// Stability of the frequency, in parts per million
+    pub wander: f64,
+    /// Time constant of the phase-locked loop
+    pub time_constant: i64,
+}
+
+impl StatsRecord for LoopStats {
+    const NAME: &'static str = "loopstats";
+
+    fn time(&self) -> StatsTime {
+        self.time
+    }
+}
+
+impl fmt::Display for LoopStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {:.9} {:.3} {:.9} {:.6} {}",
+            self.time, self.offset, self.frequency, self.jitter, self.wander, self.time_constant
+        )
+    }
+}
+
+impl FromStr for LoopStats {
+    type Err = &'static str;
+
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let mut fields = Fields::new(line);
+        let record = Self {
+            time: fields.time()?,
+            offset: fields.number()?,
+            frequency: fields.number()?,
+            jitter: fields.number()?,
+            wander: fields.number()?,
+            time_constant: fields.number()?,
+        };
+        fields.end()?;
+        Ok(record)
+    }
+}
+
+/// Timecode received from a reference clock: `MJD seconds address timecode`
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClockStats {
+    pub time: StatsTime,
+    pub address: String,
+    /// Timecode as the reference clock sent it
+    pub timecode: String,
+}
+
+impl StatsRecord for ClockStats {
+    const NAME: &'static str = "clockstats";
+
+    fn time(&self) -> StatsTime {
+        self.time
+    }
+}
+
+impl fmt::Display for ClockStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {} {}", self.time, self.address, self.timecode)
+    }
+}
+
+impl FromStr for ClockStats {
+    type Err = &'static str;
+
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let mut fields = Fields::new(line);
+        let time = fields.time()?;
+        let address = fields.next()?.to_string();
+        let timecode = fields.rest().ok_or("Missing timecode")?.to_string();
+        Ok(Self {
+            time,
+            address,
+            timecode,
+        })
+    }
+}
+
+/// Whitespace-separated fields of a record
+struct Fields<'a> {
+    rest: &'a str,
+}
+
+impl<'a> Fields<'a> {
+    fn new(line: &'a str) -> Self {
+        Self { rest: line }
+    }
+
+    fn next(&mut self) -> Result<&'a str, &'static str> {
+        let rest = self.rest.trim_start();
+        let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+        let (field, rest) = rest.split_at(end);
+        self.rest = rest;
+        if field.is_empty() {
+            return Err("Missing field");
+        }
+        Ok(field)
+    }
+
+    fn number<T: FromStr>(&mut self) -> Result<T, &'static str> {
+        self.next()?.parse().map_err(|_| "Invalid number")
+    }
+
+    fn time(&mut self) -> Result<StatsTime, &'static str> {
+        Ok(StatsTime {
+            mjd: self.number()?,
+            seconds: self.number()?,
+        })
+    }
+
+    /// Remaining text, if any
+    fn rest(&self) -> Option<&'a str> {
+        Some(self.rest.trim()).filter(|rest| !rest.is_empty())
+    }
+
+    fn end(&self) -> Result<(), &'static str> {
+        match self.rest() {
+            Some(_) => Err("Too many fields"),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Appends records to one file per day in a statistics directory
+#[derive(Debug)]
+pub struct StatsFile<R> {
+    directory: PathBuf,
+    /// Day and file the last record was written to
+    current: Option<(u32, File)>,
+    record: PhantomData<R>,
+}
+
+impl<R: StatsRecord> StatsFile<R> {
+    pub fn new(directory: impl Into<PathBuf>) -> Self {
+        Self {
+            directory: directory.into(),
+            current: None,
+            record: PhantomData,
+        }
+    }
+
+    /// File holding the records of the day `mjd`
+    pub fn path(&self, mjd: u32) -> PathBuf {
+        let (year, month, day) = civil_from_days(i64::from(mjd) - MJD_UNIX_EPOCH);
+        self.directory
+            .join(format!("{}.{year:04}{month:02}{day:02}", R::NAME))
+    }
+
+    /// Appends `record` to the file of its day, moving on to a new file when
+    /// the day changes
+    pub fn append(&mut self, record: &R) -> io::Result<()> {
+        let mjd = record.time().mjd;
+        if !matches!(self.current, Some((day, _)) if day == mjd) {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(self.path(mjd))?;
+            self.current = Some((mjd, file));
+        }
+        let (_, file) = self.current.as_mut().expect("file was just opened");
+        writeln!(file, "{record}")
+    }
+
+    /// Reads back the records of a file
+    pub fn read(path: impl AsRef<Path>) -> Result<Vec<R>, StatsError> {
+        Self::parse(&fs::read_to_string(path)?)
+    }
+
+    /// Parses the records of a file. Blank lines are skipped.
+    pub fn parse(contents: &str) -> Result<Vec<R>, StatsError> {
+        contents
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty())
+            .map(|(index, line)| {
+                line.parse().map_err(|reason| StatsError::Syntax {
+                    line: index + 1,
+                    reason,
+                })
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 2017-01-01 00:00:00 UTC
+    const JAN_1_2017: u32 = 3_692_217_600;
+
+    #[test]
+    fn time_is_mjd_and_seconds_past_midnight() {
+        let time = StatsTime::from_timestamp(NtpTimestamp::new(JAN_1_2017 + 3_723, 1 << 31));
+
+        assert_eq!(time.mjd, 57_754);
+        assert_eq!(time.seconds, 3_723.5);
+        assert_eq!(time.to_string(), "57754 3723.500");
+        assert_eq!(
+            time.to_timestamp(),
+            NtpTimestamp::new(JAN_1_2017 + 3_723, 1 << 31)
+        );
+    }
+
+    #[test]
+    fn records_use_ntpd_format() {
+        let time = StatsTime {
+            mjd: 57_754,
+            seconds: 10_847.65,
+        };
+        let peer = PeerStats {
+            time,
+            address: "192.0.2.1".to_string(),
+            status: peer_status(true, true, PeerSelect::SystemPeer),
+            offset: -0.001605376,
+            delay: 0.012,
+            dispersion: 0.001424877,
+            jitter: 0.000958674,
+        };
+        let loop_stats = LoopStats {
+            time,
+            offset: 0.000006019,
+            frequency: 13.77819,
+            jitter: 0.000351733,
+            wander: 0.0133806,
+            time_constant: 6,
+        };
+
+        assert_eq!(
+            peer.to_string(),
+            "57754 10847.650 192.0.2.1 9600 -0.001605376 0.012000000 0.001424877 0.000958674"
+        );
+        assert_eq!(
+            loop_stats.to_string(),
+            "57754 10847.650 0.000006019 13.778 0.000351733 0.013381 6"
+        );
+        assert_eq!(peer.to_string().parse(), Ok(peer));
+    }
+
+    #[test]
+    fn ntpd_lines_are_parsed() {
+        let records = StatsFile::<LoopStats>::parse(
+            "50935 75440.031 0.000006019 13.778190 0.000351733 0.013380 6\n\n",
+        )
+        .unwrap();
+        assert_eq!(records[0].time.mjd, 50_935);
+        assert_eq!(records[0].frequency, 13.77819);
+
+        let clock: ClockStats = "49213 525.624 127.127.4.1 93 226 00:08:29.606 D"
+            .parse()
+            .unwrap();
+        assert_eq!(clock.timecode, "93 226 00:08:29.606 D");
+
+        assert_eq!(
+            StatsFile::<PeerStats>::parse("57754 1.000 192.0.2.1 9614 0.1 0.1 0.1\n"),
+            Err(StatsError::Syntax {
+                line: 1,
+                reason: "Missing field"
+            })
+        );
+    }
+
+    #[test]
+    fn files_are_rotated_daily() {
+        let directory = std::env::temp_dir().join(format!("demo_ntp-stats-{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let mut file = StatsFile::<LoopStats>::new(&directory);
+        let record = |mjd| LoopStats {
+            time: StatsTime {
+                mjd,
+                seconds: 86_399.0,
+            },
+            offset: 0.0,
+            frequency: 1.5,
+            jitter: 0.0,
+            wander: 0.0,
+            time_constant: 4,
+        };
+
+        file.append(&record(57_754)).unwrap();
+        file.append(&record(57_754)).unwrap();
+        file.append(&record(57_755)).unwrap();
+
+        let first = StatsFile::<LoopStats>::read(directory.join("loopstats.20170101")).unwrap();
+        let second = StatsFile::<LoopStats>::read(directory.join("loopstats.20170102")).unwrap();
+        assert_eq!(first, vec![record(57_754), record(57_754)]);
+        assert_eq!(second, vec![record(57_755)]);
+        fs::remove_dir_all(directory).unwrap();
+    }
+}
@@ -0,0 +1,243 @@
+//! Scriptable mock server and clock and network simulator, to test the client
+//! and everything built on it deterministically and without a network
+
+use std::{
+    collections::VecDeque,
+    io,
+    net::SocketAddr,
+    sync::{Arc, Mutex, MutexGuard},
+    time::Duration,
+};
+
+use crate::{
+    codec::{TryReadFromBytes, TryWriteToBytes},
+    ntp_message_protocol::{NtpPacketHeader, NTP_HEADER_SIZE},
+    random::Random,
+    transport::{Received, Transport},
+    types::{Leap, Mode, NtpShort, NtpTimestamp, Precision, RefId, Stratum},
+};
+
+pub mod simulation;
+
+/// Clock and network the mock server simulates
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MockConfig {
+    /// Server clock minus client clock, in seconds
+    pub offset: f64,
+    /// Time a request takes to reach the server, in seconds
+    pub forward_delay: f64,
+    /// Time a reply takes to reach the client, in seconds
+    pub backward_delay: f64,
+    /// Upper bound of the random delay added in each direction, in seconds
+    pub jitter: f64,
+    /// Probability that a request or its reply is lost
+    pub loss: f64,
+    pub leap: Leap,
+    pub stratum: Stratum,
+    pub precision: Precision,
+    pub rootdelay: NtpShort,
+    pub rootdisp: NtpShort,
+    pub refid: RefId,
+    /// Age of the reference time when a request arrives, in seconds
+    pub reftime_age: f64,
+    /// Seed of the generator drawing jitter and losses
+    pub seed: u64,
+}
+
+impl Default for MockConfig {
+    fn default() -> Self {
+        Self {
+            offset: 0.0,
+            forward_delay: 0.0,
+            backward_delay: 0.0,
+            jitter: 0.0,
+            loss: 0.0,
+            leap: Leap::NoWarning,
+            stratum: Stratum::from(1),
+            precision: Precision::from(-20),
+            rootdelay: NtpShort::new(0, 0),
+            rootdisp: NtpShort::new(0, 0),
+            refid: RefId::from(*b"MOCK"),
+            reftime_age: 0.0,
+            seed: 1,
+        }
+    }
+}
+
+/// How the mock server answers one request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MockReply {
+    /// A reply following the configuration
+    Answer,
+    /// No reply, as if the request or the reply was lost
+    Lose,
+    /// A kiss-o'-death packet with the code
+    Kiss(RefId),
+    /// The bytes as they are, instead of a reply
+    Malformed(Vec<u8>),
+}
+
+#[derive(Debug)]
+struct State {
+    config: MockConfig,
+    script: VecDeque<MockReply>,
+    replies: VecDeque<(Vec<u8>, NtpTimestamp)>,
+    transmitted: Option<NtpTimestamp>,
+    requests: u64,
+    random: Random,
+}
+
+impl State {
+    /// Reply to `request`, with the time it reaches the client by the client
+    /// clock
+    fn reply(&mut self, request: &NtpPacketHeader) -> Option<(Vec<u8>, NtpTimestamp)> {
+        let config = self.config;
+        let mut reply = self.script.pop_front().unwrap_or(MockReply::Answer);
+        if reply == MockReply::Answer && config.loss > 0.0 && self.random.uniform() < config.loss {
+            reply = MockReply::Lose;
+        }
+        let forward = config.forward_delay + config.jitter * self.random.uniform();
+        let backward = config.backward_delay + config.jitter * self.random.uniform();
+
+        let t1 = request.xmt;
+        let t2 = t1.add_seconds(config.offset + forward);
+        let t4 = t2.add_seconds(backward - config.offset);
+        let mut header = NtpPacketHeader {
+            leap_indicator: config.leap,
+            version_number: request.version_number,
+            mode: Mode::Server,
+            stratum: config.stratum,
+            poll: request.poll,
+            precision: config.precision,
+            rootdelay: config.rootdelay,
+            rootdisp: config.rootdisp,
+            refid: config.refid,
+            reftime: t2.add_seconds(-config.reftime_age),
+            org: t1,
+            rec: t2,
+            xmt: t2,
+        };
+        match reply {
+            MockReply::Answer => {}
+            MockReply::Lose => return None,
+            MockReply::Kiss(code) => {
+                header.leap_indicator = Leap::Unknown;
+                header.stratum = Stratum::from(0);
+                header.refid = code;
+            }
+            MockReply::Malformed(bytes) => return Some((bytes, t4)),
+        }
+        let mut bytes = vec![0; NTP_HEADER_SIZE];
+        header.try_write_to_bytes(&mut bytes).ok()?;
+        Some((bytes, t4))
+    }
+}
+
+/// Transport answering the requests of a client itself, as a server at
+/// `address` would. The clones share their state, so a test can keep one to
+/// script replies and count requests after handing another to the client.
+///
+/// Timestamps follow the client clock: a request is sent at the time in its
+/// transmit timestamp, and the reply arrives after the configured delays,
+/// which the transport reports as its receive timestamp.
+#[derive(Debug, Clone)]
+pub struct MockServer {
+    address: SocketAddr,
+    state: Arc<Mutex<State>>,
+}
+
+impl MockServer {
+    pub fn new(address: SocketAddr, config: MockConfig) -> Self {
+        Self {
+            address,
+            state: Arc::new(Mutex::new(State {
+                config,
+                script: VecDeque::new(),
+                replies: VecDeque::new(),
+                transmitted: None,
+                requests: 0,
+                random: Random::new(config.seed),
+            })),
+        }
+    }
+
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    pub fn config(&self) -> MockConfig {
+        self.state().config
+    }
+
+    /// Changes the configuration for the following requests
+    pub fn set_config(&self, config: MockConfig) {
+        self.state().config = config;
+    }
+
+    /// Queues `reply` for a request after those already scripted. Requests
+    /// beyond the script are answered, or lost with the configured probability.
+    pub fn push(&self, reply: MockReply) {
+        self.state().script.push_back(reply);
+    }
+
+    /// Number of requests received so far
+    pub fn requests(&self) -> u64 {
+        self.state().requests
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|error| error.into_inner())
+    }
+}
+
+impl Transport for MockServer {
+    type Error = io::Error;
+
+    /// Replies never take real time to arrive, so the timeout is ignored
+    fn set_timeout(&mut self, _timeout: Option<Duration>) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    /// Handles `bytes` as a request, which must be sent to the server address.
+    /// Packets that are not requests go unanswered.
+    fn send_to(&mut self, bytes: &[u8], address: SocketAddr) -> Result<(), Self::Error> {
+        if address != self.address {
+            return Err(io::ErrorKind::HostUnreachable.into());
+        }
+        let mut state = self.state();
+        state.requests += 1;
+        state.transmitted = None;
+        let Ok((request, _)) = NtpPacketHeader::try_read_from_bytes(bytes) else {
+            return Ok(());
+        };
+        state.transmitted = Some(request.xmt);
+        if request.mode != Mode::Client {
+            return Ok(());
+        }
+        if let Some(reply) = state.reply(&request) {
+            state.replies.push_back(reply);
+        }
+        Ok(())
+    }
+
+    /// Returns the next reply, or fails with `WouldBlock` as a socket timing
+    /// out would when there is none
+    fn recv_from(&mut self, bytes: &mut [u8]) -> Result<Received, Self::Error> {
+        let (reply, timestamp) = self
+            .state()
+            .replies
+            .pop_front()
+            .ok_or(io::ErrorKind::WouldBlock)?;
+        let size = reply.len().min(bytes.len());
+        bytes[..size].copy_from_slice(&reply[..size]);
+        Ok(Received {
+            size,
+            source: self.address,
+            timestamp: Some(timestamp),
+        })
+    }
+
+    fn transmit_timestamp(&mut self) -> Result<Option<NtpTimestamp>, Self::Error> {
+        Ok(self.state().transmitted.take())
+    }
+}
@@ -0,0 +1,645 @@
+//! Discrete-event simulation of the daemon against servers with imperfect
+//! clocks behind imperfect networks. Time is virtual, so days of operation run
+//! in moments, and the true error of the local clock is known at any time.
+
+use std::{
+    collections::VecDeque,
+    io,
+    net::SocketAddr,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    association::{Association, AssociationConfig},
+    client::NtpClientBuilder,
+    clock::{Clock, ClockState, ClockStatus},
+    codec::{TryReadFromBytes, TryWriteToBytes},
+    config::{NTP_MAXPOLL, NTP_MINPOLL},
+    daemon::Daemon,
+    error::NtpResult,
+    leap::{LeapPolicy, LeapSeconds},
+    ntp_message_protocol::{NtpPacketHeader, NTP_HEADER_SIZE},
+    transport::{Received, Transport},
+    types::{Leap, Mode, NtpShort, NtpTimestamp, Precision, RefId, Stratum},
+};
+
+use crate::random::Random;
+
+/// Unix time the simulations start at, 2026-01-01
+const EPOCH: i64 = 1_767_225_600;
+
+/// Longest interval the clocks are integrated over at once, in seconds
+const INTEGRATION_STEP: f64 = 1.0;
+
+/// Interval the error of the local clock is recorded at
+const RECORD_INTERVAL: Duration = Duration::from_secs(16);
+
+/// Parameters of the Linux kernel PLL
+const SHIFT_PLL: i32 = 2;
+const DEFAULT_TIME_CONSTANT: i32 = 2;
+const MAX_TIME_CONSTANT: i64 = 10;
+const MAX_PLL_OFFSET: f64 = 0.5;
+const MAX_FREQUENCY: f64 = 500.0;
+
+/// Errors of a simulated clock relative to true time
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ClockModel {
+    /// Offset at the start, in seconds
+    pub offset: f64,
+    /// Frequency error at the start, in ppm
+    pub drift: f64,
+    /// Standard deviation of the random walk of the frequency, in ppm per
+    /// square root of a second
+    pub wander: f64,
+    /// Faults stepping the clock: when they happen, and by how many seconds
+    pub steps: Vec<(Duration, f64)>,
+}
+
+/// Distribution of the one-way delay of a network path, in seconds
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Delay {
+    Constant(f64),
+    Uniform {
+        min: f64,
+        max: f64,
+    },
+    /// `min` plus an exponentially distributed queuing delay
+    Exponential {
+        min: f64,
+        mean: f64,
+    },
+}
+
+impl Delay {
+    fn sample(&self, random: &mut Random) -> f64 {
+        match *self {
+            Self::Constant(delay) => delay,
+            Self::Uniform { min, max } => min + (max - min) * random.uniform(),
+            Self::Exponential { min, mean } => min + random.exponential(mean),
+        }
+    }
+}
+
+/// Server of a simulation and the network path to it
+#[derive(Debug, Clone, PartialEq)]
+pub struct SimulatedServer {
+    pub clock: ClockModel,
+    /// Delay of the requests
+    pub forward: Delay,
+    /// Delay of the replies
+    pub backward: Delay,
+    /// Probability that a request or its reply is lost
+    pub loss: f64,
+    pub stratum: Stratum,
+}
+
+impl Default for SimulatedServer {
+    fn default() -> Self {
+        Self {
+            clock: ClockModel::default(),
+            forward: Delay::Constant(0.0),
+            backward: Delay::Constant(0.0),
+            loss: 0.0,
+            stratum: Stratum::from(1),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SimulationConfig {
+    /// Local clock, before the daemon corrects it
+    pub local: ClockModel,
+    pub servers: Vec<SimulatedServer>,
+    pub minpoll: i8,
+    pub maxpoll: i8,
+    pub burst: bool,
+    pub iburst: bool,
+    /// Seed of the generator drawing delays, losses, wander and the spread of
+    /// the polls
+    pub seed: u64,
+}
+
+impl Default for SimulationConfig {
+    fn default() -> Self {
+        Self {
+            local: ClockModel::default(),
+            servers: Vec::new(),
+            minpoll: NTP_MINPOLL,
+            maxpoll: NTP_MAXPOLL,
+            burst: false,
+            iburst: false,
+            seed: 1,
+        }
+    }
+}
+
+/// Error of the local clock at one point of a simulation
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TracePoint {
+    /// Simulated time since the start
+    pub time: Duration,
+    /// Offset of the local clock from true time, in seconds
+    pub error: f64,
+    /// Frequency error left after the correction, in ppm
+    pub frequency_error: f64,
+}
+
+/// How well the local clock followed true time over part of a simulation
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SimulationReport {
+    /// RMS of the error of the local clock, in seconds
+    pub rms_error: f64,
+    /// Largest absolute error of the local clock, in seconds
+    pub max_error: f64,
+    /// Frequency error at the end, in ppm
+    pub frequency_error: f64,
+}
+
+/// Error of a clock integrated over simulated time
+#[derive(Debug)]
+struct Oscillator {
+    /// Offset from true time, in seconds
+    phase: f64,
+    /// Frequency error, in ppm
+    frequency: f64,
+    wander: f64,
+    /// Pending steps, by simulated time in seconds
+    steps: VecDeque<(f64, f64)>,
+}
+
+impl Oscillator {
+    fn new(model: &ClockModel) -> Self {
+        let mut steps = model
+            .steps
+            .iter()
+            .map(|&(time, size)| (time.as_secs_f64(), size))
+            .collect::<Vec<_>>();
+        steps.sort_by(|a, b| a.0.total_cmp(&b.0));
+        Self {
+            phase: model.offset,
+            frequency: model.drift,
+            wander: model.wander,
+            steps: steps.into(),
+        }
+    }
+
+    /// Advances by `step` seconds, to simulated time `time`
+    fn advance(&mut self, step: f64, time: f64, random: &mut Random) {
+        self.phase += self.frequency * 1e-6 * step;
+        if self.wander > 0.0 {
+            self.frequency += self.wander * step.sqrt() * random.normal();
+        }
+        while let Some(&(_, size)) = self.steps.front().filter(|(at, _)| *at <= time) {
+            self.phase += size;
+            self.steps.pop_front();
+        }
+    }
+
+    /// Phase `ahead` seconds from now, if the frequency holds
+    fn phase_in(&self, ahead: f64) -> f64 {
+        self.phase + self.frequency * 1e-6 * ahead
+    }
+}
+
+/// Phase-locked loop of the Linux kernel, which slews out the offsets handed
+/// to it and trains the frequency with them
+#[derive(Debug)]
+struct Pll {
+    /// Offset left to slew, in seconds
+    offset: f64,
+    /// Frequency correction, in ppm
+    frequency: f64,
+    /// Simulated time of the last offset, in seconds
+    updated: Option<f64>,
+    time_constant: i32,
+    leap: Leap,
+    synchronized: bool,
+    est_error: f64,
+    max_error: f64,
+}
+
+impl Pll {
+    fn new() -> Self {
+        Self {
+            offset: 0.0,
+            frequency: 0.0,
+            updated: None,
+            time_constant: DEFAULT_TIME_CONSTANT,
+            leap: Leap::NoWarning,
+            synchronized: false,
+            est_error: 0.0,
+            max_error: 0.0,
+        }
+    }
+
+    fn update(&mut self, offset: f64, time: f64) {
+        let offset = offset.clamp(-MAX_PLL_OFFSET, MAX_PLL_OFFSET);
+        if let Some(updated) = self.updated {
+            let seconds = (time - updated).min(2f64.powi(SHIFT_PLL + 1 + self.time_constant));
+            let gain = 2f64.powi(-2 * (SHIFT_PLL + 2 + self.time_constant));
+            self.frequency = (self.frequency + offset * seconds * gain * 1e6)
+                .clamp(-MAX_FREQUENCY, MAX_FREQUENCY);
+        }
+        self.updated = Some(time);
+        self.offset = offset;
+    }
+
+    /// Correction of the phase over `step` seconds
+    fn advance(&mut self, step: f64) -> f64 {
+        let slew = self.offset * step * 2f64.powi(-(SHIFT_PLL + self.time_constant));
+        self.offset -= slew;
+        slew + self.frequency * 1e-6 * step
+    }
+}
+
+/// Everything the daemon does not control: true time, the clocks and the
+/// network
+#[derive(Debug)]
+struct World {
+    /// Seconds since the start
+    time: f64,
+    start: NtpTimestamp,
+    local: Oscillator,
+    pll: Pll,
+    servers: Vec<(SimulatedServer, Oscillator)>,
+    random: Random,
+}
+
+impl World {
+    fn advance(&mut self, seconds: f64) {
+        let end = self.time + seconds;
+        while self.time < end {
+            let step = (end - self.time).min(INTEGRATION_STEP);
+            self.time += step;
+            self.local.phase += self.pll.advance(step);
+            self.local.advance(step, self.time, &mut self.random);
+            for (_, clock) in &mut self.servers {
+                clock.advance(step, self.time, &mut self.random);
+            }
+        }
+    }
+
+    /// Reading of the local clock `ahead` seconds from now, leaving out the
+    /// slew in between
+    fn local_time(&self, ahead: f64) -> NtpTimestamp {
+        let phase = self.local.phase_in(ahead) + self.pll.frequency * 1e-6 * ahead;
+        self.start.add_seconds(self.time + ahead + phase)
+    }
+
+    fn frequency_error(&self) -> f64 {
+        self.local.frequency + self.pll.frequency
+    }
+
+    /// Reply of server `index` to `request` sent now, with the local time it
+    /// arrives at, unless it is lost
+    fn exchange(
+        &mut self,
+        index: usize,
+        request: &NtpPacketHeader,
+    ) -> Option<(NtpPacketHeader, NtpTimestamp)> {
+        let (server, clock) = &self.servers[index];
+        if server.loss > 0.0 && self.random.uniform() < server.loss {
+            return None;
+        }
+        let forward = server.forward.sample(&mut self.random);
+        let backward = server.backward.sample(&mut self.random);
+        let received = self
+            .start
+            .add_seconds(self.time + forward + clock.phase_in(forward));
+        let reply = NtpPacketHeader {
+            leap_indicator: Leap::NoWarning,
+            version_number: request.version_number,
+            mode: Mode::Server,
+            stratum: server.stratum,
+            poll: request.poll,
+            precision: Precision::from(-20),
+            rootdelay: NtpShort::new(0, 0),
+            rootdisp: NtpShort::new(0, 0),
+            refid: RefId::from(*b"SIM\0"),
+            reftime: received,
+            org: request.xmt,
+            rec: received,
+            xmt: received,
+        };
+        Some((reply, self.local_time(forward + backward)))
+    }
+}
+
+fn lock(world: &Mutex<World>) -> MutexGuard<'_, World> {
+    world.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Local clock of a simulation, disciplined like the Linux kernel clock
+#[derive(Debug, Clone)]
+pub struct SimulatedClock {
+    world: Arc<Mutex<World>>,
+}
+
+impl Clock for SimulatedClock {
+    fn now(&self) -> NtpResult<NtpTimestamp> {
+        Ok(lock(&self.world).local_time(0.0))
+    }
+
+    fn step(&mut self, offset: f64) -> NtpResult<()> {
+        lock(&self.world).local.phase += offset;
+        Ok(())
+    }
+
+    fn adjust_offset(&mut self, offset: f64) -> NtpResult<()> {
+        let mut world = lock(&self.world);
+        let time = world.time;
+        world.pll.update(offset, time);
+        Ok(())
+    }
+
+    fn set_frequency(&mut self, ppm: f64) -> NtpResult<()> {
+        lock(&self.world).pll.frequency = ppm.clamp(-MAX_FREQUENCY, MAX_FREQUENCY);
+        Ok(())
+    }
+
+    fn set_time_constant(&mut self, constant: i64) -> NtpResult<()> {
+        lock(&self.world).pll.time_constant = constant.clamp(0, MAX_TIME_CONSTANT) as i32;
+        Ok(())
+    }
+
+    fn set_leap(&mut self, leap: Leap) -> NtpResult<()> {
+        lock(&self.world).pll.leap = leap;
+        Ok(())
+    }
+
+    fn set_sync_status(
+        &mut self,
+        synchronized: bool,
+        est_error: f64,
+        max_error: f64,
+    ) -> NtpResult<()> {
+        let pll = &mut lock(&self.world).pll;
+        pll.synchronized = synchronized;
+        pll.est_error = est_error;
+        pll.max_error = max_error;
+        Ok(())
+    }
+
+    fn status(&self) -> NtpResult<ClockStatus> {
+        let pll = &lock(&self.world).pll;
+        let state = match pll.leap {
+            _ if !pll.synchronized => ClockState::Error,
+            Leap::NoWarning => ClockState::Ok,
+            Leap::LastMinuteHas61Seconds => ClockState::InsertLeap,
+            Leap::LastMinuteHas59Seconds => ClockState::DeleteLeap,
+            Leap::Unknown => ClockState::Error,
+        };
+        Ok(ClockStatus {
+            state,
+            synchronized: pll.synchronized,
+            offset: pll.offset,
+            frequency: pll.frequency,
+            est_error: pll.est_error,
+            max_error: pll.max_error,
+            time_constant: pll.time_constant.into(),
+        })
+    }
+}
+
+/// Network path from the daemon to one simulated server
+#[derive(Debug)]
+pub struct SimulatedTransport {
+    address: SocketAddr,
+    index: usize,
+    world: Arc<Mutex<World>>,
+    reply: Option<(Vec<u8>, NtpTimestamp)>,
+    transmitted: Option<NtpTimestamp>,
+}
+
+impl Transport for SimulatedTransport {
+    type Error = io::Error;
+
+    fn set_timeout(&mut self, _timeout: Option<Duration>) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    fn send_to(&mut self, bytes: &[u8], address: SocketAddr) -> Result<(), Self::Error> {
+        if address != self.address {
+            return Err(io::ErrorKind::HostUnreachable.into());
+        }
+        let mut world = lock(&self.world);
+        self.transmitted = Some(world.local_time(0.0));
+        self.reply = None;
+        let Ok((request, _)) = NtpPacketHeader::try_read_from_bytes(bytes) else {
+            return Ok(());
+        };
+        if request.mode != Mode::Client {
+            return Ok(());
+        }
+        if let Some((reply, arrival)) = world.exchange(self.index, &request) {
+            let mut bytes = vec![0; NTP_HEADER_SIZE];
+            reply
+                .try_write_to_bytes(&mut bytes)
+                .map_err(|_| io::ErrorKind::InvalidData)?;
+            self.reply = Some((bytes, arrival));
+        }
+        Ok(())
+    }
+
+    /// Returns the reply to the last request, or fails with `WouldBlock` if it
+    /// was lost
+    fn recv_from(&mut self, bytes: &mut [u8]) -> Result<Received, Self::Error> {
+        let (reply, timestamp) = self.reply.take().ok_or(io::ErrorKind::WouldBlock)?;
+        let size = reply.len().min(bytes.len());
+        bytes[..size].copy_from_slice(&reply[..size]);
+        Ok(Received {
+            size,
+            source: self.address,
+            timestamp: Some(timestamp),
+        })
+    }
+
+    fn transmit_timestamp(&mut self) -> Result<Option<NtpTimestamp>, Self::Error> {
+        Ok(self.transmitted.take())
+    }
+}
+
+/// Daemon disciplining a simulated clock with simulated servers, at
+/// 192.0.2.1, 192.0.2.2 and so on
+pub struct Simulation {
+    world: Arc<Mutex<World>>,
+    daemon: Daemon<SimulatedClock, SimulatedTransport>,
+    /// Instant the simulated time counts from
+    start: Instant,
+    elapsed: Duration,
+    next_poll: Instant,
+    next_record: Duration,
+    trace: Vec<TracePoint>,
+}
+
+impl Simulation {
+    /// # Panics
+    /// If there are more than 254 servers
+    pub fn new(config: SimulationConfig) -> Self {
+        assert!(config.servers.len() < 255, "too many servers");
+        let world = Arc::new(Mutex::new(World {
+            time: 0.0,
+            start: NtpTimestamp::from_unix(EPOCH, 0),
+            local: Oscillator::new(&config.local),
+            pll: Pll::new(),
+            servers: config
+                .servers
+                .into_iter()
+                .map(|server| {
+                    let clock = Oscillator::new(&server.clock);
+                    (server, clock)
+                })
+                .collect(),
+            random: Random::new(config.seed),
+        }));
+        let servers = lock(&world).servers.len();
+        let associations = (0..servers)
+            .map(|index| {
+                let address = SocketAddr::from(([192, 0, 2, index as u8 + 1], 123));
+                let transport = SimulatedTransport {
+                    address,
+                    index,
+                    world: world.clone(),
+                    reply: None,
+                    transmitted: None,
+                };
+                let client = NtpClientBuilder::new(transport, address.to_string())
+                    .build()
+                    .expect("the address resolves and the timeout is ignored");
+                let config = AssociationConfig {
+                    minpoll: config.minpoll,
+                    maxpoll: config.maxpoll,
+                    burst: config.burst,
+                    iburst: config.iburst,
+                };
+                Association::new(address.to_string(), address, client, config)
+            })
+            .collect();
+
+        let clock = SimulatedClock {
+            world: world.clone(),
+        };
+        let mut daemon =
+            Daemon::new(clock, LeapSeconds::new(LeapPolicy::Step)).with_seed(config.seed);
+        daemon.set_associations(associations);
+        // After the associations, which are due at the current instant
+        let start = Instant::now();
+        Self {
+            world,
+            daemon,
+            start,
+            elapsed: Duration::ZERO,
+            next_poll: start,
+            next_record: Duration::ZERO,
+            trace: Vec::new(),
+        }
+    }
+
+    pub fn daemon(&self) -> &Daemon<SimulatedClock, SimulatedTransport> {
+        &self.daemon
+    }
+
+    /// Simulated time since the start
+    pub fn elapsed(&self) -> Duration {
+        self.elapsed
+    }
+
+    /// Offset of the local clock from true time, in seconds
+    pub fn error(&self) -> f64 {
+        lock(&self.world).local.phase
+    }
+
+    /// Runs the daemon for `duration` of simulated time, recording the error
+    /// of the local clock every 16 seconds
+    pub fn run(&mut self, duration: Duration) -> NtpResult<()> {
+        let end = self.elapsed + duration;
+        loop {
+            let now = self.start + self.elapsed;
+            if now >= self.next_poll {
+                self.next_poll = self.daemon.poll_due(now)?;
+            }
+            if self.elapsed >= self.next_record {
+                self.record();
+                self.next_record += RECORD_INTERVAL;
+            }
+            if self.elapsed >= end {
+                return Ok(());
+            }
+            let next = (self.next_poll - self.start).min(self.next_record).min(end);
+            lock(&self.world).advance((next - self.elapsed).as_secs_f64());
+            self.elapsed = next;
+        }
+    }
+
+    fn record(&mut self) {
+        let world = lock(&self.world);
+        self.trace.push(TracePoint {
+            time: self.elapsed,
+            error: world.local.phase,
+            frequency_error: world.frequency_error(),
+        });
+    }
+
+    /// Errors of the local clock recorded so far
+    pub fn trace(&self) -> &[TracePoint] {
+        &self.trace
+    }
+
+    /// Time after which the error of the local clock stayed within
+    /// `threshold` seconds, if it is within it now
+    pub fn convergence_time(&self, threshold: f64) -> Option<Duration> {
+        let last = self.trace.last()?;
+        if last.error.abs() > threshold {
+            return None;
+        }
+        let time = self
+            .trace
+            .iter()
+            .rev()
+            .find(|point| point.error.abs() > threshold)
+            .map_or(Duration::ZERO, |point| point.time + RECORD_INTERVAL);
+        Some(time)
+    }
+
+    /// Accuracy of the local clock from `settle` on
+    ///
+    /// # Panics
+    /// If nothing was recorded from `settle` on
+    pub fn report(&self, settle: Duration) -> SimulationReport {
+        let points = self
+            .trace
+            .iter()
+            .filter(|point| point.time >= settle)
+            .collect::<Vec<_>>();
+        let last = points.last().expect("no trace after the settling time");
+        let squares = points.iter().map(|point| point.error.powi(2)).sum::<f64>();
+        SimulationReport {
+            rms_error: (squares / points.len() as f64).sqrt(),
+            max_error: points
+                .iter()
+                .map(|point| point.error.abs())
+                .fold(0.0, f64::max),
+            frequency_error: last.frequency_error,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pll_slews_offsets_and_trains_the_frequency() {
+        let mut pll = Pll::new();
+        pll.update(0.01, 0.0);
+        let slewed = (0..16).map(|_| pll.advance(1.0)).sum::<f64>();
+
+        // The default time constant slews out about 1 - 1/e in 16 seconds
+        assert!((slewed - 0.01 * (1.0 - (15.0f64 / 16.0).powi(16))).abs() < 1e-12);
+        assert_eq!(pll.frequency, 0.0);
+
+        pll.update(0.001, 16.0);
+        assert!((pll.frequency - 0.001 * 16.0 / 4096.0 * 1e6).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,187 @@
+//! Datagram services the client exchanges packets over. Besides the
+//! implementations here, network stacks without `std`, such as `smoltcp`, can
+//! implement `Transport` for their UDP sockets.
+
+use core::{net::SocketAddr, time::Duration};
+
+use crate::types::NtpTimestamp;
+
+#[cfg(all(feature = "std", target_os = "linux"))]
+pub mod linux;
+
+/// Datagram received by a transport
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Received {
+    /// Bytes written to the buffer
+    pub size: usize,
+    pub source: SocketAddr,
+    /// Time the datagram arrived, if the transport timestamps datagrams
+    pub timestamp: Option<NtpTimestamp>,
+}
+
+/// Sends and receives datagrams
+pub trait Transport {
+    type Error;
+
+    /// Makes `recv_from` give up after `timeout`, or wait forever with `None`
+    fn set_timeout(&mut self, timeout: Option<Duration>) -> Result<(), Self::Error>;
+
+    fn send_to(&mut self, bytes: &[u8], address: SocketAddr) -> Result<(), Self::Error>;
+
+    /// Waits for the next datagram and writes it to `bytes`, truncated to fit
+    fn recv_from(&mut self, bytes: &mut [u8]) -> Result<Received, Self::Error>;
+
+    /// Time the last datagram sent left, if the transport timestamps sent
+    /// datagrams
+    fn transmit_timestamp(&mut self) -> Result<Option<NtpTimestamp>, Self::Error> {
+        Ok(None)
+    }
+}
+
+#[cfg(feature = "std")]
+impl Transport for std::net::UdpSocket {
+    type Error = std::io::Error;
+
+    fn set_timeout(&mut self, timeout: Option<Duration>) -> Result<(), Self::Error> {
+        self.set_read_timeout(timeout)
+    }
+
+    fn send_to(&mut self, bytes: &[u8], address: SocketAddr) -> Result<(), Self::Error> {
+        std::net::UdpSocket::send_to(self, bytes, address).map(drop)
+    }
+
+    fn recv_from(&mut self, bytes: &mut [u8]) -> Result<Received, Self::Error> {
+        let (size, source) = std::net::UdpSocket::recv_from(self, bytes)?;
+        Ok(Received {
+            size,
+            source,
+            timestamp: None,
+        })
+    }
+}
+
+#[cfg(feature = "std")]
+pub use channel::ChannelTransport;
+
+#[cfg(feature = "std")]
+mod channel {
+    use std::{
+        io,
+        net::SocketAddr,
+        sync::mpsc::{self, Receiver, RecvTimeoutError, Sender},
+        time::Duration,
+    };
+
+    use super::{Received, Transport};
+
+    /// Datagram in flight, with the address of its sender
+    type Datagram = (Vec<u8>, SocketAddr);
+
+    /// One end of an in-memory link, to exchange packets in tests without
+    /// touching the network
+    #[derive(Debug)]
+    pub struct ChannelTransport {
+        address: SocketAddr,
+        peer: SocketAddr,
+        sender: Sender<Datagram>,
+        receiver: Receiver<Datagram>,
+        timeout: Option<Duration>,
+    }
+
+    impl ChannelTransport {
+        /// Links two ends, which see each other at `a` and `b`
+        pub fn pair(a: SocketAddr, b: SocketAddr) -> (Self, Self) {
+            let (a_sender, b_receiver) = mpsc::channel();
+            let (b_sender, a_receiver) = mpsc::channel();
+            (
+                Self {
+                    address: a,
+                    peer: b,
+                    sender: a_sender,
+                    receiver: a_receiver,
+                    timeout: None,
+                },
+                Self {
+                    address: b,
+                    peer: a,
+                    sender: b_sender,
+                    receiver: b_receiver,
+                    timeout: None,
+                },
+            )
+        }
+
+        pub fn address(&self) -> SocketAddr {
+            self.address
+        }
+    }
+
+    impl Transport for ChannelTransport {
+        type Error = io::Error;
+
+        fn set_timeout(&mut self, timeout: Option<Duration>) -> Result<(), Self::Error> {
+            self.timeout = timeout;
+            Ok(())
+        }
+
+        /// Sends `bytes` to the other end, which must be at `address`
+        fn send_to(&mut self, bytes: &[u8], address: SocketAddr) -> Result<(), Self::Error> {
+            if address != self.peer {
+                return Err(io::ErrorKind::HostUnreachable.into());
+            }
+            self.sender
+                .send((bytes.to_vec(), self.address))
+                .map_err(|_| io::ErrorKind::ConnectionAborted.into())
+        }
+
+        fn recv_from(&mut self, bytes: &mut [u8]) -> Result<Received, Self::Error> {
+            let (datagram, source) = match self.timeout {
+                Some(timeout) => {
+                    self.receiver
+                        .recv_timeout(timeout)
+                        .map_err(|error| match error {
+                            RecvTimeoutError::Timeout => io::ErrorKind::WouldBlock,
+                            RecvTimeoutError::Disconnected => io::ErrorKind::ConnectionAborted,
+                        })?
+                }
+                None => self
+                    .receiver
+                    .recv()
+                    .map_err(|_| io::ErrorKind::ConnectionAborted)?,
+            };
+            let size = datagram.len().min(bytes.len());
+            bytes[..size].copy_from_slice(&datagram[..size]);
+            Ok(Received {
+                size,
+                source,
+                timestamp: None,
+            })
+        }
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn channel_ends_exchange_datagrams() {
+        let a: SocketAddr = "192.0.2.1:123".parse().unwrap();
+        let b: SocketAddr = "192.0.2.2:50000".parse().unwrap();
+        let (mut server, mut client) = ChannelTransport::pair(a, b);
+        let mut buffer = [0u8; 4];
+
+        client.send_to(b"request", a).unwrap();
+        let received = server.recv_from(&mut buffer).unwrap();
+
+        assert_eq!(received.size, 4);
+        assert_eq!(received.source, b);
+        assert_eq!(&buffer, b"requ");
+        assert!(client.send_to(b"request", b).is_err());
+        client.set_timeout(Some(Duration::from_millis(1))).unwrap();
+        assert_eq!(
+            client.recv_from(&mut buffer).unwrap_err().kind(),
+            std::io::ErrorKind::WouldBlock
+        );
+    }
+}
@@ -0,0 +1,263 @@
+use std::{
+    io,
+    mem::{self, MaybeUninit},
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, UdpSocket},
+    os::fd::AsRawFd,
+    ptr,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    transport::{Received, Transport},
+    types::NtpTimestamp,
+};
+
+/// How long to wait for the kernel to report when a datagram was sent
+const TX_TIMESTAMP_TIMEOUT: Duration = Duration::from_millis(10);
+
+/// Size of the buffer for control messages, enough for timestamps and an
+/// extended error
+const CONTROL_SIZE: usize = 256;
+
+/// UDP socket timestamping datagrams in the kernel: received ones with
+/// `SO_TIMESTAMPNS`, sent ones with `SO_TIMESTAMPING`, read back from the
+/// error queue. The timestamps leave the scheduling latency of the process out
+/// of the measured offset.
+#[derive(Debug)]
+pub struct TimestampingSocket {
+    socket: UdpSocket,
+    transmitted: Option<NtpTimestamp>,
+}
+
+impl TimestampingSocket {
+    pub fn new(socket: UdpSocket) -> io::Result<Self> {
+        set_option(&socket, libc::SO_TIMESTAMPNS, 1)?;
+        let flags = libc::SOF_TIMESTAMPING_TX_SOFTWARE
+            | libc::SOF_TIMESTAMPING_SOFTWARE
+            | libc::SOF_TIMESTAMPING_OPT_TSONLY;
+        set_option(&socket, libc::SO_TIMESTAMPING, flags as libc::c_int)?;
+        Ok(Self {
+            socket,
+            transmitted: None,
+        })
+    }
+
+    pub fn socket(&self) -> &UdpSocket {
+        &self.socket
+    }
+
+    /// Receives from the socket or its error queue, with the kernel timestamp
+    /// of the datagram, if any
+    fn recv_msg(
+        &self,
+        bytes: &mut [u8],
+        flags: libc::c_int,
+    ) -> io::Result<(usize, Option<SocketAddr>, Option<NtpTimestamp>)> {
+        let mut iov = libc::iovec {
+            iov_base: bytes.as_mut_ptr().cast(),
+            iov_len: bytes.len(),
+        };
+        let mut address = MaybeUninit::<libc::sockaddr_storage>::zeroed();
+        // Aligned for the `cmsghdr`s the kernel writes into it
+        let mut control = [0u64; CONTROL_SIZE / 8];
+        // SAFETY: `msghdr` is a plain C struct for which all zeroes is a valid value
+        let mut message: libc::msghdr = unsafe { mem::zeroed() };
+        message.msg_name = address.as_mut_ptr().cast();
+        message.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+        message.msg_iov = &mut iov;
+        message.msg_iovlen = 1;
+        message.msg_control = control.as_mut_ptr().cast();
+        message.msg_controllen = CONTROL_SIZE as _;
+
+        // SAFETY: every buffer `message` points to is valid for writes of the
+        // advertised length and outlives the call
+        let size = unsafe { libc::recvmsg(self.socket.as_raw_fd(), &mut message, flags) };
+        if size < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: the kernel wrote an address of `msg_namelen` bytes, if any
+        let source = unsafe { socket_address(address.as_ptr(), message.msg_namelen) };
+        // SAFETY: the kernel wrote `msg_controllen` bytes of control messages
+        let timestamp = unsafe { timestamp(&message) };
+        Ok((size as usize, source, timestamp))
+    }
+
+    /// Drops transmit timestamps that were not collected in time
+    fn drain_error_queue(&self) -> io::Result<()> {
+        loop {
+            match self.recv_msg(&mut [], libc::MSG_ERRQUEUE | libc::MSG_DONTWAIT) {
+                Ok(_) => continue,
+                Err(error) if error.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(error) => return Err(error),
+            }
+        }
+    }
+
+    /// Waits for the timestamp of the datagram just sent on the error queue
+    fn read_transmit_timestamp(&self) -> io::Result<Option<NtpTimestamp>> {
+        let deadline = Instant::now() + TX_TIMESTAMP_TIMEOUT;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            // Errors are reported whatever the requested events
+            let mut pollfd = libc::pollfd {
+                fd: self.socket.as_raw_fd(),
+                events: 0,
+                revents: 0,
+            };
+            // SAFETY: `pollfd` is valid for writes and is the only entry
+            let ready = unsafe { libc::poll(&mut pollfd, 1, remaining.as_millis() as libc::c_int) };
+            if ready < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            if ready == 0 {
+                return Ok(None);
+            }
+            match self.recv_msg(&mut [], libc::MSG_ERRQUEUE | libc::MSG_DONTWAIT) {
+                Ok((_, _, Some(timestamp))) => return Ok(Some(timestamp)),
+                Ok(_) => continue,
+                Err(error) if error.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(error) => return Err(error),
+            }
+        }
+    }
+}
+
+impl Transport for TimestampingSocket {
+    type Error = io::Error;
+
+    fn set_timeout(&mut self, timeout: Option<Duration>) -> Result<(), Self::Error> {
+        self.socket.set_read_timeout(timeout)
+    }
+
+    fn send_to(&mut self, bytes: &[u8], address: SocketAddr) -> Result<(), Self::Error> {
+        self.drain_error_queue()?;
+        self.socket.send_to(bytes, address)?;
+        self.transmitted = self.read_transmit_timestamp()?;
+        Ok(())
+    }
+
+    fn recv_from(&mut self, bytes: &mut [u8]) -> Result<Received, Self::Error> {
+        let (size, source, timestamp) = self.recv_msg(bytes, 0)?;
+        let source = source.ok_or(io::ErrorKind::InvalidData)?;
+        Ok(Received {
+            size: size.min(bytes.len()),
+            source,
+            timestamp,
+        })
+    }
+
+    fn transmit_timestamp(&mut self) -> Result<Option<NtpTimestamp>, Self::Error> {
+        Ok(self.transmitted.take())
+    }
+}
+
+fn set_option(socket: &UdpSocket, option: libc::c_int, value: libc::c_int) -> io::Result<()> {
+    // SAFETY: `value` is a valid `c_int` for the duration of the call
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            option,
+            ptr::from_ref(&value).cast(),
+            mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Converts the address filled in by `recvmsg`
+///
+/// # Safety
+/// `address` must point to an initialised `sockaddr_storage` holding an
+/// address of `len` bytes
+unsafe fn socket_address(
+    address: *const libc::sockaddr_storage,
+    len: libc::socklen_t,
+) -> Option<SocketAddr> {
+    let len = len as usize;
+    match (*address).ss_family as libc::c_int {
+        libc::AF_INET if len >= mem::size_of::<libc::sockaddr_in>() => {
+            let address = &*address.cast::<libc::sockaddr_in>();
+            Some(SocketAddr::V4(SocketAddrV4::new(
+                Ipv4Addr::from(u32::from_be(address.sin_addr.s_addr)),
+                u16::from_be(address.sin_port),
+            )))
+        }
+        libc::AF_INET6 if len >= mem::size_of::<libc::sockaddr_in6>() => {
+            let address = &*address.cast::<libc::sockaddr_in6>();
+            Some(SocketAddr::V6(SocketAddrV6::new(
+                Ipv6Addr::from(address.sin6_addr.s6_addr),
+                u16::from_be(address.sin6_port),
+                address.sin6_flowinfo,
+                address.sin6_scope_id,
+            )))
+        }
+        _ => None,
+    }
+}
+
+/// Kernel timestamp among the control messages of `message`
+///
+/// # Safety
+/// The control buffer of `message` must hold `msg_controllen` bytes of control
+/// messages written by `recvmsg`
+unsafe fn timestamp(message: &libc::msghdr) -> Option<NtpTimestamp> {
+    let mut header = libc::CMSG_FIRSTHDR(message);
+    while !header.is_null() {
+        let data = libc::CMSG_DATA(header);
+        match ((*header).cmsg_level, (*header).cmsg_type) {
+            (libc::SOL_SOCKET, libc::SCM_TIMESTAMPNS) => {
+                let time = ptr::read_unaligned(data.cast::<libc::timespec>());
+                return Some(to_timestamp(time));
+            }
+            // The software timestamp comes first, before deprecated and
+            // hardware ones
+            (libc::SOL_SOCKET, libc::SCM_TIMESTAMPING) => {
+                let times = ptr::read_unaligned(data.cast::<[libc::timespec; 3]>());
+                return Some(to_timestamp(times[0]));
+            }
+            _ => {}
+        }
+        header = libc::CMSG_NXTHDR(message, header);
+    }
+    None
+}
+
+fn to_timestamp(time: libc::timespec) -> NtpTimestamp {
+    NtpTimestamp::from_unix(time.tv_sec as _, time.tv_nsec as _)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::clock::system_time;
+
+    use super::*;
+
+    #[test]
+    fn loopback_datagrams_are_timestamped_by_the_kernel() {
+        let mut socket = TimestampingSocket::new(UdpSocket::bind("127.0.0.1:0").unwrap()).unwrap();
+        let address = socket.socket().local_addr().unwrap();
+        let peer = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let peer_address = peer.local_addr().unwrap();
+        let mut buffer = [0u8; 16];
+
+        let before = system_time();
+        socket.send_to(b"request", peer_address).unwrap();
+        let transmitted = socket.transmit_timestamp().unwrap().unwrap();
+        peer.recv_from(&mut buffer).unwrap();
+        peer.send_to(b"reply", address).unwrap();
+        let received = socket.recv_from(&mut buffer).unwrap();
+        let after = system_time();
+
+        assert_eq!(&buffer[..received.size], b"reply");
+        assert_eq!(received.source, peer_address);
+        let received = received.timestamp.unwrap();
+        assert!(transmitted.diff_seconds(&before) >= 0.0);
+        assert!(received.diff_seconds(&transmitted) >= 0.0);
+        assert!(after.diff_seconds(&received) >= 0.0);
+        assert_eq!(socket.transmit_timestamp().unwrap(), None);
+    }
+}
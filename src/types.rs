@@ -1,84 +1,167 @@
-use crate::codec::{TryReadFromBytes, TryWriteToBytes};
+use core::{
+    fmt,
+    net::{IpAddr, Ipv4Addr},
+    time::Duration,
+};
 
+use md5::{Digest as _, Md5};
+
+use crate::codec::{CodecError, TryReadFromBytes, TryWriteToBytes};
+
+/// Leap indicator, warning of a leap second at the end of the current day
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub struct Leap(u8);
+pub enum Leap {
+    NoWarning,
+    LastMinuteHas61Seconds,
+    LastMinuteHas59Seconds,
+    /// The clock is not synchronized
+    Unknown,
+}
 
-pub const NTP_LEAP_NO_WARNING: Leap = Leap(0);
-pub const NTP_LEAP_LAST_MINUTE_HAS_61_SECONDS: Leap = Leap(1);
-pub const NTP_LEAP_LAST_MINUTE_HAS_59_SECONDS: Leap = Leap(2);
-pub const NTP_LEAP_UNKNOWN: Leap = Leap(3);
+pub const NTP_LEAP_NO_WARNING: Leap = Leap::NoWarning;
+pub const NTP_LEAP_LAST_MINUTE_HAS_61_SECONDS: Leap = Leap::LastMinuteHas61Seconds;
+pub const NTP_LEAP_LAST_MINUTE_HAS_59_SECONDS: Leap = Leap::LastMinuteHas59Seconds;
+pub const NTP_LEAP_UNKNOWN: Leap = Leap::Unknown;
 
 impl TryFrom<u8> for Leap {
-    type Error = &'static str;
+    type Error = CodecError;
 
     fn try_from(value: u8) -> Result<Self, Self::Error> {
-        if value > 3 {
-            return Err("Value out of range for leap");
+        match value {
+            0 => Ok(Self::NoWarning),
+            1 => Ok(Self::LastMinuteHas61Seconds),
+            2 => Ok(Self::LastMinuteHas59Seconds),
+            3 => Ok(Self::Unknown),
+            _ => Err(CodecError::invalid("Value out of range for leap")),
         }
-
-        Ok(Self(value))
     }
 }
 
 impl From<Leap> for u8 {
     fn from(value: Leap) -> Self {
-        value.0
+        match value {
+            Leap::NoWarning => 0,
+            Leap::LastMinuteHas61Seconds => 1,
+            Leap::LastMinuteHas59Seconds => 2,
+            Leap::Unknown => 3,
+        }
     }
 }
 
+/// Protocol version number
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub struct Version(u8);
+pub enum Version {
+    V1,
+    V2,
+    V3,
+    V4,
+    /// Any other value that fits in the 3-bit field
+    Unknown(UnknownVersion),
+}
 
-pub const NTP_VERSION_4: Version = Version(4);
+/// Version number 0, 5, 6 or 7, only built by `Version::try_from`, so that
+/// every value of the field has one representation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UnknownVersion(u8);
+
+impl UnknownVersion {
+    pub fn value(&self) -> u8 {
+        self.0
+    }
+}
+
+pub const NTP_VERSION_4: Version = Version::V4;
+
+impl Version {
+    /// Whether packets of this version can be parsed and answered
+    pub fn is_supported(&self) -> bool {
+        !matches!(self, Self::Unknown(_))
+    }
+}
 
 impl TryFrom<u8> for Version {
-    type Error = &'static str;
+    type Error = CodecError;
 
     fn try_from(value: u8) -> Result<Self, Self::Error> {
-        if value > 7 {
-            return Err("Value out of range for version");
+        match value {
+            1 => Ok(Self::V1),
+            2 => Ok(Self::V2),
+            3 => Ok(Self::V3),
+            4 => Ok(Self::V4),
+            0 | 5..=7 => Ok(Self::Unknown(UnknownVersion(value))),
+            _ => Err(CodecError::invalid("Value out of range for version")),
         }
-
-        Ok(Self(value))
     }
 }
 
 impl From<Version> for u8 {
     fn from(value: Version) -> Self {
-        value.0
+        match value {
+            Version::V1 => 1,
+            Version::V2 => 2,
+            Version::V3 => 3,
+            Version::V4 => 4,
+            Version::Unknown(value) => value.value(),
+        }
     }
 }
 
+/// Association mode of the sender of a packet
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub struct Mode(u8);
+pub enum Mode {
+    Reserved,
+    SymmetricActive,
+    SymmetricPassive,
+    Client,
+    Server,
+    Broadcast,
+    ControlMessage,
+    ReservedForPrivateUse,
+}
+
 impl TryFrom<u8> for Mode {
-    type Error = &'static str;
+    type Error = CodecError;
 
     fn try_from(value: u8) -> Result<Self, Self::Error> {
-        if value > 7 {
-            return Err("Value out of range for mode");
+        match value {
+            0 => Ok(Self::Reserved),
+            1 => Ok(Self::SymmetricActive),
+            2 => Ok(Self::SymmetricPassive),
+            3 => Ok(Self::Client),
+            4 => Ok(Self::Server),
+            5 => Ok(Self::Broadcast),
+            6 => Ok(Self::ControlMessage),
+            7 => Ok(Self::ReservedForPrivateUse),
+            _ => Err(CodecError::invalid("Value out of range for mode")),
         }
-
-        Ok(Self(value))
     }
 }
 
 impl From<Mode> for u8 {
     fn from(value: Mode) -> Self {
-        value.0
+        match value {
+            Mode::Reserved => 0,
+            Mode::SymmetricActive => 1,
+            Mode::SymmetricPassive => 2,
+            Mode::Client => 3,
+            Mode::Server => 4,
+            Mode::Broadcast => 5,
+            Mode::ControlMessage => 6,
+            Mode::ReservedForPrivateUse => 7,
+        }
     }
 }
 
-pub const NTP_MODE_RESERVED: Mode = Mode(0);
-pub const NTP_MODE_SYMMETRIC_ACTIVE: Mode = Mode(1);
-pub const NTP_MODE_SYMMETRIC_PASSIVE: Mode = Mode(2);
-pub const NTP_MODE_CLIENT: Mode = Mode(3);
-pub const NTP_MODE_SERVER: Mode = Mode(4);
-pub const NTP_MODE_BROADCAST: Mode = Mode(5);
-pub const NTP_MODE_CONTROL_MESSAGE: Mode = Mode(6);
-pub const NTP_MODE_RESERVED_FOR_PRIVATE_USE: Mode = Mode(7);
+pub const NTP_MODE_RESERVED: Mode = Mode::Reserved;
+pub const NTP_MODE_SYMMETRIC_ACTIVE: Mode = Mode::SymmetricActive;
+pub const NTP_MODE_SYMMETRIC_PASSIVE: Mode = Mode::SymmetricPassive;
+pub const NTP_MODE_CLIENT: Mode = Mode::Client;
+pub const NTP_MODE_SERVER: Mode = Mode::Server;
+pub const NTP_MODE_BROADCAST: Mode = Mode::Broadcast;
+pub const NTP_MODE_CONTROL_MESSAGE: Mode = Mode::ControlMessage;
+pub const NTP_MODE_RESERVED_FOR_PRIVATE_USE: Mode = Mode::ReservedForPrivateUse;
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, TryWriteToBytes, TryReadFromBytes)]
 pub struct Stratum(u8);
 
 impl From<u8> for Stratum {
@@ -93,24 +176,10 @@ impl From<Stratum> for u8 {
     }
 }
 
-impl TryWriteToBytes for Stratum {
-    type Error = &'static str;
-
-    fn try_write_to_bytes(&self, bytes: &mut [u8]) -> Result<usize, Self::Error> {
-        self.0.try_write_to_bytes(bytes)
-    }
-}
-
-impl<'a> TryReadFromBytes<'a> for Stratum {
-    type Error = &'static str;
+/// Stratum of a server that is not synchronized
+pub const NTP_MAXSTRAT: u8 = 16;
 
-    fn try_read_from_bytes(bytes: &'a [u8]) -> Result<(Self, usize), Self::Error> {
-        let (value, size) = u8::try_read_from_bytes(bytes)?;
-        Ok((Self(value), size))
-    }
-}
-
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, TryWriteToBytes, TryReadFromBytes)]
 pub struct Poll(i8);
 
 impl From<i8> for Poll {
@@ -125,24 +194,23 @@ impl From<Poll> for i8 {
     }
 }
 
-impl TryWriteToBytes for Poll {
-    type Error = &'static str;
-
-    fn try_write_to_bytes(&self, bytes: &mut [u8]) -> Result<usize, Self::Error> {
-        self.0.try_write_to_bytes(bytes)
-    }
-}
-
-impl<'a> TryReadFromBytes<'a> for Poll {
-    type Error = &'static str;
-
-    fn try_read_from_bytes(bytes: &'a [u8]) -> Result<(Self, usize), Self::Error> {
-        let (value, size) = i8::try_read_from_bytes(bytes)?;
-        Ok((Self(value), size))
+impl Poll {
+    /// Interval the exponent stands for, 2^poll seconds
+    pub fn to_duration(&self) -> Duration {
+        match self.0 {
+            poll @ 0.. => 1u64
+                .checked_shl(poll as u32)
+                .map_or(Duration::MAX, Duration::from_secs),
+            poll => Duration::from_nanos(
+                1_000_000_000u64
+                    .checked_shr(poll.unsigned_abs().into())
+                    .unwrap_or(0),
+            ),
+        }
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, TryWriteToBytes, TryReadFromBytes)]
 pub struct Precision(i8);
 
 impl From<i8> for Precision {
@@ -157,56 +225,89 @@ impl From<Precision> for i8 {
     }
 }
 
-impl TryWriteToBytes for Precision {
-    type Error = &'static str;
+#[derive(Debug, Clone, Copy, PartialEq, Eq, TryWriteToBytes, TryReadFromBytes)]
+pub struct RefId([u8; 4]);
 
-    fn try_write_to_bytes(&self, bytes: &mut [u8]) -> Result<usize, Self::Error> {
-        self.0.try_write_to_bytes(bytes)
+impl From<[u8; 4]> for RefId {
+    fn from(value: [u8; 4]) -> Self {
+        Self(value)
     }
 }
 
-impl<'a> TryReadFromBytes<'a> for Precision {
-    type Error = &'static str;
-
-    fn try_read_from_bytes(bytes: &'a [u8]) -> Result<(Self, usize), Self::Error> {
-        let (value, size) = i8::try_read_from_bytes(bytes)?;
-        Ok((Self(value), size))
+impl From<RefId> for [u8; 4] {
+    fn from(value: RefId) -> Self {
+        value.0
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub struct RefId([u8; 4]);
-
-impl TryWriteToBytes for RefId {
-    type Error = &'static str;
-
-    fn try_write_to_bytes(&self, bytes: &mut [u8]) -> Result<usize, Self::Error> {
-        self.0.try_write_to_bytes(bytes)
+impl From<IpAddr> for RefId {
+    /// Reference identifier a server synchronized to `peer` advertises: the
+    /// IPv4 address itself, or the first four bytes of the MD5 hash of an IPv6 address
+    fn from(peer: IpAddr) -> Self {
+        match peer {
+            IpAddr::V4(address) => Self(address.octets()),
+            IpAddr::V6(address) => {
+                let hash = Md5::digest(address.octets());
+                Self([hash[0], hash[1], hash[2], hash[3]])
+            }
+        }
     }
 }
 
-impl<'a> TryReadFromBytes<'a> for RefId {
-    type Error = &'static str;
-
-    fn try_read_from_bytes(bytes: &'a [u8]) -> Result<(Self, usize), Self::Error> {
-        let (value, size) = <[u8; 4]>::try_read_from_bytes(bytes)?;
-        Ok((Self(value), size))
+impl RefId {
+    /// Interprets the identifier of a packet with `stratum` received from `source`
+    pub fn kind(&self, stratum: Stratum, source: IpAddr) -> RefIdKind {
+        match (u8::from(stratum), source) {
+            (0, _) => RefIdKind::KissCode(self.0),
+            (1, _) => RefIdKind::Source(self.0),
+            (_, IpAddr::V4(_)) => RefIdKind::Ipv4(Ipv4Addr::from(self.0)),
+            (_, IpAddr::V6(_)) => RefIdKind::Ipv6Hash(self.0),
+        }
     }
 }
 
-impl From<[u8; 4]> for RefId {
-    fn from(value: [u8; 4]) -> Self {
-        Self(value)
-    }
-}
+pub const NTP_KISS_DENY: RefId = RefId(*b"DENY");
+pub const NTP_KISS_RSTR: RefId = RefId(*b"RSTR");
+pub const NTP_KISS_RATE: RefId = RefId(*b"RATE");
+pub const NTP_KISS_INIT: RefId = RefId(*b"INIT");
+pub const NTP_KISS_STEP: RefId = RefId(*b"STEP");
 
-impl From<RefId> for [u8; 4] {
-    fn from(value: RefId) -> Self {
-        value.0
+/// Meaning of a reference identifier, which depends on the stratum
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RefIdKind {
+    /// Kiss-o'-Death code sent at stratum 0, such as `RATE` or `DENY`
+    KissCode([u8; 4]),
+    /// Reference source of a stratum 1 server, such as `GPS` or `PPS`
+    Source([u8; 4]),
+    /// Address of the upstream server of a stratum 2+ server reached over IPv4
+    Ipv4(Ipv4Addr),
+    /// First four bytes of the MD5 hash of the upstream server's IPv6 address
+    Ipv6Hash([u8; 4]),
+}
+
+impl fmt::Display for RefIdKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::KissCode(code) | Self::Source(code) => {
+                // Codes shorter than four characters are padded with NULs
+                for &byte in code.iter().take_while(|&&byte| byte != 0) {
+                    let printable = byte.is_ascii_graphic() || byte == b' ';
+                    write!(f, "{}", if printable { byte as char } else { '.' })?;
+                }
+                Ok(())
+            }
+            Self::Ipv4(address) => write!(f, "{address}"),
+            Self::Ipv6Hash(hash) => {
+                for byte in hash {
+                    write!(f, "{byte:02x}")?;
+                }
+                Ok(())
+            }
+        }
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, TryWriteToBytes)]
 pub struct Digest([u8; 16]);
 
 impl From<[u8; 16]> for Digest {
@@ -221,34 +322,9 @@ impl From<Digest> for [u8; 16] {
     }
 }
 
-impl TryWriteToBytes for Digest {
-    type Error = &'static str;
-
-    fn try_write_to_bytes(&self, bytes: &mut [u8]) -> Result<usize, Self::Error> {
-        self.0.try_write_to_bytes(bytes)
-    }
-}
-
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, TryWriteToBytes, TryReadFromBytes)]
 pub struct NtpShort(u32);
 
-impl TryWriteToBytes for NtpShort {
-    type Error = &'static str;
-
-    fn try_write_to_bytes(&self, bytes: &mut [u8]) -> Result<usize, Self::Error> {
-        self.0.try_write_to_bytes(bytes)
-    }
-}
-
-impl<'a> TryReadFromBytes<'a> for NtpShort {
-    type Error = &'static str;
-
-    fn try_read_from_bytes(bytes: &'a [u8]) -> Result<(Self, usize), Self::Error> {
-        let (value, size) = u32::try_read_from_bytes(bytes)?;
-        Ok((Self(value), size))
-    }
-}
-
 impl NtpShort {
     pub fn new(seconds: u16, fraction: u16) -> Self {
         Self(((seconds as u32) << 16) | (fraction as u32))
@@ -261,9 +337,21 @@ impl NtpShort {
     pub fn fraction(&self) -> u16 {
         self.0 as u16
     }
+
+    /// Converts a duration in seconds, saturating at the limits of the format
+    pub fn from_seconds(seconds: f64) -> Self {
+        Self((seconds * NTP_SHORT_SCALE).clamp(0.0, u32::MAX as f64) as u32)
+    }
+
+    pub fn to_seconds(&self) -> f64 {
+        self.0 as f64 / NTP_SHORT_SCALE
+    }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Number of short format units in one second
+const NTP_SHORT_SCALE: f64 = 65_536.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, TryWriteToBytes, TryReadFromBytes)]
 pub struct NtpTimestamp(u64);
 
 impl NtpTimestamp {
@@ -278,28 +366,136 @@ impl NtpTimestamp {
     pub fn fraction(&self) -> u32 {
         self.0 as u32
     }
-}
 
-impl TryWriteToBytes for NtpTimestamp {
-    type Error = &'static str;
+    /// Builds a timestamp from a time relative to the Unix epoch. Times outside
+    /// the current era wrap around, as they do on the wire.
+    pub fn from_unix(seconds: i64, nanos: u32) -> Self {
+        let seconds = seconds.wrapping_add(NTP_UNIX_EPOCH_OFFSET) as u32;
+        let fraction = ((nanos as u64) << 32) / 1_000_000_000;
+        Self::new(seconds, fraction as u32)
+    }
 
-    fn try_write_to_bytes(&self, bytes: &mut [u8]) -> Result<usize, Self::Error> {
-        self.0.try_write_to_bytes(bytes)
+    /// Converts the timestamp to seconds and nanoseconds since the Unix epoch,
+    /// assuming it belongs to era 0.
+    pub fn to_unix(&self) -> (i64, u32) {
+        let seconds = self.seconds() as i64 - NTP_UNIX_EPOCH_OFFSET;
+        let nanos = ((self.fraction() as u64 * 1_000_000_000) >> 32) as u32;
+        (seconds, nanos)
     }
-}
 
-impl<'a> TryReadFromBytes<'a> for NtpTimestamp {
-    type Error = &'static str;
+    /// Signed difference `self - other` in seconds. The result is correct across
+    /// era boundaries as long as both timestamps are within 68 years of each other.
+    pub fn diff_seconds(&self, other: &Self) -> f64 {
+        self.0.wrapping_sub(other.0) as i64 as f64 / NTP_TIMESTAMP_SCALE
+    }
 
-    fn try_read_from_bytes(bytes: &'a [u8]) -> Result<(Self, usize), Self::Error> {
-        let (value, size) = u64::try_read_from_bytes(bytes)?;
-        Ok((Self(value), size))
+    /// Returns the timestamp shifted by `seconds`, wrapping around the era.
+    pub fn add_seconds(&self, seconds: f64) -> Self {
+        let delta = (seconds * NTP_TIMESTAMP_SCALE) as i64;
+        Self(self.0.wrapping_add(delta as u64))
     }
 }
 
+/// Seconds between the NTP epoch (1900-01-01) and the Unix epoch (1970-01-01)
+pub const NTP_UNIX_EPOCH_OFFSET: i64 = 2_208_988_800;
+
+/// Number of timestamp units in one second
+const NTP_TIMESTAMP_SCALE: f64 = 4_294_967_296.0;
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct NtpDate {
     era_number: u32,
     era_offset: u32,
     fraction: u64,
 }
+
+#[cfg(test)]
+mod tests {
+    use core::net::Ipv6Addr;
+
+    use super::*;
+
+    const IPV4_SOURCE: IpAddr = IpAddr::V4(Ipv4Addr::new(198, 51, 100, 7));
+    const IPV6_SOURCE: IpAddr = IpAddr::V6(Ipv6Addr::LOCALHOST);
+
+    #[test]
+    fn header_bit_fields_round_trip() {
+        for value in 0..=7u8 {
+            let mode = Mode::try_from(value).unwrap();
+            let version = Version::try_from(value).unwrap();
+            assert_eq!(u8::from(mode), value);
+            assert_eq!(u8::from(version), value);
+        }
+        for value in 0..=3u8 {
+            assert_eq!(u8::from(Leap::try_from(value).unwrap()), value);
+        }
+
+        assert!(Leap::try_from(4).is_err());
+        assert!(Mode::try_from(8).is_err());
+        assert!(Version::try_from(8).is_err());
+    }
+
+    #[test]
+    fn poll_exponent_converts_to_an_interval() {
+        assert_eq!(Poll::from(6).to_duration(), Duration::from_secs(64));
+        assert_eq!(Poll::from(0).to_duration(), Duration::from_secs(1));
+        assert_eq!(Poll::from(-2).to_duration(), Duration::from_millis(250));
+        assert_eq!(Poll::from(-40).to_duration(), Duration::ZERO);
+        assert_eq!(Poll::from(127).to_duration(), Duration::MAX);
+    }
+
+    #[test]
+    fn only_versions_1_to_4_are_supported() {
+        let unknown = Version::try_from(5).unwrap();
+        assert!(matches!(unknown, Version::Unknown(version) if version.value() == 5));
+        assert!(!unknown.is_supported());
+        assert!(Version::V3.is_supported());
+        assert_eq!(Version::try_from(4), Ok(Version::V4));
+    }
+
+    #[test]
+    fn refid_kind_depends_on_stratum() {
+        let refid = RefId::from(*b"GPS\0");
+
+        assert_eq!(
+            refid.kind(Stratum::from(0), IPV4_SOURCE),
+            RefIdKind::KissCode(*b"GPS\0")
+        );
+        assert_eq!(
+            refid.kind(Stratum::from(1), IPV6_SOURCE),
+            RefIdKind::Source(*b"GPS\0")
+        );
+        assert_eq!(
+            refid.kind(Stratum::from(2), IPV4_SOURCE),
+            RefIdKind::Ipv4(Ipv4Addr::new(71, 80, 83, 0))
+        );
+        assert_eq!(
+            refid.kind(Stratum::from(3), IPV6_SOURCE),
+            RefIdKind::Ipv6Hash(*b"GPS\0")
+        );
+    }
+
+    #[test]
+    fn refid_kind_display() {
+        let source = RefId::from(*b"PPS\0").kind(Stratum::from(1), IPV4_SOURCE);
+        let kiss = NTP_KISS_RATE.kind(Stratum::from(0), IPV4_SOURCE);
+        let address = RefId::from([192, 0, 2, 1]).kind(Stratum::from(2), IPV4_SOURCE);
+        let hash = RefId::from([0x39, 0xab, 0x9b, 0x37]).kind(Stratum::from(2), IPV6_SOURCE);
+        let garbage = RefId::from([b'A', 7, b'B', 0]).kind(Stratum::from(1), IPV4_SOURCE);
+
+        assert_eq!(source.to_string(), "PPS");
+        assert_eq!(kiss.to_string(), "RATE");
+        assert_eq!(address.to_string(), "192.0.2.1");
+        assert_eq!(hash.to_string(), "39ab9b37");
+        assert_eq!(garbage.to_string(), "A.B");
+    }
+
+    #[test]
+    fn refid_from_peer_address() {
+        let ipv4 = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        let ipv6 = IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1));
+
+        assert_eq!(RefId::from(ipv4), RefId::from([192, 0, 2, 1]));
+        assert_eq!(RefId::from(ipv6), RefId::from([0x39, 0xab, 0x9b, 0x37]));
+    }
+}
@@ -0,0 +1,153 @@
+//! Round trips of every wire format type, and reads of arbitrary bytes, which
+//! must fail cleanly rather than panic
+
+use demo_ntp::{
+    codec::{TryReadFromBytes, TryWriteToBytes},
+    ntp_message_protocol::NtpPacketHeader,
+    types::{Digest, Leap, Mode, NtpShort, NtpTimestamp, Poll, Precision, RefId, Stratum, Version},
+};
+use proptest::prelude::*;
+
+/// Writes `value` into buffers too small for it, then reads it back
+fn assert_round_trip<T>(value: T, size: usize)
+where
+    T: TryWriteToBytes + for<'a> TryReadFromBytes<'a> + PartialEq + std::fmt::Debug,
+{
+    let mut bytes = vec![0u8; size];
+    for len in 0..size {
+        assert!(value.try_write_to_bytes(&mut bytes[..len]).is_err());
+    }
+    assert_eq!(value.try_write_to_bytes(&mut bytes).ok(), Some(size));
+    assert_eq!(T::try_read_from_bytes(&bytes).ok(), Some((value, size)));
+    for len in 0..size {
+        assert!(T::try_read_from_bytes(&bytes[..len]).is_err());
+    }
+}
+
+/// Reads arbitrary bytes and, if they parse, writes them back unchanged
+fn assert_read_is_exact<T>(bytes: &[u8])
+where
+    T: TryWriteToBytes + for<'a> TryReadFromBytes<'a>,
+{
+    if let Ok((value, size)) = T::try_read_from_bytes(bytes) {
+        let mut written = vec![0u8; size];
+        assert_eq!(value.try_write_to_bytes(&mut written).ok(), Some(size));
+        assert_eq!(written, bytes[..size]);
+    }
+}
+
+fn leap() -> impl Strategy<Value = Leap> {
+    (0..4u8).prop_map(|value| Leap::try_from(value).unwrap())
+}
+
+fn version() -> impl Strategy<Value = Version> {
+    (1..=4u8).prop_map(|value| Version::try_from(value).unwrap())
+}
+
+fn mode() -> impl Strategy<Value = Mode> {
+    (0..8u8).prop_map(|value| Mode::try_from(value).unwrap())
+}
+
+fn short() -> impl Strategy<Value = NtpShort> {
+    any::<(u16, u16)>().prop_map(|(seconds, fraction)| NtpShort::new(seconds, fraction))
+}
+
+fn timestamp() -> impl Strategy<Value = NtpTimestamp> {
+    any::<(u32, u32)>().prop_map(|(seconds, fraction)| NtpTimestamp::new(seconds, fraction))
+}
+
+fn header() -> impl Strategy<Value = NtpPacketHeader> {
+    (
+        (leap(), version(), mode()),
+        any::<(u8, i8, i8)>(),
+        (short(), short(), any::<[u8; 4]>()),
+        (timestamp(), timestamp(), timestamp(), timestamp()),
+    )
+        .prop_map(
+            |(
+                (leap_indicator, version_number, mode),
+                (stratum, poll, precision),
+                (rootdelay, rootdisp, refid),
+                (reftime, org, rec, xmt),
+            )| NtpPacketHeader {
+                leap_indicator,
+                version_number,
+                mode,
+                stratum: Stratum::from(stratum),
+                poll: Poll::from(poll),
+                precision: Precision::from(precision),
+                rootdelay,
+                rootdisp,
+                refid: RefId::from(refid),
+                reftime,
+                org,
+                rec,
+                xmt,
+            },
+        )
+}
+
+proptest! {
+    #[test]
+    fn integers_round_trip(a: u8, b: i8, c: u16, d: u32, e: i32, f: u64, g: [u8; 5]) {
+        assert_round_trip(a, 1);
+        assert_round_trip(b, 1);
+        assert_round_trip(c, 2);
+        assert_round_trip(d, 4);
+        assert_round_trip(e, 4);
+        assert_round_trip(f, 8);
+        assert_round_trip(g, 5);
+    }
+
+    #[test]
+    fn types_round_trip(
+        stratum: u8,
+        poll: i8,
+        precision: i8,
+        refid: [u8; 4],
+        short in short(),
+        timestamp in timestamp(),
+    ) {
+        assert_round_trip(Stratum::from(stratum), 1);
+        assert_round_trip(Poll::from(poll), 1);
+        assert_round_trip(Precision::from(precision), 1);
+        assert_round_trip(RefId::from(refid), 4);
+        assert_round_trip(short, 4);
+        assert_round_trip(timestamp, 8);
+    }
+
+    #[test]
+    fn digest_is_written_whole(digest: [u8; 16], len in 0..32usize) {
+        let mut bytes = vec![0u8; len];
+        let written = Digest::from(digest).try_write_to_bytes(&mut bytes);
+        if len < 16 {
+            prop_assert!(written.is_err());
+        } else {
+            prop_assert_eq!(written.ok(), Some(16));
+            prop_assert_eq!(&bytes[..16], &digest[..]);
+        }
+    }
+
+    #[test]
+    fn header_round_trips(header in header()) {
+        assert_round_trip(header, 48);
+    }
+
+    #[test]
+    fn arbitrary_bytes_are_read_exactly(bytes in prop::collection::vec(any::<u8>(), 0..64)) {
+        assert_read_is_exact::<u8>(&bytes);
+        assert_read_is_exact::<i8>(&bytes);
+        assert_read_is_exact::<u16>(&bytes);
+        assert_read_is_exact::<u32>(&bytes);
+        assert_read_is_exact::<i32>(&bytes);
+        assert_read_is_exact::<u64>(&bytes);
+        assert_read_is_exact::<[u8; 16]>(&bytes);
+        assert_read_is_exact::<Stratum>(&bytes);
+        assert_read_is_exact::<Poll>(&bytes);
+        assert_read_is_exact::<Precision>(&bytes);
+        assert_read_is_exact::<RefId>(&bytes);
+        assert_read_is_exact::<NtpShort>(&bytes);
+        assert_read_is_exact::<NtpTimestamp>(&bytes);
+        assert_read_is_exact::<NtpPacketHeader>(&bytes);
+    }
+}
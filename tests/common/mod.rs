@@ -0,0 +1,28 @@
+//! Helpers shared by the integration tests
+
+use std::time::{Duration, Instant};
+
+use demo_ntp::{
+    clock::{linux::LinuxClock, system_time, Clock},
+    daemon::Daemon,
+    error::NtpError,
+    transport::Transport,
+};
+
+/// Polls the associations of `daemon` `polls` times, `interval` apart, and
+/// returns the offset of its clock from the system clock, in seconds. The root
+/// distance of a server is below the limit once its filter has a few samples.
+pub fn run_daemon<T: Transport>(
+    daemon: &mut Daemon<LinuxClock, T>,
+    polls: u32,
+    interval: Duration,
+) -> f64
+where
+    NtpError: From<T::Error>,
+{
+    let start = Instant::now();
+    for poll in 0..polls {
+        daemon.poll_due(start + interval * poll).unwrap();
+    }
+    daemon.clock().now().unwrap().diff_seconds(&system_time())
+}
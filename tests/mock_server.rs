@@ -0,0 +1,241 @@
+#![cfg(feature = "testing")]
+
+#[cfg(target_os = "linux")]
+mod common;
+
+use std::{io, net::SocketAddr};
+
+use demo_ntp::{
+    association::{Association, AssociationConfig, Rejection},
+    client::{ClientError, NtpClient, NtpClientBuilder},
+    codec::CodecErrorKind,
+    testing::{MockConfig, MockReply, MockServer},
+    types::{Leap, NtpShort, Stratum, NTP_KISS_RATE},
+};
+
+const SERVER: &str = "192.0.2.1:123";
+
+const CONFIG: AssociationConfig = AssociationConfig {
+    minpoll: 4,
+    maxpoll: 10,
+    burst: false,
+    iburst: false,
+};
+
+fn client(config: MockConfig) -> (MockServer, NtpClient<MockServer>) {
+    let server = MockServer::new(SERVER.parse().unwrap(), config);
+    let client = NtpClientBuilder::new(server.clone(), SERVER)
+        .build()
+        .unwrap();
+    (server, client)
+}
+
+fn association(server: &MockServer, client: NtpClient<MockServer>) -> Association<MockServer> {
+    Association::new(
+        server.address().to_string(),
+        server.address(),
+        client,
+        CONFIG,
+    )
+}
+
+#[test]
+fn client_measures_offset_and_delay() {
+    let (_, mut client) = client(MockConfig {
+        offset: 0.25,
+        forward_delay: 0.010,
+        backward_delay: 0.030,
+        ..MockConfig::default()
+    });
+
+    let sample = client.query().unwrap();
+
+    // Asymmetry biases the offset by half the difference of the delays
+    assert!((sample.offset - 0.24).abs() < 1e-6);
+    assert!((sample.delay - 0.04).abs() < 1e-6);
+    assert_eq!(sample.source, SERVER.parse::<SocketAddr>().unwrap());
+}
+
+#[test]
+fn client_reports_the_leap_indicator_and_stratum() {
+    let (_, mut client) = client(MockConfig {
+        leap: Leap::LastMinuteHas61Seconds,
+        stratum: Stratum::from(3),
+        ..MockConfig::default()
+    });
+
+    let sample = client.query().unwrap();
+
+    assert_eq!(sample.leap(), Leap::LastMinuteHas61Seconds);
+    assert_eq!(sample.stratum(), Stratum::from(3));
+}
+
+#[test]
+fn client_fails_on_scripted_replies() {
+    let (server, mut client) = client(MockConfig::default());
+    server.push(MockReply::Lose);
+    server.push(MockReply::Kiss(NTP_KISS_RATE));
+    server.push(MockReply::Malformed(vec![0x23; 20]));
+
+    assert!(matches!(
+        client.query(),
+        Err(ClientError::Transport(error)) if error.kind() == io::ErrorKind::WouldBlock
+    ));
+    assert!(matches!(
+        client.query(),
+        Err(ClientError::KissOfDeath(code)) if code == NTP_KISS_RATE
+    ));
+    assert!(matches!(
+        client.query(),
+        Err(ClientError::Codec(error))
+            if matches!(error.kind, CodecErrorKind::BufferTooSmall { .. })
+    ));
+    assert!(client.query().is_ok());
+    assert_eq!(server.requests(), 4);
+}
+
+#[test]
+fn jitter_and_losses_repeat_with_the_seed() {
+    let config = MockConfig {
+        jitter: 0.1,
+        loss: 0.3,
+        seed: 42,
+        ..MockConfig::default()
+    };
+    let run = || {
+        let (_, mut client) = client(config);
+        (0..50)
+            .map(|_| client.query().ok().map(|sample| sample.delay))
+            .collect::<Vec<_>>()
+    };
+
+    let first = run();
+
+    assert_eq!(first, run());
+    let lost = first.iter().filter(|delay| delay.is_none()).count();
+    assert!((5..=25).contains(&lost));
+    assert!(first
+        .iter()
+        .flatten()
+        .all(|delay| (0.0..0.2).contains(delay)));
+}
+
+#[test]
+fn association_tracks_reach_and_kisses() {
+    let (server, client) = client(MockConfig::default());
+    let mut association = association(&server, client);
+    server.push(MockReply::Answer);
+    server.push(MockReply::Lose);
+    server.push(MockReply::Kiss(NTP_KISS_RATE));
+
+    for _ in 0..4 {
+        let _ = association.poll_server();
+    }
+
+    assert_eq!(association.reach(), 0b1001);
+    assert_eq!(association.kisses(), 1);
+    // The RATE kiss asked for fewer requests
+    assert_eq!(association.poll(), 5);
+}
+
+#[test]
+fn association_rejects_unusable_servers() {
+    let cases = [
+        (
+            MockConfig {
+                leap: Leap::Unknown,
+                ..MockConfig::default()
+            },
+            Rejection::Unsynchronized,
+        ),
+        (
+            MockConfig {
+                stratum: Stratum::from(16),
+                ..MockConfig::default()
+            },
+            Rejection::Stratum,
+        ),
+        (
+            MockConfig {
+                reftime_age: 2.0 * 86400.0,
+                ..MockConfig::default()
+            },
+            Rejection::StaleReference,
+        ),
+        (
+            MockConfig {
+                rootdisp: NtpShort::from_seconds(2.0),
+                ..MockConfig::default()
+            },
+            Rejection::Distance,
+        ),
+    ];
+    for (config, reason) in cases {
+        let (server, client) = client(config);
+        let mut association = association(&server, client);
+
+        for _ in 0..8 {
+            assert_eq!(association.poll_server().unwrap(), None);
+        }
+
+        assert_eq!(association.rejection(), Some(reason));
+        assert_eq!(association.rejections(), 8);
+        assert!(association.candidate().is_none());
+    }
+}
+
+#[test]
+fn association_accepts_a_server_once_its_distance_is_low() {
+    let (server, client) = client(MockConfig::default());
+    let mut association = association(&server, client);
+
+    // Empty filter stages count with the maximum dispersion
+    for _ in 0..3 {
+        assert_eq!(association.poll_server().unwrap(), None);
+        assert_eq!(association.rejection(), Some(Rejection::Distance));
+    }
+    assert!(association.poll_server().unwrap().is_some());
+
+    assert_eq!(association.rejection(), None);
+    assert!(association.root_distance().unwrap() < 1.5);
+    assert!(association.candidate().is_some());
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn daemon_steps_to_mock_servers() {
+    use std::time::Duration;
+
+    use demo_ntp::{
+        clock::linux::LinuxClock,
+        daemon::Daemon,
+        leap::{LeapPolicy, LeapSeconds},
+    };
+
+    let associations = (1..=3)
+        .map(|host| {
+            let address = SocketAddr::from(([192, 0, 2, host], 123));
+            let server = MockServer::new(
+                address,
+                MockConfig {
+                    offset: 1.0,
+                    forward_delay: 0.001 * f64::from(host),
+                    backward_delay: 0.001 * f64::from(host),
+                    ..MockConfig::default()
+                },
+            );
+            let client = NtpClientBuilder::new(server.clone(), address.to_string())
+                .build()
+                .unwrap();
+            association(&server, client)
+        })
+        .collect();
+    let clock = LinuxClock::dry_run(libc::CLOCK_REALTIME).unwrap();
+    let mut daemon = Daemon::new(clock, LeapSeconds::new(LeapPolicy::Step));
+    daemon.set_associations(associations);
+
+    let clock_offset = common::run_daemon(&mut daemon, 4, Duration::from_secs(64));
+
+    assert!((clock_offset - 1.0).abs() < 0.01);
+    assert_eq!(daemon.state().stratum, Stratum::from(2));
+}
@@ -1,13 +1,110 @@
-use std::net::UdpSocket;
+#![cfg(feature = "std")]
 
-use demo_ntp::client::NtpClientBuilder;
+use std::{net::UdpSocket, thread, time::Duration};
+
+use demo_ntp::{
+    client::{ClientError, NtpClientBuilder},
+    ntp_message_protocol::NtpPacketViewMut,
+    transport::{ChannelTransport, Transport},
+    types::{Mode, RefId, Stratum},
+};
 
 #[test]
 fn get_offset_from_ntp_client() {
-    let udp_socket = UdpSocket::bind("0.0.0.0:0").unwrap();
-    let ntp_client = NtpClientBuilder::new(udp_socket, "pool.ntp.org:123")
+    let server_address = "192.0.2.1:123".parse().unwrap();
+    let (mut server, client) =
+        ChannelTransport::pair(server_address, "192.0.2.2:50000".parse().unwrap());
+    let server_thread = thread::spawn(move || {
+        let mut buffer = [0u8; 48];
+        let received = server.recv_from(&mut buffer).unwrap();
+        let mut packet = NtpPacketViewMut::new(&mut buffer).unwrap();
+        let xmt = packet.as_view().xmt();
+        packet.set_mode(Mode::Server);
+        packet.set_stratum(Stratum::from(1));
+        packet.set_org(xmt);
+        packet.set_rec(xmt.add_seconds(3.5));
+        packet.set_xmt(xmt.add_seconds(3.5));
+        server.send_to(&buffer, received.source).unwrap();
+    });
+
+    let mut ntp_client = NtpClientBuilder::new(client, server_address.to_string())
+        .timeout(Duration::from_secs(5))
+        .build()
+        .unwrap();
+    let offset = ntp_client.get_offset();
+    server_thread.join().unwrap();
+
+    assert_eq!(offset.unwrap(), 3);
+}
+
+#[test]
+fn get_offset_reports_kiss_o_death() {
+    let server_address = "192.0.2.1:123".parse().unwrap();
+    let (mut server, client) =
+        ChannelTransport::pair(server_address, "192.0.2.2:50000".parse().unwrap());
+    let server_thread = thread::spawn(move || {
+        let mut buffer = [0u8; 48];
+        let received = server.recv_from(&mut buffer).unwrap();
+        let mut packet = NtpPacketViewMut::new(&mut buffer).unwrap();
+        let xmt = packet.as_view().xmt();
+        packet.set_mode(Mode::Server);
+        packet.set_stratum(Stratum::from(0));
+        packet.set_refid(RefId::from(*b"RATE"));
+        packet.set_org(xmt);
+        server.send_to(&buffer, received.source).unwrap();
+    });
+
+    let mut ntp_client = NtpClientBuilder::new(client, server_address.to_string())
+        .timeout(Duration::from_secs(5))
         .build()
         .unwrap();
     let offset = ntp_client.get_offset();
-    println!("Clock offset: {}", offset);
+    server_thread.join().unwrap();
+
+    assert!(matches!(
+        offset,
+        Err(ClientError::KissOfDeath(code)) if code == RefId::from(*b"RATE")
+    ));
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn query_local_server() {
+    use demo_ntp::{
+        clock::{linux::LinuxClock, Clock},
+        server::{NtpServerBuilder, ServerState},
+        types::Leap,
+    };
+
+    let mut clock = LinuxClock::dry_run(libc::CLOCK_REALTIME).unwrap();
+    clock.step(2.5).unwrap();
+    let server_socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let server_address = server_socket.local_addr().unwrap();
+    let mut server = NtpServerBuilder::new(server_socket, clock)
+        .state(ServerState {
+            leap: Leap::NoWarning,
+            stratum: Stratum::from(1),
+            refid: RefId::from(*b"GPS\0"),
+            ..ServerState::default()
+        })
+        .build()
+        .unwrap();
+    let server_thread = thread::spawn(move || server.serve_one().unwrap());
+
+    let mut ntp_client = NtpClientBuilder::new(
+        UdpSocket::bind("127.0.0.1:0").unwrap(),
+        server_address.to_string(),
+    )
+    .timeout(Duration::from_secs(5))
+    .build()
+    .unwrap();
+    let sample = ntp_client.query().unwrap();
+    server_thread.join().unwrap();
+
+    assert!((sample.offset - 2.5).abs() < 0.1);
+    assert!(sample.delay >= 0.0 && sample.delay < 0.1);
+    assert_eq!(sample.source, server_address);
+    assert_eq!(sample.stratum(), Stratum::from(1));
+    assert_eq!(sample.refid(), RefId::from(*b"GPS\0"));
+    assert_eq!(sample.leap(), Leap::NoWarning);
 }
@@ -0,0 +1,108 @@
+#![cfg(all(target_os = "linux", feature = "std"))]
+
+mod common;
+
+use std::{net::UdpSocket, thread, time::Duration};
+
+use demo_ntp::{
+    clock::{linux::LinuxClock, Clock},
+    config::Config,
+    daemon::{associations_from_config, Daemon},
+    leap::{LeapPolicy, LeapSeconds},
+    server::{NtpServerBuilder, ServerState},
+    types::{Leap, Precision, RefId, Stratum},
+};
+
+#[test]
+fn daemon_steps_to_local_servers() {
+    let mut servers = Vec::new();
+    let mut addresses = Vec::new();
+    for _ in 0..3 {
+        let mut clock = LinuxClock::dry_run(libc::CLOCK_REALTIME).unwrap();
+        clock.step(1.0).unwrap();
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        addresses.push(socket.local_addr().unwrap());
+        let reftime = clock.now().unwrap();
+        servers.push(
+            NtpServerBuilder::new(socket, clock)
+                .state(ServerState {
+                    leap: Leap::NoWarning,
+                    stratum: Stratum::from(1),
+                    refid: RefId::from(*b"GPS\0"),
+                    precision: Precision::from(-20),
+                    reftime,
+                    ..ServerState::default()
+                })
+                .keys(vec![Config::from_toml(KEYS).unwrap().key(1).unwrap()])
+                .build()
+                .unwrap(),
+        );
+    }
+    let server_threads = servers
+        .into_iter()
+        .map(|mut server| {
+            thread::spawn(move || {
+                for _ in 0..POLLS {
+                    server.serve_one().unwrap();
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let config = Config::from_toml(&format!(
+        "{KEYS}
+[[server]]
+address = \"{}\"
+key = 1
+
+[[server]]
+address = \"{}\"
+
+[[server]]
+address = \"{}\"
+",
+        addresses[0], addresses[1], addresses[2]
+    ))
+    .unwrap();
+    let (associations, errors) = associations_from_config(&config);
+    assert!(errors.is_empty());
+
+    let clock = LinuxClock::dry_run(libc::CLOCK_REALTIME).unwrap();
+    let mut daemon = Daemon::new(clock, LeapSeconds::new(LeapPolicy::Step)).with_statistics();
+    daemon.set_associations(associations);
+    let clock_offset = common::run_daemon(&mut daemon, POLLS.into(), Duration::from_secs(3600));
+    for thread in server_threads {
+        thread.join().unwrap();
+    }
+
+    assert!(daemon
+        .associations()
+        .iter()
+        .all(|association| association.reach() == 0b1111));
+    assert!((clock_offset - 1.0).abs() < 0.1);
+
+    let state = daemon.state();
+    assert_eq!(state.stratum, Stratum::from(2));
+    assert_eq!(state.leap, Leap::NoWarning);
+    assert!(addresses
+        .iter()
+        .any(|address| state.refid == RefId::from(address.ip())));
+
+    let peer_stats = daemon.take_peer_stats();
+    // Recorded when the filter selects a new sample
+    assert!((3..=3 * usize::from(POLLS)).contains(&peer_stats.len()));
+    assert!(peer_stats
+        .iter()
+        .all(|record| (record.offset - 1.0).abs() < 0.1 && record.address == "127.0.0.1"));
+    assert_eq!(daemon.take_loop_stats().len(), 1);
+    assert!(daemon.take_peer_stats().is_empty());
+}
+
+const POLLS: u8 = 4;
+
+const KEYS: &str = "
+[[key]]
+id = 1
+algorithm = \"sha1\"
+secret = \"secret\"
+";
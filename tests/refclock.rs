@@ -0,0 +1,150 @@
+#![cfg(all(target_os = "linux", feature = "std"))]
+
+mod common;
+
+use std::time::Duration;
+
+use demo_ntp::{
+    association::{Association, AssociationConfig, Rejection},
+    clock::{linux::LinuxClock, system_time},
+    daemon::Daemon,
+    error::NtpResult,
+    leap::{LeapPolicy, LeapSeconds},
+    refclock::{refclock_address, RefClock, RefClockSample},
+    types::{Leap, Precision, RefId, Stratum},
+};
+
+const CONFIG: AssociationConfig = AssociationConfig {
+    minpoll: 4,
+    maxpoll: 4,
+    burst: false,
+    iburst: false,
+};
+
+/// Reference a constant offset away from the system clock, which has a sample
+/// at every other poll when `gaps` is set. With `pulse`, it is a more precise
+/// pulse per second that does not name the second.
+struct ScriptedRefClock {
+    offset: f64,
+    leap: Leap,
+    gaps: bool,
+    pulse: bool,
+    polls: u32,
+}
+
+impl ScriptedRefClock {
+    fn new(offset: f64) -> Self {
+        Self {
+            offset,
+            leap: Leap::NoWarning,
+            gaps: false,
+            pulse: false,
+            polls: 0,
+        }
+    }
+}
+
+impl RefClock for ScriptedRefClock {
+    fn refid(&self) -> RefId {
+        if self.pulse {
+            RefId::from(*b"PPS\0")
+        } else {
+            RefId::from(*b"GPS\0")
+        }
+    }
+
+    fn precision(&self) -> Precision {
+        Precision::from(if self.pulse { -20 } else { -10 })
+    }
+
+    fn pulse_only(&self) -> bool {
+        self.pulse
+    }
+
+    fn sample(&mut self) -> NtpResult<Option<RefClockSample>> {
+        self.polls += 1;
+        if self.gaps && self.polls.is_multiple_of(2) {
+            return Ok(None);
+        }
+        Ok(Some(RefClockSample {
+            offset: self.offset,
+            time: system_time(),
+            leap: self.leap,
+            dispersion: 0.0,
+        }))
+    }
+}
+
+fn association(refclock: ScriptedRefClock) -> Association {
+    Association::with_refclock(
+        "GPS(0)",
+        refclock_address(20, 0),
+        Box::new(refclock),
+        CONFIG,
+    )
+}
+
+#[test]
+fn polls_without_a_sample_are_missed() {
+    let mut association = association(ScriptedRefClock {
+        gaps: true,
+        ..ScriptedRefClock::new(0.0)
+    });
+
+    for _ in 0..4 {
+        let _ = association.poll_server();
+    }
+
+    assert_eq!(association.reach(), 0b1010);
+    let sample = association.last_sample().unwrap();
+    assert_eq!(sample.stratum(), Stratum::from(0));
+    assert_eq!(sample.refid(), RefId::from(*b"GPS\0"));
+    assert_eq!(sample.source.to_string(), "127.127.20.0:0");
+}
+
+#[test]
+fn reference_without_a_fix_is_rejected() {
+    let mut association = association(ScriptedRefClock {
+        leap: Leap::Unknown,
+        ..ScriptedRefClock::new(0.0)
+    });
+
+    assert_eq!(association.poll_server().unwrap(), None);
+    assert_eq!(association.rejection(), Some(Rejection::Unsynchronized));
+}
+
+#[test]
+fn daemon_becomes_a_primary_server() {
+    let clock = LinuxClock::dry_run(libc::CLOCK_REALTIME).unwrap();
+    let mut daemon = Daemon::new(clock, LeapSeconds::new(LeapPolicy::Step));
+    daemon.set_associations(vec![association(ScriptedRefClock::new(1.0))]);
+
+    let clock_offset = common::run_daemon(&mut daemon, 4, Duration::from_secs(64));
+
+    assert!((clock_offset - 1.0).abs() < 0.01);
+    let state = daemon.state();
+    assert_eq!(state.stratum, Stratum::from(1));
+    assert_eq!(state.refid, RefId::from(*b"GPS\0"));
+}
+
+#[test]
+fn pulses_need_another_source_to_name_the_second() {
+    let pulse = || {
+        association(ScriptedRefClock {
+            pulse: true,
+            ..ScriptedRefClock::new(0.2)
+        })
+    };
+    let clock = LinuxClock::dry_run(libc::CLOCK_REALTIME).unwrap();
+    let mut daemon = Daemon::new(clock, LeapSeconds::new(LeapPolicy::Step));
+
+    daemon.set_associations(vec![pulse()]);
+    common::run_daemon(&mut daemon, 4, Duration::from_secs(64));
+    assert!(!daemon.is_synchronized());
+
+    // The more precise pulse becomes the system peer
+    daemon.set_associations(vec![association(ScriptedRefClock::new(0.2)), pulse()]);
+    common::run_daemon(&mut daemon, 4, Duration::from_secs(64));
+    assert!(daemon.is_synchronized());
+    assert_eq!(daemon.state().refid, RefId::from(*b"PPS\0"));
+}
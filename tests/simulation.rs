@@ -0,0 +1,160 @@
+#![cfg(feature = "testing")]
+
+//! Accuracy of the daemon in simulated conditions. The bounds leave some
+//! margin over the results when they were set, so a change that makes them
+//! fail made the filter, the selection or the discipline worse.
+
+use std::time::Duration;
+
+use demo_ntp::testing::simulation::{
+    ClockModel, Delay, SimulatedServer, Simulation, SimulationConfig, SimulationReport,
+};
+
+const HOUR: Duration = Duration::from_secs(3600);
+
+/// Server on the local network, with a few milliseconds of delay each way
+fn lan_server() -> SimulatedServer {
+    SimulatedServer {
+        forward: Delay::Uniform {
+            min: 0.002,
+            max: 0.006,
+        },
+        backward: Delay::Uniform {
+            min: 0.002,
+            max: 0.006,
+        },
+        ..SimulatedServer::default()
+    }
+}
+
+/// Three servers and a local clock 50 ms and 20 ppm off, polled every 64 s
+fn config() -> SimulationConfig {
+    SimulationConfig {
+        local: ClockModel {
+            offset: 0.05,
+            drift: 20.0,
+            wander: 0.001,
+            ..ClockModel::default()
+        },
+        servers: vec![lan_server(), lan_server(), lan_server()],
+        minpoll: 6,
+        maxpoll: 6,
+        ..SimulationConfig::default()
+    }
+}
+
+/// Runs a day and reports on the second half
+fn run_day(config: SimulationConfig) -> (Simulation, SimulationReport) {
+    let mut simulation = Simulation::new(config);
+    simulation.run(24 * HOUR).unwrap();
+    let report = simulation.report(12 * HOUR);
+    (simulation, report)
+}
+
+#[test]
+fn converges_on_a_quiet_network() {
+    let (simulation, report) = run_day(config());
+
+    assert!(report.rms_error < 0.0005, "{report:?}");
+    assert!(report.max_error < 0.0015, "{report:?}");
+    assert!(report.frequency_error.abs() < 0.5, "{report:?}");
+    assert!(simulation.convergence_time(0.01).unwrap() < HOUR);
+    assert!(simulation.convergence_time(0.001).unwrap() < 3 * HOUR);
+}
+
+#[test]
+fn steps_a_large_initial_offset() {
+    let mut config = config();
+    config.local.offset = 2.0;
+    let (simulation, report) = run_day(config);
+
+    // Stepped once the filter holds enough samples for the root distance
+    assert!(simulation.convergence_time(0.01).unwrap() < Duration::from_secs(5 * 64));
+    assert!(report.rms_error < 0.0005, "{report:?}");
+}
+
+#[test]
+fn outvotes_a_server_stepping_away() {
+    let mut config = config();
+    config.servers.push(SimulatedServer {
+        clock: ClockModel {
+            steps: vec![(2 * HOUR, 1.0)],
+            ..ClockModel::default()
+        },
+        ..lan_server()
+    });
+    let (_, report) = run_day(config);
+
+    assert!(report.rms_error < 0.0005, "{report:?}");
+    assert!(report.max_error < 0.0015, "{report:?}");
+}
+
+#[test]
+fn filters_queuing_delays() {
+    let mut config = config();
+    for server in &mut config.servers {
+        server.forward = Delay::Exponential {
+            min: 0.002,
+            mean: 0.01,
+        };
+        server.backward = server.forward;
+    }
+    let (_, report) = run_day(config);
+
+    assert!(report.rms_error < 0.0015, "{report:?}");
+    assert!(report.max_error < 0.005, "{report:?}");
+}
+
+#[test]
+fn tolerates_packet_loss() {
+    let mut config = config();
+    for server in &mut config.servers {
+        server.loss = 0.2;
+    }
+    let (_, report) = run_day(config);
+
+    assert!(report.rms_error < 0.0006, "{report:?}");
+}
+
+#[test]
+fn limits_the_error_of_an_asymmetric_path() {
+    let mut config = config();
+    config.servers[0].forward = Delay::Constant(0.02);
+    let (_, report) = run_day(config);
+
+    assert!(report.rms_error < 0.0015, "{report:?}");
+    assert!(report.max_error < 0.005, "{report:?}");
+}
+
+#[test]
+fn lengthens_the_poll_interval_once_stable() {
+    let config = SimulationConfig {
+        minpoll: SimulationConfig::default().minpoll,
+        maxpoll: SimulationConfig::default().maxpoll,
+        iburst: true,
+        ..config()
+    };
+    let (simulation, report) = run_day(config);
+
+    assert!(report.rms_error < 0.002, "{report:?}");
+    assert!(report.max_error < 0.004, "{report:?}");
+    assert!(simulation
+        .daemon()
+        .associations()
+        .iter()
+        .all(|association| association.poll() == 10));
+}
+
+#[test]
+fn repeats_with_the_seed() {
+    let mut config = config();
+    config.servers[0].loss = 0.1;
+    config.seed = 7;
+    let mut first = Simulation::new(config.clone());
+    let mut second = Simulation::new(config);
+
+    first.run(6 * HOUR).unwrap();
+    second.run(6 * HOUR).unwrap();
+
+    assert_eq!(first.trace(), second.trace());
+}